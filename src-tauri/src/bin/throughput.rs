@@ -0,0 +1,36 @@
+//! Pipeline throughput on loopback with synthetic frames, no screen or second machine needed:
+//! `cargo run --release --bin throughput [frames]`
+
+use screenshare_udp_native_lib::broadcast::bench::{run_conversion, run_throughput, ThroughputCase};
+
+const DEFAULT_FRAMES: u32 = 300;
+const RESOLUTIONS: [(u32, u32); 3] = [(1280, 720), (1920, 1080), (2560, 1440)];
+const BITRATES_KBPS: [u32; 3] = [2000, 5000, 10000];
+/// Sizes around the encoder's parallel conversion threshold (1080p)
+const CONVERSION_SIZES: [(u32, u32); 4] = [(1280, 720), (1600, 900), (1920, 1080), (3840, 2160)];
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let frames = std::env::args().nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_FRAMES);
+
+    println!("{} frames per run", frames);
+    for (width, height) in RESOLUTIONS {
+        for bitrate_kbps in BITRATES_KBPS {
+            match run_throughput(ThroughputCase { width, height, bitrate_kbps, frames }) {
+                Ok(report) => println!("{}", report.to_text()),
+                Err(e) => println!("{}x{} {} kbps: failed: {}", width, height, bitrate_kbps, e),
+            }
+        }
+    }
+
+    println!("RGB to YUV conversion, {} frames per size", frames);
+    for (width, height) in CONVERSION_SIZES {
+        match run_conversion(width, height, frames) {
+            Ok(report) => println!("{}", report.to_text()),
+            Err(e) => println!("{}x{} conversion: failed: {}", width, height, e),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Loss-driven degradation: steps the teacher's bitrate, then resolution, then frame rate
+//! down while students report sustained packet loss, and back up once it clears
+
+use std::fmt;
+
+use super::types::RateControl;
+
+/// Loss fraction above which a report counts as congested
+const HIGH_LOSS: f32 = 0.05;
+/// Loss fraction below which a report counts as clear; in between the level holds
+const LOW_LOSS: f32 = 0.01;
+/// Congested reports in a row before stepping down
+const DOWN_AFTER: u32 = 3;
+/// Clear reports in a row before stepping up, much longer than `DOWN_AFTER` so the
+/// controller does not bounce between two levels
+const UP_AFTER: u32 = 10;
+/// Smallest encoded width or height
+const MIN_DIMENSION: u32 = 160;
+
+/// One rung of the degradation ladder, relative to the configured stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveStep {
+    pub bitrate_scale: f32,
+    pub resolution_scale: f32,
+    pub fps_scale: f32,
+}
+
+/// Bitrate first, then resolution, then frame rate
+const LADDER: [AdaptiveStep; 6] = [
+    AdaptiveStep { bitrate_scale: 1.0, resolution_scale: 1.0, fps_scale: 1.0 },
+    AdaptiveStep { bitrate_scale: 0.7, resolution_scale: 1.0, fps_scale: 1.0 },
+    AdaptiveStep { bitrate_scale: 0.5, resolution_scale: 1.0, fps_scale: 1.0 },
+    AdaptiveStep { bitrate_scale: 0.5, resolution_scale: 0.75, fps_scale: 1.0 },
+    AdaptiveStep { bitrate_scale: 0.5, resolution_scale: 0.5, fps_scale: 1.0 },
+    AdaptiveStep { bitrate_scale: 0.5, resolution_scale: 0.5, fps_scale: 0.5 },
+];
+
+impl AdaptiveStep {
+    /// Encoded size for a `width`x`height` capture, kept even for 4:2:0
+    pub fn scale_size(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.resolution_scale >= 1.0 {
+            return (width, height);
+        }
+        let scale = |d: u32| ((d as f32 * self.resolution_scale) as u32).max(MIN_DIMENSION.min(d)) & !1;
+        (scale(width), scale(height))
+    }
+
+    pub fn scale_fps(&self, fps: u32) -> u32 {
+        ((fps as f32 * self.fps_scale).round() as u32).max(1)
+    }
+
+    /// Lower the target bitrate, or raise the QP in constant quality mode
+    pub fn scale_rate_control(&self, rate_control: RateControl) -> RateControl {
+        match rate_control {
+            RateControl::Bitrate(kbps) => RateControl::Bitrate((kbps as f32 * self.bitrate_scale) as u32),
+            // Each +6 QP roughly halves the bitrate
+            RateControl::Quality(qp) => {
+                let raise = (-6.0 * self.bitrate_scale.log2()).round() as u32;
+                RateControl::Quality((qp + raise).min(51))
+            }
+        }
+    }
+}
+
+impl fmt::Display for AdaptiveStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.0}% bitrate, {:.0}% resolution, {:.0}% fps",
+            self.bitrate_scale * 100.0, self.resolution_scale * 100.0, self.fps_scale * 100.0)
+    }
+}
+
+/// Walks `LADDER` from the packet loss students report, with hysteresis
+pub struct AdaptiveController {
+    level: usize,
+    congested: u32,
+    clear: u32,
+}
+
+impl AdaptiveController {
+    pub fn new() -> Self {
+        Self {
+            level: 0,
+            congested: 0,
+            clear: 0,
+        }
+    }
+
+    /// Current rung, 0 = full quality
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub fn step(&self) -> AdaptiveStep {
+        LADDER[self.level]
+    }
+
+    /// Feed the loss fraction (0..=1) of one report interval.
+    /// Returns the new step when the level changes.
+    pub fn update(&mut self, loss: f32) -> Option<AdaptiveStep> {
+        if loss > HIGH_LOSS {
+            self.congested += 1;
+            self.clear = 0;
+        } else if loss < LOW_LOSS {
+            self.clear += 1;
+            self.congested = 0;
+        } else {
+            self.congested = 0;
+            self.clear = 0;
+        }
+
+        if self.congested >= DOWN_AFTER && self.level + 1 < LADDER.len() {
+            self.level += 1;
+            self.congested = 0;
+            return Some(self.step());
+        }
+        if self.clear >= UP_AFTER && self.level > 0 {
+            self.level -= 1;
+            self.clear = 0;
+            return Some(self.step());
+        }
+        None
+    }
+}
+
+impl Default for AdaptiveController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `loss` `count` times, returning the level changes it caused
+    fn feed(controller: &mut AdaptiveController, loss: f32, count: u32) -> Vec<AdaptiveStep> {
+        (0..count).filter_map(|_| controller.update(loss)).collect()
+    }
+
+    #[test]
+    fn steps_down_after_sustained_loss_and_up_after_longer_clear() {
+        let mut controller = AdaptiveController::new();
+        
+        // One report short of DOWN_AFTER holds; the next one steps down
+        assert!(feed(&mut controller, 0.2, DOWN_AFTER - 1).is_empty());
+        assert_eq!(controller.update(0.2), Some(LADDER[1]));
+        
+        // Clear reports step back up only after UP_AFTER of them
+        assert!(feed(&mut controller, 0.0, UP_AFTER - 1).is_empty());
+        assert_eq!(controller.update(0.0), Some(LADDER[0]));
+        assert_eq!(controller.level(), 0);
+        
+        // Already at full quality: clear reports change nothing
+        assert!(feed(&mut controller, 0.0, UP_AFTER * 2).is_empty());
+    }
+
+    #[test]
+    fn thresholds_are_exclusive_and_in_between_holds() {
+        let mut controller = AdaptiveController::new();
+        
+        // Exactly 5% is not congestion
+        assert!(feed(&mut controller, HIGH_LOSS, DOWN_AFTER * 3).is_empty());
+        assert_eq!(controller.level(), 0);
+        assert_eq!(feed(&mut controller, 0.051, DOWN_AFTER).len(), 1);
+        
+        // Exactly 1% is not clear either
+        assert!(feed(&mut controller, LOW_LOSS, UP_AFTER * 3).is_empty());
+        assert_eq!(controller.level(), 1);
+        
+        // A report in between restarts the count in both directions
+        assert!(feed(&mut controller, 0.2, DOWN_AFTER - 1).is_empty());
+        assert!(controller.update(0.03).is_none());
+        assert!(feed(&mut controller, 0.2, DOWN_AFTER - 1).is_empty());
+        assert!(feed(&mut controller, 0.005, UP_AFTER - 1).is_empty());
+        assert!(controller.update(0.03).is_none());
+        assert!(feed(&mut controller, 0.005, UP_AFTER - 1).is_empty());
+        assert_eq!(controller.level(), 1);
+    }
+
+    #[test]
+    fn ladder_lowers_bitrate_then_resolution_then_fps() {
+        let mut controller = AdaptiveController::new();
+        let steps = feed(&mut controller, 1.0, DOWN_AFTER * 20);
+        assert_eq!(steps, LADDER[1..]);
+        assert_eq!(controller.level(), LADDER.len() - 1);
+        
+        // Each rung lowers one thing at a time, never raising another
+        let mut previous = LADDER[0];
+        let mut lowered = Vec::new();
+        for step in &LADDER[1..] {
+            let changes = [
+                step.bitrate_scale < previous.bitrate_scale,
+                step.resolution_scale < previous.resolution_scale,
+                step.fps_scale < previous.fps_scale,
+            ];
+            assert!(step.bitrate_scale <= previous.bitrate_scale
+                && step.resolution_scale <= previous.resolution_scale
+                && step.fps_scale <= previous.fps_scale, "{} after {}", step, previous);
+            assert_eq!(changes.iter().filter(|&&c| c).count(), 1, "{} after {}", step, previous);
+            lowered.push(changes.iter().position(|&c| c).unwrap());
+            previous = *step;
+        }
+        assert!(lowered.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", lowered);
+        
+        // Sizes stay even; the frame rate never reaches 0
+        let bottom = LADDER[LADDER.len() - 1];
+        assert_eq!(bottom.scale_size(1920, 1080), (960, 540));
+        assert_eq!(bottom.scale_fps(1), 1);
+        assert_eq!(bottom.scale_rate_control(RateControl::Quality(24)), RateControl::Quality(30));
+    }
+}
@@ -0,0 +1,159 @@
+//! Throughput benchmark of the whole pipeline on loopback, fed by a synthetic source
+//! synthetic frame -> encode -> packetize -> send/receive on localhost -> depacketize -> decode,
+//! and timing of the RGB to YUV conversion alone
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::decoder::H264Decoder;
+use super::encoder::{H264Encoder, OpenH264Encoder};
+use super::network::{RtpReceiver, RtpSender};
+use super::session::ProcessCpuMonitor;
+use super::source::{FrameSource, SyntheticSource};
+use super::types::{BroadcastError, ColorSpace, H264Profile, RateControl, StreamConfig};
+
+/// How long one frame may take to come back on loopback before it counts as lost
+const FRAME_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// One benchmark run: stream size, bitrate and how many frames to push through
+#[derive(Debug, Clone, Copy)]
+pub struct ThroughputCase {
+    pub width: u32,
+    pub height: u32,
+    pub bitrate_kbps: u32,
+    pub frames: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThroughputReport {
+    pub width: u32,
+    pub height: u32,
+    pub bitrate_kbps: u32,
+    pub frames_decoded: u32,
+    pub frames_lost: u32,
+    /// Frames per second the pipeline sustained, one frame in flight at a time
+    pub fps: f32,
+    /// Bitrate actually produced by the encoder
+    pub actual_kbps: f32,
+    /// Process CPU over the run, in percent (0..=100 per core)
+    pub cpu_usage: f32,
+    /// Source frame to decoded frame
+    pub avg_latency_ms: f32,
+    pub max_latency_ms: f32,
+}
+
+impl ThroughputReport {
+    /// One line per run for the console
+    pub fn to_text(&self) -> String {
+        format!("{:>4}x{:<4} {:>6} kbps  {:>6.1} fps  {:>7.0} kbps out  cpu {:>5.0}%  latency avg/max {:>6.1}/{:>6.1} ms  lost {}",
+            self.width, self.height, self.bitrate_kbps, self.fps, self.actual_kbps, self.cpu_usage,
+            self.avg_latency_ms, self.max_latency_ms, self.frames_lost)
+    }
+}
+
+/// Push `case.frames` generated frames through the pipeline on loopback as fast as it goes
+pub fn run_throughput(case: ThroughputCase) -> Result<ThroughputReport, BroadcastError> {
+    let config = StreamConfig::default();
+    let mut source = SyntheticSource::new(case.width, case.height);
+    let (width, height) = source.dimensions();
+
+    let mut encoder = H264Encoder::new(width, height, config.fps, RateControl::Bitrate(case.bitrate_kbps), &config)?;
+    let mut decoder = H264Decoder::new(config.decoder_backend, config.color_space, config.full_range)?;
+
+    let receive_config = StreamConfig { port: 0, ..config.clone() };
+    let mut receiver = RtpReceiver::new(&receive_config)?;
+    let port = receiver.local_addr()?.port();
+    let mut sender = RtpSender::new(&config)?;
+    sender.set_target(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+
+    let mut cpu_monitor = ProcessCpuMonitor::new();
+    let mut frames_decoded = 0u32;
+    let mut frames_lost = 0u32;
+    let mut h264_bytes = 0u64;
+    let mut latency_sum = Duration::ZERO;
+    let mut max_latency = Duration::ZERO;
+    let start = Instant::now();
+
+    for _ in 0..case.frames {
+        let frame_start = Instant::now();
+        let Some(rgb) = source.capture_frame()? else { continue };
+        let (h264, _) = encoder.encode(&rgb)?;
+        if h264.is_empty() {
+            continue;
+        }
+        h264_bytes += h264.len() as u64;
+        sender.send_frame(&h264)?;
+
+        let mut received = None;
+        while received.is_none() && frame_start.elapsed() < FRAME_TIMEOUT {
+            received = receiver.receive_frame()?;
+        }
+        let decoded = match received {
+            Some(frame) => decoder.decode(&frame)?,
+            None => None,
+        };
+        if decoded.is_none() {
+            frames_lost += 1;
+            continue;
+        }
+
+        let latency = frame_start.elapsed();
+        frames_decoded += 1;
+        latency_sum += latency;
+        max_latency = max_latency.max(latency);
+    }
+
+    let elapsed = start.elapsed().as_secs_f32();
+    Ok(ThroughputReport {
+        width,
+        height,
+        bitrate_kbps: case.bitrate_kbps,
+        frames_decoded,
+        frames_lost,
+        fps: frames_decoded as f32 / elapsed,
+        actual_kbps: h264_bytes as f32 * 8.0 / 1000.0 / elapsed,
+        cpu_usage: cpu_monitor.sample(),
+        avg_latency_ms: latency_sum.as_secs_f32() * 1000.0 / frames_decoded.max(1) as f32,
+        max_latency_ms: max_latency.as_secs_f32() * 1000.0,
+    })
+}
+
+/// RGB to YUV conversion time per frame at one size, serial and in parallel bands
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversionReport {
+    pub width: u32,
+    pub height: u32,
+    /// Bands of the parallel run, one per rayon thread
+    pub bands: usize,
+    pub serial_ms: f32,
+    pub banded_ms: f32,
+}
+
+impl ConversionReport {
+    /// One line per size for the console
+    pub fn to_text(&self) -> String {
+        format!("{:>4}x{:<4} serial {:>6.2} ms  {:>2} bands {:>6.2} ms  speedup {:>4.1}x",
+            self.width, self.height, self.serial_ms, self.bands, self.banded_ms,
+            self.serial_ms / self.banded_ms.max(f32::EPSILON))
+    }
+}
+
+/// Convert the same `width`x`height` frame `frames` times serially, then in one band per thread
+pub fn run_conversion(width: u32, height: u32, frames: u32) -> Result<ConversionReport, BroadcastError> {
+    let rgb = SyntheticSource::new(width, height).capture_frame()?.unwrap_or_default();
+    let bands = rayon::current_num_threads();
+    let time = |threads: usize| -> Result<f32, BroadcastError> {
+        let mut encoder = OpenH264Encoder::new(width, height, 30, RateControl::Bitrate(5000),
+            ColorSpace::Bt709, false, H264Profile::Baseline)?.with_conversion_threads(threads as u32);
+        // Not timed: wakes the thread pool and pages in the buffers
+        encoder.rgb_to_yuv420_fast(&rgb);
+        let start = Instant::now();
+        for _ in 0..frames {
+            encoder.rgb_to_yuv420_fast(&rgb);
+        }
+        Ok(start.elapsed().as_secs_f32() * 1000.0 / frames.max(1) as f32)
+    };
+    Ok(ConversionReport { width, height, bands, serial_ms: time(1)?, banded_ms: time(bands)? })
+}
@@ -4,14 +4,36 @@ use std::time::{Duration, Instant};
 use parking_lot::Mutex;
 use std::sync::Arc;
 
-use super::types::BroadcastError;
+use super::types::{BroadcastError, CaptureRect};
+
+/// How often to try reopening the display while it is locked, asleep or lost
+const DISPLAY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Access-lost errors in a row, without a frame in between, before capture gives up
+const MAX_ACCESS_LOST: u32 = 10;
+/// Most buffered frames skipped in one `capture_frame` call with `drain_stale` on
+const MAX_DRAINED_FRAMES: u32 = 16;
 
 pub struct ScreenCapture {
     capturer: Arc<Mutex<Option<Capturer>>>,
+    /// Display size
     width: u32,
     height: u32,
+    /// Requested part of the screen, see `set_region`
+    region: Option<CaptureRect>,
+    /// `region` clamped to the display; `None` captures the whole display
+    crop: Option<CaptureRect>,
     last_capture: Instant,
     frame_interval: Duration,
+    /// Set while the display is unavailable: when to next try reopening it
+    retry_at: Option<Instant>,
+    /// Size change noticed while reopening, reported by `check_display_change`
+    pending_resize: Option<(u32, u32)>,
+    /// Consecutive DXGI access-lost errors since the last captured frame
+    access_lost_count: u32,
+    /// Skip frames the capturer buffered and return the newest, see `set_drain_stale`
+    drain_stale: bool,
+    /// BGRA copy of the newest frame while draining
+    drain_buffer: Vec<u8>,
 }
 
 impl ScreenCapture {
@@ -29,54 +51,257 @@ impl ScreenCapture {
             capturer: Arc::new(Mutex::new(Some(capturer))),
             width,
             height,
+            region: None,
+            crop: None,
             last_capture: Instant::now(),
-            frame_interval: Duration::from_millis(1000 / fps as u64),
+            frame_interval: frame_interval(fps),
+            retry_at: None,
+            pending_resize: None,
+            access_lost_count: 0,
+            drain_stale: false,
+            drain_buffer: Vec::new(),
         })
     }
 
+    /// Before returning a frame, pull every frame the capturer already has ready and keep
+    /// only the newest, so capturing below the display refresh rate does not lag behind
+    pub fn set_drain_stale(&mut self, enabled: bool) {
+        self.drain_stale = enabled;
+        if !enabled {
+            self.drain_buffer = Vec::new();
+        }
+    }
+
+    /// False while the display is locked, asleep or otherwise not capturable;
+    /// `capture_frame` then returns `Ok(None)` and retries about once a second
+    pub fn is_display_available(&self) -> bool {
+        self.retry_at.is_none()
+    }
+
+    /// Size of the frames returned, the capture region's when one is set
     pub fn dimensions(&self) -> (u32, u32) {
-        (self.width, self.height)
+        let crop = self.crop_rect();
+        (crop.width, crop.height)
+    }
+
+    /// Capture only `region` of the display, or all of it with `None`. The region is
+    /// clamped to the display, now and whenever the display changes size; a region off
+    /// the display captures everything. A change is reported by `check_display_change`,
+    /// so the encoder is rebuilt for it.
+    pub fn set_region(&mut self, region: Option<CaptureRect>) {
+        if region == self.region {
+            return;
+        }
+        self.region = region;
+        self.update_crop();
+        self.pending_resize = Some(self.dimensions());
+    }
+
+    fn update_crop(&mut self) {
+        self.crop = self.region.and_then(|region| region.clamp_to(self.width, self.height));
+        if let (Some(region), None) = (self.region, self.crop) {
+            log::warn!("Capture region {:?} is off the {}x{} display, capturing all of it",
+                region, self.width, self.height);
+        }
+    }
+
+    /// Part of the display frames are cut from
+    fn crop_rect(&self) -> CaptureRect {
+        self.crop.unwrap_or(CaptureRect { x: 0, y: 0, width: self.width, height: self.height })
+    }
+
+    /// When the last frame was captured
+    pub fn last_capture_time(&self) -> Instant {
+        self.last_capture
     }
 
     /// Capture a frame and return RGB data - optimized for speed
     pub fn capture_frame(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
-        let mut capturer_guard = self.capturer.lock();
-        let capturer = capturer_guard.as_mut()
-            .ok_or_else(|| BroadcastError::CaptureError("Capturer not initialized".into()))?;
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at || !self.reopen() {
+                return Ok(None);
+            }
+        }
         
-        // Fast path - try once first
-        match capturer.frame() {
-            Ok(frame) => {
-                self.last_capture = Instant::now();
-                // Convert from BGRA to RGB for encoder
-                let rgb_data = bgra_to_rgb(&frame, self.width as usize, self.height as usize);
-                return Ok(Some(rgb_data));
+        let crop = self.crop_rect();
+        let error = {
+            let mut capturer_guard = self.capturer.lock();
+            let capturer = capturer_guard.as_mut()
+                .ok_or_else(|| BroadcastError::CaptureError("Capturer not initialized".into()))?;
+            
+            // Fast path - try once first
+            match capturer.frame() {
+                Ok(frame) if self.drain_stale => {
+                    // The frame borrows the capturer, so keep a copy while asking for newer ones
+                    self.drain_buffer.clear();
+                    self.drain_buffer.extend_from_slice(&frame);
+                    let mut drained = 0;
+                    while drained < MAX_DRAINED_FRAMES {
+                        // Errors surface again on the next call
+                        let Ok(newer) = capturer.frame() else { break };
+                        if !keep_if_fresh(&mut self.drain_buffer, &newer) {
+                            break;
+                        }
+                        drained += 1;
+                    }
+                    if drained > 0 {
+                        log::debug!("Skipped {} stale frames", drained);
+                    }
+                    self.last_capture = Instant::now();
+                    self.access_lost_count = 0;
+                    let rgb_data = bgra_to_rgb(&self.drain_buffer, self.height as usize, crop);
+                    return Ok(Some(rgb_data));
+                }
+                Ok(frame) => {
+                    self.last_capture = Instant::now();
+                    self.access_lost_count = 0;
+                    // Convert from BGRA to RGB for encoder
+                    let rgb_data = bgra_to_rgb(&frame, self.height as usize, crop);
+                    return Ok(Some(rgb_data));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    // No frame available - this is normal, return None immediately
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => {
+                    // DXGI access lost (mode change, UAC prompt, fullscreen switch): this
+                    // capturer never recovers, replace it right away below
+                    *capturer_guard = None;
+                    e
+                }
+                Err(e) if is_display_lost(&e) => {
+                    // Locked session, display asleep or disconnected: wait for the display
+                    // and build a new capturer
+                    log::warn!("Display unavailable ({}), capture paused", e);
+                    *capturer_guard = None;
+                    self.retry_at = Some(Instant::now() + DISPLAY_RETRY_INTERVAL);
+                    return Ok(None);
+                }
+                Err(e) => {
+                    return Err(BroadcastError::CaptureError(format!("Capture failed: {}", e)));
+                }
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                // No frame available - this is normal, return None immediately
-                return Ok(None);
+        };
+        
+        self.access_lost_count += 1;
+        if self.access_lost_count > MAX_ACCESS_LOST {
+            return Err(BroadcastError::CaptureError(format!(
+                "Capture access lost {} times in a row: {}", self.access_lost_count, error)));
+        }
+        log::warn!("Display access lost ({}), recreating capturer", error);
+        // Falls back to the slow retry if the display cannot be opened yet
+        self.reopen();
+        Ok(None)
+    }
+
+    /// Try to recreate the capturer after the display went away
+    fn reopen(&mut self) -> bool {
+        let capturer = Display::primary().and_then(|display| {
+            let size = (display.width() as u32, display.height() as u32);
+            Capturer::new(display).map(|capturer| (capturer, size))
+        });
+        
+        match capturer {
+            Ok((capturer, (width, height))) => {
+                *self.capturer.lock() = Some(capturer);
+                if (width, height) != (self.width, self.height) {
+                    self.width = width;
+                    self.height = height;
+                    self.update_crop();
+                    self.pending_resize = Some(self.dimensions());
+                }
+                self.retry_at = None;
+                log::info!("Display available again, capture resumed");
+                true
             }
-            Err(e) => {
-                return Err(BroadcastError::CaptureError(format!("Capture failed: {}", e)));
+            Err(_) => {
+                self.retry_at = Some(Instant::now() + DISPLAY_RETRY_INTERVAL);
+                false
             }
         }
     }
 
+    /// Reopen the capturer if the primary display changed size (display switch,
+    /// resolution change). Returns the new dimensions if they changed.
+    pub fn check_display_change(&mut self) -> Result<Option<(u32, u32)>, BroadcastError> {
+        if let Some(size) = self.pending_resize.take() {
+            return Ok(Some(size));
+        }
+        if !self.is_display_available() {
+            // `capture_frame` reopens the display once it is back
+            return Ok(None);
+        }
+        let display = Display::primary()
+            .map_err(|e| BroadcastError::CaptureError(format!("No primary display: {}", e)))?;
+        let (width, height) = (display.width() as u32, display.height() as u32);
+        if (width, height) == (self.width, self.height) {
+            return Ok(None);
+        }
+        
+        // Drop the old capturer first, some platforms allow only one per display
+        let mut capturer = self.capturer.lock();
+        *capturer = None;
+        *capturer = Some(Capturer::new(display)
+            .map_err(|e| BroadcastError::CaptureError(format!("Failed to create capturer: {}", e)))?);
+        drop(capturer);
+        self.width = width;
+        self.height = height;
+        self.update_crop();
+        Ok(Some(self.dimensions()))
+    }
+
     pub fn set_fps(&mut self, fps: u32) {
-        self.frame_interval = Duration::from_millis(1000 / fps.max(1) as u64);
+        self.frame_interval = frame_interval(fps);
+    }
+}
+
+/// Replace `newest` with `frame` unless they are identical. Some backends hand the
+/// last frame out again instead of returning WouldBlock, so an unchanged frame means
+/// there is nothing newer to drain.
+fn keep_if_fresh(newest: &mut Vec<u8>, frame: &[u8]) -> bool {
+    if newest.as_slice() == frame {
+        return false;
     }
+    newest.clear();
+    newest.extend_from_slice(frame);
+    true
+}
+
+/// Capture errors meaning the display is gone for now rather than a real failure.
+/// scrap maps DXGI_ERROR_ACCESS_LOST to ConnectionReset, E_ACCESSDENIED (secure desktop,
+/// e.g. the lock screen) to PermissionDenied, SESSION_DISCONNECTED to ConnectionAborted and
+/// NOT_CURRENTLY_AVAILABLE to Interrupted.
+fn is_display_lost(e: &std::io::Error) -> bool {
+    matches!(e.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::PermissionDenied
+        | ErrorKind::ConnectionAborted | ErrorKind::Interrupted)
 }
 
-/// Convert BGRA to RGB - optimized version
+/// Time between frames at `fps`; 0 fps (e.g. a cleared UI field) counts as 1
+pub fn frame_interval(fps: u32) -> Duration {
+    Duration::from_millis(1000 / fps.max(1) as u64)
+}
+
+/// Exact 64-bit FNV-1a hash of frame content, stable across machines and builds
+pub fn frame_hash(data: &[u8]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    
+    data.iter().fold(FNV_OFFSET, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Convert the `crop` part of a BGRA frame `height` rows high to RGB - optimized version
 #[inline]
-fn bgra_to_rgb(bgra: &[u8], width: usize, height: usize) -> Vec<u8> {
-    let mut rgb = Vec::with_capacity(width * height * 3);
-    let stride = bgra.len() / height;
+fn bgra_to_rgb(bgra: &[u8], height: usize, crop: CaptureRect) -> Vec<u8> {
+    let (left, top) = (crop.x as usize, crop.y as usize);
+    let (width, crop_height) = (crop.width as usize, crop.height as usize);
+    let mut rgb = Vec::with_capacity(width * crop_height * 3);
+    let stride = bgra.len() / height.max(1);
     
     // Process in chunks for better cache locality
-    for y in 0..height {
+    for y in top..top + crop_height {
         let row_start = y * stride;
-        for x in 0..width {
+        for x in left..left + width {
             let idx = row_start + x * 4;
             if idx + 2 < bgra.len() {
                 rgb.push(bgra[idx + 2]); // R
@@ -88,6 +313,28 @@ fn bgra_to_rgb(bgra: &[u8], width: usize, height: usize) -> Vec<u8> {
     rgb
 }
 
+/// Nearest-neighbour resize of packed RGB, used when the stream is sent below capture size
+pub fn scale_rgb(rgb: &[u8], width: usize, height: usize, new_width: usize, new_height: usize) -> Vec<u8> {
+    scale_packed::<3>(rgb, width, height, new_width, new_height)
+}
+
+/// Nearest-neighbour resize of packed RGBA, used when students show frames below decoded size
+pub fn scale_rgba(rgba: &[u8], width: usize, height: usize, new_width: usize, new_height: usize) -> Vec<u8> {
+    scale_packed::<4>(rgba, width, height, new_width, new_height)
+}
+
+fn scale_packed<const BPP: usize>(data: &[u8], width: usize, height: usize, new_width: usize, new_height: usize) -> Vec<u8> {
+    let mut scaled = Vec::with_capacity(new_width * new_height * BPP);
+    for y in 0..new_height {
+        let row = (y * height / new_height) * width;
+        for x in 0..new_width {
+            let idx = (row + x * width / new_width) * BPP;
+            scaled.extend_from_slice(data.get(idx..idx + BPP).unwrap_or(&[0; BPP]));
+        }
+    }
+    scaled
+}
+
 /// Convert RGB to YUV I420 (planar format for H.264)
 pub fn rgb_to_yuv420(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
     let y_size = width * height;
@@ -126,3 +373,111 @@ pub fn rgb_to_yuv420(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
     
     yuv
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_fps_does_not_divide_by_zero() {
+        assert_eq!(frame_interval(0), Duration::from_secs(1));
+        assert_eq!(frame_interval(30), Duration::from_millis(33));
+
+        // Without a display there is nothing to construct
+        if let Ok(mut capture) = ScreenCapture::new(0) {
+            assert_eq!(capture.frame_interval, Duration::from_secs(1));
+            capture.set_fps(0);
+            assert_eq!(capture.frame_interval, Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn draining_stops_at_a_repeated_frame() {
+        let frames: [&[u8]; 4] = [&[2; 4], &[3; 4], &[3; 4], &[4; 4]];
+        let mut newest = vec![1; 4];
+        
+        // Same loop as capture_frame, over a backend that repeats its last frame
+        let drained = frames.iter().take_while(|frame| keep_if_fresh(&mut newest, frame)).count();
+        assert_eq!(drained, 2);
+        assert_eq!(newest, [3; 4]);
+        
+        assert!(!keep_if_fresh(&mut newest, &[3; 4]));
+        assert!(keep_if_fresh(&mut newest, &[]));
+        assert!(newest.is_empty());
+    }
+
+    /// Capture state for a `width`x`height` display, with no capturer behind it
+    fn capture_of(width: u32, height: u32) -> ScreenCapture {
+        ScreenCapture {
+            capturer: Arc::new(Mutex::new(None)),
+            width,
+            height,
+            region: None,
+            crop: None,
+            last_capture: Instant::now(),
+            frame_interval: frame_interval(30),
+            retry_at: None,
+            pending_resize: None,
+            access_lost_count: 0,
+            drain_stale: false,
+            drain_buffer: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn regions_are_clamped_to_the_display() {
+        let rect = |x, y, width, height| CaptureRect { x, y, width, height };
+        assert_eq!(rect(100, 50, 800, 600).clamp_to(1920, 1080), Some(rect(100, 50, 800, 600)));
+        assert_eq!(rect(1500, 900, 800, 600).clamp_to(1920, 1080), Some(rect(1500, 900, 420, 180)));
+        assert_eq!(rect(0, 0, u32::MAX, u32::MAX).clamp_to(1920, 1080), Some(rect(0, 0, 1920, 1080)));
+        assert_eq!(rect(1920, 0, 100, 100).clamp_to(1920, 1080), None);
+        assert_eq!(rect(10, 10, 0, 100).clamp_to(1920, 1080), None);
+    }
+
+    #[test]
+    fn odd_regions_are_rounded_to_even_sizes() {
+        let rect = |x, y, width, height| CaptureRect { x, y, width, height };
+        assert_eq!(rect(0, 0, 641, 481).clamp_to(1920, 1080), Some(rect(0, 0, 640, 480)));
+        assert_eq!(rect(1500, 900, 800, 600).clamp_to(1921, 1081), Some(rect(1500, 900, 420, 180)));
+        assert_eq!(rect(10, 10, 1, 1).clamp_to(1920, 1080), Some(rect(10, 10, 2, 2)));
+        assert_eq!(rect(1919, 1079, 100, 100).clamp_to(1920, 1080), Some(rect(1918, 1078, 2, 2)));
+        assert_eq!(rect(0, 0, 5, 5).clamp_to(1, 1), None);
+        
+        // The frame is the even size, with whole chroma rows for the encoder
+        let mut capture = capture_of(1920, 1080);
+        capture.set_region(Some(rect(1, 1, 641, 361)));
+        assert_eq!(capture.dimensions(), (640, 360));
+        let bgra = vec![0; 1920 * 1080 * 4];
+        assert_eq!(bgra_to_rgb(&bgra, 1080, capture.crop_rect()).len(), 640 * 360 * 3);
+    }
+
+    #[test]
+    fn dimensions_follow_the_clamped_region() {
+        let mut capture = capture_of(1920, 1080);
+        assert_eq!(capture.dimensions(), (1920, 1080));
+        
+        // A new region is reported once, so the encoder is rebuilt at its size
+        capture.set_region(Some(CaptureRect { x: 1600, y: 0, width: 640, height: 480 }));
+        assert_eq!(capture.dimensions(), (320, 480));
+        assert_eq!(capture.check_display_change().expect("check"), Some((320, 480)));
+        capture.set_region(Some(CaptureRect { x: 1600, y: 0, width: 640, height: 480 }));
+        assert_eq!(capture.pending_resize, None);
+        
+        // A smaller display clamps the region again; one off it captures everything
+        (capture.width, capture.height) = (1700, 1000);
+        capture.update_crop();
+        assert_eq!(capture.dimensions(), (100, 480));
+        capture.set_region(Some(CaptureRect { x: 5000, y: 0, width: 640, height: 480 }));
+        assert_eq!(capture.dimensions(), (1700, 1000));
+        capture.set_region(None);
+        assert_eq!(capture.dimensions(), (1700, 1000));
+    }
+
+    #[test]
+    fn crop_keeps_only_the_region_pixels() {
+        // 3x2 BGRA frame, each pixel's blue channel its index
+        let bgra: Vec<u8> = (0..6).flat_map(|i| [i, 10, 20, 255]).collect();
+        let crop = CaptureRect { x: 1, y: 1, width: 2, height: 1 };
+        assert_eq!(bgra_to_rgb(&bgra, 2, crop), [20, 10, 4, 20, 10, 5]);
+    }
+}
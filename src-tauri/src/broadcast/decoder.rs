@@ -1,35 +1,139 @@
 //! H.264 Decoder wrapper
 
-use openh264::decoder::Decoder;
+use openh264::decoder::{DecodedYUV, Decoder};
 use openh264::formats::YUVSource;
 
-use super::types::BroadcastError;
+use super::h264::sps_dimensions;
+use super::rtp::{contains_keyframe, nal_units, NalType};
+use super::types::{luma_offset, BroadcastError, CodecBackend, ColorSpace, DecodedFrame};
 
-pub struct H264Decoder {
+/// Common interface of the H.264 decoder backends
+pub trait VideoDecoder {
+    /// Decode H.264 data to RGBA
+    fn decode(&mut self, h264_data: &[u8]) -> Result<Option<DecodedFrame>, BroadcastError>;
+
+    /// Decode H.264 data and return the YUV 4:2:0 planes without converting to RGBA.
+    /// The planes borrow the decoder and are valid until the next decode call.
+    fn decode_yuv(&mut self, h264_data: &[u8]) -> Result<Option<YuvFrame<'_>>, BroadcastError>;
+
+    /// Backend name for logs
+    fn name(&self) -> &'static str;
+
+    /// Display size the stream's latest SPS declares, cropping applied
+    fn display_size(&self) -> Option<(u32, u32)> {
+        None
+    }
+}
+
+/// Decoder factory
+pub struct H264Decoder;
+
+impl H264Decoder {
+    /// Create a decoder for `backend`, falling back to openh264 if hardware setup fails
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        backend: CodecBackend,
+        color_space: ColorSpace,
+        full_range: bool,
+    ) -> Result<Box<dyn VideoDecoder>, BroadcastError> {
+        let decoder: Box<dyn VideoDecoder> = match backend {
+            CodecBackend::Hardware => match create_hardware_decoder() {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    log::warn!("Hardware decoder unavailable ({}), falling back to software", e);
+                    Box::new(OpenH264Decoder::new(color_space, full_range)?)
+                }
+            },
+            CodecBackend::Software => Box::new(OpenH264Decoder::new(color_space, full_range)?),
+        };
+        
+        log::info!("Active decoder backend: {}", decoder.name());
+        Ok(decoder)
+    }
+}
+
+/// Hardware decoders (DXVA/VideoToolbox/VAAPI) need a platform decoder binding,
+/// which this build does not include
+fn create_hardware_decoder() -> Result<Box<dyn VideoDecoder>, BroadcastError> {
+    Err(BroadcastError::DecoderError("no hardware decoder support in this build".into()))
+}
+
+/// Software decoder using openh264
+pub struct OpenH264Decoder {
     decoder: Decoder,
     frame_count: u64,
+    color_space: ColorSpace,
+    full_range: bool,
+    /// From the latest SPS; None until one arrives
+    display_size: Option<(u32, u32)>,
+    /// A mismatch with `display_size` was logged; reset by the next SPS
+    size_warned: bool,
 }
 
-impl H264Decoder {
-    pub fn new() -> Result<Self, BroadcastError> {
+impl OpenH264Decoder {
+    pub fn new(color_space: ColorSpace, full_range: bool) -> Result<Self, BroadcastError> {
         let decoder = Decoder::new()
             .map_err(|e| BroadcastError::DecoderError(format!("Failed to create decoder: {}", e)))?;
         
         Ok(Self {
             decoder,
             frame_count: 0,
+            color_space,
+            full_range,
+            display_size: None,
+            size_warned: false,
         })
     }
 
-    /// Decode H.264 data to RGBA
-    pub fn decode(&mut self, h264_data: &[u8]) -> Result<Option<DecodedFrame>, BroadcastError> {
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Pick up the display size of any SPS in `h264_data`
+    fn read_sps(&mut self, h264_data: &[u8]) {
+        let Some((_, sps)) = nal_units(h264_data).find(|(t, _)| *t == NalType::Sps) else { return };
+        match sps_dimensions(sps) {
+            Some(size) => {
+                if self.display_size != Some(size) {
+                    log::info!("Stream SPS: {}x{}", size.0, size.1);
+                }
+                self.display_size = Some(size);
+            }
+            None => log::warn!("Could not parse SPS ({} bytes)", sps.len()),
+        }
+        self.size_warned = false;
+    }
+}
+
+/// Warn once per SPS when the decoder's output is not the size the SPS declares. Takes
+/// the fields, as the decoded frame still borrows the decoder.
+fn check_size(display_size: Option<(u32, u32)>, warned: &mut bool, width: usize, height: usize) {
+    let Some((sps_width, sps_height)) = display_size else { return };
+    if !*warned && (width as u32, height as u32) != (sps_width, sps_height) {
+        log::warn!("Decoded {}x{} but the SPS declares {}x{}", width, height, sps_width, sps_height);
+        *warned = true;
+    }
+}
+
+impl VideoDecoder for OpenH264Decoder {
+    fn decode(&mut self, h264_data: &[u8]) -> Result<Option<DecodedFrame>, BroadcastError> {
+        self.read_sps(h264_data);
         match self.decoder.decode(h264_data) {
             Ok(Some(yuv)) => {
                 let (width, height) = yuv.dimensions();
-                let mut rgba = vec![0u8; width * height * 4];
+                check_size(self.display_size, &mut self.size_warned, width, height);
                 
-                // Convert YUV to RGBA
-                yuv.write_rgba8(&mut rgba);
+                // Convert YUV to RGBA; openh264's own conversion only knows limited range BT.601
+                let rgba = if self.color_space == ColorSpace::Bt601 && !self.full_range {
+                    let mut rgba = vec![0u8; width * height * 4];
+                    yuv.write_rgba8(&mut rgba);
+                    rgba
+                } else {
+                    let mut rgba = Vec::with_capacity(width * height * 4);
+                    i420_to_rgb(&yuv, self.color_space, self.full_range,
+                        |r, g, b| rgba.extend_from_slice(&[r, g, b, 255]));
+                    rgba
+                };
                 
                 self.frame_count += 1;
                 
@@ -37,6 +141,8 @@ impl H264Decoder {
                     rgba_data: rgba,
                     width: width as u32,
                     height: height as u32,
+                    timestamp: 0,
+                    is_keyframe: contains_keyframe(h264_data),
                 }))
             }
             Ok(None) => Ok(None),
@@ -47,14 +153,110 @@ impl H264Decoder {
         }
     }
 
-    pub fn frame_count(&self) -> u64 {
-        self.frame_count
+    fn decode_yuv(&mut self, h264_data: &[u8]) -> Result<Option<YuvFrame<'_>>, BroadcastError> {
+        self.read_sps(h264_data);
+        match self.decoder.decode(h264_data) {
+            Ok(Some(yuv)) => {
+                let (width, height) = yuv.dimensions();
+                check_size(self.display_size, &mut self.size_warned, width, height);
+                self.frame_count += 1;
+                Ok(Some(YuvFrame {
+                    yuv,
+                    color_space: self.color_space,
+                    full_range: self.full_range,
+                    is_keyframe: contains_keyframe(h264_data),
+                }))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                log::warn!("Decode error: {}", e);
+                Err(BroadcastError::DecoderError(e.to_string()))
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "openh264 (software)"
+    }
+
+    fn display_size(&self) -> Option<(u32, u32)> {
+        self.display_size
+    }
+}
+
+/// Decoded I420 planes borrowed from the decoder
+pub struct YuvFrame<'a> {
+    yuv: DecodedYUV<'a>,
+    color_space: ColorSpace,
+    full_range: bool,
+    is_keyframe: bool,
+}
+
+impl YuvFrame<'_> {
+    /// Decoded from an IDR (or SPS-led) access unit
+    pub fn is_keyframe(&self) -> bool {
+        self.is_keyframe
+    }
+
+    pub fn width(&self) -> u32 {
+        self.yuv.dimensions().0 as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.yuv.dimensions().1 as u32
+    }
+
+    /// Row strides of the Y, U and V planes
+    pub fn strides(&self) -> (usize, usize, usize) {
+        self.yuv.strides()
+    }
+
+    pub fn y(&self) -> &[u8] {
+        self.yuv.y()
+    }
+
+    pub fn u(&self) -> &[u8] {
+        self.yuv.u()
+    }
+
+    pub fn v(&self) -> &[u8] {
+        self.yuv.v()
+    }
+
+    /// Convert to RGB with the stream's color space and range, calling `put(r, g, b)` per pixel in row order
+    pub fn for_each_rgb(&self, put: impl FnMut(u8, u8, u8)) {
+        i420_to_rgb(&self.yuv, self.color_space, self.full_range, put);
     }
 }
 
-#[derive(Clone)]
-pub struct DecodedFrame {
-    pub rgba_data: Vec<u8>,
-    pub width: u32,
-    pub height: u32,
+/// I420 to RGB with `color_space`'s matrix, calling `put(r, g, b)` per pixel in row order
+pub(crate) fn i420_to_rgb(
+    yuv: &impl YUVSource,
+    color_space: ColorSpace,
+    full_range: bool,
+    mut put: impl FnMut(u8, u8, u8),
+) {
+    let (width, height) = yuv.dimensions();
+    let (y_stride, u_stride, v_stride) = yuv.strides();
+    let (y_plane, u_plane, v_plane) = (yuv.y(), yuv.u(), yuv.v());
+    let [y_scale, v_r, u_g, v_g, u_b] = color_space.yuv_to_rgb(full_range);
+    let y_offset = luma_offset(full_range);
+    
+    for row in 0..height {
+        let y_row = &y_plane[row * y_stride..][..width];
+        let u_row = &u_plane[(row / 2) * u_stride..];
+        let v_row = &v_plane[(row / 2) * v_stride..];
+        
+        for (col, &y) in y_row.iter().enumerate() {
+            let c = y_scale * (y as i32 - y_offset);
+            let d = u_row[col / 2] as i32 - 128;
+            let e = v_row[col / 2] as i32 - 128;
+            
+            put(
+                ((c + v_r * e + 128) >> 8).clamp(0, 255) as u8,
+                ((c - u_g * d - v_g * e + 128) >> 8).clamp(0, 255) as u8,
+                ((c + u_b * d + 128) >> 8).clamp(0, 255) as u8,
+            );
+        }
+    }
 }
@@ -2,25 +2,130 @@
 //! Allows teachers and students to find each other on the LAN
 
 use std::collections::HashMap;
-use std::net::{UdpSocket, SocketAddr};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use parking_lot::Mutex;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use uuid::Uuid;
+
+use super::types::{BroadcastError, H264Profile};
 
 pub const DISCOVERY_PORT: u16 = 5001;
 pub const DISCOVERY_MAGIC: &[u8] = b"SCRSHARE";
+/// Version of the stream and discovery wire format, announced to peers.
+/// Bump it whenever older peers would mis-parse what this build sends.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Default `DiscoveryConfig::announce_interval`
 pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// Default `DiscoveryConfig::peer_timeout`
 pub const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Peers loaded from the cache that never announce again are dropped after this long
+pub const STALE_PEER_TIMEOUT: Duration = Duration::from_secs(300);
+/// Pings without a pong after this long are considered lost
+pub const PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// Largest discovery packet accepted; anything longer is dropped as truncated
+pub const MAX_PACKET_SIZE: usize = 8192;
+/// Longest id, name, ip or version string accepted in a `PeerInfo`, in bytes
+const MAX_FIELD_LEN: usize = 128;
+/// Peers kept at most, so a flood of fake announcements cannot grow the list forever
+const MAX_PEERS: usize = 512;
+
+/// Discovery port and timing: a large classroom may announce less often, a flaky network
+/// may keep silent peers around longer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoveryConfig {
+    /// How often to announce ourselves
+    pub announce_interval: Duration,
+    /// Peers that stop announcing are dropped after this long
+    pub peer_timeout: Duration,
+    /// Port every peer listens and announces on
+    pub port: u16,
+    /// Port to listen on if `port` cannot be bound even with address reuse. Only replies
+    /// to our own queries reach it, so the peer list is less complete.
+    pub fallback_port: Option<u16>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            announce_interval: ANNOUNCE_INTERVAL,
+            peer_timeout: PEER_TIMEOUT,
+            port: DISCOVERY_PORT,
+            fallback_port: None,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /// The timeout must leave room for at least one missed announcement
+    pub fn validate(&self) -> Result<(), BroadcastError> {
+        if self.port == 0 || self.fallback_port == Some(0) {
+            return Err(BroadcastError::ConfigError("discovery ports must be between 1 and 65535".into()));
+        }
+        if self.announce_interval.is_zero() {
+            return Err(BroadcastError::ConfigError("announce_interval must be greater than 0".into()));
+        }
+        if self.peer_timeout <= self.announce_interval {
+            return Err(BroadcastError::ConfigError(format!(
+                "peer_timeout ({:?}) must be longer than announce_interval ({:?})",
+                self.peer_timeout, self.announce_interval)));
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
+    #[serde(deserialize_with = "bounded_string")]
     pub id: String,
+    #[serde(deserialize_with = "bounded_string")]
     pub name: String,
     pub role: PeerRole,
+    #[serde(deserialize_with = "bounded_string")]
     pub ip: String,
     pub stream_port: u16,
+    #[serde(deserialize_with = "bounded_string")]
     pub version: String,
+    /// Highest H.264 profile this peer decodes; peers that don't say are assumed Baseline only
+    #[serde(default)]
+    pub max_profile: Option<H264Profile>,
+    /// Largest stream this peer decodes smoothly (None = no limit)
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub max_fps: Option<u32>,
+    /// Loaded from the peer cache and not seen live since
+    #[serde(default)]
+    pub stale: bool,
+    /// Wall clock ms since the UNIX epoch when the peer last announced itself
+    #[serde(default)]
+    pub last_seen: u64,
+    /// Wire format the peer speaks, see `PROTOCOL_VERSION`; 0 for peers from before it
+    /// was announced
+    #[serde(default)]
+    pub protocol: u8,
+    /// Set locally when `protocol` differs from ours: the peer is listed by `get_peers`
+    /// but left out of `get_teachers`/`get_students`, so no stream is set up with it
+    #[serde(default)]
+    pub protocol_mismatch: bool,
+}
+
+/// What every discovered student can decode, the tightest of their limits
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StudentLimits {
+    pub max_profile: Option<H264Profile>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_fps: Option<u32>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -34,76 +139,358 @@ enum DiscoveryMessage {
     Announce(PeerInfo),
     Query,
     Response(PeerInfo),
+    /// `sent_at`/`replied_at` are wall clock ms since the UNIX epoch
+    Ping { nonce: u64, sent_at: u64 },
+    Pong { nonce: u64, sent_at: u64, replied_at: u64 },
+    /// Student to teacher: fraction (0..=1) of the stream on `stream_port` lost recently
+    LossReport { stream_port: u16, loss: f32 },
 }
 
-pub struct DiscoveryService {
+/// Latest round-trip measurement to a peer
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RttSample {
+    pub rtt_ms: f32,
+    /// Peer clock minus local clock, assuming a symmetric path
+    pub clock_offset_ms: f64,
+}
+
+/// State shared between a `DiscoveryService` and its background thread
+struct DiscoveryShared {
     socket: UdpSocket,
-    local_info: PeerInfo,
-    peers: Arc<Mutex<HashMap<String, (PeerInfo, Instant)>>>,
-    running: Arc<Mutex<bool>>,
+    local_info: Mutex<PeerInfo>,
+    peers: Mutex<HashMap<String, (PeerInfo, Instant)>>,
+    running: AtomicBool,
+    /// Outstanding pings: nonce -> (peer id, send time)
+    pending_pings: Mutex<HashMap<u64, (String, Instant)>>,
+    rtts: Mutex<HashMap<String, RttSample>>,
+    next_nonce: AtomicU64,
+    /// Worst loss reported per stream port since it was last taken
+    loss_reports: Mutex<HashMap<u16, f32>>,
+    config: DiscoveryConfig,
+}
+
+/// Finds peers on the LAN. After `start`, a background thread announces us every
+/// `announce_interval`, answers queries and pings, and expires silent peers.
+pub struct DiscoveryService {
+    shared: Arc<DiscoveryShared>,
+    thread: Option<thread::JoinHandle<()>>,
+    /// Where the peer list is saved on `stop`, see `with_peer_cache`
+    peer_cache: Option<PathBuf>,
 }
 
 impl DiscoveryService {
-    pub fn new(name: &str, role: PeerRole, stream_port: u16) -> std::io::Result<Self> {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", DISCOVERY_PORT))?;
-        socket.set_broadcast(true)?;
-        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    pub fn new(name: &str, role: PeerRole, stream_port: u16, config: DiscoveryConfig) -> Result<Self, BroadcastError> {
+        config.validate()?;
+        let socket = match (open_discovery_socket(config.port), config.fallback_port) {
+            (Ok(socket), _) => socket,
+            (Err(e), Some(fallback)) => {
+                log::warn!("Discovery port {} unavailable ({}), trying {}", config.port, e, fallback);
+                open_discovery_socket(fallback).map_err(|fallback_error| BroadcastError::ConfigError(format!(
+                    "Discovery port {} unavailable ({}), fallback port {} too ({})",
+                    config.port, e, fallback, fallback_error)))?
+            }
+            (Err(e), None) => {
+                return Err(BroadcastError::ConfigError(format!("Discovery port {} unavailable: {}", config.port, e)));
+            }
+        };
         
         // Get local IP
         let local_ip = get_local_ip().unwrap_or_else(|| "0.0.0.0".to_string());
         
         let local_info = PeerInfo {
             id: generate_id(),
-            name: name.to_string(),
+            // Keep our own announcements within what other peers accept
+            name: truncate_field(name),
             role,
             ip: local_ip,
             stream_port,
             version: env!("CARGO_PKG_VERSION").to_string(),
+            // openh264 decodes progressive High profile
+            max_profile: Some(H264Profile::High),
+            max_width: None,
+            max_height: None,
+            max_fps: None,
+            stale: false,
+            last_seen: 0,
+            protocol: PROTOCOL_VERSION,
+            protocol_mismatch: false,
         };
         
         log::info!("Discovery service created: {} ({:?}) at {}:{}", 
             local_info.name, local_info.role, local_info.ip, stream_port);
         
         Ok(Self {
-            socket,
-            local_info,
-            peers: Arc::new(Mutex::new(HashMap::new())),
-            running: Arc::new(Mutex::new(false)),
+            shared: Arc::new(DiscoveryShared {
+                socket,
+                local_info: Mutex::new(local_info),
+                peers: Mutex::new(HashMap::new()),
+                running: AtomicBool::new(false),
+                pending_pings: Mutex::new(HashMap::new()),
+                rtts: Mutex::new(HashMap::new()),
+                next_nonce: AtomicU64::new(now_ms().wrapping_mul(0x9E37_79B9_7F4A_7C15)),
+                loss_reports: Mutex::new(HashMap::new()),
+                config,
+            }),
+            thread: None,
+            peer_cache: None,
         })
     }
 
-    /// Start discovery service in background
-    pub fn start(&self) -> std::io::Result<()> {
-        *self.running.lock() = true;
+    pub fn config(&self) -> DiscoveryConfig {
+        self.shared.config
+    }
+
+    /// Announce decode limits, e.g. for a low-end student machine
+    pub fn with_decode_limits(self, max_width: Option<u32>, max_height: Option<u32>, max_fps: Option<u32>) -> Self {
+        {
+            let mut local_info = self.shared.local_info.lock();
+            local_info.max_width = max_width;
+            local_info.max_height = max_height;
+            local_info.max_fps = max_fps;
+        }
+        self
+    }
+
+    /// Keep the peer id in `path` so a restarted peer keeps its identity.
+    /// Call before `with_peer_cache` so our own saved entry is recognised.
+    pub fn with_persistent_id(self, path: &Path) -> Self {
+        match load_or_create_id(path) {
+            Ok(id) => self.shared.local_info.lock().id = id,
+            Err(e) => log::warn!("Could not persist peer id to {}: {}", path.display(), e),
+        }
+        self
+    }
+
+    /// Keep the peer list in `path`: loaded now as stale peers so the list is not empty
+    /// until the next announce cycle, and saved again on `stop`
+    pub fn with_peer_cache(mut self, path: PathBuf) -> Self {
+        match self.load_peers(&path) {
+            Ok(count) => log::info!("Loaded {} known peer(s) from {}", count, path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Could not load known peers from {}: {}", path.display(), e),
+        }
+        self.peer_cache = Some(path);
+        self
+    }
+
+    /// Start announcing, answering and expiring peers on a background thread
+    pub fn start(&mut self) -> std::io::Result<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+        self.shared.running.store(true, Ordering::SeqCst);
+        // Ask for everyone now instead of waiting for their next announcement
+        self.shared.broadcast_message(&DiscoveryMessage::Query)?;
+        
+        let shared = self.shared.clone();
+        self.thread = Some(thread::Builder::new()
+            .name("discovery".into())
+            .spawn(move || shared.run())?);
         Ok(())
     }
 
-    /// Stop discovery service
-    pub fn stop(&self) {
-        *self.running.lock() = false;
+    /// Stop the background thread and save the peer list if `with_peer_cache` was used
+    pub fn stop(&mut self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.thread.take() {
+            // The thread wakes up at least every socket read timeout
+            let _ = handle.join();
+        }
+        if let Some(path) = &self.peer_cache {
+            if let Err(e) = self.save_peers(path) {
+                log::warn!("Could not save known peers to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Write the current peer list to `path` as JSON
+    pub fn save_peers(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.get_peers())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, json)
     }
 
-    /// Send announcement broadcast
+    /// Preload peers written by `save_peers`, marked stale until they announce again.
+    /// Returns how many were added.
+    pub fn load_peers(&self, path: &Path) -> io::Result<usize> {
+        let saved: Vec<PeerInfo> = serde_json::from_slice(&fs::read(path)?)?;
+        let local_id = self.shared.local_info.lock().id.clone();
+        let mut peers = self.shared.peers.lock();
+        let mut loaded = 0;
+        for mut peer in saved {
+            if peers.len() >= MAX_PEERS {
+                break;
+            }
+            if peer.id == local_id || peers.contains_key(&peer.id) {
+                continue;
+            }
+            peer.stale = true;
+            peer.protocol_mismatch = peer.protocol != PROTOCOL_VERSION;
+            peers.insert(peer.id.clone(), (peer, Instant::now()));
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Send announcement broadcast now, without waiting for the next interval
     pub fn announce(&self) -> std::io::Result<()> {
-        let msg = DiscoveryMessage::Announce(self.local_info.clone());
-        self.broadcast_message(&msg)
+        self.shared.announce()
     }
 
     /// Send query to find peers
     pub fn query(&self) -> std::io::Result<()> {
         let msg = DiscoveryMessage::Query;
+        self.shared.broadcast_message(&msg)
+    }
+
+    /// Send a ping to a discovered peer; the RTT is available from `rtt_ms` once the pong arrives
+    pub fn ping(&self, peer_id: &str) -> std::io::Result<u64> {
+        let addr = self.peer_addr(peer_id)?;
+        
+        let nonce = self.shared.next_nonce.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut pending = self.shared.pending_pings.lock();
+            // Lost pings never get a pong
+            pending.retain(|_, (_, sent)| sent.elapsed() < PING_TIMEOUT);
+            pending.insert(nonce, (peer_id.to_string(), Instant::now()));
+        }
+        
+        self.shared.send_to(&DiscoveryMessage::Ping { nonce, sent_at: now_ms() }, addr)?;
+        Ok(nonce)
+    }
+
+    /// Tell a teacher how much of its stream on `stream_port` was lost recently (fraction 0..=1)
+    pub fn report_loss(&self, peer_id: &str, stream_port: u16, loss: f32) -> std::io::Result<()> {
+        let addr = self.peer_addr(peer_id)?;
+        self.shared.send_to(&DiscoveryMessage::LossReport { stream_port, loss }, addr)
+    }
+
+    /// Worst loss students reported for `stream_port` since the last call
+    pub fn take_loss_report(&self, stream_port: u16) -> Option<f32> {
+        self.shared.loss_reports.lock().remove(&stream_port)
+    }
+
+    /// Discovery address of a known peer
+    fn peer_addr(&self, peer_id: &str) -> std::io::Result<SocketAddr> {
+        let peers = self.shared.peers.lock();
+        let (peer, _) = peers.get(peer_id).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, format!("Unknown peer: {}", peer_id))
+        })?;
+        let ip = peer.ip.parse().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Bad peer address: {}", peer.ip))
+        })?;
+        Ok(SocketAddr::new(ip, self.shared.config.port))
+    }
+
+    /// Last measured round-trip time to a peer
+    pub fn rtt_ms(&self, peer_id: &str) -> Option<f32> {
+        self.shared.rtts.lock().get(peer_id).map(|s| s.rtt_ms)
+    }
+
+    /// Last round-trip measurement to a peer, including the clock offset estimate
+    pub fn rtt_sample(&self, peer_id: &str) -> Option<RttSample> {
+        self.shared.rtts.lock().get(peer_id).copied()
+    }
+
+    /// Get list of discovered peers, as last updated by the background thread
+    pub fn get_peers(&self) -> Vec<PeerInfo> {
+        self.shared.peers.lock().values().map(|(p, _)| p.clone()).collect()
+    }
+
+    /// Get teachers we can receive from (same protocol version)
+    pub fn get_teachers(&self) -> Vec<PeerInfo> {
+        self.get_peers()
+            .into_iter()
+            .filter(|p| p.role == PeerRole::Teacher && !p.protocol_mismatch)
+            .collect()
+    }
+
+    /// Limits every discovered student can handle; all None without students
+    pub fn student_limits(&self) -> StudentLimits {
+        let students = self.get_students();
+        StudentLimits {
+            max_profile: students.iter().map(|p| p.max_profile.unwrap_or(H264Profile::Baseline)).min(),
+            max_width: students.iter().filter_map(|p| p.max_width).min(),
+            max_height: students.iter().filter_map(|p| p.max_height).min(),
+            max_fps: students.iter().filter_map(|p| p.max_fps).min(),
+        }
+    }
+
+    /// Get students only
+    pub fn get_students(&self) -> Vec<PeerInfo> {
+        self.get_peers()
+            .into_iter()
+            .filter(|p| p.role == PeerRole::Student && !p.protocol_mismatch)
+            .collect()
+    }
+
+    pub fn local_info(&self) -> PeerInfo {
+        self.shared.local_info.lock().clone()
+    }
+}
+
+impl Drop for DiscoveryService {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.stop();
+        }
+    }
+}
+
+impl DiscoveryShared {
+    /// Background loop: announce and expire every interval, handle messages in between
+    fn run(&self) {
+        let mut last_announce: Option<Instant> = None;
+        while self.running.load(Ordering::SeqCst) {
+            let interval = self.config.announce_interval;
+            if last_announce.is_none_or(|at| at.elapsed() >= interval) {
+                if let Err(e) = self.announce() {
+                    log::warn!("Discovery announce failed: {}", e);
+                }
+                self.expire_peers();
+                last_announce = Some(Instant::now());
+            }
+            
+            // Blocks for at most the socket read timeout
+            match self.process() {
+                Ok(Some(peer)) => log::info!("Discovered: {} ({:?}) at {}", peer.name, peer.role, peer.ip),
+                Ok(None) => {}
+                Err(e) => log::warn!("Discovery error: {}", e),
+            }
+        }
+    }
+
+    fn announce(&self) -> std::io::Result<()> {
+        let msg = DiscoveryMessage::Announce(self.local_info.lock().clone());
         self.broadcast_message(&msg)
     }
 
-    /// Process incoming messages (call in a loop)
-    pub fn process(&self) -> std::io::Result<Option<PeerInfo>> {
-        let mut buf = [0u8; 2048];
+    /// Remove peers that stopped announcing, and cached ones that never came back
+    fn expire_peers(&self) {
+        let peer_timeout = self.config.peer_timeout;
+        let now = Instant::now();
+        self.peers.lock().retain(|_, (peer, last_seen)| {
+            let timeout = if peer.stale { STALE_PEER_TIMEOUT } else { peer_timeout };
+            now.duration_since(*last_seen) < timeout
+        });
+    }
+
+    /// Handle one incoming message, waiting up to the socket read timeout for it
+    fn process(&self) -> std::io::Result<Option<PeerInfo>> {
+        // One spare byte tells a packet that exactly fits from one that was cut off
+        let mut buf = [0u8; MAX_PACKET_SIZE + 1];
         
         match self.socket.recv_from(&mut buf) {
             Ok((size, addr)) => {
                 if size < DISCOVERY_MAGIC.len() {
                     return Ok(None);
                 }
+                if size > MAX_PACKET_SIZE {
+                    log::debug!("Dropping oversized discovery packet from {}", addr);
+                    return Ok(None);
+                }
                 
                 // Check magic header
                 if &buf[..DISCOVERY_MAGIC.len()] != DISCOVERY_MAGIC {
@@ -112,8 +499,9 @@ impl DiscoveryService {
                 
                 // Parse message
                 let json_data = &buf[DISCOVERY_MAGIC.len()..size];
-                if let Ok(msg) = serde_json::from_slice::<DiscoveryMessage>(json_data) {
-                    return self.handle_message(msg, addr);
+                match serde_json::from_slice::<DiscoveryMessage>(json_data) {
+                    Ok(msg) => return self.handle_message(msg, addr),
+                    Err(e) => log::debug!("Ignoring malformed discovery packet from {}: {}", addr, e),
                 }
             }
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock 
@@ -135,36 +523,52 @@ impl DiscoveryService {
                 peer.ip = addr.ip().to_string();
                 
                 // Don't add ourselves
-                if peer.id == self.local_info.id {
+                if peer.id == self.local_info.lock().id {
                     return Ok(None);
                 }
                 
                 log::debug!("Discovered peer: {} ({:?}) at {}", peer.name, peer.role, peer.ip);
                 
-                let mut peers = self.peers.lock();
-                let is_new = !peers.contains_key(&peer.id);
-                peers.insert(peer.id.clone(), (peer.clone(), Instant::now()));
-                
-                if is_new {
+                if self.insert_live_peer(&mut peer) {
                     return Ok(Some(peer));
                 }
             }
             DiscoveryMessage::Query => {
                 // Respond with our info
-                let response = DiscoveryMessage::Response(self.local_info.clone());
+                let response = DiscoveryMessage::Response(self.local_info.lock().clone());
                 self.send_to(&response, addr)?;
             }
+            DiscoveryMessage::Ping { nonce, sent_at } => {
+                let pong = DiscoveryMessage::Pong { nonce, sent_at, replied_at: now_ms() };
+                self.send_to(&pong, addr)?;
+            }
+            DiscoveryMessage::Pong { nonce, sent_at, replied_at } => {
+                // Unknown nonces (late, duplicate or not ours) are ignored
+                let Some((peer_id, sent)) = self.pending_pings.lock().remove(&nonce) else {
+                    return Ok(None);
+                };
+                let rtt = sent.elapsed();
+                if rtt > PING_TIMEOUT {
+                    return Ok(None);
+                }
+                
+                let rtt_ms = rtt.as_secs_f32() * 1000.0;
+                let clock_offset_ms = replied_at as f64 - (sent_at as f64 + rtt_ms as f64 / 2.0);
+                log::debug!("RTT to {}: {:.1} ms, clock offset {:.0} ms", peer_id, rtt_ms, clock_offset_ms);
+                self.rtts.lock().insert(peer_id, RttSample { rtt_ms, clock_offset_ms });
+            }
+            DiscoveryMessage::LossReport { stream_port, loss } => {
+                if self.local_info.lock().role == PeerRole::Teacher && (0.0..=1.0).contains(&loss) {
+                    let mut reports = self.loss_reports.lock();
+                    let worst = reports.entry(stream_port).or_insert(loss);
+                    *worst = worst.max(loss);
+                }
+            }
             DiscoveryMessage::Response(mut peer) => {
                 peer.ip = addr.ip().to_string();
                 
-                if peer.id != self.local_info.id {
-                    let mut peers = self.peers.lock();
-                    let is_new = !peers.contains_key(&peer.id);
-                    peers.insert(peer.id.clone(), (peer.clone(), Instant::now()));
-                    
-                    if is_new {
-                        return Ok(Some(peer));
-                    }
+                if peer.id != self.local_info.lock().id && self.insert_live_peer(&mut peer) {
+                    return Ok(Some(peer));
                 }
             }
         }
@@ -172,19 +576,43 @@ impl DiscoveryService {
         Ok(None)
     }
 
+    /// Record a peer that just announced itself. Returns true if it is new or was only
+    /// known from the cache.
+    fn insert_live_peer(&self, peer: &mut PeerInfo) -> bool {
+        peer.stale = false;
+        peer.last_seen = now_ms();
+        peer.protocol_mismatch = peer.protocol != PROTOCOL_VERSION;
+        
+        let mut peers = self.peers.lock();
+        if !peers.contains_key(&peer.id) && peers.len() >= MAX_PEERS {
+            log::warn!("Peer list full, ignoring {} at {}", peer.id, peer.ip);
+            return false;
+        }
+        let is_new = peers.get(&peer.id).is_none_or(|(known, _)| known.stale);
+        if is_new && peer.protocol_mismatch {
+            log::warn!("Peer {} at {} speaks protocol {}, we speak {}: version mismatch, not streaming with it",
+                peer.name, peer.ip, peer.protocol, PROTOCOL_VERSION);
+        }
+        // A restarted peer comes back under a new id; drop its cached entry
+        peers.retain(|id, (known, _)| !(known.stale && *id != peer.id && known.ip == peer.ip
+            && known.role == peer.role && known.stream_port == peer.stream_port));
+        peers.insert(peer.id.clone(), (peer.clone(), Instant::now()));
+        is_new
+    }
+
     fn broadcast_message(&self, msg: &DiscoveryMessage) -> std::io::Result<()> {
-        let json = serde_json::to_vec(msg).unwrap();
+        let json = serde_json::to_vec(msg)?;
         let mut packet = Vec::with_capacity(DISCOVERY_MAGIC.len() + json.len());
         packet.extend_from_slice(DISCOVERY_MAGIC);
         packet.extend_from_slice(&json);
         
-        let broadcast_addr = format!("255.255.255.255:{}", DISCOVERY_PORT);
+        let broadcast_addr = SocketAddrV4::new(Ipv4Addr::BROADCAST, self.config.port);
         self.socket.send_to(&packet, broadcast_addr)?;
         Ok(())
     }
 
     fn send_to(&self, msg: &DiscoveryMessage, addr: SocketAddr) -> std::io::Result<()> {
-        let json = serde_json::to_vec(msg).unwrap();
+        let json = serde_json::to_vec(msg)?;
         let mut packet = Vec::with_capacity(DISCOVERY_MAGIC.len() + json.len());
         packet.extend_from_slice(DISCOVERY_MAGIC);
         packet.extend_from_slice(&json);
@@ -192,50 +620,280 @@ impl DiscoveryService {
         self.socket.send_to(&packet, addr)?;
         Ok(())
     }
+}
 
-    /// Get list of discovered peers
-    pub fn get_peers(&self) -> Vec<PeerInfo> {
-        let mut peers = self.peers.lock();
-        let now = Instant::now();
+/// Broadcast socket on `port`, shared with other instances on this machine (e.g. a
+/// teacher and a test student) through address reuse
+fn open_discovery_socket(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_broadcast(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    Ok(socket.into())
+}
+
+fn get_local_ip() -> Option<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    socket.local_addr().ok().map(|a| a.ip().to_string())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Deserialize a string, rejecting anything longer than `MAX_FIELD_LEN`
+fn bounded_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    if s.len() > MAX_FIELD_LEN {
+        return Err(de::Error::invalid_length(s.len(), &"a string of at most 128 bytes"));
+    }
+    Ok(s)
+}
+
+/// `s` cut to at most `MAX_FIELD_LEN` bytes on a char boundary
+fn truncate_field(s: &str) -> String {
+    let mut end = s.len().min(MAX_FIELD_LEN);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s[..end].to_string()
+}
+
+/// Random v4 UUID, so peers started at the same instant cannot collide
+fn generate_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// The id saved in `path`, or a fresh one written there if it is missing or invalid
+fn load_or_create_id(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(saved) if Uuid::parse_str(saved.trim()).is_ok() => return Ok(saved.trim().to_string()),
+        Ok(_) => log::warn!("Ignoring invalid peer id in {}", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    
+    let id = generate_id();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, &id)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Service on a port of its own, so tests don't hear each other or a running app
+    fn service(role: PeerRole) -> DiscoveryService {
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        service_on(role, port)
+    }
+
+    fn service_on(role: PeerRole, port: u16) -> DiscoveryService {
+        let config = DiscoveryConfig { port, announce_interval: Duration::from_millis(200), ..DiscoveryConfig::default() };
+        DiscoveryService::new("test", role, 5000, config).expect("discovery service")
+    }
+
+    fn peer(id: &str, role: PeerRole) -> PeerInfo {
+        PeerInfo {
+            id: id.into(),
+            name: id.into(),
+            role,
+            ip: String::new(),
+            stream_port: 5000,
+            version: "0.1.0".into(),
+            max_profile: None,
+            max_width: None,
+            max_height: None,
+            max_fps: None,
+            stale: false,
+            last_seen: 0,
+            protocol: PROTOCOL_VERSION,
+            protocol_mismatch: false,
+        }
+    }
+
+    /// Record `peer` as announced from localhost
+    fn announce(service: &DiscoveryService, peer: PeerInfo) -> Option<PeerInfo> {
+        let from = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), service.config().port);
+        service.shared.handle_message(DiscoveryMessage::Announce(peer), from).expect("handle announce")
+    }
+
+    #[test]
+    fn ping_measures_round_trip_over_loopback() {
+        let service = service(PeerRole::Teacher);
+        // A peer at 127.0.0.1 on our discovery port is this very service: it answers its
+        // own ping and then receives the pong
+        announce(&service, peer("echo", PeerRole::Student));
+        assert_eq!(service.rtt_ms("echo"), None);
+
+        service.ping("echo").expect("ping");
+        for _ in 0..2 {
+            service.shared.process().expect("process");
+        }
+        let rtt = service.rtt_ms("echo").expect("rtt");
+        assert!((0.0..100.0).contains(&rtt), "{} ms", rtt);
+        let offset = service.rtt_sample("echo").expect("sample").clock_offset_ms;
+        assert!(offset.abs() < 100.0, "{} ms", offset);
+    }
+
+    #[test]
+    fn unknown_pongs_and_peers_are_ignored() {
+        let service = service(PeerRole::Teacher);
+        let from = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1);
+        let pong = DiscoveryMessage::Pong { nonce: 42, sent_at: now_ms(), replied_at: now_ms() };
+        assert!(service.shared.handle_message(pong, from).expect("handle pong").is_none());
+        assert!(service.shared.rtts.lock().is_empty());
+
+        let error = service.ping("nobody").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn profile_is_capped_by_the_least_capable_student() {
+        let service = service(PeerRole::Teacher);
+        assert_eq!(service.student_limits().max_profile, None);
         
-        // Remove stale peers
-        peers.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < PEER_TIMEOUT);
+        let with_profile = |id: &str, role, max_profile| PeerInfo { max_profile, ..peer(id, role) };
+        announce(&service, with_profile("high", PeerRole::Student, Some(H264Profile::High)));
+        announce(&service, with_profile("main", PeerRole::Student, Some(H264Profile::Main)));
+        // Teachers don't decode our stream
+        announce(&service, with_profile("teacher", PeerRole::Teacher, Some(H264Profile::Baseline)));
+        assert_eq!(service.student_limits().max_profile, Some(H264Profile::Main));
         
-        peers.values().map(|(p, _)| p.clone()).collect()
+        // A student that does not say is taken as Baseline only
+        announce(&service, with_profile("old", PeerRole::Student, None));
+        assert_eq!(service.student_limits().max_profile, Some(H264Profile::Baseline));
     }
 
-    /// Get teachers only
-    pub fn get_teachers(&self) -> Vec<PeerInfo> {
-        self.get_peers()
-            .into_iter()
-            .filter(|p| p.role == PeerRole::Teacher)
-            .collect()
+    #[test]
+    fn ids_are_unique_and_kept_across_restarts() {
+        let ids: std::collections::HashSet<_> = (0..10_000).map(|_| generate_id()).collect();
+        assert_eq!(ids.len(), 10_000);
+        
+        let dir = std::env::temp_dir().join(format!("discovery-id-{}", generate_id()));
+        let path = dir.join("peer_id");
+        let id = load_or_create_id(&path).expect("create id");
+        assert_eq!(load_or_create_id(&path).expect("load id"), id);
+        
+        // A corrupt file is replaced with a fresh id
+        fs::write(&path, "not an id").unwrap();
+        let replaced = load_or_create_id(&path).expect("replace id");
+        assert_ne!(replaced, id);
+        assert!(Uuid::parse_str(&replaced).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    /// Get students only
-    pub fn get_students(&self) -> Vec<PeerInfo> {
-        self.get_peers()
-            .into_iter()
-            .filter(|p| p.role == PeerRole::Student)
-            .collect()
+    #[test]
+    fn malformed_and_oversized_packets_are_dropped() {
+        let service = service(PeerRole::Teacher);
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let to = (Ipv4Addr::LOCALHOST, service.config().port);
+        let packet = |json: &[u8]| [DISCOVERY_MAGIC, json].concat();
+        let announce_json = |peer: PeerInfo| serde_json::to_vec(&DiscoveryMessage::Announce(peer)).unwrap();
+        
+        let long_name = PeerInfo { name: "x".repeat(MAX_FIELD_LEN + 1), ..peer("long", PeerRole::Student) };
+        let mut oversized = packet(&announce_json(peer("big", PeerRole::Student)));
+        oversized.resize(MAX_PACKET_SIZE + 1, b' ');
+        let valid = announce_json(peer("valid", PeerRole::Student));
+        let hostile = [
+            b"SCR".to_vec(),
+            b"NOTMAGIC{}".to_vec(),
+            packet(b"{\"Announce\": garbage"),
+            packet(&valid[..valid.len() / 2]),
+            packet(&[0xFF; 64]),
+            packet(&announce_json(long_name)),
+            oversized,
+        ];
+        for hostile in &hostile {
+            socket.send_to(hostile, to).unwrap();
+            assert!(service.shared.process().expect("process").is_none(), "{} byte packet accepted", hostile.len());
+        }
+        assert!(service.get_peers().is_empty());
+        
+        // Still listening afterwards
+        socket.send_to(&packet(&valid), to).unwrap();
+        assert_eq!(service.shared.process().expect("process").map(|p| p.id), Some("valid".into()));
+        
+        // A flood of distinct ids stops at MAX_PEERS
+        for i in 0..MAX_PEERS + 50 {
+            announce(&service, peer(&format!("flood-{}", i), PeerRole::Student));
+        }
+        assert_eq!(service.get_peers().len(), MAX_PEERS);
     }
 
-    pub fn local_info(&self) -> &PeerInfo {
-        &self.local_info
+    #[test]
+    fn background_thread_discovers_peers_on_its_own() {
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let mut teacher = service_on(PeerRole::Teacher, port);
+        let mut student = service_on(PeerRole::Student, port);
+        teacher.start().expect("start teacher");
+        student.start().expect("start student");
+        
+        // Only the background threads announce and receive here
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (teacher.get_students().is_empty() || student.get_teachers().is_empty()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(teacher.get_students().iter().map(|p| &p.id).collect::<Vec<_>>(), [&student.local_info().id]);
+        assert_eq!(student.get_teachers().iter().map(|p| &p.id).collect::<Vec<_>>(), [&teacher.local_info().id]);
+        
+        teacher.stop();
+        student.stop();
     }
-}
 
-fn get_local_ip() -> Option<String> {
-    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
-    socket.connect("8.8.8.8:80").ok()?;
-    socket.local_addr().ok().map(|a| a.ip().to_string())
-}
+    #[test]
+    fn services_share_a_port_and_fall_back_when_it_is_taken() {
+        let free_port = || UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        
+        // Two instances on one port, as a teacher and a test student on one machine, both hear a broadcast
+        let port = free_port();
+        let first = service_on(PeerRole::Teacher, port);
+        let second = service_on(PeerRole::Student, port);
+        first.announce().expect("announce");
+        assert_eq!(second.shared.process().expect("process").map(|p| p.id), Some(first.local_info().id));
+        assert!(first.shared.process().expect("process").is_none(), "own announcement");
+        
+        // A socket without address reuse holds the port: the fallback is used, or a ConfigError
+        let exclusive = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let taken = exclusive.local_addr().unwrap().port();
+        let config = DiscoveryConfig { port: taken, ..DiscoveryConfig::default() };
+        let error = DiscoveryService::new("test", PeerRole::Student, 5000, config).err().expect("port taken");
+        assert!(matches!(error, BroadcastError::ConfigError(_)), "{}", error);
+        
+        let fallback = free_port();
+        let config = DiscoveryConfig { port: taken, fallback_port: Some(fallback), ..DiscoveryConfig::default() };
+        let service = DiscoveryService::new("test", PeerRole::Student, 5000, config).expect("fallback port");
+        assert_eq!(service.shared.socket.local_addr().unwrap().port(), fallback);
+    }
 
-fn generate_id() -> String {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos();
-    format!("{:x}", timestamp)
+    #[test]
+    fn mismatched_protocol_versions_are_listed_but_not_used() {
+        let service = service(PeerRole::Student);
+        let newer = PeerInfo { protocol: PROTOCOL_VERSION + 1, ..peer("newer", PeerRole::Teacher) };
+        announce(&service, newer);
+        announce(&service, peer("current", PeerRole::Teacher));
+        
+        // An older build sends no protocol field at all
+        let mut old = serde_json::to_value(peer("old", PeerRole::Teacher)).unwrap();
+        old.as_object_mut().unwrap().remove("protocol");
+        let old: PeerInfo = serde_json::from_value(old).unwrap();
+        assert_eq!(old.protocol, 0);
+        let from = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1);
+        service.shared.handle_message(DiscoveryMessage::Response(old), from).expect("handle response");
+        
+        let mut peers: Vec<_> = service.get_peers().into_iter().map(|p| (p.id, p.protocol_mismatch)).collect();
+        peers.sort();
+        assert_eq!(peers, [("current".into(), false), ("newer".into(), true), ("old".into(), true)]);
+        let teachers: Vec<_> = service.get_teachers().into_iter().map(|p| p.id).collect();
+        assert_eq!(teachers, ["current"]);
+    }
 }
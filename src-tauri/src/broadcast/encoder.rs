@@ -1,50 +1,164 @@
-use openh264::encoder::{Encoder, EncoderConfig};
+use openh264::encoder::{Encoder, EncoderConfig, Profile, QpRange, RateControlMode};
 use openh264::formats::YUVSource;
 use openh264::OpenH264API;
+use rayon::prelude::*;
 
-use super::types::BroadcastError;
+use super::rtp::contains_keyframe;
+use super::types::{luma_offset, BroadcastError, CodecBackend, ColorSpace, H264Profile, RateControl, StreamConfig};
 
-pub struct H264Encoder {
+/// Common interface of the H.264 encoder backends
+pub trait VideoEncoder {
+    /// Encode an RGB frame, returns the H.264 data and whether it is a keyframe
+    fn encode(&mut self, rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError>;
+
+    /// Change the frame rate and the bitrate or quality target; the next frame is a keyframe
+    /// if the encoder had to be rebuilt
+    fn set_rate_control(&mut self, fps: u32, rate_control: RateControl) -> Result<(), BroadcastError>;
+
+    /// Make the next encoded frame an IDR so receivers can start decoding from it
+    fn force_keyframe(&mut self);
+
+    /// Drain output still held back (lookahead, B-frames) when the stream ends.
+    /// Empty for encoders that emit every frame as it is encoded, such as openh264
+    /// in its low-latency configuration.
+    fn flush(&mut self) -> Result<Vec<u8>, BroadcastError> {
+        Ok(Vec::new())
+    }
+
+    /// Backend name for logs
+    fn name(&self) -> &'static str;
+}
+
+/// Encoder factory
+pub struct H264Encoder;
+
+impl H264Encoder {
+    /// Create an encoder for `config.encoder_backend`, falling back to openh264 if hardware
+    /// setup fails. Size, frame rate and rate control are passed separately since the
+    /// stream may run below the configured ones.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        width: u32,
+        height: u32,
+        fps: u32,
+        rate_control: RateControl,
+        config: &StreamConfig,
+    ) -> Result<Box<dyn VideoEncoder>, BroadcastError> {
+        let software = || OpenH264Encoder::new(width, height, fps, rate_control,
+            config.color_space, config.full_range, config.profile)
+            .map(|encoder| encoder.with_conversion_threads(config.conversion_threads));
+        let encoder: Box<dyn VideoEncoder> = match config.encoder_backend {
+            CodecBackend::Hardware => match create_hardware_encoder(width, height, fps, rate_control) {
+                Ok(encoder) => encoder,
+                Err(e) => {
+                    log::warn!("Hardware encoder unavailable ({}), falling back to software", e);
+                    Box::new(software()?)
+                }
+            },
+            CodecBackend::Software => Box::new(software()?),
+        };
+        
+        log::info!("Active encoder backend: {}", encoder.name());
+        Ok(encoder)
+    }
+}
+
+/// Hardware encoders (NVENC/QuickSync/VAAPI) need a platform encoder binding,
+/// which this build does not include
+fn create_hardware_encoder(
+    _width: u32,
+    _height: u32,
+    _fps: u32,
+    _rate_control: RateControl,
+) -> Result<Box<dyn VideoEncoder>, BroadcastError> {
+    Err(BroadcastError::EncoderError("no hardware encoder support in this build".into()))
+}
+
+/// Software encoder using openh264
+pub struct OpenH264Encoder {
     encoder: Encoder,
     width: u32,
     height: u32,
+    fps: u32,
+    rate_control: RateControl,
     frame_count: u64,
+    color_space: ColorSpace,
+    full_range: bool,
+    profile: H264Profile,
+    /// See `StreamConfig::conversion_threads`
+    conversion_threads: u32,
     // Pre-allocated YUV buffer for zero-copy
     yuv_buffer: Vec<u8>,
 }
 
-impl H264Encoder {
-    pub fn new(width: u32, height: u32, fps: u32, bitrate_kbps: u32) -> Result<Self, BroadcastError> {
-        let api = OpenH264API::from_source();
-        
-        // Optimize for LOW LATENCY
-        let config = EncoderConfig::new()
-            .set_bitrate_bps(bitrate_kbps * 1000)
-            .max_frame_rate(fps as f32)
-            .enable_skip_frame(false);
-        
-        let encoder = Encoder::with_api_config(api, config)
-            .map_err(|e| BroadcastError::EncoderError(format!("Failed to create encoder: {}", e)))?;
+impl OpenH264Encoder {
+    pub fn new(
+        width: u32,
+        height: u32,
+        fps: u32,
+        rate_control: RateControl,
+        color_space: ColorSpace,
+        full_range: bool,
+        profile: H264Profile,
+    ) -> Result<Self, BroadcastError> {
+        let encoder = Self::create(fps, rate_control, profile)?;
         
         // Pre-allocate YUV buffer
         let y_size = (width * height) as usize;
         let uv_size = y_size / 4;
         let yuv_buffer = vec![0u8; y_size + uv_size * 2];
         
-        log::info!("H264 Encoder: {}x{} @ {} fps, {} kbps", width, height, fps, bitrate_kbps);
+        log::info!("H264 Encoder: {}x{} @ {} fps, {}, {:?} profile, {:?} {} range", width, height, fps,
+            rate_control, profile, color_space, if full_range { "full" } else { "limited" });
         
         Ok(Self {
             encoder,
             width,
             height,
+            fps,
+            rate_control,
             frame_count: 0,
+            color_space,
+            full_range,
+            profile,
+            conversion_threads: 0,
             yuv_buffer,
         })
     }
 
+    /// Convert RGB to YUV in this many bands in parallel (0 = auto, 1 = serial)
+    pub fn with_conversion_threads(mut self, threads: u32) -> Self {
+        self.conversion_threads = threads;
+        self
+    }
+
+    fn create(fps: u32, rate_control: RateControl, profile: H264Profile) -> Result<Encoder, BroadcastError> {
+        let api = OpenH264API::from_source();
+        
+        // Optimize for LOW LATENCY
+        let config = EncoderConfig::new()
+            .max_frame_rate(fps.max(1) as f32)
+            .enable_skip_frame(false)
+            .profile(match profile {
+                H264Profile::Baseline => Profile::Baseline,
+                H264Profile::Main => Profile::Main,
+                H264Profile::High => Profile::High,
+            });
+        let config = match rate_control {
+            RateControl::Bitrate(kbps) => config.set_bitrate_bps(kbps * 1000),
+            // Pin the QP so frame size follows content instead of a bitrate target
+            RateControl::Quality(qp) => config
+                .rate_control_mode(RateControlMode::Quality)
+                .qp(QpRange::new(qp as u8, qp as u8)),
+        };
+        
+        Encoder::with_api_config(api, config)
+            .map_err(|e| BroadcastError::EncoderError(format!("Failed to create encoder: {}", e)))
+    }
+
     /// Encode RGB frame to H.264 - OPTIMIZED for low latency
     #[inline]
-    pub fn encode(&mut self, rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError> {
+    fn encode_frame(&mut self, rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError> {
         // Fast RGB to YUV conversion (in-place)
         self.rgb_to_yuv420_fast(rgb_data);
         
@@ -65,25 +179,88 @@ impl H264Encoder {
             return Ok((Vec::new(), false));
         }
         
-        // Fast keyframe detection
-        let is_keyframe = self.is_keyframe(&raw);
+        let is_keyframe = contains_keyframe(&raw);
         self.frame_count += 1;
         
         Ok((raw, is_keyframe))
     }
 
-    /// Fast RGB to YUV420 conversion using SIMD-friendly patterns
+    /// Fast RGB to YUV420 conversion using SIMD-friendly patterns, split into horizontal
+    /// bands converted in parallel for large frames
     #[inline]
-    fn rgb_to_yuv420_fast(&mut self, rgb: &[u8]) {
+    pub(crate) fn rgb_to_yuv420_fast(&mut self, rgb: &[u8]) {
         let width = self.width as usize;
         let height = self.height as usize;
         let y_size = width * height;
-        let uv_width = width / 2;
+        let conversion = YuvConversion {
+            width,
+            coefficients: self.color_space.rgb_to_yuv(self.full_range),
+            y_offset: luma_offset(self.full_range),
+        };
+        let bands = self.conversion_bands();
         
         // Split buffer into planes
         let (y_plane, uv_planes) = self.yuv_buffer.split_at_mut(y_size);
         let (u_plane, v_plane) = uv_planes.split_at_mut(y_size / 4);
         
+        if bands <= 1 {
+            conversion.convert_band(rgb, height, y_plane, u_plane, v_plane);
+            return;
+        }
+        
+        // Bands start on even rows so each one owns whole 2x2 chroma blocks
+        let band_rows = (height.div_ceil(bands) + 1) & !1;
+        let uv_band_len = band_rows / 2 * (width / 2);
+        y_plane.par_chunks_mut(band_rows * width)
+            .zip(u_plane.par_chunks_mut(uv_band_len))
+            .zip(v_plane.par_chunks_mut(uv_band_len))
+            .enumerate()
+            .for_each(|(band, ((y_band, u_band), v_band))| {
+                let rgb_band = rgb.get(band * band_rows * width * 3..).unwrap_or(&[]);
+                conversion.convert_band(rgb_band, y_band.len() / width, y_band, u_band, v_band);
+            });
+    }
+
+    /// Number of bands to convert the frame in
+    fn conversion_bands(&self) -> usize {
+        let (width, height) = (self.width as usize, self.height as usize);
+        // Odd sizes have a partial chroma row that does not split evenly
+        if width % 2 != 0 || height % 2 != 0 {
+            return 1;
+        }
+        let bands = match self.conversion_threads {
+            // Small frames convert faster than the threads take to wake up
+            0 if width * height >= PARALLEL_CONVERSION_MIN_PIXELS => rayon::current_num_threads(),
+            0 => 1,
+            threads => threads as usize,
+        };
+        bands.clamp(1, height / 2)
+    }
+}
+
+/// Smallest frame `conversion_threads: 0` converts in parallel: from 900p, serial conversion
+/// takes over a quarter of a 60 fps frame (`throughput` measures 720p 3.2 ms, 1080p 7.4 ms
+/// and 2160p 30 ms on one core)
+const PARALLEL_CONVERSION_MIN_PIXELS: usize = 1600 * 900;
+
+/// RGB to YUV 4:2:0 conversion parameters, shared by every band of a frame
+#[derive(Clone, Copy)]
+struct YuvConversion {
+    width: usize,
+    coefficients: [[i32; 3]; 3],
+    y_offset: i32,
+}
+
+impl YuvConversion {
+    /// Convert `height` rows of `rgb`, starting on an even row, into the matching
+    /// slices of the Y, U and V planes
+    #[inline]
+    fn convert_band(&self, rgb: &[u8], height: usize, y_plane: &mut [u8], u_plane: &mut [u8], v_plane: &mut [u8]) {
+        let width = self.width;
+        let uv_width = width / 2;
+        let [y_row, u_row, v_row] = self.coefficients;
+        let y_offset = self.y_offset;
+        
         // Process 2x2 blocks for better cache locality
         for j in (0..height).step_by(2) {
             for i in (0..width).step_by(2) {
@@ -105,8 +282,8 @@ impl H264Encoder {
                         let g = rgb[rgb_idx + 1] as i32;
                         let b = rgb[rgb_idx + 2] as i32;
                         
-                        // Y plane - BT.601
-                        let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+                        // Y plane
+                        let y = ((y_row[0] * r + y_row[1] * g + y_row[2] * b + 128) >> 8) + y_offset;
                         y_plane[y_pos * width + x_pos] = y.clamp(0, 255) as u8;
                         
                         sum_r += r;
@@ -120,8 +297,8 @@ impl H264Encoder {
                 let avg_g = sum_g >> 2;
                 let avg_b = sum_b >> 2;
                 
-                let u = ((-38 * avg_r - 74 * avg_g + 112 * avg_b + 128) >> 8) + 128;
-                let v = ((112 * avg_r - 94 * avg_g - 18 * avg_b + 128) >> 8) + 128;
+                let u = ((u_row[0] * avg_r + u_row[1] * avg_g + u_row[2] * avg_b + 128) >> 8) + 128;
+                let v = ((v_row[0] * avg_r + v_row[1] * avg_g + v_row[2] * avg_b + 128) >> 8) + 128;
                 
                 let uv_idx = (j / 2) * uv_width + (i / 2);
                 if uv_idx < u_plane.len() {
@@ -131,30 +308,31 @@ impl H264Encoder {
             }
         }
     }
+}
 
-    /// Fast keyframe detection
-    #[inline]
-    fn is_keyframe(&self, data: &[u8]) -> bool {
-        // Look for IDR NAL (type 5) or SPS (type 7)
-        for i in 0..data.len().saturating_sub(5) {
-            if data[i] == 0 && data[i+1] == 0 {
-                let (offset, found) = if data[i+2] == 1 {
-                    (i + 3, true)
-                } else if data[i+2] == 0 && i + 3 < data.len() && data[i+3] == 1 {
-                    (i + 4, true)
-                } else {
-                    (0, false)
-                };
-                
-                if found && offset < data.len() {
-                    let nal_type = data[offset] & 0x1F;
-                    if nal_type == 5 || nal_type == 7 {
-                        return true;
-                    }
-                }
-            }
+impl VideoEncoder for OpenH264Encoder {
+    fn encode(&mut self, rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError> {
+        self.encode_frame(rgb_data)
+    }
+
+    fn set_rate_control(&mut self, fps: u32, rate_control: RateControl) -> Result<(), BroadcastError> {
+        if (fps, rate_control) == (self.fps, self.rate_control) {
+            return Ok(());
         }
-        false
+        // The openh264 bindings only take the frame rate and rate control at creation, so rebuild
+        self.encoder = Self::create(fps, rate_control, self.profile)?;
+        self.fps = fps;
+        self.rate_control = rate_control;
+        log::info!("H264 Encoder now {} fps, {}", fps, rate_control);
+        Ok(())
+    }
+
+    fn force_keyframe(&mut self) {
+        self.encoder.force_intra_frame();
+    }
+
+    fn name(&self) -> &'static str {
+        "openh264 (software)"
     }
 }
 
@@ -190,3 +368,111 @@ impl<'a> YUVSource for YUVBufferRef<'a> {
         &self.data[y_size + u_size..]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcast::source::{FrameSource, SyntheticSource};
+
+    /// `width`x`height` packed RGB converted to I420 the way the encoder does
+    fn to_i420(rgb: &[u8], width: usize, height: usize, color_space: ColorSpace, full_range: bool) -> Vec<u8> {
+        let conversion = YuvConversion {
+            width,
+            coefficients: color_space.rgb_to_yuv(full_range),
+            y_offset: luma_offset(full_range),
+        };
+        let mut yuv = vec![0; width * height * 3 / 2];
+        let (y_plane, uv_planes) = yuv.split_at_mut(width * height);
+        let (u_plane, v_plane) = uv_planes.split_at_mut(width * height / 4);
+        conversion.convert_band(rgb, height, y_plane, u_plane, v_plane);
+        yuv
+    }
+
+    #[test]
+    fn color_patches_match_reference_matrices() {
+        // Limited range Y'CbCr from the BT.601 and BT.709 equations, rounded
+        let cases = [
+            (ColorSpace::Bt601, [255, 0, 0], [81, 90, 240]),
+            (ColorSpace::Bt601, [0, 255, 0], [145, 54, 34]),
+            (ColorSpace::Bt601, [0, 0, 255], [41, 240, 110]),
+            (ColorSpace::Bt601, [128, 128, 128], [126, 128, 128]),
+            (ColorSpace::Bt709, [255, 0, 0], [63, 102, 240]),
+            (ColorSpace::Bt709, [0, 255, 0], [173, 42, 26]),
+            (ColorSpace::Bt709, [0, 0, 255], [32, 240, 118]),
+            (ColorSpace::Bt709, [128, 128, 128], [126, 128, 128]),
+        ];
+        for (color_space, rgb, expected) in cases {
+            let patch = rgb.repeat(4);
+            let yuv = to_i420(&patch, 2, 2, color_space, false);
+            for (plane, (&got, want)) in [yuv[0], yuv[4], yuv[5]].iter().zip(expected).enumerate() {
+                assert!((got as i32 - want).abs() <= 1,
+                    "{:?} {:?} plane {}: {} instead of {}", color_space, rgb, plane, got, want);
+            }
+        }
+    }
+
+    #[test]
+    fn gradient_endpoints_survive_the_round_trip() {
+        // Black to white, left to right, through the encoder conversion and the decoder's
+        let (width, height) = (256, 2);
+        let rgb: Vec<u8> = (0..height).flat_map(|_| (0..width).flat_map(|x| [x as u8; 3])).collect();
+        for full_range in [true, false] {
+            let data = to_i420(&rgb, width, height, ColorSpace::Bt709, full_range);
+            
+            // Full range uses all of Y; limited range stays in 16-235
+            let (black, white) = (data[0], data[width - 1]);
+            assert_eq!((black, white), if full_range { (0, 255) } else { (16, 235) });
+            
+            let mut decoded = Vec::new();
+            let yuv = YUVBufferRef { data: &data, width, height };
+            crate::broadcast::decoder::i420_to_rgb(&yuv, ColorSpace::Bt709, full_range, |r, g, b| decoded.push([r, g, b]));
+            for (x, want) in [(0, 0), (width - 1, 255)] {
+                for channel in decoded[x] {
+                    assert!((channel as i32 - want).abs() <= 2,
+                        "full_range={} pixel {}: {:?}", full_range, x, decoded[x]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn banded_conversion_matches_serial() {
+        // 3 bands over 70 rows: 24-row bands and a short last one, all starting on even rows
+        let (width, height) = (64, 70);
+        let mut source = SyntheticSource::new(width, height);
+        let rgb = source.capture_frame().expect("frame").expect("frame");
+        let convert = |threads| {
+            let mut encoder = OpenH264Encoder::new(width, height, 30, RateControl::Bitrate(1000),
+                ColorSpace::Bt709, false, H264Profile::Baseline).expect("encoder").with_conversion_threads(threads);
+            assert_eq!(encoder.conversion_bands(), threads as usize);
+            encoder.rgb_to_yuv420_fast(&rgb);
+            encoder.yuv_buffer
+        };
+        assert!(convert(3) == convert(1), "banded conversion differs from serial");
+    }
+
+    #[test]
+    fn static_content_costs_less_in_quality_mode() {
+        // A still slide with detail: bitrate mode spends its budget refining it, constant
+        // quality stops once it reaches its QP
+        let (width, height) = (640, 360);
+        let slide: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                let ink = if (x / 3 + y / 5) % 7 == 0 { 20 } else { 235 };
+                [ink, ink, (x % 256) as u8]
+            })
+            .collect();
+        let total_bytes = |rate_control| {
+            let mut encoder = OpenH264Encoder::new(width as u32, height as u32, 30, rate_control,
+                ColorSpace::Bt601, false, H264Profile::Baseline).expect("encoder");
+            (0..30).map(|_| encoder.encode(&slide).expect("encode").0.len()).sum::<usize>()
+        };
+        
+        let bitrate = total_bytes(RateControl::Bitrate(8000));
+        let quality = total_bytes(RateControl::Quality(30));
+        assert!(quality > 0);
+        assert!(quality < bitrate, "quality mode {} bytes, bitrate mode {} bytes", quality, bitrate);
+    }
+}
+
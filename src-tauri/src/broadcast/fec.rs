@@ -0,0 +1,423 @@
+//! XOR parity forward error correction for the RTP stream
+//! One parity packet per group of K media packets recovers a single lost packet
+
+use std::collections::{HashMap, VecDeque};
+
+use super::rtp::RTP_PAYLOAD_TYPE_FEC;
+
+/// Parity header after the RTP header: base sequence (2), count (1), length XOR (2)
+pub const FEC_HEADER_SIZE: usize = 5;
+const RTP_HEADER_SIZE: usize = 12;
+/// A parity packet is this much larger than the largest media packet in its group
+pub const FEC_PACKET_OVERHEAD: usize = RTP_HEADER_SIZE + FEC_HEADER_SIZE;
+/// Released packets kept around so later parity packets can use them
+const HISTORY_SIZE: usize = 256;
+/// Packets held back waiting for a gap to be filled before giving up on it
+const MAX_HELD: usize = 128;
+
+/// Builds one parity packet per group of media packets (sender side)
+pub struct FecEncoder {
+    group_size: usize,
+    sequence: u16,
+    base_seq: u16,
+    count: usize,
+    length_xor: u16,
+    parity: Vec<u8>,
+    ssrc: u32,
+    timestamp: u32,
+}
+
+impl FecEncoder {
+    pub fn new(group_size: usize) -> Self {
+        Self {
+            group_size: group_size.clamp(2, u8::MAX as usize),
+            sequence: 0,
+            base_seq: 0,
+            count: 0,
+            length_xor: 0,
+            parity: Vec::new(),
+            ssrc: 0,
+            timestamp: 0,
+        }
+    }
+
+    /// Add a media packet; returns the parity packet when the group is full
+    pub fn push(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < RTP_HEADER_SIZE {
+            return None;
+        }
+
+        if self.count == 0 {
+            self.base_seq = u16::from_be_bytes([packet[2], packet[3]]);
+            self.ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        }
+        self.timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+
+        // Shorter packets are implicitly zero padded to the longest one
+        if self.parity.len() < packet.len() {
+            self.parity.resize(packet.len(), 0);
+        }
+        for (p, b) in self.parity.iter_mut().zip(packet) {
+            *p ^= b;
+        }
+        self.length_xor ^= packet.len() as u16;
+        self.count += 1;
+
+        if self.count >= self.group_size {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// Emit parity for a partial group (e.g. at the end of a frame)
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let seq = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut packet = Vec::with_capacity(RTP_HEADER_SIZE + FEC_HEADER_SIZE + self.parity.len());
+        // RTP header with its own payload type and sequence space
+        packet.push(0x80);
+        packet.push(RTP_PAYLOAD_TYPE_FEC);
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        // FEC header
+        packet.extend_from_slice(&self.base_seq.to_be_bytes());
+        packet.push(self.count as u8);
+        packet.extend_from_slice(&self.length_xor.to_be_bytes());
+        packet.extend_from_slice(&self.parity);
+
+        self.count = 0;
+        self.length_xor = 0;
+        self.parity.clear();
+
+        Some(packet)
+    }
+}
+
+/// Reorders media packets and recovers single losses per group (receiver side)
+pub struct FecDecoder {
+    /// Source of the packets held and remembered; another source restarts the decoder
+    ssrc: Option<u32>,
+    next_seq: Option<u16>,
+    held: HashMap<u16, Vec<u8>>,
+    history: HashMap<u16, Vec<u8>>,
+    history_order: VecDeque<u16>,
+    recovered: u64,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self {
+            ssrc: None,
+            next_seq: None,
+            held: HashMap::new(),
+            history: HashMap::new(),
+            history_order: VecDeque::new(),
+            recovered: 0,
+        }
+    }
+
+    /// Number of packets rebuilt from parity so far
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// Forget all packets and the expected sequence, e.g. when the sender restarts.
+    /// The recovered count is kept.
+    pub fn reset(&mut self) {
+        self.ssrc = None;
+        self.next_seq = None;
+        self.held.clear();
+        self.history.clear();
+        self.history_order.clear();
+    }
+
+    /// Add a media packet; returns the packets that can now be processed, in order
+    pub fn on_media(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        if packet.len() < RTP_HEADER_SIZE {
+            return Vec::new();
+        }
+
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        self.check_source(packet);
+        if let Some(next) = self.next_seq {
+            // Far outside the window of any group: a restarted sequence, not a late
+            // or early packet of this one
+            let offset = seq.wrapping_sub(next) as i16 as i32;
+            if offset.unsigned_abs() as usize > HISTORY_SIZE {
+                log::info!("FEC sequence jumped from {} to {}, restarting", next, seq);
+                self.reset();
+                self.check_source(packet);
+            }
+        }
+        self.remember(seq, packet);
+
+        let next = *self.next_seq.get_or_insert(seq);
+        if (seq.wrapping_sub(next) as i16) < 0 {
+            // Too late, its gap was already skipped
+            return Vec::new();
+        }
+
+        self.held.insert(seq, packet.to_vec());
+
+        if self.held.len() > MAX_HELD {
+            // Parity never came: give up on the oldest gap
+            if let Some(oldest) = self.held.keys().copied().min_by_key(|s| s.wrapping_sub(next)) {
+                return self.release_through(oldest);
+            }
+        }
+
+        self.release()
+    }
+
+    /// Add a parity packet; recovers a single missing packet of its group
+    pub fn on_parity(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        if packet.len() < RTP_HEADER_SIZE + FEC_HEADER_SIZE {
+            return Vec::new();
+        }
+        self.check_source(packet);
+
+        let header = &packet[RTP_HEADER_SIZE..RTP_HEADER_SIZE + FEC_HEADER_SIZE];
+        let base_seq = u16::from_be_bytes([header[0], header[1]]);
+        let count = header[2] as u16;
+        let length_xor = u16::from_be_bytes([header[3], header[4]]);
+        let parity = &packet[RTP_HEADER_SIZE + FEC_HEADER_SIZE..];
+
+        // The header comes off the wire: a group must be non-empty and lie around the
+        // packets we hold or remember, otherwise release_through could skip far ahead
+        // and make every following media packet look late
+        if count == 0 {
+            log::debug!("FEC packet with an empty group dropped");
+            return Vec::new();
+        }
+        if let Some(next) = self.next_seq {
+            let offset = base_seq.wrapping_sub(next) as i16 as i32;
+            if offset > MAX_HELD as i32 || offset < -(HISTORY_SIZE as i32) {
+                log::debug!("FEC group at seq {} too far from seq {}, dropped", base_seq, next);
+                return Vec::new();
+            }
+        }
+
+        let group: Vec<u16> = (0..count).map(|i| base_seq.wrapping_add(i)).collect();
+        let missing: Vec<u16> = group.iter().copied().filter(|s| !self.history.contains_key(s)).collect();
+
+        if let [lost] = missing[..] {
+            let mut data = parity.to_vec();
+            let mut length = length_xor;
+            for seq in group.iter().filter(|s| **s != lost) {
+                let other = &self.history[seq];
+                if data.len() < other.len() {
+                    data.resize(other.len(), 0);
+                }
+                for (d, b) in data.iter_mut().zip(other) {
+                    *d ^= b;
+                }
+                length ^= other.len() as u16;
+            }
+
+            let length = length as usize;
+            if length >= RTP_HEADER_SIZE && length <= data.len() {
+                data.truncate(length);
+                log::debug!("FEC recovered packet seq {}", lost);
+                self.recovered += 1;
+                self.remember(lost, &data);
+                let next = *self.next_seq.get_or_insert(lost);
+                if (lost.wrapping_sub(next) as i16) >= 0 {
+                    self.held.insert(lost, data);
+                }
+            }
+        }
+
+        // Nothing more can be recovered for this group
+        self.release_through(base_seq.wrapping_add(count))
+    }
+
+    /// Restart on a packet from another SSRC: its sequence numbers have nothing to do
+    /// with the packets held or remembered
+    fn check_source(&mut self, packet: &[u8]) {
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        if self.ssrc.is_some_and(|current| current != ssrc) {
+            log::info!("FEC source changed to SSRC {:08x}, restarting", ssrc);
+            self.reset();
+        }
+        self.ssrc = Some(ssrc);
+    }
+
+    /// Release held packets while the sequence is contiguous
+    fn release(&mut self) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(next) = self.next_seq {
+            match self.held.remove(&next) {
+                Some(packet) => {
+                    out.push(packet);
+                    self.next_seq = Some(next.wrapping_add(1));
+                }
+                None => break,
+            }
+        }
+        out
+    }
+
+    /// Release everything before `end`, skipping gaps that can no longer be filled
+    fn release_through(&mut self, end: u16) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(next) = self.next_seq {
+            if (end.wrapping_sub(next) as i16) <= 0 {
+                break;
+            }
+            if let Some(packet) = self.held.remove(&next) {
+                out.push(packet);
+            }
+            self.next_seq = Some(next.wrapping_add(1));
+        }
+        out.extend(self.release());
+        out
+    }
+
+    fn remember(&mut self, seq: u16, packet: &[u8]) {
+        if self.history.insert(seq, packet.to_vec()).is_none() {
+            self.history_order.push_back(seq);
+        }
+        while self.history_order.len() > HISTORY_SIZE {
+            if let Some(old) = self.history_order.pop_front() {
+                self.history.remove(&old);
+            }
+        }
+    }
+}
+
+impl Default for FecDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Media packet of `len` bytes whose payload bytes differ per sequence number
+    fn media(ssrc: u32, seq: u16, len: usize) -> Vec<u8> {
+        let mut packet = vec![0x80, 96];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&(seq as u32 * 3000).to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.extend((0..len - RTP_HEADER_SIZE).map(|i| (i as u16 ^ seq) as u8));
+        packet
+    }
+
+    #[test]
+    fn one_loss_per_group_is_recovered() {
+        // Sizes differ within each group and the sequence wraps around
+        let packets: Vec<Vec<u8>> = (0..16u16)
+            .map(|i| media(7, 65530u16.wrapping_add(i), 40 + 13 * i as usize))
+            .collect();
+        let mut encoder = FecEncoder::new(4);
+        let mut decoder = FecDecoder::new();
+
+        let mut released = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            let parity = encoder.push(packet);
+            // A different member of every group is lost, the largest one included
+            if i % 4 != 3 - i / 4 {
+                released.extend(decoder.on_media(packet));
+            }
+            if let Some(parity) = parity {
+                released.extend(decoder.on_parity(&parity));
+            }
+        }
+        assert_eq!(released, packets);
+        assert_eq!(decoder.recovered(), 4);
+    }
+
+    #[test]
+    fn new_ssrc_restarts_the_sequence() {
+        let mut decoder = FecDecoder::new();
+        for seq in 1000..1005 {
+            assert_eq!(decoder.on_media(&media(1, seq, 20)), [media(1, seq, 20)]);
+        }
+
+        // A restarted teacher picks a new SSRC and sequence; its packets are not late
+        assert_eq!(decoder.on_media(&media(2, 10, 20)), [media(2, 10, 20)]);
+        assert_eq!(decoder.on_media(&media(2, 11, 20)), [media(2, 11, 20)]);
+    }
+
+    #[test]
+    fn sequence_jump_restarts_the_sequence() {
+        let mut decoder = FecDecoder::new();
+        for seq in 100..105 {
+            decoder.on_media(&media(1, seq, 20));
+        }
+
+        // Ahead of the window: released at once instead of held behind a huge gap
+        assert_eq!(decoder.on_media(&media(1, 5000, 20)), [media(1, 5000, 20)]);
+        // Behind it: the sequence restarted, nothing is late
+        assert_eq!(decoder.on_media(&media(1, 3, 20)), [media(1, 3, 20)]);
+    }
+
+    #[test]
+    fn reset_forgets_the_previous_stream() {
+        let mut decoder = FecDecoder::new();
+        let mut encoder = FecEncoder::new(2);
+        let old = media(1, 50, 30);
+        decoder.on_media(&old);
+        encoder.push(&old);
+        decoder.reset();
+
+        // Same SSRC and nearby sequence after a BYE: 49 is not late, and the old
+        // packet is not used to rebuild the new 51
+        assert_eq!(decoder.on_media(&media(1, 49, 20)), [media(1, 49, 20)]);
+        let parity = encoder.push(&media(1, 51, 30)).expect("parity");
+        assert_eq!(decoder.on_parity(&parity), Vec::<Vec<u8>>::new());
+        assert_eq!(decoder.recovered(), 0);
+    }
+
+    /// Parity packet with a hand-written header, as a hostile or corrupt sender could send
+    fn parity(ssrc: u32, base_seq: u16, count: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, RTP_PAYLOAD_TYPE_FEC, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.extend_from_slice(&base_seq.to_be_bytes());
+        packet.push(count);
+        packet.extend_from_slice(&[0, 20]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn hostile_parity_headers_do_not_skip_the_sequence() {
+        let mut decoder = FecDecoder::new();
+        for seq in 100..105 {
+            decoder.on_media(&media(1, seq, 20));
+        }
+        
+        // An empty group, groups far ahead of or behind the sequence, and a truncated header
+        let hostile = [
+            parity(1, 105, 0, &[0; 8]),
+            parity(1, 105 + 30_000, u8::MAX, &[0; 8]),
+            parity(1, 105 + MAX_HELD as u16 + 1, 4, &[0; 8]),
+            parity(1, 105u16.wrapping_sub(HISTORY_SIZE as u16 + 1), 4, &[0; 8]),
+            parity(1, 105, 4, &[])[..RTP_HEADER_SIZE + FEC_HEADER_SIZE - 1].to_vec(),
+        ];
+        for packet in &hostile {
+            assert_eq!(decoder.on_parity(packet), Vec::<Vec<u8>>::new());
+        }
+        assert_eq!(decoder.recovered(), 0);
+        
+        // The next media packets are still in sequence, not dropped as late
+        for seq in 105..108 {
+            assert_eq!(decoder.on_media(&media(1, seq, 20)), [media(1, seq, 20)]);
+        }
+        
+        // A parity packet whose XORed length is off does not invent a packet
+        decoder.on_media(&media(1, 109, 20));
+        let bogus = parity(1, 108, 2, &[0xFF; 8]);
+        assert_eq!(decoder.on_parity(&bogus), [media(1, 109, 20)]);
+        assert_eq!(decoder.recovered(), 0);
+    }
+}
@@ -0,0 +1,464 @@
+//! H.264 bitstream helpers: Annex-B (start codes, what the pipeline carries) to and from
+//! AVCC (length prefixed NALs, what MP4 and some decoders expect), the avcC record, and the
+//! SPS display size
+
+use super::rtp::{nal_units, NalType};
+
+/// Bytes of the NAL length prefix in the AVCC this module writes, and the usual size read
+pub const AVCC_LENGTH_SIZE: usize = 4;
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Annex-B to AVCC with 4-byte big endian lengths. NAL payloads, emulation prevention
+/// bytes included, are copied as they are.
+pub fn annexb_to_avcc(data: &[u8]) -> Vec<u8> {
+    let mut avcc = Vec::with_capacity(data.len() + AVCC_LENGTH_SIZE);
+    for (_, nal) in nal_units(data) {
+        avcc.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        avcc.extend_from_slice(nal);
+    }
+    avcc
+}
+
+/// AVCC with `length_size`-byte lengths (the avcC record's lengthSizeMinusOne + 1) to
+/// Annex-B with 4-byte start codes. A length running past the end of `data` is cut to
+/// what is there; a length size outside 1-4 gives nothing.
+pub fn avcc_to_annexb(data: &[u8], length_size: usize) -> Vec<u8> {
+    let mut annexb = Vec::with_capacity(data.len() + START_CODE.len());
+    if !(1..=4).contains(&length_size) {
+        return annexb;
+    }
+    let mut rest = data;
+    while rest.len() >= length_size {
+        let (prefix, tail) = rest.split_at(length_size);
+        let len = prefix.iter().fold(0, |len, &byte| len << 8 | byte as usize).min(tail.len());
+        annexb.extend_from_slice(&START_CODE);
+        annexb.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+    }
+    annexb
+}
+
+/// First SPS and PPS of an Annex-B stream, e.g. of a keyframe
+pub fn parameter_sets(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut sps = None;
+    let mut pps = None;
+    for (nal_type, nal) in nal_units(data) {
+        match nal_type {
+            NalType::Sps if sps.is_none() => sps = Some(nal),
+            NalType::Pps if pps.is_none() => pps = Some(nal),
+            _ => {}
+        }
+    }
+    sps.zip(pps)
+}
+
+/// AVCDecoderConfigurationRecord (ISO/IEC 14496-15 5.3.3.1) for one SPS and PPS, declaring
+/// 4-byte NAL lengths. `None` if the SPS is too short to hold its profile and level.
+pub fn avcc_config(sps: &[u8], pps: &[u8]) -> Option<Vec<u8>> {
+    let [_, profile, compatibility, level, ..] = *sps else { return None };
+    let mut record = vec![
+        1, // configurationVersion
+        profile,
+        compatibility,
+        level,
+        0xFC | (AVCC_LENGTH_SIZE as u8 - 1),
+        0xE0 | 1, // one SPS
+    ];
+    record.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    record.extend_from_slice(sps);
+    record.push(1); // one PPS
+    record.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    record.extend_from_slice(pps);
+
+    // High profiles also state chroma format and bit depth: what the encoder produces,
+    // 4:2:0 at 8 bits, and no SPS extensions
+    if matches!(profile, 100 | 110 | 122 | 144) {
+        record.extend_from_slice(&[0xFC | 1, 0xF8, 0xF8, 0]);
+    }
+    Some(record)
+}
+
+/// Display size an SPS declares (H.264 7.3.2.1.1): the coded macroblock size minus the
+/// frame cropping, e.g. 1920x1080 for a 1920x1088 coded picture. `sps` is the NAL unit
+/// with its header byte; `None` if it is truncated or malformed.
+pub fn sps_dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    let rbsp = unescape_rbsp(sps.get(1..)?);
+    let mut bits = BitReader::new(&rbsp);
+
+    let profile = bits.bits(8)?;
+    bits.bits(16)?; // constraint flags, level
+    bits.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format = 1;
+    let mut separate_planes = false;
+    if matches!(profile, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+        chroma_format = bits.ue()?;
+        if chroma_format == 3 {
+            separate_planes = bits.flag()?;
+        }
+        bits.ue()?; // bit_depth_luma_minus8
+        bits.ue()?; // bit_depth_chroma_minus8
+        bits.flag()?; // qpprime_y_zero_transform_bypass_flag
+        if bits.flag()? {
+            let lists = if chroma_format == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if bits.flag()? {
+                    skip_scaling_list(&mut bits, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    bits.ue()?; // log2_max_frame_num_minus4
+    match bits.ue()? {
+        0 => {
+            bits.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            bits.flag()?; // delta_pic_order_always_zero_flag
+            bits.se()?; // offset_for_non_ref_pic
+            bits.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..bits.ue()? {
+                bits.se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    bits.ue()?; // max_num_ref_frames
+    bits.flag()?; // gaps_in_frame_num_value_allowed_flag
+
+    let width_in_mbs = bits.ue()?.checked_add(1)?;
+    let height_in_map_units = bits.ue()?.checked_add(1)?;
+    let frame_mbs_only = bits.flag()?;
+    if !frame_mbs_only {
+        bits.flag()?; // mb_adaptive_frame_field_flag
+    }
+    bits.flag()?; // direct_8x8_inference_flag
+
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let width = width_in_mbs.checked_mul(16)?;
+    let height = height_in_map_units.checked_mul(16 * field_factor)?;
+    if !bits.flag()? {
+        return Some((width, height));
+    }
+
+    // Crop offsets count in chroma samples (frame rows for interlaced streams)
+    let (crop_x, crop_y) = match (separate_planes, chroma_format) {
+        (true, _) | (false, 0) => (1, field_factor),
+        (false, 1) => (2, 2 * field_factor),
+        (false, 2) => (2, field_factor),
+        _ => (1, field_factor),
+    };
+    let (left, right, top, bottom) = (bits.ue()?, bits.ue()?, bits.ue()?, bits.ue()?);
+    let width = width.checked_sub(left.checked_add(right)?.checked_mul(crop_x)?)?;
+    let height = height.checked_sub(top.checked_add(bottom)?.checked_mul(crop_y)?)?;
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+fn skip_scaling_list(bits: &mut BitReader, size: usize) -> Option<()> {
+    let mut last = 8i64;
+    let mut next = 8i64;
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + bits.se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+/// NAL payload without its emulation prevention bytes (the 03 of every 00 00 03)
+fn unescape_rbsp(payload: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(payload.len());
+    let mut zeros = 0;
+    for &byte in payload {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// MSB-first bit reader with the exp-Golomb codes SPS fields use
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = byte >> (7 - self.pos % 8) & 1;
+        self.pos += 1;
+        Some(bit == 1)
+    }
+
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| Some(value << 1 | self.flag()? as u32))
+    }
+
+    /// Unsigned exp-Golomb, ue(v)
+    fn ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.flag()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        let suffix = self.bits(leading_zeros)?;
+        ((1u64 << leading_zeros) - 1 + suffix as u64).try_into().ok()
+    }
+
+    /// Signed exp-Golomb, se(v)
+    fn se(&mut self) -> Option<i64> {
+        let code = self.ue()? as i64;
+        Some(if code % 2 == 1 { (code + 1) / 2 } else { -(code / 2) })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn avcc_lengths_of_every_size_are_read() {
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E];
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        let annexb = [&START_CODE[..], sps, &START_CODE, idr].concat();
+        
+        let cases: [(usize, &[u8]); 3] = [
+            (1, &[4, 0x67, 0x42, 0x00, 0x1E, 3, 0x65, 0x88, 0x84]),
+            (2, &[0, 4, 0x67, 0x42, 0x00, 0x1E, 0, 3, 0x65, 0x88, 0x84]),
+            (4, &[0, 0, 0, 4, 0x67, 0x42, 0x00, 0x1E, 0, 0, 0, 3, 0x65, 0x88, 0x84]),
+        ];
+        for (length_size, avcc) in cases {
+            assert_eq!(avcc_to_annexb(avcc, length_size), annexb, "{}-byte lengths", length_size);
+        }
+        assert!(avcc_to_annexb(cases[2].1, 0).is_empty());
+    }
+
+    #[test]
+    fn multi_nal_frames_round_trip() {
+        // Emulation prevention bytes and a NAL that ends in 03 stay as they are
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x00, 0x03, 0x01, 0x1E];
+        let pps: &[u8] = &[0x68, 0xCE, 0x38, 0x80];
+        let idr: &[u8] = &[0x65, 0x88, 0x00, 0x00, 0x03, 0x00, 0x84, 0x03];
+        let annexb = [&START_CODE[..], sps, &START_CODE, pps, &START_CODE, idr].concat();
+        
+        let avcc = annexb_to_avcc(&annexb);
+        assert_eq!(avcc, [&[0, 0, 0, 7][..], sps, &[0, 0, 0, 4], pps, &[0, 0, 0, 8], idr].concat());
+        assert_eq!(avcc_to_annexb(&avcc, AVCC_LENGTH_SIZE), annexb);
+    }
+
+    #[test]
+    fn three_byte_start_codes_come_back_as_four() {
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E];
+        let slice: &[u8] = &[0x41, 0x9A, 0x01];
+        let annexb = [&[0, 0, 1][..], sps, &[0, 0, 1], slice].concat();
+        
+        let avcc = annexb_to_avcc(&annexb);
+        assert_eq!(avcc, [&[0, 0, 0, 4][..], sps, &[0, 0, 0, 3], slice].concat());
+        assert_eq!(avcc_to_annexb(&avcc, AVCC_LENGTH_SIZE), [&START_CODE[..], sps, &START_CODE, slice].concat());
+    }
+
+    #[test]
+    fn truncated_avcc_keeps_what_is_there() {
+        let slice: &[u8] = &[0x41, 0x9A, 0x01];
+        
+        // A length running past the end is cut to the bytes present
+        let overlong = [&[0, 0, 0, 10][..], slice].concat();
+        assert_eq!(avcc_to_annexb(&overlong, 4), [&START_CODE[..], slice].concat());
+        
+        // A length prefix cut short after the last NAL adds nothing
+        let cut_prefix = [&[0, 3][..], slice, &[0]].concat();
+        assert_eq!(avcc_to_annexb(&cut_prefix, 2), [&START_CODE[..], slice].concat());
+        assert!(avcc_to_annexb(&[0, 0, 0], 4).is_empty());
+        assert!(annexb_to_avcc(&[]).is_empty());
+    }
+
+    /// x264's SPS for 1080p: 1920x1088 coded, 8 rows cropped off the bottom
+    const SPS_1080P: [u8; 27] = [
+        0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0xC0, 0x44, 0x00,
+        0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xF0, 0x3C, 0x60, 0xC6, 0x58,
+    ];
+
+    /// Writes SPS fields MSB first, for streams no encoder at hand produces
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, count: u32) {
+            for i in (0..count).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |= ((value >> i & 1) as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        fn ue(&mut self, value: u32) {
+            let code = value + 1;
+            let len = 32 - code.leading_zeros();
+            self.bits(0, len - 1);
+            self.bits(code, len);
+        }
+
+        fn se(&mut self, value: i32) {
+            self.ue(if value > 0 { 2 * value as u32 - 1 } else { 2 * value.unsigned_abs() });
+        }
+
+        /// NAL unit: header byte, then the RBSP with its stop bit and emulation prevention
+        fn into_nal(mut self, header: u8) -> Vec<u8> {
+            self.bits(1, 1);
+            let mut nal = vec![header];
+            let mut zeros = 0;
+            for byte in self.bytes {
+                if zeros >= 2 && byte <= 3 {
+                    nal.push(3);
+                    zeros = 0;
+                }
+                zeros = if byte == 0 { zeros + 1 } else { 0 };
+                nal.push(byte);
+            }
+            nal
+        }
+    }
+
+    /// Baseline SPS for a macroblock-aligned size, like an encoder at that size sends
+    pub(crate) fn baseline_sps(width: u32, height: u32) -> Vec<u8> {
+        let mut sps = BitWriter::default();
+        sps.bits(66, 8); // profile_idc: Baseline
+        sps.bits(0, 8);
+        sps.bits(40, 8); // level 4.0
+        sps.ue(0); // seq_parameter_set_id
+        sps.ue(0); // log2_max_frame_num_minus4
+        sps.ue(0); // pic_order_cnt_type
+        sps.ue(0);
+        sps.ue(1); // max_num_ref_frames
+        sps.bits(0, 1);
+        sps.ue(width / 16 - 1);
+        sps.ue(height / 16 - 1);
+        sps.bits(1, 1); // frame_mbs_only_flag
+        sps.bits(1, 1);
+        sps.bits(0, 1); // no cropping
+        sps.bits(0, 1); // no VUI
+        sps.into_nal(0x67)
+    }
+
+    #[test]
+    fn parameter_sets_are_the_first_sps_and_pps() {
+        let stream = [
+            &[0, 0, 0, 1, 0x09, 0xF0][..], // access unit delimiter
+            &[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1F],
+            &[0, 0, 1, 0x68, 0xCE, 0x38, 0x80],
+            &[0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28],
+            &[0, 0, 0, 1, 0x65, 0x88, 0x84],
+            &[0, 0, 0, 1, 0x68, 0xEE, 0x3C, 0x80],
+        ].concat();
+        assert_eq!(parameter_sets(&stream), Some((&[0x67, 0x42, 0x00, 0x1F][..], &[0x68, 0xCE, 0x38, 0x80][..])));
+        
+        // Both are needed; a P frame or an SPS alone has no parameter sets
+        assert_eq!(parameter_sets(&[0, 0, 0, 1, 0x41, 0x9A]), None);
+        assert_eq!(parameter_sets(&stream[..14]), None);
+    }
+
+    #[test]
+    fn avcc_config_lists_one_sps_and_pps() {
+        let sps = baseline_sps(1280, 720);
+        let pps = [0x68, 0xCE, 0x38, 0x80];
+        let record = avcc_config(&sps, &pps).expect("record");
+        
+        // Version, profile, compatibility, level, 4-byte lengths, one SPS
+        assert_eq!(record[..6], [1, 66, 0, 40, 0xFF, 0xE1]);
+        assert_eq!(record[6..8], (sps.len() as u16).to_be_bytes());
+        assert_eq!(record[8..8 + sps.len()], sps);
+        let rest = &record[8 + sps.len()..];
+        assert_eq!(rest, [&[1, 0, 4][..], &pps].concat());
+        
+        // Too short for profile and level
+        assert_eq!(avcc_config(&sps[..3], &pps), None);
+    }
+
+    #[test]
+    fn high_profile_avcc_config_states_chroma_and_bit_depth() {
+        let pps = [0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0];
+        let record = avcc_config(&SPS_1080P, &pps).expect("record");
+        assert_eq!(record[1..4], [0x64, 0x00, 0x28]);
+        assert_eq!(record.len(), 6 + 2 + SPS_1080P.len() + 3 + pps.len() + 4);
+        
+        // chroma_format 4:2:0, 8-bit luma and chroma, no SPS extensions
+        assert_eq!(record[record.len() - 4..], [0xFD, 0xF8, 0xF8, 0]);
+    }
+
+    #[test]
+    fn cropped_1080p_sps_reports_1080_rows() {
+        assert_eq!(sps_dimensions(&SPS_1080P), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn high_profile_scaling_lists_are_skipped() {
+        let mut sps = BitWriter::default();
+        sps.bits(100, 8); // profile_idc: High
+        sps.bits(0, 8);
+        sps.bits(31, 8); // level 3.1
+        sps.ue(0); // seq_parameter_set_id
+        sps.ue(1); // chroma_format_idc 4:2:0
+        sps.ue(0);
+        sps.ue(0);
+        sps.bits(0, 1);
+        sps.bits(1, 1); // seq_scaling_matrix_present_flag
+        for list in 0..8 {
+            match list {
+                // A full 4x4 list of varying steps
+                0 => {
+                    sps.bits(1, 1);
+                    for i in 0..16 {
+                        sps.se(if i % 2 == 0 { 5 } else { -3 });
+                    }
+                }
+                // An 8x8 list cut short by a step to 0: use the default matrix
+                6 => {
+                    sps.bits(1, 1);
+                    sps.se(4);
+                    sps.se(-12);
+                }
+                _ => sps.bits(0, 1),
+            }
+        }
+        sps.ue(0); // log2_max_frame_num_minus4
+        sps.ue(0); // pic_order_cnt_type
+        sps.ue(0);
+        sps.ue(1); // max_num_ref_frames
+        sps.bits(0, 1);
+        sps.ue(1280 / 16 - 1);
+        sps.ue(720 / 16 - 1);
+        sps.bits(1, 1); // frame_mbs_only_flag
+        sps.bits(1, 1);
+        sps.bits(0, 1); // no cropping
+        sps.bits(0, 1); // no VUI
+        assert_eq!(sps_dimensions(&sps.into_nal(0x67)), Some((1280, 720)));
+    }
+
+    #[test]
+    fn truncated_sps_is_rejected() {
+        // The crop offsets end in the 11th byte; anything shorter cannot give a size
+        assert_eq!(sps_dimensions(&SPS_1080P[..11]), Some((1920, 1080)));
+        for len in 0..11 {
+            assert_eq!(sps_dimensions(&SPS_1080P[..len]), None, "{} bytes", len);
+        }
+    }
+}
@@ -3,14 +3,25 @@ pub mod encoder;
 pub mod decoder;
 pub mod network;
 pub mod rtp;
+pub mod h264;
+pub mod fec;
 pub mod discovery;
 pub mod types;
 pub mod native_viewer;
+pub mod selftest;
+pub mod session;
+pub mod adaptive;
+pub mod source;
+pub mod playback;
+pub mod bench;
 
 pub use capture::ScreenCapture;
-pub use encoder::H264Encoder;
-pub use decoder::H264Decoder;
+pub use source::{FrameSource, SyntheticSource};
+pub use encoder::{H264Encoder, VideoEncoder};
+pub use decoder::{H264Decoder, VideoDecoder};
 pub use network::{RtpSender, RtpReceiver};
-pub use discovery::{DiscoveryService, PeerInfo, PeerRole};
+pub use discovery::{DiscoveryConfig, DiscoveryService, PeerInfo, PeerRole, RttSample, StudentLimits};
 pub use native_viewer::NativeViewer;
+pub use adaptive::AdaptiveController;
+pub use session::{BroadcastEvent, BroadcastSession, FrameCallback, ReceiveEvent, ReceiveSession, SessionId, SessionManager};
 pub use types::*;
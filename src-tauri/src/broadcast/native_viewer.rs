@@ -1,35 +1,145 @@
 //! Native Window Video Viewer - Ultra low latency rendering
 //! Bypasses JavaScript completely for realtime performance
 
+use std::collections::VecDeque;
 use std::num::NonZeroU32;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError};
+use parking_lot::Mutex;
 use winit::application::ApplicationHandler;
 use winit::dpi::{LogicalSize, PhysicalSize};
 use winit::event::WindowEvent;
 use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
 use winit::window::{Window, WindowId};
 
-use super::decoder::H264Decoder;
+use super::decoder::{H264Decoder, YuvFrame};
 use super::network::RtpReceiver;
-use super::types::{BroadcastError, StreamConfig};
+use super::rtp::{contains_keyframe, RTP_CLOCK_RATE};
+use super::types::{BroadcastError, StreamConfig, ViewerStats};
+
+/// Largest backwards timestamp step treated as a late frame (1s at 90 kHz)
+const MAX_REORDER_TICKS: i32 = 90_000;
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Called from the window thread with fresh statistics every `STATS_INTERVAL`
+pub type ViewerStatsCallback = Box<dyn FnMut(&ViewerStats) + Send>;
+
+/// State shared between a `NativeViewer`, its receiver threads and its window
+#[derive(Default)]
+struct ViewerShared {
+    stats: Mutex<ViewerStats>,
+    /// Counted by the receiver threads, all streams together
+    decoded: AtomicU64,
+    dropped: AtomicU64,
+}
 
 /// Frame data for rendering
 pub struct FrameBuffer {
     pub data: Vec<u32>, // ARGB format for softbuffer
     pub width: u32,
     pub height: u32,
+    /// RTP timestamp (90 kHz) - frames are presented in timestamp order
+    pub timestamp: u32,
+    /// Which stream shown in the cell the frame belongs to, bumped by `switch_stream`;
+    /// timestamps are only compared within one
+    pub stream: u32,
+}
+
+/// Holds frames back by a fixed delay and releases them at the spacing of their RTP
+/// timestamps, evening out jittery arrival at the cost of that much latency
+pub struct JitterBuffer {
+    delay: Duration,
+    frames: VecDeque<FrameBuffer>,
+    /// Local time and RTP timestamp presentation times are measured from
+    anchor: Option<(Instant, u32)>,
+    /// `FrameBuffer::stream` the anchor belongs to
+    anchor_stream: u32,
+}
+
+impl JitterBuffer {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay, frames: VecDeque::new(), anchor: None, anchor_stream: 0 }
+    }
+
+    /// Queue a frame that arrived at `now`; late duplicates of shown frames are dropped
+    pub fn push(&mut self, frame: FrameBuffer, now: Instant) {
+        if let Some(last) = self.frames.back().filter(|last| last.stream == frame.stream) {
+            let delta = frame.timestamp.wrapping_sub(last.timestamp) as i32;
+            if delta <= 0 && delta > -MAX_REORDER_TICKS {
+                return;
+            }
+        }
+        // Start over on the first frame, a restarted or switched sender, or once the two
+        // clocks have drifted so far that frames arrive after their slot or long before it
+        let due = self.due_at(frame.timestamp).filter(|_| frame.stream == self.anchor_stream);
+        let resync = due.is_none_or(|due| due + self.delay < now || due > now + 2 * self.delay);
+        if resync {
+            self.anchor = Some((now, frame.timestamp));
+            self.anchor_stream = frame.stream;
+            self.frames.clear();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// The newest frame whose presentation time has come, skipping older due ones
+    pub fn pop_due(&mut self, now: Instant) -> Option<FrameBuffer> {
+        let mut due = None;
+        while let Some(front) = self.frames.front() {
+            if self.due_at(front.timestamp).is_some_and(|at| at > now) {
+                break;
+            }
+            due = self.frames.pop_front();
+        }
+        due
+    }
+
+    /// Frames waiting to be presented
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn due_at(&self, timestamp: u32) -> Option<Instant> {
+        let (anchor_time, anchor_timestamp) = self.anchor?;
+        let ticks = timestamp.wrapping_sub(anchor_timestamp) as i32;
+        if !(-MAX_REORDER_TICKS..=MAX_REORDER_TICKS * 10).contains(&ticks) {
+            return None;
+        }
+        let offset = Duration::from_secs_f64(ticks.unsigned_abs() as f64 / RTP_CLOCK_RATE as f64);
+        let base = anchor_time + self.delay;
+        Some(if ticks >= 0 { base + offset } else { base.checked_sub(offset).unwrap_or(base) })
+    }
+}
+
+/// A stream's receiver thread, with a flag that stops just this one
+struct ReceiverThread {
+    active: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl ReceiverThread {
+    fn stop(&mut self) {
+        self.active.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
 }
 
 /// Native video viewer with direct rendering
 pub struct NativeViewer {
     running: Arc<AtomicBool>,
-    frame_tx: Option<Sender<FrameBuffer>>,
-    receiver_thread: Option<thread::JoinHandle<()>>,
+    frame_tx: Option<Sender<(usize, FrameBuffer)>>,
+    receivers: Vec<ReceiverThread>,
+    /// `FrameBuffer::stream` of the newest receiver
+    stream: u32,
+    /// Presentation delay of the jitter buffer, zero to show frames as soon as they decode
+    jitter_delay: Duration,
+    shared: Arc<ViewerShared>,
+    on_stats: Option<ViewerStatsCallback>,
 }
 
 impl NativeViewer {
@@ -37,46 +147,148 @@ impl NativeViewer {
         Self {
             running: Arc::new(AtomicBool::new(false)),
             frame_tx: None,
-            receiver_thread: None,
+            receivers: Vec::new(),
+            stream: 0,
+            jitter_delay: Duration::ZERO,
+            shared: Arc::new(ViewerShared::default()),
+            on_stats: None,
         }
     }
 
+    /// Hold frames back by `ms` and present them at their timestamps' spacing, smoothing
+    /// out jittery networks such as Wi-Fi. 0 (the default) shows every frame as soon as it
+    /// is decoded. Applies from the next `start`.
+    pub fn set_jitter_buffer_ms(&mut self, ms: u32) {
+        self.jitter_delay = Duration::from_millis(ms as u64);
+    }
+
+    /// Call `callback` with the statistics every second while the window is open.
+    /// Applies from the next `start`.
+    pub fn on_stats(&mut self, callback: impl FnMut(&ViewerStats) + Send + 'static) {
+        self.on_stats = Some(Box::new(callback));
+    }
+
+    /// Statistics of the last full second
+    pub fn stats(&self) -> ViewerStats {
+        self.shared.stats.lock().clone()
+    }
+
     /// Start receiving and displaying video in a native window
     pub fn start(&mut self, config: StreamConfig) -> Result<(), BroadcastError> {
+        self.start_grid(vec![(String::new(), config)])
+    }
+
+    /// Start receiving several streams and display them tiled in one window.
+    /// Each stream gets its own receiver thread, depacketizer and decoder.
+    pub fn start_grid(&mut self, streams: Vec<(String, StreamConfig)>) -> Result<(), BroadcastError> {
         if self.running.load(Ordering::SeqCst) {
             return Err(BroadcastError::NetworkError("Already running".into()));
         }
+        if streams.is_empty() {
+            return Err(BroadcastError::ConfigError("No streams to view".into()));
+        }
+        if cfg!(target_os = "macos") {
+            // AppKit runs event loops on the main thread only, and Tauri's owns it
+            return Err(BroadcastError::ViewerError(
+                "The native viewer is not supported on macOS, use the in-app view".into()));
+        }
 
         self.running.store(true, Ordering::SeqCst);
+        self.shared = Arc::new(ViewerShared::default());
 
-        // Channel for frames: receiver thread -> render thread
-        let (frame_tx, frame_rx) = bounded::<FrameBuffer>(2); // Small buffer for low latency
+        // Channel for frames: receiver threads -> render thread, tagged with the stream index
+        let (frame_tx, frame_rx) = bounded::<(usize, FrameBuffer)>(2 * streams.len()); // Small buffer for low latency
         self.frame_tx = Some(frame_tx.clone());
 
-        let running = self.running.clone();
+        let labels: Vec<String> = streams.iter().map(|(label, _)| label.clone()).collect();
 
-        // Start network receiver thread
-        self.receiver_thread = Some(thread::spawn(move || {
-            if let Err(e) = run_receiver(running, config, frame_tx) {
-                log::error!("Receiver error: {}", e);
-            }
-        }));
+        // Start one network receiver thread per stream
+        for (index, (label, config)) in streams.into_iter().enumerate() {
+            let receiver = self.spawn_receiver(index, label, config, frame_tx.clone());
+            self.receivers.push(receiver);
+        }
 
-        // Start window in main thread (required by winit)
+        // The window gets its own thread, Tauri runs its event loop on the main one;
+        // wait until the window's event loop exists so a failure reaches the caller
         let running_window = self.running.clone();
+        let jitter_delay = self.jitter_delay;
+        let shared = self.shared.clone();
+        let on_stats = self.on_stats.take();
+        let (ready_tx, ready_rx) = bounded(1);
         thread::spawn(move || {
-            if let Err(e) = run_window(running_window, frame_rx) {
+            let event_loop = match new_event_loop() {
+                Ok(event_loop) => {
+                    let _ = ready_tx.send(Ok(()));
+                    event_loop
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let mut app = VideoApp::new(running_window.clone(), labels, frame_rx, jitter_delay, shared);
+            app.on_stats = on_stats;
+            if let Err(e) = run_window(event_loop, app) {
                 log::error!("Window error: {:?}", e);
             }
+            // No window left to show frames in
+            running_window.store(false, Ordering::SeqCst);
         });
 
+        let started = ready_rx.recv()
+            .unwrap_or_else(|_| Err(BroadcastError::ViewerError("Window thread exited before starting".into())));
+        if started.is_err() {
+            self.stop();
+        }
+        started
+    }
+
+    /// Show another stream in cell `index` (0 for a single-stream viewer) without closing
+    /// the window: only that cell's receiver thread is replaced. The cell keeps its last
+    /// frame until the new stream's first keyframe is decoded.
+    pub fn switch_stream(&mut self, index: usize, config: StreamConfig) -> Result<(), BroadcastError> {
+        if !self.is_running() {
+            return Err(BroadcastError::ViewerError("Not running".into()));
+        }
+        config.validate()?;
+        let Some(frame_tx) = self.frame_tx.clone() else {
+            return Err(BroadcastError::ViewerError("Not running".into()));
+        };
+        let Some(old) = self.receivers.get_mut(index) else {
+            return Err(BroadcastError::ConfigError(format!("No stream {} to switch", index)));
+        };
+        // The new receiver may bind the same port, so the old socket goes first
+        old.stop();
+        log::info!("Native viewer stream #{} switching to port {}", index, config.port);
+        self.receivers[index] = self.spawn_receiver(index, String::new(), config, frame_tx);
         Ok(())
     }
 
+    fn spawn_receiver(
+        &mut self,
+        index: usize,
+        label: String,
+        config: StreamConfig,
+        frame_tx: Sender<(usize, FrameBuffer)>,
+    ) -> ReceiverThread {
+        self.stream = self.stream.wrapping_add(1);
+        let stream = self.stream;
+        let running = self.running.clone();
+        let active = Arc::new(AtomicBool::new(true));
+        let shared = self.shared.clone();
+        let thread_active = active.clone();
+        let handle = thread::spawn(move || {
+            if let Err(e) = run_receiver(running, thread_active, shared, index, stream, config, frame_tx) {
+                log::error!("Receiver error ({}): {}", label, e);
+            }
+        });
+        ReceiverThread { active, handle: Some(handle) }
+    }
+
     pub fn stop(&mut self) {
         self.running.store(false, Ordering::SeqCst);
-        if let Some(handle) = self.receiver_thread.take() {
-            let _ = handle.join();
+        for mut receiver in self.receivers.drain(..) {
+            receiver.stop();
         }
     }
 
@@ -94,25 +306,26 @@ impl Drop for NativeViewer {
 /// Network receiver thread - receives RTP and decodes H.264
 fn run_receiver(
     running: Arc<AtomicBool>,
+    active: Arc<AtomicBool>,
+    shared: Arc<ViewerShared>,
+    index: usize,
+    stream: u32,
     config: StreamConfig,
-    frame_tx: Sender<FrameBuffer>,
+    frame_tx: Sender<(usize, FrameBuffer)>,
 ) -> Result<(), BroadcastError> {
-    log::info!("Native viewer receiver starting: port {}", config.port);
+    log::info!("Native viewer receiver #{} starting: port {}", index, config.port);
 
-    let mut receiver = RtpReceiver::new(config.port, config.network_mode)?;
-    let mut decoder = H264Decoder::new()?;
+    let mut receiver = RtpReceiver::new(&config)?;
+    let mut decoder = H264Decoder::new(config.decoder_backend, config.color_space, config.full_range)?;
     
     let mut waiting_for_keyframe = true;
     let mut frames_decoded = 0u64;
 
-    while running.load(Ordering::SeqCst) {
+    while running.load(Ordering::SeqCst) && active.load(Ordering::SeqCst) {
         match receiver.receive_frame() {
             Ok(Some(h264_frame)) => {
-                // Check for keyframe
-                let is_keyframe = is_h264_keyframe(&h264_frame);
-                
                 if waiting_for_keyframe {
-                    if is_keyframe {
+                    if contains_keyframe(&h264_frame) {
                         log::info!("Got keyframe, starting decode");
                         waiting_for_keyframe = false;
                     } else {
@@ -120,25 +333,30 @@ fn run_receiver(
                     }
                 }
 
-                // Decode H.264 to RGBA
-                match decoder.decode(&h264_frame) {
+                // Decode H.264 to YUV planes
+                match decoder.decode_yuv(&h264_frame) {
                     Ok(Some(frame)) => {
                         frames_decoded += 1;
+                        shared.decoded.fetch_add(1, Ordering::Relaxed);
                         
-                        // Convert RGBA to ARGB (softbuffer format)
-                        let argb = rgba_to_argb(&frame.rgba_data, frame.width, frame.height);
+                        // Convert YUV straight to ARGB (softbuffer format)
+                        let argb = yuv_to_argb(&frame);
                         
                         let buffer = FrameBuffer {
                             data: argb,
-                            width: frame.width,
-                            height: frame.height,
+                            width: frame.width(),
+                            height: frame.height(),
+                            timestamp: receiver.last_frame_timestamp().unwrap_or(0),
+                            stream,
                         };
 
                         // Send to render thread (non-blocking, drop old frames)
-                        let _ = frame_tx.try_send(buffer);
+                        if frame_tx.try_send((index, buffer)).is_err() {
+                            shared.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
                         
                         if frames_decoded % 60 == 0 {
-                            log::info!("Decoded {} frames", frames_decoded);
+                            log::info!("Stream #{}: decoded {} frames", index, frames_decoded);
                         }
                     }
                     Ok(None) => {}
@@ -149,6 +367,10 @@ fn run_receiver(
                 }
             }
             Ok(None) => {
+                if receiver.is_reconnecting() {
+                    // Assembly restarts on the new socket
+                    waiting_for_keyframe = true;
+                }
                 thread::sleep(Duration::from_micros(500));
             }
             Err(e) => {
@@ -158,82 +380,108 @@ fn run_receiver(
         }
     }
 
-    log::info!("Receiver stopped, decoded {} frames", frames_decoded);
+    log::info!("Receiver #{} stopped, decoded {} frames", index, frames_decoded);
     Ok(())
 }
 
-/// Check if H.264 frame contains keyframe (IDR)
-#[inline]
-fn is_h264_keyframe(data: &[u8]) -> bool {
-    for i in 0..data.len().saturating_sub(4) {
-        if data[i] == 0 && data[i+1] == 0 {
-            let (offset, found) = if data[i+2] == 1 {
-                (i + 3, true)
-            } else if data[i+2] == 0 && i + 3 < data.len() && data[i+3] == 1 {
-                (i + 4, true)
-            } else {
-                (0, false)
-            };
-            
-            if found && offset < data.len() {
-                let nal_type = data[offset] & 0x1F;
-                if nal_type == 5 || nal_type == 7 { // IDR or SPS
-                    return true;
-                }
-            }
-        }
-    }
-    false
-}
-
-/// Convert RGBA to ARGB (u32 array for softbuffer)
-#[inline]
-fn rgba_to_argb(rgba: &[u8], width: u32, height: u32) -> Vec<u32> {
-    let pixel_count = (width * height) as usize;
-    let mut argb = Vec::with_capacity(pixel_count);
-    
-    for i in 0..pixel_count {
-        let idx = i * 4;
-        if idx + 3 < rgba.len() {
-            let r = rgba[idx] as u32;
-            let g = rgba[idx + 1] as u32;
-            let b = rgba[idx + 2] as u32;
-            // ARGB format: 0xAARRGGBB
-            argb.push(0xFF000000 | (r << 16) | (g << 8) | b);
-        }
-    }
-    
+/// Convert I420 to ARGB (u32 array for softbuffer)
+fn yuv_to_argb(frame: &YuvFrame) -> Vec<u32> {
+    let mut argb = Vec::with_capacity(frame.width() as usize * frame.height() as usize);
+    // ARGB format: 0xAARRGGBB
+    frame.for_each_rgb(|r, g, b| argb.push(0xFF000000 | ((r as u32) << 16) | ((g as u32) << 8) | b as u32));
     argb
 }
 
 /// Window application handler
 struct VideoApp {
     running: Arc<AtomicBool>,
-    frame_rx: Receiver<FrameBuffer>,
+    labels: Vec<String>,
+    frame_rx: Receiver<(usize, FrameBuffer)>,
     window: Option<Arc<Window>>,
     surface: Option<softbuffer::Surface<Arc<Window>, Arc<Window>>>,
-    current_size: (u32, u32),
+    /// Stream the window was sized to on its first frame; after that the user's size is kept
+    sized_for: Option<u32>,
+    /// Latest frame per stream, one grid cell each
+    cells: Vec<Option<FrameBuffer>>,
+    /// One per stream when the jitter buffer is on
+    jitter: Vec<JitterBuffer>,
+    shared: Arc<ViewerShared>,
+    on_stats: Option<ViewerStatsCallback>,
+    /// Frames drawn and the decoded count at the start of the stats interval
+    rendered: u32,
+    decoded_at_last_stats: u64,
+    last_stats: Instant,
 }
 
 impl VideoApp {
-    fn new(running: Arc<AtomicBool>, frame_rx: Receiver<FrameBuffer>) -> Self {
+    fn new(
+        running: Arc<AtomicBool>,
+        labels: Vec<String>,
+        frame_rx: Receiver<(usize, FrameBuffer)>,
+        jitter_delay: Duration,
+        shared: Arc<ViewerShared>,
+    ) -> Self {
+        let cells = labels.iter().map(|_| None).collect();
+        let jitter = if jitter_delay.is_zero() {
+            Vec::new()
+        } else {
+            labels.iter().map(|_| JitterBuffer::new(jitter_delay)).collect()
+        };
         Self {
             running,
+            labels,
             frame_rx,
             window: None,
             surface: None,
-            current_size: (1280, 720),
+            sized_for: None,
+            cells,
+            jitter,
+            shared,
+            on_stats: None,
+            rendered: 0,
+            decoded_at_last_stats: 0,
+            last_stats: Instant::now(),
+        }
+    }
+
+    /// Publish the statistics once per `STATS_INTERVAL`
+    fn update_stats(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_stats);
+        if elapsed < STATS_INTERVAL {
+            return;
+        }
+        let secs = elapsed.as_secs_f32();
+        let decoded = self.shared.decoded.load(Ordering::Relaxed);
+        let (width, height) = self.cells.iter().flatten().next().map_or((0, 0), |f| (f.width, f.height));
+        let stats = ViewerStats {
+            decoded_fps: decoded.saturating_sub(self.decoded_at_last_stats) as f32 / secs,
+            rendered_fps: self.rendered as f32 / secs,
+            frames_decoded: decoded,
+            dropped_frames: self.shared.dropped.load(Ordering::Relaxed),
+            width,
+            height,
+            buffered_frames: self.jitter.iter().map(JitterBuffer::depth).sum(),
+        };
+        self.rendered = 0;
+        self.decoded_at_last_stats = decoded;
+        self.last_stats = now;
+
+        *self.shared.stats.lock() = stats.clone();
+        if let Some(callback) = &mut self.on_stats {
+            callback(&stats);
         }
     }
 
-    fn render_frame(&mut self, frame: &FrameBuffer) {
+    fn render(&mut self) {
         let Some(surface) = &mut self.surface else { return };
         let Some(window) = &self.window else { return };
 
-        // Resize surface if needed
-        if self.current_size != (frame.width, frame.height) {
-            self.current_size = (frame.width, frame.height);
-            let _ = window.request_inner_size(PhysicalSize::new(frame.width, frame.height));
+        // Single stream: open at the video resolution once per stream, then leave sizing to the user
+        if let [Some(frame)] = self.cells.as_slice() {
+            if self.sized_for != Some(frame.stream) {
+                self.sized_for = Some(frame.stream);
+                let _ = window.request_inner_size(PhysicalSize::new(frame.width, frame.height));
+            }
         }
 
         let size = window.inner_size();
@@ -246,32 +494,25 @@ impl VideoApp {
             let _ = surface.resize(w, h);
         }
 
-        // Get buffer and copy frame data
+        // Get buffer and draw each stream into its cell
         if let Ok(mut buffer) = surface.buffer_mut() {
-            let buf_len = buffer.len();
-            let frame_len = frame.data.len();
-
-            if buf_len == frame_len {
-                // Direct copy - fastest
-                buffer.copy_from_slice(&frame.data);
-            } else {
-                // Scale to fit (simple nearest neighbor)
-                let src_w = frame.width as usize;
-                let src_h = frame.height as usize;
-                let dst_w = size.width as usize;
-                let dst_h = size.height as usize;
-
-                for y in 0..dst_h {
-                    for x in 0..dst_w {
-                        let src_x = x * src_w / dst_w;
-                        let src_y = y * src_h / dst_h;
-                        let src_idx = src_y * src_w + src_x;
-                        let dst_idx = y * dst_w + x;
-                        
-                        if src_idx < frame_len && dst_idx < buf_len {
-                            buffer[dst_idx] = frame.data[src_idx];
-                        }
-                    }
+            let dst_w = size.width as usize;
+            let dst_h = size.height as usize;
+            let (cols, rows) = grid_layout(self.cells.len());
+
+            for (i, cell) in self.cells.iter().enumerate() {
+                let (col, row) = (i % cols, i / cols);
+                let x0 = col * dst_w / cols;
+                let x1 = (col + 1) * dst_w / cols;
+                let y0 = row * dst_h / rows;
+                let y1 = (row + 1) * dst_h / rows;
+
+                fill_rect(&mut buffer, dst_w, x0, y0, x1 - x0, y1 - y0, 0xFF000000);
+                if let Some(frame) = cell {
+                    // Keep the aspect ratio, black bars around the rest of the cell
+                    let (w, h) = fit_aspect(frame.width as usize, frame.height as usize, x1 - x0, y1 - y0);
+                    let (x, y) = (x0 + (x1 - x0 - w) / 2, y0 + (y1 - y0 - h) / 2);
+                    blit_scaled(&mut buffer, dst_w, frame, x, y, w, h);
                 }
             }
 
@@ -280,14 +521,84 @@ impl VideoApp {
     }
 }
 
+/// Grid columns and rows for `count` streams (single stream is a 1x1 grid)
+fn grid_layout(count: usize) -> (usize, usize) {
+    let count = count.max(1);
+    let cols = (count as f64).sqrt().ceil() as usize;
+    let rows = count.div_ceil(cols);
+    (cols, rows)
+}
+
+/// Largest size with the `src_w`:`src_h` aspect ratio that fits in `max_w`x`max_h`
+fn fit_aspect(src_w: usize, src_h: usize, max_w: usize, max_h: usize) -> (usize, usize) {
+    if src_w == 0 || src_h == 0 {
+        return (max_w, max_h);
+    }
+    if src_w * max_h <= src_h * max_w {
+        // Bounded by height
+        ((src_w * max_h / src_h).min(max_w), max_h)
+    } else {
+        (max_w, (src_h * max_w / src_w).min(max_h))
+    }
+}
+
+/// Draw a frame into a cell of the destination buffer
+fn blit_scaled(dst: &mut [u32], dst_w: usize, frame: &FrameBuffer, x0: usize, y0: usize, w: usize, h: usize) {
+    let src_w = frame.width as usize;
+    let src_h = frame.height as usize;
+    if w == 0 || h == 0 || src_w == 0 || src_h == 0 {
+        return;
+    }
+
+    for y in 0..h {
+        let dst_start = (y0 + y) * dst_w + x0;
+        if dst_start + w > dst.len() {
+            break;
+        }
+        let dst_row = &mut dst[dst_start..dst_start + w];
+
+        if src_w == w && src_h == h {
+            // Direct copy - fastest
+            let src_start = y * src_w;
+            if src_start + w <= frame.data.len() {
+                dst_row.copy_from_slice(&frame.data[src_start..src_start + w]);
+            }
+            continue;
+        }
+
+        // Scale to fit (simple nearest neighbor)
+        let src_y = y * src_h / h;
+        for (x, pixel) in dst_row.iter_mut().enumerate() {
+            let src_x = x * src_w / w;
+            if let Some(&p) = frame.data.get(src_y * src_w + src_x) {
+                *pixel = p;
+            }
+        }
+    }
+}
+
+fn fill_rect(dst: &mut [u32], dst_w: usize, x0: usize, y0: usize, w: usize, h: usize, color: u32) {
+    for y in 0..h {
+        let dst_start = (y0 + y) * dst_w + x0;
+        if let Some(row) = dst.get_mut(dst_start..dst_start + w) {
+            row.fill(color);
+        }
+    }
+}
+
 impl ApplicationHandler for VideoApp {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         if self.window.is_some() {
             return;
         }
 
+        let title = match self.labels.as_slice() {
+            [] | [_] => "Screen Broadcast - Student View".to_string(),
+            labels => format!("Screen Broadcast - {}", labels.join(" | ")),
+        };
+
         let attrs = Window::default_attributes()
-            .with_title("Screen Broadcast - Student View")
+            .with_title(title)
             .with_inner_size(LogicalSize::new(1280, 720));
 
         match event_loop.create_window(attrs) {
@@ -316,12 +627,35 @@ impl ApplicationHandler for VideoApp {
                 self.running.store(false, Ordering::SeqCst);
                 event_loop.exit();
             }
+            WindowEvent::Resized(_) => {
+                // Redraw the frames we have at the new size rather than waiting for the next ones
+                self.render();
+            }
             WindowEvent::RedrawRequested => {
-                // Try to get latest frame
-                let mut latest_frame = None;
+                // Try to get latest frame of every stream
+                let mut updated = false;
+                let now = Instant::now();
                 loop {
                     match self.frame_rx.try_recv() {
-                        Ok(frame) => latest_frame = Some(frame),
+                        Ok((index, frame)) if !self.jitter.is_empty() => {
+                            if let Some(jitter) = self.jitter.get_mut(index) {
+                                jitter.push(frame, now);
+                            }
+                        }
+                        Ok((index, frame)) => {
+                            if let Some(cell) = self.cells.get_mut(index) {
+                                // Never step back to a slightly older frame of the same stream;
+                                // a large jump backwards is a restarted sender, not reordering
+                                let stale = cell.as_ref().is_some_and(|current| {
+                                    let delta = frame.timestamp.wrapping_sub(current.timestamp) as i32;
+                                    current.stream == frame.stream && delta < 0 && delta > -MAX_REORDER_TICKS
+                                });
+                                if !stale {
+                                    *cell = Some(frame);
+                                    updated = true;
+                                }
+                            }
+                        }
                         Err(TryRecvError::Empty) => break,
                         Err(TryRecvError::Disconnected) => {
                             event_loop.exit();
@@ -330,9 +664,19 @@ impl ApplicationHandler for VideoApp {
                     }
                 }
 
-                if let Some(frame) = latest_frame {
-                    self.render_frame(&frame);
+                // Buffered: show what is due, at the pace of the stream's timestamps
+                for (cell, jitter) in self.cells.iter_mut().zip(&mut self.jitter) {
+                    if let Some(frame) = jitter.pop_due(now) {
+                        *cell = Some(frame);
+                        updated = true;
+                    }
+                }
+
+                if updated {
+                    self.render();
+                    self.rendered += 1;
                 }
+                self.update_stats(now);
 
                 // Request next frame
                 if let Some(window) = &self.window {
@@ -356,15 +700,22 @@ impl ApplicationHandler for VideoApp {
     }
 }
 
+/// Window event loop for the calling thread, which is not the main thread: winit
+/// allows that on Windows and Linux when asked explicitly
+fn new_event_loop() -> Result<EventLoop<()>, BroadcastError> {
+    #[allow(unused_mut)]
+    let mut builder = EventLoop::builder();
+    #[cfg(target_os = "windows")]
+    winit::platform::windows::EventLoopBuilderExtWindows::with_any_thread(&mut builder, true);
+    #[cfg(target_os = "linux")]
+    winit::platform::x11::EventLoopBuilderExtX11::with_any_thread(&mut builder, true);
+    builder.build()
+        .map_err(|e| BroadcastError::ViewerError(format!("Cannot start the window event loop: {}", e)))
+}
+
 /// Run the native window event loop
-fn run_window(
-    running: Arc<AtomicBool>,
-    frame_rx: Receiver<FrameBuffer>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let event_loop = EventLoop::new()?;
+fn run_window(event_loop: EventLoop<()>, mut app: VideoApp) -> Result<(), Box<dyn std::error::Error>> {
     event_loop.set_control_flow(ControlFlow::Poll);
-
-    let mut app = VideoApp::new(running, frame_rx);
     event_loop.run_app(&mut app)?;
 
     Ok(())
@@ -375,3 +726,94 @@ impl Default for NativeViewer {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    fn frame(timestamp: u32) -> FrameBuffer {
+        FrameBuffer { data: Vec::new(), width: 0, height: 0, timestamp, stream: 0 }
+    }
+
+    #[test]
+    fn jittery_arrivals_are_presented_evenly() {
+        // 30 fps frames (3000 ticks apart) arriving in bursts, up to 120 ms apart
+        let arrivals_ms = [0, 45, 50, 52, 170, 171, 172, 180, 270, 300];
+        let start = Instant::now();
+        let mut jitter = JitterBuffer::new(Duration::from_millis(100));
+        
+        // Poll once a millisecond, like a redraw loop
+        let mut presented = Vec::new();
+        let mut deepest = 0;
+        for ms in 0..600 {
+            let now = start + Duration::from_millis(ms);
+            for (i, _) in arrivals_ms.iter().enumerate().filter(|(_, &at)| at == ms) {
+                jitter.push(frame(i as u32 * 3000), now);
+            }
+            deepest = deepest.max(jitter.depth());
+            if let Some(frame) = jitter.pop_due(now) {
+                presented.push((frame.timestamp / 3000, ms));
+            }
+        }
+        
+        // Every frame is shown, in order, 33-34 ms apart, starting one delay after the first
+        let order: Vec<_> = presented.iter().map(|&(i, _)| i).collect();
+        assert_eq!(order, (0..10).collect::<Vec<_>>());
+        assert_eq!(presented[0].1, 100);
+        for pair in presented.windows(2) {
+            let gap = pair[1].1 - pair[0].1;
+            assert!((33..=34).contains(&gap), "{:?}", presented);
+        }
+        assert!(deepest >= 3, "bursts should queue up, deepest was {}", deepest);
+        assert_eq!(jitter.depth(), 0);
+    }
+
+    /// Whether something holds `port`; a socket without SO_REUSEADDR cannot share it
+    fn port_in_use(port: u16) -> bool {
+        UdpSocket::bind(("0.0.0.0", port)).is_err()
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn switching_replaces_the_receiver_thread() {
+        let free_port = || UdpSocket::bind("0.0.0.0:0").and_then(|s| s.local_addr()).expect("free port").port();
+        let (first, second) = (free_port(), free_port());
+        
+        // What start_grid sets up, without opening a window
+        let mut viewer = NativeViewer::new();
+        viewer.running.store(true, Ordering::SeqCst);
+        let (frame_tx, _frame_rx) = bounded(2);
+        viewer.frame_tx = Some(frame_tx.clone());
+        let receiver = viewer.spawn_receiver(0, String::new(), StreamConfig { port: first, ..StreamConfig::default() }, frame_tx);
+        viewer.receivers.push(receiver);
+        assert!(wait_for(|| port_in_use(first)), "first receiver never bound");
+        
+        // Back and forth, including onto the port just released: the old thread is
+        // joined and its socket closed before the new one binds
+        for (round, port) in [second, first, second].into_iter().enumerate() {
+            let previous = if port == first { second } else { first };
+            viewer.switch_stream(0, StreamConfig { port, ..StreamConfig::default() }).expect("switch");
+            assert!(!port_in_use(previous), "round {}: port {} still held", round, previous);
+            assert!(wait_for(|| port_in_use(port)), "round {}: new receiver never bound", round);
+            assert_eq!(viewer.receivers.len(), 1);
+            assert_eq!(viewer.stream, round as u32 + 2);
+        }
+        
+        let error = viewer.switch_stream(1, StreamConfig::default()).unwrap_err();
+        assert!(matches!(error, BroadcastError::ConfigError(_)), "{}", error);
+        viewer.stop();
+        assert!(!port_in_use(second));
+        assert!(matches!(viewer.switch_stream(0, StreamConfig::default()), Err(BroadcastError::ViewerError(_))));
+    }
+}
@@ -2,62 +2,161 @@
 
 use socket2::{Domain, Protocol, Socket, Type};
 use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket, SocketAddr};
-use std::sync::Arc;
-use std::time::Duration;
-use parking_lot::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use serde::Serialize;
 
-use super::rtp::{RtpPacketizer, RtpDepacketizer};
-use super::types::{BroadcastError, NetworkMode};
+use super::fec::{FecDecoder, FecEncoder, FEC_PACKET_OVERHEAD};
+use super::rtp::{is_rtcp_bye, rtcp_bye, RtpPacketizer, RtpDepacketizer, MAX_MTU, RTP_CLOCK_RATE, RTP_PAYLOAD_TYPE_FEC, RTP_PAYLOAD_TYPE_H264};
+use super::types::{BroadcastError, IntervalStats, NetworkMode, StreamConfig};
 
 pub const STREAM_PORT: u16 = 5000;
 pub const MULTICAST_ADDR: &str = "239.255.0.1";
 pub const RTP_HEADER_SIZE: usize = 12;
+/// Frames with at most this many packets are sent without pacing
+const PACING_MIN_PACKETS: usize = 4;
+/// Share of the frame interval a paced frame may be spread over
+const PACING_SPREAD: f64 = 0.8;
+/// A receiver that was just stopped may still be closing its socket: retry binding for a while
+const BIND_RETRIES: u32 = 10;
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Copies of the end-of-stream BYE sent, since any one of them may be lost
+const BYE_REPEATS: u32 = 3;
+const BYE_SPACING: Duration = Duration::from_millis(20);
 
 /// RTP Sender - sends H.264 frames as RTP packets
 pub struct RtpSender {
     socket: UdpSocket,
     target: SocketAddr,
+    mode: NetworkMode,
+    interface: Ipv4Addr,
+    send_buffer_bytes: usize,
+    /// Set while the socket is being rebuilt after a network error
+    reconnect: Option<Backoff>,
     packetizer: RtpPacketizer,
+    /// Packets of the last frame, reused by the packetizer for the next one
+    packets: Vec<Vec<u8>>,
+    mtu: usize,
+    fec: Option<FecEncoder>,
+    pacing: bool,
+    frame_interval: Duration,
     frame_count: u64,
+    /// Gaps between frames going out, with diagnostics on
+    send_intervals: Option<IntervalTracker>,
 }
 
 impl RtpSender {
-    pub fn new(port: u16, mode: NetworkMode) -> Result<Self, BroadcastError> {
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        
-        socket.set_reuse_address(true)?;
-        socket.set_broadcast(true)?;
-        
-        if mode == NetworkMode::Multicast {
-            socket.set_multicast_ttl_v4(1)?;
-            socket.set_multicast_loop_v4(true)?;
+    pub fn new(config: &StreamConfig) -> Result<Self, BroadcastError> {
+        let port = config.port;
+        let mode = config.network_mode;
+        if config.send_buffer_bytes == 0 {
+            return Err(BroadcastError::ConfigError("send_buffer_bytes must be greater than 0".into()));
         }
         
-        // Bind to any port
-        let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-        socket.bind(&bind_addr.into())?;
-        
-        // Set send buffer
-        socket.set_send_buffer_size(2 * 1024 * 1024)?;
+        let interface = match config.interface {
+            Some(ip) => check_interface(ip)?,
+            None => Ipv4Addr::UNSPECIFIED,
+        };
+        let socket = open_sender_socket(mode, interface, config.send_buffer_bytes)?;
         
         let target: SocketAddr = match mode {
             NetworkMode::Broadcast => format!("255.255.255.255:{}", port).parse().unwrap(),
             NetworkMode::Multicast => format!("{}:{}", MULTICAST_ADDR, port).parse().unwrap(),
         };
         
-        log::info!("RTP Sender ready: {:?} mode, target: {}", mode, target);
+        log::info!("RTP Sender ready: {:?} mode, target: {}, interface: {}", mode, target, interface);
         
         Ok(Self {
-            socket: socket.into(),
+            socket,
             target,
-            packetizer: RtpPacketizer::new(),
+            mode,
+            interface,
+            send_buffer_bytes: config.send_buffer_bytes,
+            reconnect: None,
+            packetizer: RtpPacketizer::with_mtu(config.mtu),
+            packets: Vec::new(),
+            mtu: config.mtu,
+            fec: None,
+            pacing: false,
+            frame_interval: Duration::from_millis(1000 / 30),
             frame_count: 0,
+            send_intervals: config.diagnostics.then(IntervalTracker::new),
         })
     }
 
+    /// Send to a specific address instead of the broadcast/multicast group (e.g. loopback)
+    pub fn set_target(&mut self, target: SocketAddr) {
+        self.target = target;
+    }
+
+    /// Frame rate used to advance RTP timestamps
+    pub fn set_frame_rate(&mut self, fps: u32) {
+        self.packetizer.set_frame_rate(fps);
+        self.frame_interval = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+    }
+
+    /// Spread each frame's packets over the frame interval instead of sending them in one burst
+    pub fn set_pacing(&mut self, enabled: bool) {
+        self.pacing = enabled;
+    }
+
+    /// Start each frame with an access unit delimiter NAL
+    pub fn set_insert_aud(&mut self, enabled: bool) {
+        self.packetizer.set_insert_aud(enabled);
+    }
+
+    /// Record the intervals between frames sent, see `take_send_intervals`
+    pub fn set_diagnostics(&mut self, enabled: bool) {
+        if enabled != self.send_intervals.is_some() {
+            self.send_intervals = enabled.then(IntervalTracker::new);
+        }
+    }
+
+    /// Send interval statistics since the last call, `None` without diagnostics or frames
+    pub fn take_send_intervals(&mut self) -> Option<IntervalStats> {
+        self.send_intervals.as_mut()?.take()
+    }
+
+    /// Wall clock capture time of the next frame sent, carried in an RTP header extension
+    pub fn set_capture_time(&mut self, capture_time: SystemTime) {
+        self.packetizer.set_capture_time(capture_time);
+    }
+
+    /// Path MTU that packets (parity packets included) must fit in
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+        // Parity packets carry a whole media packet plus their own headers
+        let reserve = if self.fec.is_some() { FEC_PACKET_OVERHEAD } else { 0 };
+        self.packetizer.set_mtu(mtu.saturating_sub(reserve));
+    }
+
+    /// Enable XOR parity with one parity packet per `group_size` packets, or disable it with `None`
+    pub fn set_fec(&mut self, group_size: Option<usize>) {
+        self.fec = group_size.map(FecEncoder::new);
+        self.set_mtu(self.mtu);
+        if let Some(size) = group_size {
+            log::info!("FEC enabled: 1 parity packet per {} packets", size);
+        }
+    }
+
     /// Send H.264 frame as RTP packets
-    pub fn send_frame(&mut self, h264_data: &[u8], timestamp_ms: u32) -> Result<usize, BroadcastError> {
-        let packets = self.packetizer.packetize(h264_data, timestamp_ms);
+    pub fn send_frame(&mut self, h264_data: &[u8]) -> Result<usize, BroadcastError> {
+        let mut packets = std::mem::take(&mut self.packets);
+        self.packetizer.packetize_into(h264_data, &mut packets);
+        let result = self.send_packets(h264_data, &packets);
+        self.packets = packets;
+        result
+    }
+
+    /// Send H.264 frame with its RTP timestamp taken from the capture time (since stream start)
+    pub fn send_frame_at(&mut self, h264_data: &[u8], capture_time: Duration) -> Result<usize, BroadcastError> {
+        let mut packets = std::mem::take(&mut self.packets);
+        self.packetizer.packetize_at_into(h264_data, capture_time, &mut packets);
+        let result = self.send_packets(h264_data, &packets);
+        self.packets = packets;
+        result
+    }
+
+    fn send_packets(&mut self, h264_data: &[u8], packets: &[Vec<u8>]) -> Result<usize, BroadcastError> {
         let mut total_bytes = 0;
         
         if packets.is_empty() {
@@ -65,14 +164,47 @@ impl RtpSender {
             return Ok(0);
         }
         
-        for packet in &packets {
-            match self.socket.send_to(packet, self.target) {
-                Ok(n) => total_bytes += n,
-                Err(e) => {
-                    log::error!("Send error: {}", e);
-                    return Err(BroadcastError::NetworkError(e.to_string()));
+        // Frames are dropped while the network is away
+        if !self.try_reconnect() {
+            return Ok(0);
+        }
+        
+        // Packet i is due at start + i * spacing; sleeping towards absolute deadlines keeps
+        // oversleeping from adding up, so the frame still fits within its interval
+        let spacing = if self.pacing && packets.len() > PACING_MIN_PACKETS {
+            Some(self.frame_interval.mul_f64(PACING_SPREAD) / packets.len() as u32)
+        } else {
+            None
+        };
+        let start = Instant::now();
+        if let Some(intervals) = &mut self.send_intervals {
+            intervals.mark_at(start);
+        }
+        
+        for (i, packet) in packets.iter().enumerate() {
+            if let Some(spacing) = spacing {
+                let due = start + spacing * i as u32;
+                let now = Instant::now();
+                if due > now {
+                    std::thread::sleep(due - now);
                 }
             }
+            
+            let Some(sent) = self.send_packet(packet)? else { return Ok(total_bytes) };
+            total_bytes += sent;
+            
+            let parity = self.fec.as_mut().and_then(|fec| fec.push(packet));
+            if let Some(parity) = parity {
+                let Some(sent) = self.send_packet(&parity)? else { return Ok(total_bytes) };
+                total_bytes += sent;
+            }
+        }
+        
+        // Groups never span frames, so a lost packet can be recovered without waiting
+        let parity = self.fec.as_mut().and_then(|fec| fec.flush());
+        if let Some(parity) = parity {
+            let Some(sent) = self.send_packet(&parity)? else { return Ok(total_bytes) };
+            total_bytes += sent;
         }
         
         self.frame_count += 1;
@@ -81,11 +213,75 @@ impl RtpSender {
         if self.frame_count % 30 == 0 {
             log::info!("Sent frame {}: {} packets, {} bytes to {}", 
                 self.frame_count, packets.len(), total_bytes, self.target);
+            if spacing.is_some() {
+                log::info!("Paced over {:?} ({:?} per packet)",
+                    start.elapsed(), start.elapsed() / packets.len() as u32);
+            }
         }
         
         Ok(total_bytes)
     }
 
+    /// Returns `None` when a transient error started a reconnect
+    fn send_packet(&mut self, packet: &[u8]) -> Result<Option<usize>, BroadcastError> {
+        match self.socket.send_to(packet, self.target) {
+            Ok(n) => Ok(Some(n)),
+            Err(e) if is_transient(&e) => {
+                log::warn!("Send error: {}, reconnecting", e);
+                self.reconnect = Some(Backoff::new());
+                Ok(None)
+            }
+            Err(e) => {
+                log::error!("Send error: {}", e);
+                Err(BroadcastError::NetworkError(e.to_string()))
+            }
+        }
+    }
+
+    /// Rebuild the socket if a reconnect is due; returns whether the socket is usable
+    fn try_reconnect(&mut self) -> bool {
+        let Some(backoff) = &mut self.reconnect else { return true };
+        if !backoff.is_due() {
+            return false;
+        }
+        
+        match open_sender_socket(self.mode, self.interface, self.send_buffer_bytes) {
+            Ok(socket) => {
+                log::info!("RTP Sender reconnected after {} attempt(s)", backoff.attempts + 1);
+                self.socket = socket;
+                self.reconnect = None;
+                true
+            }
+            Err(e) => {
+                backoff.failed();
+                log::warn!("RTP Sender reconnect failed: {}, retrying in {:?}", e, backoff.remaining());
+                false
+            }
+        }
+    }
+
+    /// True while the socket is being rebuilt after a network error
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnect.is_some()
+    }
+
+    /// Tell students the stream is over with an RTCP BYE, sent a few times over
+    pub fn send_bye(&mut self) -> Result<(), BroadcastError> {
+        if !self.try_reconnect() {
+            return Ok(());
+        }
+        let bye = rtcp_bye(self.packetizer.ssrc());
+        for i in 0..BYE_REPEATS {
+            if i > 0 {
+                std::thread::sleep(BYE_SPACING);
+            }
+            if self.send_packet(&bye)?.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     pub fn frame_count(&self) -> u64 {
         self.frame_count
     }
@@ -93,96 +289,840 @@ impl RtpSender {
 
 /// RTP Receiver - receives RTP packets and reassembles H.264 frames
 pub struct RtpReceiver {
-    socket: Arc<Mutex<UdpSocket>>,
+    /// Owned by this receiver alone: two readers on one socket would each get part
+    /// of the packets and corrupt both depacketizers
+    socket: UdpSocket,
+    /// Non-blocking handle on `socket` registered with tokio, see `recv_frame_async`
+    async_socket: Option<tokio::net::UdpSocket>,
     depacketizer: RtpDepacketizer,
     buffer: Vec<u8>,
+    port: u16,
+    mode: NetworkMode,
+    recv_buffer_bytes: usize,
+    interface: Ipv4Addr,
+    /// Packets from other senders are dropped when set
+    source: Option<Ipv4Addr>,
+    /// Set while the socket is being rebuilt after a network error
+    reconnect: Option<Backoff>,
+    stats: ReceptionStats,
+    /// Created when the first parity packet arrives
+    fec: Option<FecDecoder>,
+    /// Passed to every depacketizer this receiver creates
+    max_temporal_layer: Option<u8>,
+    /// Gaps between assembled frames, with diagnostics on
+    arrival_intervals: Option<IntervalTracker>,
+    /// The sender said goodbye with an RTCP BYE and has sent no media since
+    stream_ended: bool,
 }
 
 impl RtpReceiver {
-    pub fn new(port: u16, mode: NetworkMode) -> Result<Self, BroadcastError> {
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        
-        socket.set_reuse_address(true)?;
-        socket.set_broadcast(true)?;
-        
-        #[cfg(not(windows))]
-        socket.set_reuse_port(true)?;
-        
-        // Bind to port
-        let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-        socket.bind(&bind_addr.into())?;
-        
-        log::info!("RTP Receiver bound to 0.0.0.0:{}", port);
-        
-        // Join multicast if needed
-        if mode == NetworkMode::Multicast {
-            let multicast_ip: Ipv4Addr = MULTICAST_ADDR.parse().unwrap();
-            socket.join_multicast_v4(&multicast_ip, &Ipv4Addr::UNSPECIFIED)
-                .map_err(|e| BroadcastError::NetworkError(format!("Join multicast failed: {}", e)))?;
-            log::info!("Joined multicast group: {}", MULTICAST_ADDR);
+    pub fn new(config: &StreamConfig) -> Result<Self, BroadcastError> {
+        let port = config.port;
+        let mode = config.network_mode;
+        if config.recv_buffer_bytes == 0 {
+            return Err(BroadcastError::ConfigError("recv_buffer_bytes must be greater than 0".into()));
         }
         
-        // Set receive buffer
-        socket.set_recv_buffer_size(4 * 1024 * 1024)?;
+        let interface = match config.interface {
+            Some(ip) => check_interface(ip)?,
+            None => Ipv4Addr::UNSPECIFIED,
+        };
         
-        // Blocking with timeout
-        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let socket = open_receiver_socket(port, mode, config.recv_buffer_bytes, interface)?;
         
         log::info!("RTP Receiver ready: {:?} mode, port: {}", mode, port);
+        if let Some(source) = config.source {
+            log::info!("RTP Receiver accepting packets from {} only", source);
+        }
+        if let Some(layer) = config.max_temporal_layer {
+            log::info!("RTP Receiver keeping temporal layers up to {}", layer);
+        }
         
         Ok(Self {
-            socket: Arc::new(Mutex::new(socket.into())),
-            depacketizer: RtpDepacketizer::new(),
-            buffer: vec![0u8; 2048],
+            socket,
+            async_socket: None,
+            depacketizer: RtpDepacketizer::with_max_temporal_layer(config.max_temporal_layer),
+            buffer: vec![0u8; MAX_MTU],
+            port,
+            mode,
+            recv_buffer_bytes: config.recv_buffer_bytes,
+            interface,
+            source: config.source,
+            reconnect: None,
+            stats: ReceptionStats::new(),
+            fec: None,
+            max_temporal_layer: config.max_temporal_layer,
+            arrival_intervals: config.diagnostics.then(IntervalTracker::new),
+            stream_ended: false,
         })
     }
 
+    pub fn mode(&self) -> NetworkMode {
+        self.mode
+    }
+
+    /// Address the socket is bound to (tells the port when created with port 0)
+    pub fn local_addr(&self) -> Result<SocketAddr, BroadcastError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Re-open the socket in another network mode on the same port.
+    /// Any partially assembled frame is discarded.
+    pub fn switch_mode(&mut self, mode: NetworkMode) -> Result<(), BroadcastError> {
+        let socket = open_receiver_socket(self.port, mode, self.recv_buffer_bytes, self.interface)?;
+        self.socket = socket;
+        self.async_socket = None;
+        self.depacketizer = RtpDepacketizer::with_max_temporal_layer(self.max_temporal_layer);
+        self.fec = None;
+        self.mode = mode;
+        self.stream_ended = false;
+        
+        log::info!("RTP Receiver switched to {:?} mode, port: {}", mode, self.port);
+        Ok(())
+    }
+
+    /// RTP timestamp of the last frame returned by `receive_frame`
+    pub fn last_frame_timestamp(&self) -> Option<u32> {
+        self.depacketizer.last_frame_timestamp()
+    }
+
+    /// Sender capture time (wall clock ms) of the last frame returned by `receive_frame`
+    pub fn last_frame_capture_ms(&self) -> Option<u64> {
+        self.depacketizer.last_frame_capture_ms()
+    }
+
+    /// True while the socket is being rebuilt after a network error.
+    /// Assembly restarts afterwards, so callers should wait for a keyframe.
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnect.is_some()
+    }
+
+    /// True once the sender announced the end of the stream (RTCP BYE), until its
+    /// media arrives again
+    pub fn stream_ended(&self) -> bool {
+        self.stream_ended
+    }
+
     /// Receive and process RTP packets, returns complete H.264 frame if available
     pub fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
-        let socket = self.socket.lock();
+        // Frames completed by an earlier packet come first
+        if let Some(frame) = self.depacketizer.take_pending() {
+            return Ok(Some(frame));
+        }
+        
+        if !self.try_reconnect() {
+            return Ok(None);
+        }
         
         // Try to receive packets
-        match socket.recv_from(&mut self.buffer) {
-            Ok((size, addr)) => {
-                // Log first few packets
-                static PACKET_COUNT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
-                let count = PACKET_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                
-                if count < 10 || count % 100 == 0 {
-                    log::info!("RTP packet #{}: {} bytes from {}", count, size, addr);
-                }
-                
-                if size < RTP_HEADER_SIZE {
-                    log::warn!("Packet too small: {} bytes", size);
-                    return Ok(None);
-                }
-                
-                // Process RTP packet
-                if let Some(frame) = self.depacketizer.depacketize(&self.buffer[..size]) {
-                    log::info!("Frame assembled: {} bytes", frame.len());
-                    return Ok(Some(frame));
-                }
-                
-                Ok(None)
-            }
+        let result = self.socket.recv_from(&mut self.buffer);
+        
+        match result {
+            Ok((size, addr)) => Ok(self.handle_packet(size, addr)),
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock 
                    || e.kind() == std::io::ErrorKind::TimedOut => {
                 Ok(None)
             }
+            Err(e) if is_transient(&e) => {
+                log::warn!("Socket error: {}, reconnecting", e);
+                self.reconnect = Some(Backoff::new());
+                Ok(None)
+            }
             Err(e) => {
                 log::error!("Socket error: {}", e);
                 Err(BroadcastError::NetworkError(e.to_string()))
             }
         }
     }
+
+    /// Wait for the next complete frame without a thread sitting in timed reads: the task
+    /// sleeps until the socket is readable. Must run inside a tokio runtime.
+    /// This switches the socket to non-blocking, so a later `receive_frame` on the same
+    /// receiver returns at once instead of waiting for its read timeout.
+    pub async fn recv_frame_async(&mut self) -> Result<Vec<u8>, BroadcastError> {
+        loop {
+            if let Some(frame) = self.depacketizer.take_pending() {
+                return Ok(frame);
+            }
+            
+            // Wait out the backoff here so `try_reconnect` never sleeps the runtime thread
+            if let Some(backoff) = &self.reconnect {
+                tokio::time::sleep(backoff.remaining()).await;
+                if !self.try_reconnect() {
+                    continue;
+                }
+            }
+            
+            let socket = match &self.async_socket {
+                Some(socket) => socket,
+                None => {
+                    let socket = self.socket.try_clone()?;
+                    socket.set_nonblocking(true)?;
+                    self.async_socket.insert(tokio::net::UdpSocket::from_std(socket)?)
+                }
+            };
+            
+            match socket.recv_from(&mut self.buffer).await {
+                Ok((size, addr)) => {
+                    if let Some(frame) = self.handle_packet(size, addr) {
+                        return Ok(frame);
+                    }
+                }
+                Err(e) if is_transient(&e) => {
+                    log::warn!("Socket error: {}, reconnecting", e);
+                    self.reconnect = Some(Backoff::new());
+                }
+                Err(e) => {
+                    log::error!("Socket error: {}", e);
+                    return Err(BroadcastError::NetworkError(e.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Rebuild the socket if a reconnect is due; returns whether the socket is usable
+    fn try_reconnect(&mut self) -> bool {
+        let Some(backoff) = &mut self.reconnect else { return true };
+        if !backoff.is_due() {
+            // Stand in for the socket read timeout so callers don't spin
+            std::thread::sleep(backoff.remaining().min(Duration::from_millis(100)));
+            return false;
+        }
+        
+        match open_receiver_socket(self.port, self.mode, self.recv_buffer_bytes, self.interface) {
+            Ok(socket) => {
+                log::info!("RTP Receiver reconnected after {} attempt(s)", backoff.attempts + 1);
+                self.socket = socket;
+                self.async_socket = None;
+                self.depacketizer = RtpDepacketizer::with_max_temporal_layer(self.max_temporal_layer);
+                self.fec = None;
+                self.reconnect = None;
+                true
+            }
+            Err(e) => {
+                backoff.failed();
+                log::warn!("RTP Receiver reconnect failed: {}, retrying in {:?}", e, backoff.remaining());
+                false
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, size: usize, addr: SocketAddr) -> Option<Vec<u8>> {
+        // Log first few packets
+        static PACKET_COUNT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let count = PACKET_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        
+        if count < 10 || count.is_multiple_of(100) {
+            log::info!("RTP packet #{}: {} bytes from {}", count, size, addr);
+        }
+        
+        if let Some(source) = self.source {
+            if addr.ip() != std::net::IpAddr::V4(source) {
+                return None;
+            }
+        }
+        
+        let packet = &self.buffer[..size];
+        if is_rtcp_bye(packet) {
+            if !self.stream_ended {
+                log::info!("{} ended the stream", addr);
+            }
+            // A restarted teacher starts over with new sequence numbers
+            if let Some(fec) = &mut self.fec {
+                fec.reset();
+            }
+            self.stream_ended = true;
+            return None;
+        }
+        
+        if size < RTP_HEADER_SIZE {
+            log::warn!("Packet too small: {} bytes", size);
+            return None;
+        }
+        
+        let payload_type = packet[1] & 0x7F;
+        if payload_type == RTP_PAYLOAD_TYPE_H264 {
+            self.stream_ended = false;
+            let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+            let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+            self.stats.on_packet(sequence, timestamp);
+        }
+        
+        // With FEC, packets pass through the decoder, which puts them back in order
+        // and fills single gaps per group
+        if payload_type == RTP_PAYLOAD_TYPE_FEC && self.fec.is_none() {
+            log::info!("FEC parity received, enabling recovery");
+            self.fec = Some(FecDecoder::new());
+        }
+        let released = match (&mut self.fec, payload_type) {
+            (Some(fec), RTP_PAYLOAD_TYPE_FEC) => fec.on_parity(packet),
+            (Some(fec), _) => fec.on_media(packet),
+            (None, _) => vec![packet.to_vec()],
+        };
+        for packet in &released {
+            self.depacketizer.push_packet(packet);
+        }
+        
+        // Process RTP packet
+        if let Some(frame) = self.depacketizer.take_pending() {
+            log::info!("Frame assembled: {} bytes", frame.len());
+            if let Some(intervals) = &mut self.arrival_intervals {
+                intervals.mark();
+            }
+            return Some(frame);
+        }
+        
+        None
+    }
+
+    /// Number of H.264 RTP packets received
+    pub fn packets_received(&self) -> u64 {
+        self.stats.packets_received
+    }
+
+    /// Number of packets missing from the sequence (late arrivals are subtracted again)
+    pub fn packets_lost(&self) -> u64 {
+        self.stats.packets_lost
+    }
+
+    /// Number of packets that arrived after a later sequence number
+    pub fn out_of_order(&self) -> u64 {
+        self.stats.out_of_order
+    }
+
+    /// Number of lost packets rebuilt from FEC parity
+    pub fn packets_recovered(&self) -> u64 {
+        self.fec.as_ref().map_or(0, |fec| fec.recovered())
+    }
+
+    /// Interarrival jitter estimate (RFC 3550) in milliseconds
+    pub fn jitter_ms(&self) -> f32 {
+        (self.stats.jitter / (RTP_CLOCK_RATE as f64 / 1000.0)) as f32
+    }
+
+    /// Frame arrival interval statistics since the last call, `None` without diagnostics or frames
+    pub fn take_arrival_intervals(&mut self) -> Option<IntervalStats> {
+        self.arrival_intervals.as_mut()?.take()
+    }
+}
+
+/// Running min/max/mean/standard deviation of the gaps between successive marks
+pub struct IntervalTracker {
+    last: Option<Instant>,
+    count: u64,
+    min: Duration,
+    max: Duration,
+    mean_ms: f64,
+    /// Sum of squared differences from the mean (Welford)
+    m2: f64,
+}
+
+impl IntervalTracker {
+    pub fn new() -> Self {
+        Self {
+            last: None,
+            count: 0,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+            mean_ms: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn mark(&mut self) {
+        self.mark_at(Instant::now());
+    }
+
+    /// Record the gap since the previous mark
+    pub fn mark_at(&mut self, now: Instant) {
+        if let Some(last) = self.last {
+            self.record(now.saturating_duration_since(last));
+        }
+        self.last = Some(now);
+    }
+
+    pub fn record(&mut self, interval: Duration) {
+        let ms = interval.as_secs_f64() * 1000.0;
+        self.count += 1;
+        self.min = self.min.min(interval);
+        self.max = self.max.max(interval);
+        let delta = ms - self.mean_ms;
+        self.mean_ms += delta / self.count as f64;
+        self.m2 += delta * (ms - self.mean_ms);
+    }
+
+    /// Statistics of the intervals recorded so far, then start a new period.
+    /// The last mark is kept so the gap across the boundary still counts.
+    pub fn take(&mut self) -> Option<IntervalStats> {
+        if self.count == 0 {
+            return None;
+        }
+        let stats = IntervalStats {
+            count: self.count,
+            min_ms: self.min.as_secs_f32() * 1000.0,
+            max_ms: self.max.as_secs_f32() * 1000.0,
+            mean_ms: self.mean_ms as f32,
+            stddev_ms: (self.m2 / self.count as f64).sqrt() as f32,
+        };
+        *self = Self { last: self.last, ..Self::new() };
+        Some(stats)
+    }
+}
+
+impl Default for IntervalTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Packet loss and jitter tracking for one receiver
+struct ReceptionStats {
+    packets_received: u64,
+    packets_lost: u64,
+    out_of_order: u64,
+    highest_seq: Option<u16>,
+    /// Jitter in RTP timestamp units
+    jitter: f64,
+    last_transit: Option<u32>,
+    epoch: Instant,
+}
+
+impl ReceptionStats {
+    fn new() -> Self {
+        Self {
+            packets_received: 0,
+            packets_lost: 0,
+            out_of_order: 0,
+            highest_seq: None,
+            jitter: 0.0,
+            last_transit: None,
+            epoch: Instant::now(),
+        }
+    }
+
+    fn on_packet(&mut self, sequence: u16, timestamp: u32) {
+        self.packets_received += 1;
+        
+        match self.highest_seq {
+            None => self.highest_seq = Some(sequence),
+            Some(highest) => {
+                let delta = sequence.wrapping_sub(highest) as i16;
+                if delta > 0 {
+                    self.packets_lost += (delta - 1) as u64;
+                    self.highest_seq = Some(sequence);
+                } else if delta < 0 {
+                    // Late packet that was counted as lost
+                    self.out_of_order += 1;
+                    self.packets_lost = self.packets_lost.saturating_sub(1);
+                }
+                // delta == 0 is a duplicate
+            }
+        }
+        
+        // Interarrival jitter: J += (|D| - J) / 16, with transit times in RTP units
+        let arrival = (self.epoch.elapsed().as_micros() * RTP_CLOCK_RATE as u128 / 1_000_000) as u32;
+        let transit = arrival.wrapping_sub(timestamp);
+        if let Some(last) = self.last_transit {
+            let d = (transit.wrapping_sub(last) as i32).unsigned_abs() as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
 }
 
-impl Clone for RtpReceiver {
-    fn clone(&self) -> Self {
+/// Exponential backoff between socket rebuild attempts
+struct Backoff {
+    attempts: u32,
+    next_try: Instant,
+}
+
+impl Backoff {
+    const BASE_DELAY: Duration = Duration::from_millis(100);
+    const MAX_DELAY: Duration = Duration::from_secs(5);
+
+    fn new() -> Self {
         Self {
-            socket: self.socket.clone(),
-            depacketizer: RtpDepacketizer::new(),
-            buffer: vec![0u8; 2048],
+            attempts: 0,
+            next_try: Instant::now() + Self::BASE_DELAY,
         }
     }
+
+    fn is_due(&self) -> bool {
+        Instant::now() >= self.next_try
+    }
+
+    fn remaining(&self) -> Duration {
+        self.next_try.saturating_duration_since(Instant::now())
+    }
+
+    fn failed(&mut self) {
+        self.attempts += 1;
+        let delay = (Self::BASE_DELAY * 2u32.pow(self.attempts.min(6))).min(Self::MAX_DELAY);
+        self.next_try = Instant::now() + delay;
+    }
+}
+
+/// Errors caused by the network going away (Wi-Fi drop, adapter reset) rather than by our setup
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e.kind(),
+        NetworkDown | NetworkUnreachable | HostUnreachable | AddrNotAvailable
+        | ConnectionReset | ConnectionAborted | ConnectionRefused | NotConnected | Interrupted)
+}
+
+fn open_sender_socket(mode: NetworkMode, interface: Ipv4Addr, send_buffer_bytes: usize) -> Result<UdpSocket, BroadcastError> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    
+    if mode == NetworkMode::Multicast {
+        socket.set_multicast_ttl_v4(1)?;
+        socket.set_multicast_loop_v4(true)?;
+        if !interface.is_unspecified() {
+            socket.set_multicast_if_v4(&interface)?;
+        }
+    }
+    
+    // Bind to any port, on the chosen interface if any
+    let bind_addr = SocketAddrV4::new(interface, 0);
+    socket.bind(&bind_addr.into())?;
+    
+    // Set send buffer; the OS may clamp (or on Linux double) the requested size
+    socket.set_send_buffer_size(send_buffer_bytes)?;
+    log::info!("Send buffer: requested {} bytes, granted {} bytes",
+        send_buffer_bytes, socket.send_buffer_size()?);
+    
+    Ok(socket.into())
+}
+
+fn open_receiver_socket(
+    port: u16,
+    mode: NetworkMode,
+    recv_buffer_bytes: usize,
+    interface: Ipv4Addr,
+) -> Result<UdpSocket, BroadcastError> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    
+    // Lets several receivers (student, native viewer) share the port and a restart rebind it
+    // at once. On Windows SO_REUSEADDR alone does both; SO_EXCLUSIVEADDRUSE would forbid sharing.
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    
+    // Bind to port on all addresses; binding to the interface IP would filter out broadcasts
+    let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+    let mut attempt = 0;
+    loop {
+        match socket.bind(&bind_addr.into()) {
+            Ok(()) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempt < BIND_RETRIES => {
+                attempt += 1;
+                log::warn!("Port {} still in use, retrying bind ({}/{})", port, attempt, BIND_RETRIES);
+                std::thread::sleep(BIND_RETRY_DELAY);
+            }
+            Err(e) => {
+                return Err(BroadcastError::NetworkError(format!("Bind to port {} failed: {}", port, e)));
+            }
+        }
+    }
+    
+    log::info!("RTP Receiver bound to 0.0.0.0:{}", port);
+    
+    // Join multicast if needed
+    if mode == NetworkMode::Multicast {
+        let multicast_ip: Ipv4Addr = MULTICAST_ADDR.parse().unwrap();
+        socket.join_multicast_v4(&multicast_ip, &interface)
+            .map_err(|e| BroadcastError::NetworkError(format!("Join multicast failed: {}", e)))?;
+        log::info!("Joined multicast group: {} on interface {}", MULTICAST_ADDR, interface);
+    }
+    
+    // Set receive buffer; the OS may clamp (or on Linux double) the requested size
+    socket.set_recv_buffer_size(recv_buffer_bytes)?;
+    log::info!("Receive buffer: requested {} bytes, granted {} bytes",
+        recv_buffer_bytes, socket.recv_buffer_size()?);
+    
+    // Blocking with timeout
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    
+    Ok(socket.into())
+}
+
+/// A local IPv4 interface that streams can be bound to
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+}
+
+/// IPv4 interfaces of this machine, loopback excluded
+pub fn list_interfaces() -> Result<Vec<NetworkInterface>, BroadcastError> {
+    let interfaces = if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match iface.ip() {
+            std::net::IpAddr::V4(ip) => Some(NetworkInterface { name: iface.name, ip }),
+            std::net::IpAddr::V6(_) => None,
+        })
+        .collect();
+    Ok(interfaces)
+}
+
+/// Make sure `ip` belongs to a local interface
+fn check_interface(ip: Ipv4Addr) -> Result<Ipv4Addr, BroadcastError> {
+    if list_interfaces()?.iter().any(|iface| iface.ip == ip) {
+        Ok(ip)
+    } else {
+        Err(BroadcastError::ConfigError(format!("Network interface {} not found", ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Receiver on an OS-assigned localhost port and a plain socket to feed it packets
+    fn receiver_and_socket() -> (RtpReceiver, UdpSocket, SocketAddr) {
+        let receiver = RtpReceiver::new(&StreamConfig { port: 0, ..StreamConfig::default() }).expect("bind receiver");
+        let port = receiver.local_addr().expect("receiver address").port();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind socket");
+        (receiver, socket, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))
+    }
+
+    /// Read until `packets` H.264 packets were counted or a second has passed
+    fn receive_packets(receiver: &mut RtpReceiver, packets: u64) {
+        let start = Instant::now();
+        while receiver.packets_received() < packets && start.elapsed() < Duration::from_secs(1) {
+            receiver.receive_frame().expect("receive");
+        }
+        assert_eq!(receiver.packets_received(), packets);
+    }
+
+    #[test]
+    fn sequence_gap_counts_as_lost() {
+        let (mut receiver, socket, target) = receiver_and_socket();
+        let mut packetizer = RtpPacketizer::new();
+        let packets: Vec<Vec<u8>> = (0..10)
+            .flat_map(|_| packetizer.packetize(&[0, 0, 0, 1, 0x41, 0x9A, 0x01]))
+            .collect();
+
+        // Packets 3 and 4 never arrive
+        for (i, packet) in packets.iter().enumerate() {
+            if i != 3 && i != 4 {
+                socket.send_to(packet, target).expect("send");
+            }
+        }
+        receive_packets(&mut receiver, 8);
+        assert_eq!(receiver.packets_lost(), 2);
+        assert_eq!(receiver.out_of_order(), 0);
+    }
+
+    #[test]
+    fn late_packet_is_no_longer_lost() {
+        let mut stats = ReceptionStats::new();
+        for sequence in [65534, 65535, 1, 2] {
+            stats.on_packet(sequence, 0);
+        }
+        assert_eq!(stats.packets_lost, 1);
+
+        // Sequence 0, across the wraparound, turns up after all
+        stats.on_packet(0, 0);
+        assert_eq!(stats.packets_lost, 0);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.packets_received, 5);
+    }
+
+    /// Arrival times of the packets of one frame sent by `sender` to `socket`
+    fn frame_arrivals(sender: &mut RtpSender, socket: &UdpSocket, frame: &[u8]) -> Vec<Instant> {
+        let socket = socket.try_clone().expect("clone socket");
+        socket.set_read_timeout(Some(Duration::from_millis(200))).expect("timeout");
+        let reader = std::thread::spawn(move || {
+            let mut buffer = [0u8; MAX_MTU];
+            let mut arrivals = Vec::new();
+            while socket.recv_from(&mut buffer).is_ok() {
+                arrivals.push(Instant::now());
+            }
+            arrivals
+        });
+
+        let start = Instant::now();
+        sender.send_frame(frame).expect("send");
+        assert!(start.elapsed() < sender.frame_interval, "sending took {:?}", start.elapsed());
+        reader.join().expect("reader")
+    }
+
+    #[test]
+    fn pacing_spreads_packets_over_the_frame_interval() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind socket");
+        let mut sender = RtpSender::new(&StreamConfig::default()).expect("open sender");
+        sender.set_target(socket.local_addr().expect("socket address"));
+        sender.set_frame_rate(10);
+        // One slice in 20 FU-A fragments
+        let mut frame = vec![0, 0, 0, 1, 0x65];
+        frame.extend((0..20 * 1400).map(|i| (i % 251) as u8 + 1));
+
+        let burst = frame_arrivals(&mut sender, &socket, &frame);
+        assert_eq!(burst.len(), 20);
+        assert!(burst[19] - burst[0] < Duration::from_millis(20));
+
+        // 80% of the 100 ms interval: packets 4 ms apart, the last one 76 ms in
+        sender.set_pacing(true);
+        let paced = frame_arrivals(&mut sender, &socket, &frame);
+        assert_eq!(paced.len(), 20);
+        assert!(paced[19] - paced[0] >= Duration::from_millis(70), "spread over {:?}", paced[19] - paced[0]);
+        let closest = paced.windows(2).map(|pair| pair[1] - pair[0]).min().unwrap();
+        assert!(closest >= Duration::from_millis(2), "packets {:?} apart", closest);
+
+        // A frame of a few packets is not held back
+        let small = frame_arrivals(&mut sender, &socket, &frame[..3 * 1400]);
+        assert_eq!(small.len(), 3);
+        assert!(small[2] - small[0] < Duration::from_millis(20));
+    }
+
+    /// The state a transient socket error leaves behind, with the first retry due now
+    fn failed_socket() -> Option<Backoff> {
+        Some(Backoff { attempts: 0, next_try: Instant::now() })
+    }
+
+    #[test]
+    fn receiver_rebuilds_its_socket_after_an_error() {
+        let (mut receiver, socket, _) = receiver_and_socket();
+        let frame = [0, 0, 0, 1, 0x65, 0x88, 0x84];
+        let mut packetizer = RtpPacketizer::new();
+
+        receiver.reconnect = Some(Backoff::new());
+        assert!(receiver.is_reconnecting());
+        // Not due yet: no read, no frame
+        assert_eq!(receiver.receive_frame().expect("receive"), None);
+        assert!(receiver.is_reconnecting());
+
+        receiver.reconnect = failed_socket();
+        let start = Instant::now();
+        let mut received = None;
+        while received.is_none() && start.elapsed() < Duration::from_secs(1) {
+            // Bound to port 0, the rebuilt socket has a port of its own
+            if !receiver.is_reconnecting() {
+                let port = receiver.local_addr().expect("receiver address").port();
+                let target = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+                socket.send_to(&packetizer.packetize(&frame)[0], target).expect("send");
+            }
+            received = receiver.receive_frame().expect("receive");
+        }
+        assert!(!receiver.is_reconnecting());
+        assert_eq!(received.as_deref(), Some(&frame[..]));
+    }
+
+    #[test]
+    fn sender_drops_frames_until_its_socket_is_rebuilt() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind socket");
+        socket.set_read_timeout(Some(Duration::from_secs(1))).expect("timeout");
+        let mut sender = RtpSender::new(&StreamConfig::default()).expect("open sender");
+        sender.set_target(socket.local_addr().expect("socket address"));
+        let frame = [0, 0, 0, 1, 0x65, 0x88, 0x84];
+
+        sender.reconnect = Some(Backoff::new());
+        assert_eq!(sender.send_frame(&frame).expect("send"), 0);
+        assert!(sender.is_reconnecting());
+
+        sender.reconnect = failed_socket();
+        assert!(sender.send_frame(&frame).expect("send") > 0);
+        assert!(!sender.is_reconnecting());
+        let mut buffer = [0u8; MAX_MTU];
+        assert!(socket.recv_from(&mut buffer).is_ok());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let mut backoff = Backoff::new();
+        assert!(backoff.remaining() <= Backoff::BASE_DELAY);
+        backoff.failed();
+        assert!(backoff.remaining() > Backoff::BASE_DELAY);
+        for _ in 0..10 {
+            backoff.failed();
+        }
+        assert!(backoff.remaining() <= Backoff::MAX_DELAY);
+        assert!(backoff.remaining() > Backoff::MAX_DELAY / 2);
+    }
+
+    #[test]
+    fn only_network_errors_are_transient() {
+        use std::io::{Error, ErrorKind};
+        assert!(is_transient(&Error::from(ErrorKind::NetworkUnreachable)));
+        assert!(is_transient(&Error::from(ErrorKind::ConnectionReset)));
+        assert!(!is_transient(&Error::from(ErrorKind::PermissionDenied)));
+        assert!(!is_transient(&Error::from(ErrorKind::InvalidInput)));
+    }
+
+    #[test]
+    fn packets_split_between_two_readers_lose_the_frame() {
+        // What clones sharing one socket did: each read took whichever packet came next
+        // into its own depacketizer, so a fragmented frame was dealt out between them
+        let frame = [&[0, 0, 0, 1][..], &[0x65; 5000]].concat();
+        let packets = RtpPacketizer::new().packetize(&frame);
+        assert!(packets.len() > 2);
+        
+        let mut readers = [RtpDepacketizer::new(), RtpDepacketizer::new()];
+        let mut frames: Vec<_> = packets.iter().enumerate()
+            .filter_map(|(i, packet)| readers[i % 2].depacketize(packet))
+            .collect();
+        frames.extend(readers.iter_mut().filter_map(|reader| reader.take_pending()));
+        assert!(!frames.contains(&frame));
+        
+        // One receiver per socket gets it whole
+        let mut reader = RtpDepacketizer::new();
+        let frames: Vec<_> = packets.iter().filter_map(|packet| reader.depacketize(packet)).collect();
+        assert_eq!(frames, [frame]);
+    }
+
+    #[test]
+    fn async_receive_awaits_a_whole_frame() {
+        let (mut receiver, socket, addr) = receiver_and_socket();
+        let frame = [&[0, 0, 0, 1][..], &[0x65; 3000]].concat();
+        let packets = RtpPacketizer::new().packetize(&frame);
+        
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let received = runtime.block_on(async {
+            // Sent while the receive is already waiting
+            let sender = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                for packet in &packets {
+                    socket.send_to(packet, addr).unwrap();
+                }
+            });
+            let received = tokio::time::timeout(Duration::from_secs(1), receiver.recv_frame_async()).await;
+            sender.await.unwrap();
+            received
+        });
+        assert_eq!(received.expect("frame in time").expect("receive"), frame);
+        
+        // The socket is non-blocking now: nothing queued means an immediate return
+        let start = Instant::now();
+        assert_eq!(receiver.receive_frame().expect("receive"), None);
+        assert!(start.elapsed() < Duration::from_millis(50), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn interval_stats_from_known_marks() {
+        let mut tracker = IntervalTracker::new();
+        assert_eq!(tracker.take(), None);
+        
+        // Gaps of 10, 20, 10 and 20 ms
+        let start = Instant::now();
+        for ms in [0, 10, 30, 40, 60] {
+            tracker.mark_at(start + Duration::from_millis(ms));
+        }
+        let stats = tracker.take().expect("stats");
+        assert_eq!(stats.count, 4);
+        assert_eq!((stats.min_ms, stats.max_ms), (10.0, 20.0));
+        assert!((stats.mean_ms - 15.0).abs() < 1e-3, "{}", stats.mean_ms);
+        assert!((stats.stddev_ms - 5.0).abs() < 1e-3, "{}", stats.stddev_ms);
+        
+        // A new period counts the gap across the boundary, and nothing else
+        tracker.mark_at(start + Duration::from_millis(95));
+        let stats = tracker.take().expect("stats");
+        assert_eq!((stats.count, stats.min_ms, stats.max_ms, stats.stddev_ms), (1, 35.0, 35.0, 0.0));
+        assert_eq!(tracker.take(), None);
+    }
 }
@@ -0,0 +1,252 @@
+//! Recorded H.264 as a broadcast source: access units are read from an Annex-B file or
+//! the H.264 track of an MP4 and re-packetized as they are, without re-encoding
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use super::h264::{avcc_to_annexb, AVCC_LENGTH_SIZE};
+use super::rtp::{find_start_code, NalType};
+use super::types::BroadcastError;
+
+/// Bytes read from an Annex-B file at a time
+const READ_CHUNK: usize = 1 << 20;
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// One frame's worth of NAL units in Annex-B form
+pub struct AccessUnit {
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+    /// How long the frame is shown, i.e. until the next one is due
+    pub duration: Duration,
+}
+
+/// Reads a recording one access unit at a time
+pub trait AccessUnitReader: Send {
+    /// Next access unit, `Ok(None)` at the end of the file
+    fn next_unit(&mut self) -> Result<Option<AccessUnit>, BroadcastError>;
+
+    /// Go back to the start of the file
+    fn rewind(&mut self) -> Result<(), BroadcastError>;
+
+    /// Coded size, when the container states it
+    fn dimensions(&self) -> Option<(u32, u32)> {
+        None
+    }
+}
+
+/// Open `path` by extension: .mp4/.m4v/.mov as MP4, anything else as an Annex-B stream
+/// whose frames are `fps` apart (raw H.264 carries no timing)
+pub fn open(path: &Path, fps: u32) -> Result<Box<dyn AccessUnitReader>, BroadcastError> {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("mp4" | "m4v" | "mov") => Ok(Box::new(Mp4File::open(path)?)),
+        _ => Ok(Box::new(AnnexBFile::open(path, fps)?)),
+    }
+}
+
+/// Raw H.264 elementary stream (.h264, .264)
+pub struct AnnexBFile {
+    file: File,
+    buf: Vec<u8>,
+    eof: bool,
+    /// First NAL of the next access unit, read while finding the end of the current one
+    pending: Option<Vec<u8>>,
+    frame_duration: Duration,
+}
+
+impl AnnexBFile {
+    pub fn open(path: &Path, fps: u32) -> Result<Self, BroadcastError> {
+        let file = File::open(path)
+            .map_err(|e| BroadcastError::ConfigError(format!("Cannot open {}: {}", path.display(), e)))?;
+        Ok(Self {
+            file,
+            buf: Vec::new(),
+            eof: false,
+            pending: None,
+            frame_duration: Duration::from_secs(1) / fps.max(1),
+        })
+    }
+
+    /// Next NAL unit without its start code
+    fn next_nal(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
+        loop {
+            match find_start_code(&self.buf, 0) {
+                Some((_, start)) => {
+                    // A NAL is complete once the next start code (or the end of the file) is in
+                    let end = match find_start_code(&self.buf, start) {
+                        Some((end, _)) => Some(end),
+                        None if self.eof => Some(self.buf.len()),
+                        None => None,
+                    };
+                    if let Some(end) = end {
+                        let mut nal = &self.buf[start..end];
+                        while let [rest @ .., 0] = nal {
+                            nal = rest;
+                        }
+                        let nal = nal.to_vec();
+                        self.buf.drain(..end);
+                        if !nal.is_empty() {
+                            return Ok(Some(nal));
+                        }
+                        continue;
+                    }
+                }
+                None if self.eof => return Ok(None),
+                None => {}
+            }
+
+            let len = self.buf.len();
+            self.buf.resize(len + READ_CHUNK, 0);
+            let read = self.file.read(&mut self.buf[len..])?;
+            self.buf.truncate(len + read);
+            self.eof = read == 0;
+        }
+    }
+}
+
+impl AccessUnitReader for AnnexBFile {
+    fn next_unit(&mut self) -> Result<Option<AccessUnit>, BroadcastError> {
+        let mut data = Vec::new();
+        let mut has_slice = false;
+        let mut keyframe = false;
+
+        loop {
+            let nal = match self.pending.take() {
+                Some(nal) => nal,
+                None => match self.next_nal()? {
+                    Some(nal) => nal,
+                    None => break,
+                },
+            };
+            let nal_type = NalType::from_header(nal[0]);
+            if has_slice && starts_access_unit(nal_type, &nal) {
+                self.pending = Some(nal);
+                break;
+            }
+            has_slice |= is_slice(nal_type);
+            keyframe |= nal_type.is_keyframe();
+            data.extend_from_slice(&START_CODE);
+            data.extend_from_slice(&nal);
+        }
+
+        Ok((!data.is_empty()).then_some(AccessUnit { data, keyframe, duration: self.frame_duration }))
+    }
+
+    fn rewind(&mut self) -> Result<(), BroadcastError> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.buf.clear();
+        self.eof = false;
+        self.pending = None;
+        Ok(())
+    }
+}
+
+fn is_slice(nal_type: NalType) -> bool {
+    matches!(nal_type, NalType::Slice | NalType::PartitionA | NalType::Idr)
+}
+
+/// Whether `nal`, following a frame's slices, begins the next access unit (H.264 7.4.1.2.3)
+fn starts_access_unit(nal_type: NalType, nal: &[u8]) -> bool {
+    match nal_type {
+        NalType::Aud | NalType::Sps | NalType::Pps | NalType::Sei => true,
+        // first_mb_in_slice == 0, coded as a single 1 bit
+        NalType::Slice | NalType::PartitionA | NalType::Idr => nal.get(1).is_some_and(|b| b & 0x80 != 0),
+        _ => false,
+    }
+}
+
+/// H.264 track of an MP4/MOV file
+pub struct Mp4File {
+    reader: mp4::Mp4Reader<BufReader<File>>,
+    track_id: u32,
+    sample_count: u32,
+    /// 1-based, as in the MP4 sample table
+    next_sample: u32,
+    timescale: u32,
+    /// SPS and PPS from the sample description, in Annex-B form; samples do not carry them
+    parameter_sets: Vec<u8>,
+    /// Bytes of each NAL length prefix in the samples, from the avcC record
+    length_size: usize,
+    dimensions: (u32, u32),
+}
+
+impl Mp4File {
+    pub fn open(path: &Path) -> Result<Self, BroadcastError> {
+        let open_error = |e: &dyn std::fmt::Display| BroadcastError::ConfigError(format!("Cannot open {}: {}", path.display(), e));
+        let file = File::open(path).map_err(|e| open_error(&e))?;
+        let size = file.metadata()?.len();
+        let reader = mp4::Mp4Reader::read_header(BufReader::new(file), size).map_err(|e| open_error(&e))?;
+
+        let track = reader.tracks()
+            .values()
+            .find(|t| matches!(t.media_type(), Ok(mp4::MediaType::H264)))
+            .ok_or_else(|| BroadcastError::ConfigError(format!("{} has no H.264 track", path.display())))?;
+        let mut parameter_sets = Vec::new();
+        for nal in [track.sequence_parameter_set(), track.picture_parameter_set()] {
+            parameter_sets.extend_from_slice(&START_CODE);
+            parameter_sets.extend_from_slice(nal.map_err(|e| open_error(&e))?);
+        }
+        // lengthSizeMinusOne is 0, 1 or 3; 2 is reserved
+        let length_size = track.trak.mdia.minf.stbl.stsd.avc1.as_ref()
+            .map_or(AVCC_LENGTH_SIZE, |avc1| avc1.avcc.length_size_minus_one as usize + 1);
+        if !matches!(length_size, 1 | 2 | 4) {
+            return Err(BroadcastError::ConfigError(format!(
+                "{} uses {}-byte NAL lengths, only 1, 2 and 4 are valid", path.display(), length_size)));
+        }
+        let track_id = track.track_id();
+        let timescale = track.timescale().max(1);
+        let dimensions = (track.width() as u32, track.height() as u32);
+        let sample_count = reader.sample_count(track_id).map_err(|e| open_error(&e))?;
+
+        Ok(Self {
+            reader,
+            track_id,
+            sample_count,
+            next_sample: 1,
+            timescale,
+            parameter_sets,
+            length_size,
+            dimensions,
+        })
+    }
+}
+
+impl AccessUnitReader for Mp4File {
+    fn next_unit(&mut self) -> Result<Option<AccessUnit>, BroadcastError> {
+        while self.next_sample <= self.sample_count {
+            let sample_id = self.next_sample;
+            self.next_sample += 1;
+            let sample = self.reader.read_sample(self.track_id, sample_id)
+                .map_err(|e| BroadcastError::ConfigError(format!("Cannot read sample {}: {}", sample_id, e)))?;
+            let Some(sample) = sample else { continue };
+
+            // Sync samples get the parameter sets so any of them can start decoding
+            let mut data = Vec::with_capacity(sample.bytes.len() + self.parameter_sets.len());
+            if sample.is_sync {
+                data.extend_from_slice(&self.parameter_sets);
+            }
+            // Samples hold NALs behind big endian lengths of the size the avcC record declares
+            data.extend_from_slice(&avcc_to_annexb(&sample.bytes, self.length_size));
+
+            return Ok(Some(AccessUnit {
+                data,
+                keyframe: sample.is_sync,
+                duration: Duration::from_secs_f64(sample.duration as f64 / self.timescale as f64),
+            }));
+        }
+        Ok(None)
+    }
+
+    fn rewind(&mut self) -> Result<(), BroadcastError> {
+        self.next_sample = 1;
+        Ok(())
+    }
+
+    fn dimensions(&self) -> Option<(u32, u32)> {
+        Some(self.dimensions)
+    }
+}
@@ -1,21 +1,71 @@
 //! RTP handling using rtp-rs library
 //! H.264 packetization according to RFC 6184
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub const RTP_PAYLOAD_TYPE_H264: u8 = 96;
-pub const MAX_RTP_PAYLOAD: usize = 1400;
+/// Payload type of XOR parity packets (see `fec`)
+pub const RTP_PAYLOAD_TYPE_FEC: u8 = 97;
+/// RTCP packet type of a BYE (RFC 3550 6.6): the sender has stopped
+pub const RTCP_BYE: u8 = 203;
+/// Path MTU assumed unless configured (Ethernet)
+pub const DEFAULT_MTU: usize = 1500;
+/// Accepted MTU range: the IPv4 minimum reassembly size up to jumbo frames
+pub const MIN_MTU: usize = 576;
+pub const MAX_MTU: usize = 9000;
+/// IPv4 (20) + UDP (8) headers
+const IP_UDP_HEADER_SIZE: usize = 28;
+const RTP_HEADER_SIZE: usize = 12;
 pub const RTP_CLOCK_RATE: u32 = 90000;
+/// One-byte header extension element (RFC 8285) carrying the capture time:
+/// wall clock milliseconds since the UNIX epoch, big endian u64
+pub const CAPTURE_TIME_EXT_ID: u8 = 1;
+/// Header extension block: profile (2) + length (2) + element header (1) + value (8) + padding (3)
+const CAPTURE_TIME_EXT_SIZE: usize = 16;
+/// Access unit delimiter NAL: NRI 0, type 9, primary_pic_type 7 (any slice type)
+const AUD_NAL: [u8; 2] = [0x09, 0xF0];
+/// Largest frame (or single NAL) reassembled; anything bigger is corrupt or hostile
+/// and is dropped instead of growing the buffer without bound
+const MAX_FRAME_BYTES: usize = 32 << 20;
+/// Completed frames kept for `take_pending`; the oldest are dropped past this
+const MAX_COMPLETED_FRAMES: usize = 32;
 
 /// RTP Packetizer for H.264 using rtp-rs
 pub struct RtpPacketizer {
     ssrc: u32,
     sequence: u16,
     clock_rate: u32,
+    fps: u32,
+    /// RTP timestamp of the next frame, advanced by `clock_rate / fps` per frame
+    timestamp: u32,
+    last_timestamp: Option<u32>,
+    /// (capture clock ticks, RTP timestamp) of the first frame sent with a capture time
+    time_base: Option<(u32, u32)>,
+    /// Wall clock capture time (ms) for the next frame, set by `set_capture_time`
+    next_capture_ms: Option<u64>,
+    /// Capture time written into the packets of the frame being packetized
+    capture_ms: u64,
+    /// Largest RTP payload that keeps a packet within the MTU
+    max_payload: usize,
+    /// Most recent SPS and PPS, repeated ahead of IDRs the encoder sent without them
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    /// Packet buffers handed back through `packetize_into`, reused for the next frame
+    spare: Vec<Vec<u8>>,
+    /// Start every frame with an access unit delimiter
+    insert_aud: bool,
 }
 
 impl RtpPacketizer {
     pub fn new() -> Self {
+        Self::with_mtu(DEFAULT_MTU)
+    }
+
+    /// Packetizer whose packets fit in `mtu` bytes on the wire, IP and UDP headers included
+    pub fn with_mtu(mtu: usize) -> Self {
         let ssrc = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -25,43 +75,187 @@ impl RtpPacketizer {
             ssrc,
             sequence: 0,
             clock_rate: RTP_CLOCK_RATE,
+            fps: 30,
+            // Random initial timestamp (RFC 3550), wraps around like any other
+            timestamp: ssrc.rotate_left(16),
+            last_timestamp: None,
+            time_base: None,
+            next_capture_ms: None,
+            capture_ms: 0,
+            max_payload: max_payload_for_mtu(mtu),
+            sps: None,
+            pps: None,
+            spare: Vec::new(),
+            insert_aud: false,
         }
     }
 
-    /// Packetize H.264 frame into RTP packets
-    pub fn packetize(&mut self, h264_data: &[u8], timestamp_ms: u32) -> Vec<Vec<u8>> {
+    /// Change the MTU packets must fit in, from the next frame on
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.max_payload = max_payload_for_mtu(mtu);
+    }
+
+    /// Start each frame with an AUD NAL, a frame boundary students can still see when
+    /// the marked last packet of the previous frame is lost
+    pub fn set_insert_aud(&mut self, enabled: bool) {
+        self.insert_aud = enabled;
+    }
+
+    /// Synchronization source identifier in every packet of this stream
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: u32) {
+        self.clock_rate = clock_rate.max(1);
+    }
+
+    /// Nominal frame rate used to advance the timestamp between frames
+    pub fn set_frame_rate(&mut self, fps: u32) {
+        self.fps = fps.max(1);
+    }
+
+    /// RTP timestamp the next packetized frame will carry
+    pub fn next_timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Wall clock capture time of the next frame; defaults to the packetize time
+    pub fn set_capture_time(&mut self, capture_time: SystemTime) {
+        self.next_capture_ms = Some(unix_ms(capture_time));
+    }
+
+    fn frame_duration(&self) -> u32 {
+        (self.clock_rate / self.fps).max(1)
+    }
+
+    /// Packetize H.264 frame into RTP packets.
+    /// Every call is one frame and gets a strictly increasing (wrapping) timestamp.
+    pub fn packetize(&mut self, h264_data: &[u8]) -> Vec<Vec<u8>> {
         let mut packets = Vec::new();
-        let timestamp = (timestamp_ms as u64 * self.clock_rate as u64 / 1000) as u32;
+        self.packetize_into(h264_data, &mut packets);
+        packets
+    }
+
+    /// Like `packetize`, but replaces the contents of `out`, reusing the packet buffers
+    /// it held from the previous frame instead of allocating new ones
+    pub fn packetize_into(&mut self, h264_data: &[u8], out: &mut Vec<Vec<u8>>) {
+        let timestamp = self.timestamp;
+        self.packetize_frame(h264_data, timestamp, out);
+    }
+
+    /// Packetize a frame stamped with its actual capture time (time since stream start),
+    /// for variable frame rate streams. Timestamps still never go backwards.
+    pub fn packetize_at(&mut self, h264_data: &[u8], capture_time: Duration) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        self.packetize_at_into(h264_data, capture_time, &mut packets);
+        packets
+    }
+
+    /// `packetize_at` writing into a reused `out`, see `packetize_into`
+    pub fn packetize_at_into(&mut self, h264_data: &[u8], capture_time: Duration, out: &mut Vec<Vec<u8>>) {
+        let ticks = (capture_time.as_micros() * self.clock_rate as u128 / 1_000_000) as u32;
+        let (base_ticks, base_timestamp) = *self.time_base.get_or_insert((ticks, self.timestamp));
+        let timestamp = base_timestamp.wrapping_add(ticks.wrapping_sub(base_ticks));
+        self.packetize_frame(h264_data, timestamp, out);
+    }
+
+    fn packetize_frame(&mut self, h264_data: &[u8], timestamp: u32, out: &mut Vec<Vec<u8>>) {
+        // Recycle last frame's packets; `out` keeps its capacity. Reversed, so `start_packet`
+        // pops them in their old order and a full-size buffer is not handed the short tail
+        self.spare.extend(out.drain(..).rev());
+        
+        // Keep timestamps strictly increasing so the depacketizer sees a new frame
+        let timestamp = match self.last_timestamp {
+            Some(last) if (timestamp.wrapping_sub(last) as i32) <= 0 => last.wrapping_add(1),
+            _ => timestamp,
+        };
+        self.last_timestamp = Some(timestamp);
+        self.timestamp = timestamp.wrapping_add(self.frame_duration());
+        self.capture_ms = self.next_capture_ms.take().unwrap_or_else(|| unix_ms(SystemTime::now()));
         
-        // Find NAL units
-        let nal_units = find_nal_units(h264_data);
+        // Split into NAL units in one pass, peeking ahead to mark the last one
+        let h264_data = self.with_parameter_sets(h264_data);
+        let mut nals = nal_units(&h264_data).peekable();
         
-        for (i, nal) in nal_units.iter().enumerate() {
-            let is_last_nal = i == nal_units.len() - 1;
+        let starts_with_aud = matches!(nals.peek(), Some((NalType::Aud, _)));
+        if self.insert_aud && nals.peek().is_some() && !starts_with_aud {
+            let mut packet = self.start_packet(timestamp, false, AUD_NAL.len());
+            packet.extend_from_slice(&AUD_NAL);
+            out.push(packet);
+        }
+        
+        while let Some((_, nal)) = nals.next() {
+            let is_last_nal = nals.peek().is_none();
             
-            if nal.len() <= MAX_RTP_PAYLOAD {
+            if nal.len() <= self.max_payload {
                 // Single NAL unit mode
-                let packet = self.build_packet(nal, timestamp, is_last_nal);
-                packets.push(packet);
+                let mut packet = self.start_packet(timestamp, is_last_nal, nal.len());
+                packet.extend_from_slice(nal);
+                out.push(packet);
             } else {
                 // FU-A fragmentation
-                let fu_packets = self.fragment_nal(nal, timestamp, is_last_nal);
-                packets.extend(fu_packets);
+                self.fragment_nal(nal, timestamp, is_last_nal, out);
+            }
+        }
+    }
+
+    /// Remember the latest SPS/PPS and insert them ahead of the IDR of an access unit that
+    /// lacks them, so a student that missed the originals can start decoding at any keyframe
+    fn with_parameter_sets<'a>(&mut self, h264_data: &'a [u8]) -> Cow<'a, [u8]> {
+        let (mut has_sps, mut has_pps, mut idr_at) = (false, false, None);
+        for (nal_type, nal) in nal_units(h264_data) {
+            match nal_type {
+                NalType::Sps => {
+                    has_sps = true;
+                    self.sps = Some(nal.to_vec());
+                }
+                NalType::Pps => {
+                    has_pps = true;
+                    self.pps = Some(nal.to_vec());
+                }
+                NalType::Idr if idr_at.is_none() => {
+                    // Back from the NAL header over its 3 or 4 byte start code
+                    let header = nal.as_ptr() as usize - h264_data.as_ptr() as usize;
+                    let start = header.saturating_sub(3);
+                    idr_at = Some(if start > 0 && h264_data[start - 1] == 0 { start - 1 } else { start });
+                }
+                _ => {}
             }
         }
         
-        packets
+        let Some(idr_at) = idr_at else { return Cow::Borrowed(h264_data) };
+        if has_sps && has_pps {
+            return Cow::Borrowed(h264_data);
+        }
+        
+        let mut data = h264_data[..idr_at].to_vec();
+        for (present, cached) in [(has_sps, &self.sps), (has_pps, &self.pps)] {
+            if let (false, Some(nal)) = (present, cached) {
+                data.extend_from_slice(&[0, 0, 0, 1]);
+                data.extend_from_slice(nal);
+            }
+        }
+        if data.len() == idr_at {
+            return Cow::Borrowed(h264_data);
+        }
+        data.extend_from_slice(&h264_data[idr_at..]);
+        Cow::Owned(data)
     }
 
-    fn build_packet(&mut self, payload: &[u8], timestamp: u32, marker: bool) -> Vec<u8> {
+    /// A packet holding just the RTP header, in a recycled buffer when there is one;
+    /// the caller appends the `payload_len` bytes of payload
+    fn start_packet(&mut self, timestamp: u32, marker: bool, payload_len: usize) -> Vec<u8> {
         let seq = self.sequence;
         self.sequence = self.sequence.wrapping_add(1);
         
-        let mut packet = Vec::with_capacity(12 + payload.len());
+        let mut packet = self.spare.pop().unwrap_or_default();
+        packet.clear();
+        packet.reserve(RTP_HEADER_SIZE + CAPTURE_TIME_EXT_SIZE + payload_len);
         
         // RTP Header (12 bytes)
-        // V=2, P=0, X=0, CC=0
-        packet.push(0x80);
+        // V=2, P=0, X=1, CC=0
+        packet.push(0x90);
         // M bit + PT
         packet.push(if marker { 0x80 | RTP_PAYLOAD_TYPE_H264 } else { RTP_PAYLOAD_TYPE_H264 });
         // Sequence number
@@ -78,17 +272,18 @@ impl RtpPacketizer {
         packet.push((self.ssrc >> 8) as u8);
         packet.push(self.ssrc as u8);
         
-        // Payload
-        packet.extend_from_slice(payload);
+        // Header extension with the capture time, on every packet so loss doesn't drop it
+        packet.extend_from_slice(&[0xBE, 0xDE, 0x00, 0x03]);
+        packet.push((CAPTURE_TIME_EXT_ID << 4) | 7);
+        packet.extend_from_slice(&self.capture_ms.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0]);
         
         packet
     }
 
-    fn fragment_nal(&mut self, nal: &[u8], timestamp: u32, is_last_nal: bool) -> Vec<Vec<u8>> {
-        let mut packets = Vec::new();
-        
+    fn fragment_nal(&mut self, nal: &[u8], timestamp: u32, is_last_nal: bool, out: &mut Vec<Vec<u8>>) {
         if nal.is_empty() {
-            return packets;
+            return;
         }
         
         let nal_header = nal[0];
@@ -99,30 +294,26 @@ impl RtpPacketizer {
         let fu_indicator = nri | 28;
         
         let payload = &nal[1..]; // Skip original NAL header
-        let max_fragment = MAX_RTP_PAYLOAD - 2; // Reserve 2 bytes for FU indicator + header
+        let max_fragment = self.max_payload - 2; // Reserve 2 bytes for FU indicator + header
         
-        let chunks: Vec<&[u8]> = payload.chunks(max_fragment).collect();
+        let chunk_count = payload.len().div_ceil(max_fragment);
         
-        for (i, chunk) in chunks.iter().enumerate() {
+        for (i, chunk) in payload.chunks(max_fragment).enumerate() {
             let is_first = i == 0;
-            let is_last = i == chunks.len() - 1;
+            let is_last = i == chunk_count - 1;
             
             // FU header: S E R Type
             let fu_header = ((is_first as u8) << 7) 
                           | ((is_last as u8) << 6) 
                           | nal_type;
             
-            let mut fu_payload = Vec::with_capacity(2 + chunk.len());
-            fu_payload.push(fu_indicator);
-            fu_payload.push(fu_header);
-            fu_payload.extend_from_slice(chunk);
-            
             let marker = is_last && is_last_nal;
-            let packet = self.build_packet(&fu_payload, timestamp, marker);
-            packets.push(packet);
+            let mut packet = self.start_packet(timestamp, marker, 2 + chunk.len());
+            packet.push(fu_indicator);
+            packet.push(fu_header);
+            packet.extend_from_slice(chunk);
+            out.push(packet);
         }
-        
-        packets
     }
 }
 
@@ -130,33 +321,84 @@ impl RtpPacketizer {
 pub struct RtpDepacketizer {
     current_frame: Vec<u8>,
     current_timestamp: Option<u32>,
+    current_capture_ms: Option<u64>,
+    last_frame_timestamp: Option<u32>,
+    last_frame_capture_ms: Option<u64>,
+    /// Completed frames waiting to be returned
+    completed: VecDeque<CompletedFrame>,
     fu_buffer: Vec<u8>,
     fu_started: bool,
+    /// The current frame outgrew `MAX_FRAME_BYTES`; its remaining packets are ignored
+    oversized: bool,
     last_seq: Option<u16>,
+    /// Slices of higher temporal layers are dropped (None = keep all)
+    max_temporal_layer: Option<u8>,
+    /// temporal_id from the SVC prefix NAL preceding the next slice
+    prefix_temporal_id: Option<u8>,
 }
 
 impl RtpDepacketizer {
     pub fn new() -> Self {
+        Self::with_max_temporal_layer(None)
+    }
+
+    /// Depacketizer that only keeps temporal layers up to `max_temporal_layer`,
+    /// e.g. `Some(0)` decodes the base layer of a two layer stream at half the frame rate
+    pub fn with_max_temporal_layer(max_temporal_layer: Option<u8>) -> Self {
         Self {
             current_frame: Vec::new(),
             current_timestamp: None,
+            current_capture_ms: None,
+            last_frame_timestamp: None,
+            last_frame_capture_ms: None,
+            completed: VecDeque::new(),
             fu_buffer: Vec::new(),
             fu_started: false,
+            oversized: false,
             last_seq: None,
+            max_temporal_layer,
+            prefix_temporal_id: None,
         }
     }
 
-    /// Process RTP packet, returns complete H.264 frame when marker bit is set
+    /// RTP timestamp of the last complete frame returned
+    pub fn last_frame_timestamp(&self) -> Option<u32> {
+        self.last_frame_timestamp
+    }
+
+    /// Sender capture time (wall clock ms) of the last complete frame returned,
+    /// if the stream carries the capture time extension
+    pub fn last_frame_capture_ms(&self) -> Option<u64> {
+        self.last_frame_capture_ms
+    }
+
+    /// Process RTP packet, returns complete H.264 frame when marker bit is set.
+    /// If a frame's marked packet was lost, the frame is flushed when the next
+    /// timestamp starts; call `take_pending` for any further completed frames.
     pub fn depacketize(&mut self, rtp_data: &[u8]) -> Option<Vec<u8>> {
-        if rtp_data.len() < 12 {
-            return None;
+        self.push_packet(rtp_data);
+        self.take_pending()
+    }
+
+    /// Next completed frame not yet returned by `depacketize`
+    pub fn take_pending(&mut self) -> Option<Vec<u8>> {
+        let frame = self.completed.pop_front()?;
+        self.last_frame_timestamp = Some(frame.timestamp);
+        self.last_frame_capture_ms = frame.capture_ms;
+        Some(frame.data)
+    }
+
+    /// Process RTP packet without returning frames; completed frames go to `take_pending`
+    pub fn push_packet(&mut self, rtp_data: &[u8]) {
+        if rtp_data.len() < RTP_HEADER_SIZE {
+            return;
         }
         
         // Parse RTP header manually for reliability
         let version = (rtp_data[0] >> 6) & 0x03;
         if version != 2 {
             log::warn!("Invalid RTP version: {}", version);
-            return None;
+            return;
         }
         
         let marker = (rtp_data[1] >> 7) & 0x01 == 1;
@@ -168,7 +410,7 @@ impl RtpDepacketizer {
                       | (rtp_data[7] as u32);
         
         if payload_type != RTP_PAYLOAD_TYPE_H264 {
-            return None;
+            return;
         }
         
         // Check sequence
@@ -183,29 +425,41 @@ impl RtpDepacketizer {
         }
         self.last_seq = Some(sequence);
         
-        let payload = &rtp_data[12..];
+        let Some((payload_range, capture_ms)) = parse_payload_range(rtp_data) else {
+            log::warn!("Malformed RTP header");
+            return;
+        };
+        let payload = &rtp_data[payload_range];
         if payload.is_empty() {
-            return None;
+            return;
         }
         
         // New timestamp = new frame
         if self.current_timestamp != Some(timestamp) {
-            if !self.current_frame.is_empty() && self.current_timestamp.is_some() {
-                // Previous frame wasn't completed, discard
-                log::debug!("Discarding incomplete frame");
+            if let Some(previous) = self.current_timestamp {
+                // Marked packet of the previous frame was lost: flush what we have
+                self.flush_frame(previous, "new timestamp");
             }
             self.current_frame.clear();
+            self.fu_buffer.clear();
+            self.fu_started = false;
+            self.oversized = false;
             self.current_timestamp = Some(timestamp);
+            self.current_capture_ms = None;
+        }
+        if capture_ms.is_some() {
+            self.current_capture_ms = capture_ms;
         }
         
         // Parse NAL unit type
         let nal_type = payload[0] & 0x1F;
         
         match nal_type {
-            28 => {
-                // FU-A
-                if payload.len() < 2 {
-                    return None;
+            28 | 29 => {
+                // FU-A, or FU-B whose FU header is followed by a 2 byte DON
+                let header_len = if nal_type == 29 { 4 } else { 2 };
+                if payload.len() < header_len {
+                    return;
                 }
                 
                 let fu_indicator = payload[0];
@@ -222,96 +476,344 @@ impl RtpDepacketizer {
                     self.fu_started = true;
                 }
                 
-                if self.fu_started && payload.len() > 2 {
-                    self.fu_buffer.extend_from_slice(&payload[2..]);
+                if self.fu_started && payload.len() > header_len {
+                    if self.fu_buffer.len() + payload.len() > MAX_FRAME_BYTES {
+                        log::warn!("Fragmented NAL over {} bytes dropped", MAX_FRAME_BYTES);
+                        self.fu_buffer = Vec::new();
+                        self.fu_started = false;
+                        return;
+                    }
+                    self.fu_buffer.extend_from_slice(&payload[header_len..]);
                 }
                 
                 if end && self.fu_started {
                     // Complete NAL unit
-                    self.current_frame.extend_from_slice(&[0, 0, 0, 1]);
-                    self.current_frame.extend_from_slice(&self.fu_buffer);
-                    self.fu_buffer.clear();
+                    let mut nal = std::mem::take(&mut self.fu_buffer);
+                    self.push_nal(&nal);
+                    nal.clear();
+                    self.fu_buffer = nal;
                     self.fu_started = false;
                 }
             }
             1..=23 => {
                 // Single NAL unit
-                self.current_frame.extend_from_slice(&[0, 0, 0, 1]);
-                self.current_frame.extend_from_slice(payload);
+                self.push_nal(payload);
             }
-            24 => {
-                // STAP-A (aggregation)
-                let mut offset = 1;
-                while offset + 2 < payload.len() {
-                    let size = ((payload[offset] as usize) << 8) | (payload[offset + 1] as usize);
-                    offset += 2;
-                    if offset + size <= payload.len() {
-                        self.current_frame.extend_from_slice(&[0, 0, 0, 1]);
-                        self.current_frame.extend_from_slice(&payload[offset..offset + size]);
-                        offset += size;
-                    } else {
-                        break;
-                    }
-                }
+            24 | 25 => {
+                // STAP-A, or STAP-B which starts with a 2 byte DON
+                let start = if nal_type == 25 { 3 } else { 1 };
+                self.push_aggregated(payload, start, 0);
+            }
+            26 | 27 => {
+                // MTAP16/MTAP24: DONB, then each unit has a DOND (1) and a 2 or 3 byte timestamp offset
+                let unit_header = if nal_type == 26 { 3 } else { 4 };
+                self.push_aggregated(payload, 3, unit_header);
             }
             _ => {
                 log::debug!("Unknown NAL type: {}", nal_type);
             }
         }
         
-        // Return frame if marker bit is set
+        // Frame is complete if marker bit is set
         if marker && !self.current_frame.is_empty() {
-            let frame = std::mem::take(&mut self.current_frame);
+            let data = std::mem::take(&mut self.current_frame);
+            log::debug!("Complete frame: {} bytes", data.len());
+            self.complete(CompletedFrame {
+                timestamp,
+                capture_ms: self.current_capture_ms,
+                data,
+            });
             self.current_timestamp = None;
-            log::debug!("Complete frame: {} bytes", frame.len());
-            return Some(frame);
         }
-        
-        None
     }
 }
 
-/// Find NAL units in H.264 bitstream
-fn find_nal_units(data: &[u8]) -> Vec<&[u8]> {
-    let mut units = Vec::new();
-    let mut i = 0;
-    let mut start = None;
-    
-    while i < data.len() {
-        // Look for start code
-        if i + 2 < data.len() && data[i] == 0 && data[i + 1] == 0 {
-            let (code_len, found) = if data[i + 2] == 1 {
-                (3, true)
-            } else if i + 3 < data.len() && data[i + 2] == 0 && data[i + 3] == 1 {
-                (4, true)
-            } else {
-                (0, false)
+impl RtpDepacketizer {
+    /// Append the NAL units of an aggregation packet. Units start at `start`, each is a
+    /// 2 byte NAL unit size, `unit_header` bytes to skip and then the NAL unit.
+    /// A malformed packet is dropped whole rather than appending partial units.
+    fn push_aggregated(&mut self, payload: &[u8], start: usize, unit_header: usize) {
+        let mut units = Vec::new();
+        let mut offset = start;
+        while offset < payload.len() {
+            let Some(size) = payload.get(offset..offset + 2) else {
+                log::debug!("Truncated aggregation packet dropped");
+                return;
             };
-            
-            if found {
-                if let Some(s) = start {
-                    // Save previous NAL (without trailing zeros)
-                    let end = i;
-                    if end > s {
-                        units.push(&data[s..end]);
-                    }
+            let size = u16::from_be_bytes([size[0], size[1]]) as usize;
+            offset += 2 + unit_header;
+            if size == 0 || offset + size > payload.len() {
+                log::debug!("Malformed aggregation packet dropped");
+                return;
+            }
+            units.push(&payload[offset..offset + size]);
+            offset += size;
+        }
+        
+        for unit in units {
+            self.push_nal(unit);
+        }
+    }
+
+    /// Append a complete NAL unit to the current frame, unless it is a slice (or its
+    /// prefix) from a temporal layer above `max_temporal_layer`
+    fn push_nal(&mut self, nal: &[u8]) {
+        // An AUD starts a new access unit: whatever came before it is a whole frame
+        // whose marked packet was lost
+        if nal.first().is_some_and(|&header| NalType::from_header(header) == NalType::Aud) {
+            if let Some(timestamp) = self.current_timestamp {
+                self.flush_frame(timestamp, "access unit delimiter");
+            }
+        }
+        
+        if let (Some(max), Some(&header)) = (self.max_temporal_layer, nal.first()) {
+            let layer = match header & 0x1F {
+                // SVC prefix NAL: temporal_id of the slice that follows
+                14 if nal.len() >= 4 => {
+                    self.prefix_temporal_id = Some(nal[3] >> 5);
+                    self.prefix_temporal_id
                 }
-                start = Some(i + code_len);
-                i += code_len;
-                continue;
+                // Without a prefix, non-reference slices (NRI 0) are the droppable top layer
+                1..=5 => Some(self.prefix_temporal_id.take()
+                    .unwrap_or(if header & 0x60 == 0 { 1 } else { 0 })),
+                _ => None,
+            };
+            if layer.is_some_and(|layer| layer > max) {
+                return;
             }
         }
-        i += 1;
+        
+        if self.oversized {
+            return;
+        }
+        if self.current_frame.len() + 4 + nal.len() > MAX_FRAME_BYTES {
+            log::warn!("Frame over {} bytes dropped", MAX_FRAME_BYTES);
+            self.current_frame = Vec::new();
+            self.oversized = true;
+            return;
+        }
+        
+        self.current_frame.extend_from_slice(&[0, 0, 0, 1]);
+        self.current_frame.extend_from_slice(nal);
+    }
+
+    /// Complete the frame assembled so far without having seen its marker
+    fn flush_frame(&mut self, timestamp: u32, reason: &str) {
+        if self.current_frame.is_empty() {
+            return;
+        }
+        log::debug!("Flushing frame without marker at {}: {} bytes", reason, self.current_frame.len());
+        let data = std::mem::take(&mut self.current_frame);
+        self.complete(CompletedFrame {
+            timestamp,
+            capture_ms: self.current_capture_ms,
+            data,
+        });
+    }
+
+    /// Queue a finished frame, dropping the oldest if the caller is not keeping up
+    fn complete(&mut self, frame: CompletedFrame) {
+        if self.completed.len() >= MAX_COMPLETED_FRAMES {
+            log::warn!("{} completed frames not taken, dropping the oldest", self.completed.len());
+            self.completed.pop_front();
+        }
+        self.completed.push_back(frame);
     }
+}
+
+/// RTCP BYE for `ssrc`, telling receivers no more packets follow
+pub fn rtcp_bye(ssrc: u32) -> [u8; 8] {
+    let ssrc = ssrc.to_be_bytes();
+    // Version 2, one source; length 1 = two 32-bit words minus one
+    [0x80 | 1, RTCP_BYE, 0, 1, ssrc[0], ssrc[1], ssrc[2], ssrc[3]]
+}
+
+/// Whether `packet` is an RTCP BYE rather than RTP media or parity
+pub fn is_rtcp_bye(packet: &[u8]) -> bool {
+    packet.len() >= 8 && packet[0] >> 6 == 2 && packet[1] == RTCP_BYE
+}
+
+struct CompletedFrame {
+    timestamp: u32,
+    capture_ms: Option<u64>,
+    data: Vec<u8>,
+}
+
+/// Payload byte range of an RTP packet, skipping CSRCs, the header extension and
+/// trailing padding, plus the capture time if the extension carries one
+fn parse_payload_range(rtp_data: &[u8]) -> Option<(Range<usize>, Option<u64>)> {
+    let has_padding = rtp_data[0] & 0x20 != 0;
+    let has_extension = rtp_data[0] & 0x10 != 0;
+    let csrc_count = (rtp_data[0] & 0x0F) as usize;
+    let mut offset = RTP_HEADER_SIZE + csrc_count * 4;
+    let mut capture_ms = None;
     
-    // Last NAL
-    if let Some(s) = start {
-        if s < data.len() {
-            units.push(&data[s..]);
+    if has_extension {
+        let header = rtp_data.get(offset..offset + 4)?;
+        let profile = u16::from_be_bytes([header[0], header[1]]);
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize * 4;
+        let data = rtp_data.get(offset + 4..offset + 4 + length)?;
+        offset += 4 + length;
+        
+        // One-byte header elements: ID (4 bits), length - 1 (4 bits), data
+        if profile == 0xBEDE {
+            let mut i = 0;
+            while i < data.len() {
+                let id = data[i] >> 4;
+                let len = (data[i] & 0x0F) as usize + 1;
+                if id == 0 {
+                    // Padding byte
+                    i += 1;
+                    continue;
+                }
+                if id == 15 {
+                    break;
+                }
+                let Some(value) = data.get(i + 1..i + 1 + len) else { break };
+                if id == CAPTURE_TIME_EXT_ID && len == 8 {
+                    capture_ms = Some(u64::from_be_bytes(value.try_into().ok()?));
+                }
+                i += 1 + len;
+            }
         }
     }
     
-    units
+    // With P set, the last byte counts the padding bytes (itself included)
+    let padding = if has_padding { *rtp_data.last()? as usize } else { 0 };
+    let end = rtp_data.len().checked_sub(padding)?;
+    
+    (offset <= end).then_some((offset..end, capture_ms))
+}
+
+/// RTP payload size that fits in `mtu` after the IP, UDP, RTP and extension headers.
+/// `mtu` is clamped to `MIN_MTU..=MAX_MTU`.
+pub fn max_payload_for_mtu(mtu: usize) -> usize {
+    mtu.clamp(MIN_MTU, MAX_MTU) - IP_UDP_HEADER_SIZE - RTP_HEADER_SIZE - CAPTURE_TIME_EXT_SIZE
+}
+
+/// Milliseconds since the UNIX epoch
+pub fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// H.264 NAL unit type (RFC 6184 types 24..=29 are RTP packetization types)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalType {
+    Slice,
+    PartitionA,
+    PartitionB,
+    PartitionC,
+    Idr,
+    Sei,
+    Sps,
+    Pps,
+    Aud,
+    EndOfSequence,
+    EndOfStream,
+    Filler,
+    StapA,
+    StapB,
+    Mtap16,
+    Mtap24,
+    FuA,
+    FuB,
+    Other(u8),
+}
+
+impl NalType {
+    /// Parse the type from a NAL header byte
+    pub fn from_header(header: u8) -> Self {
+        match header & 0x1F {
+            1 => NalType::Slice,
+            2 => NalType::PartitionA,
+            3 => NalType::PartitionB,
+            4 => NalType::PartitionC,
+            5 => NalType::Idr,
+            6 => NalType::Sei,
+            7 => NalType::Sps,
+            8 => NalType::Pps,
+            9 => NalType::Aud,
+            10 => NalType::EndOfSequence,
+            11 => NalType::EndOfStream,
+            12 => NalType::Filler,
+            24 => NalType::StapA,
+            25 => NalType::StapB,
+            26 => NalType::Mtap16,
+            27 => NalType::Mtap24,
+            28 => NalType::FuA,
+            29 => NalType::FuB,
+            other => NalType::Other(other),
+        }
+    }
+
+    /// IDR slices and SPS both mark a point where decoding can start
+    pub fn is_keyframe(self) -> bool {
+        matches!(self, NalType::Idr | NalType::Sps)
+    }
+}
+
+/// Iterator over the NAL units of an Annex-B bitstream, see [`nal_units`]
+pub struct NalUnits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+/// Iterate over the NAL units of an Annex-B bitstream without copying.
+///
+/// Start codes (3 or 4 bytes) are skipped and trailing zero bytes are trimmed.
+/// Emulation prevention guarantees `00 00 01` never occurs inside a NAL, so the
+/// scan only ever stops on real start codes.
+pub fn nal_units(data: &[u8]) -> NalUnits<'_> {
+    let pos = find_start_code(data, 0).map_or(data.len(), |(_, end)| end);
+    NalUnits { data, pos }
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = (NalType, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.data.len() {
+            let (end, next) = find_start_code(self.data, self.pos)
+                .unwrap_or((self.data.len(), self.data.len()));
+
+            let mut nal = &self.data[self.pos..end];
+            self.pos = next;
+
+            // Drop trailing_zero_8bits before the next start code
+            while let [rest @ .., 0] = nal {
+                nal = rest;
+            }
+
+            if let Some(&header) = nal.first() {
+                return Some((NalType::from_header(header), nal));
+            }
+        }
+        None
+    }
+}
+
+/// Find the next `00 00 01` start code at or after `from`.
+/// Returns (start of the code including a leading zero byte, first byte after the code).
+pub(crate) fn find_start_code(data: &[u8], from: usize) -> Option<(usize, usize)> {
+    let mut i = from;
+    while i + 2 < data.len() {
+        if data[i + 2] > 1 {
+            // No start code can begin at i, i+1 or i+2
+            i += 3;
+            continue;
+        }
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            let start = if i > from && data[i - 1] == 0 { i - 1 } else { i };
+            return Some((start, i + 3));
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Check whether an Annex-B access unit contains a keyframe (IDR or SPS)
+pub fn contains_keyframe(data: &[u8]) -> bool {
+    nal_units(data).any(|(nal_type, _)| nal_type.is_keyframe())
 }
 
 impl Default for RtpPacketizer {
@@ -325,3 +827,452 @@ impl Default for RtpDepacketizer {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Annex-B stream of `nals`, each behind a 4-byte start code
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter().flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat()).collect()
+    }
+
+    fn types(data: &[u8]) -> Vec<NalType> {
+        nal_units(data).map(|(nal_type, _)| nal_type).collect()
+    }
+
+    fn timestamp(packet: &[u8]) -> u32 {
+        u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]])
+    }
+
+    #[test]
+    fn emulation_prevention_bytes_stay_inside_the_nal() {
+        // 00 00 03 01 is an escaped 00 00 01, 00 00 03 00 an escaped 00 00 00
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x00, 0x03, 0x01, 0x1E, 0x00, 0x00, 0x03, 0x00, 0x80];
+        let slice: &[u8] = &[0x41, 0x9A, 0x00, 0x00, 0x03, 0x01, 0x65, 0x88];
+        let data = annexb(&[sps, slice]);
+
+        let nals: Vec<_> = nal_units(&data).collect();
+        assert_eq!(nals, [(NalType::Sps, sps), (NalType::Slice, slice)]);
+    }
+
+    #[test]
+    fn escaped_idr_header_is_not_a_keyframe() {
+        // The 65 after 00 00 03 01 is slice data, not the header of an IDR
+        let data = annexb(&[&[0x41, 0x9A, 0x00, 0x00, 0x03, 0x01, 0x65, 0x88, 0x84]]);
+        assert_eq!(types(&data), [NalType::Slice]);
+        assert!(!contains_keyframe(&data));
+
+        let data = annexb(&[&[0x41, 0x9A], &[0x65, 0x88, 0x00, 0x00, 0x03, 0x02]]);
+        assert!(contains_keyframe(&data));
+    }
+
+    #[test]
+    fn find_start_code_reports_three_and_four_byte_codes() {
+        let data = [0x00, 0x00, 0x01, 0x67, 0x00, 0x00, 0x00, 0x01, 0x68];
+        assert_eq!(find_start_code(&data, 0), Some((0, 3)));
+        assert_eq!(find_start_code(&data, 3), Some((4, 8)));
+        assert_eq!(find_start_code(&data, 8), None);
+        assert_eq!(find_start_code(&[0x00, 0x00, 0x03, 0x01], 0), None);
+    }
+
+    #[test]
+    fn timestamps_keep_increasing_across_wraparound() {
+        let mut packetizer = RtpPacketizer::new();
+        packetizer.set_frame_rate(30);
+        packetizer.timestamp = u32::MAX - 5_000;
+        let frame = annexb(&[&[0x41, 0x9A, 0x01]]);
+
+        let stamps: Vec<u32> = (0..4).map(|_| timestamp(&packetizer.packetize(&frame)[0])).collect();
+        assert_eq!(stamps, [u32::MAX - 5_000, u32::MAX - 2_000, 999, 3_999]);
+        for pair in stamps.windows(2) {
+            assert!((pair[1].wrapping_sub(pair[0]) as i32) > 0);
+        }
+    }
+
+    #[test]
+    fn equal_capture_times_get_distinct_timestamps() {
+        let mut packetizer = RtpPacketizer::new();
+        packetizer.timestamp = u32::MAX;
+        let frame = annexb(&[&[0x41, 0x9A, 0x01]]);
+        let capture_time = Duration::from_millis(40);
+
+        let first = packetizer.packetize_at(&frame, capture_time);
+        let second = packetizer.packetize_at(&frame, capture_time);
+        assert_eq!(timestamp(&first[0]), u32::MAX);
+        assert_eq!(timestamp(&second[0]), 0);
+
+        let mut depacketizer = RtpDepacketizer::new();
+        assert_eq!(depacketizer.depacketize(&first[0]), Some(frame.clone()));
+        assert_eq!(depacketizer.depacketize(&second[0]), Some(frame));
+    }
+
+    #[test]
+    fn lost_marker_is_flushed_by_the_next_timestamp() {
+        let mut packetizer = RtpPacketizer::new();
+        let first_slice: &[u8] = &[0x41, 0x9A, 0x01];
+        let second_frame = annexb(&[&[0x41, 0x9A, 0x02]]);
+        let mut first = packetizer.packetize(&annexb(&[first_slice, &[0x41, 0x9A, 0x03]]));
+        let second = packetizer.packetize(&second_frame);
+        assert_eq!(first.len(), 2);
+
+        // The marked last packet of the first frame is lost
+        first.pop();
+        let mut depacketizer = RtpDepacketizer::new();
+        assert_eq!(depacketizer.depacketize(&first[0]), None);
+        assert_eq!(depacketizer.depacketize(&second[0]), Some(annexb(&[first_slice])));
+        assert_eq!(depacketizer.last_frame_timestamp(), Some(timestamp(&first[0])));
+        assert_eq!(depacketizer.take_pending(), Some(second_frame));
+    }
+
+    #[test]
+    fn zero_frame_rate_advances_one_second_per_frame() {
+        let mut packetizer = RtpPacketizer::new();
+        packetizer.set_frame_rate(0);
+        let frame = annexb(&[&[0x41, 0x9A, 0x01]]);
+
+        let first = timestamp(&packetizer.packetize(&frame)[0]);
+        let second = timestamp(&packetizer.packetize(&frame)[0]);
+        assert_eq!(second.wrapping_sub(first), RTP_CLOCK_RATE);
+    }
+
+    /// Packets of `frame` from a packetizer for `mtu`, checked to fit it, and the frame
+    /// they reassemble to
+    fn round_trip_at_mtu(mtu: usize, frame: &[u8]) -> (usize, Vec<u8>) {
+        let mut packetizer = RtpPacketizer::with_mtu(mtu);
+        let packets = packetizer.packetize(frame);
+        for packet in &packets {
+            assert!(packet.len() + IP_UDP_HEADER_SIZE <= mtu, "{} byte packet at MTU {}", packet.len(), mtu);
+        }
+
+        let mut depacketizer = RtpDepacketizer::new();
+        let frames: Vec<_> = packets.iter().filter_map(|packet| depacketizer.depacketize(packet)).collect();
+        assert_eq!(frames.len(), 1);
+        (packets.len(), frames.into_iter().next().unwrap())
+    }
+
+    #[test]
+    fn small_and_jumbo_mtus_fit_and_round_trip() {
+        let slice: Vec<u8> = [0x65].into_iter().chain((0..50_000).map(|i| (i % 251) as u8 + 1)).collect();
+        let frame = annexb(&[&[0x67, 0x42, 0x00, 0x1E], &[0x68, 0xCE, 0x38, 0x80], &slice]);
+
+        let (small_packets, small) = round_trip_at_mtu(MIN_MTU, &frame);
+        let (jumbo_packets, jumbo) = round_trip_at_mtu(MAX_MTU, &frame);
+        assert_eq!(small, frame);
+        assert_eq!(jumbo, frame);
+        // Full FU-A fragments: a jumbo MTU needs a fraction of the packets
+        assert_eq!(small_packets, 2 + slice.len().div_ceil(max_payload_for_mtu(MIN_MTU) - 2));
+        assert_eq!(jumbo_packets, 2 + slice.len().div_ceil(max_payload_for_mtu(MAX_MTU) - 2));
+    }
+
+    /// RTP header of a marked H.264 packet with the given first byte (V, P, X, CC)
+    fn rtp_header(first_byte: u8, sequence: u16, timestamp: u32) -> Vec<u8> {
+        let mut header = vec![first_byte, 0x80 | RTP_PAYLOAD_TYPE_H264];
+        header.extend_from_slice(&sequence.to_be_bytes());
+        header.extend_from_slice(&timestamp.to_be_bytes());
+        header.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn csrc_extension_and_padding_are_skipped() {
+        let nal: &[u8] = &[0x65, 0x88, 0x84, 0x21];
+
+        // X=1, CC=1: one CSRC, then a two word extension from another profile
+        let mut packet = rtp_header(0x91, 1, 3000);
+        packet.extend_from_slice(&0xCAFE_F00Du32.to_be_bytes());
+        packet.extend_from_slice(&[0x10, 0x00, 0x00, 0x02, 1, 2, 3, 4, 5, 6, 7, 8]);
+        packet.extend_from_slice(nal);
+        let mut depacketizer = RtpDepacketizer::new();
+        assert_eq!(depacketizer.depacketize(&packet), Some(annexb(&[nal])));
+        assert_eq!(depacketizer.last_frame_capture_ms(), None);
+
+        // P=1, CC=2: the last byte counts the padding to drop
+        let mut packet = rtp_header(0xA2, 2, 6000);
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(nal);
+        packet.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(depacketizer.depacketize(&packet), Some(annexb(&[nal])));
+
+        // An extension running past the end of the packet is dropped, not misread
+        let mut packet = rtp_header(0x90, 3, 9000);
+        packet.extend_from_slice(&[0xBE, 0xDE, 0x00, 0x40]);
+        packet.extend_from_slice(nal);
+        assert_eq!(depacketizer.depacketize(&packet), None);
+    }
+
+    /// Marked (`last`) or unmarked RTP packet around `payload`
+    fn rtp_packet(sequence: u16, timestamp: u32, last: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = rtp_header(0x80, sequence, timestamp);
+        if !last {
+            packet[1] &= 0x7F;
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn fu_b_and_mtap_packets_are_reassembled() {
+        // IDR in three FU-B fragments (NRI 3), each FU header followed by a DON
+        let idr: Vec<u8> = [0x65].into_iter().chain(1..=30).collect();
+        let fragments = [
+            [&[0x7D, 0x85, 0x00, 0x07][..], &idr[1..11]].concat(),
+            [&[0x7D, 0x05, 0x00, 0x08][..], &idr[11..21]].concat(),
+            [&[0x7D, 0x45, 0x00, 0x09][..], &idr[21..]].concat(),
+        ];
+        let mut depacketizer = RtpDepacketizer::new();
+        assert_eq!(depacketizer.depacketize(&rtp_packet(1, 3000, false, &fragments[0])), None);
+        assert_eq!(depacketizer.depacketize(&rtp_packet(2, 3000, false, &fragments[1])), None);
+        assert_eq!(depacketizer.depacketize(&rtp_packet(3, 3000, true, &fragments[2])), Some(annexb(&[&idr])));
+
+        // MTAP16: DONB, then per unit its size, DOND and a 2 byte timestamp offset
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E];
+        let pps: &[u8] = &[0x68, 0xCE, 0x38, 0x80];
+        let mut mtap = vec![0x7A, 0x00, 0x01];
+        for unit in [sps, pps] {
+            mtap.extend_from_slice(&(unit.len() as u16).to_be_bytes());
+            mtap.extend_from_slice(&[0x00, 0x00, 0x00]);
+            mtap.extend_from_slice(unit);
+        }
+        assert_eq!(depacketizer.depacketize(&rtp_packet(4, 6000, true, &mtap)), Some(annexb(&[sps, pps])));
+
+        // A truncated aggregation packet and reserved types add nothing to the frame
+        let slice: &[u8] = &[0x41, 0x9A, 0x01];
+        assert_eq!(depacketizer.depacketize(&rtp_packet(5, 9000, false, &mtap[..mtap.len() - 2])), None);
+        assert_eq!(depacketizer.depacketize(&rtp_packet(6, 9000, false, &[0x1E, 1, 2, 3])), None);
+        assert_eq!(depacketizer.depacketize(&rtp_packet(7, 9000, true, slice)), Some(annexb(&[slice])));
+    }
+
+    /// Frames the packets of `frames` reassemble to
+    fn depacketize_all(packetizer: &mut RtpPacketizer, frames: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut depacketizer = RtpDepacketizer::new();
+        frames.iter()
+            .flat_map(|frame| packetizer.packetize(frame))
+            .filter_map(|packet| depacketizer.depacketize(&packet))
+            .collect()
+    }
+
+    #[test]
+    fn parameter_sets_are_repeated_before_bare_idrs() {
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E];
+        let pps: &[u8] = &[0x68, 0xCE, 0x38, 0x80];
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        let slice: &[u8] = &[0x41, 0x9A, 0x01];
+        let mut packetizer = RtpPacketizer::new();
+
+        // Nothing to inject before the first SPS/PPS
+        let frames = depacketize_all(&mut packetizer, &[annexb(&[idr])]);
+        assert_eq!(frames, [annexb(&[idr])]);
+
+        // A student joining at the second keyframe still gets what it needs to decode
+        let frames = depacketize_all(&mut packetizer, &[annexb(&[sps, pps, idr]), annexb(&[slice]), annexb(&[idr])]);
+        assert_eq!(frames[1], annexb(&[slice]));
+        assert_eq!(frames[2], annexb(&[sps, pps, idr]));
+        assert_eq!(types(&frames[2]), [NalType::Sps, NalType::Pps, NalType::Idr]);
+
+        // Only the missing one is added, the latest one, right before the IDR
+        let new_pps: &[u8] = &[0x68, 0xEE, 0x3C, 0x80];
+        let aud: &[u8] = &[0x09, 0xF0];
+        let frames = depacketize_all(&mut packetizer, &[annexb(&[new_pps, slice]), annexb(&[aud, sps, idr])]);
+        assert_eq!(frames[1], annexb(&[aud, sps, new_pps, idr]));
+    }
+
+    #[test]
+    fn base_layer_only_keeps_every_other_frame() {
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E];
+        let pps: &[u8] = &[0x68, 0xCE, 0x38, 0x80];
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        // Reference slice (NRI 2) in the base layer, non-reference (NRI 0) on top
+        let base: &[u8] = &[0x41, 0x9A, 0x01];
+        let top: &[u8] = &[0x01, 0x9E, 0x02];
+        let mut frames = vec![annexb(&[sps, pps, idr])];
+        for _ in 0..4 {
+            frames.push(annexb(&[top]));
+            frames.push(annexb(&[base]));
+        }
+        
+        let mut packetizer = RtpPacketizer::new();
+        let mut depacketizer = RtpDepacketizer::with_max_temporal_layer(Some(0));
+        let kept: Vec<_> = frames.iter()
+            .flat_map(|frame| packetizer.packetize(frame))
+            .filter_map(|packet| depacketizer.depacketize(&packet))
+            .filter(|frame| !frame.is_empty())
+            .collect();
+        
+        // Half the frame rate, every frame a reference the next one can decode against
+        let mut expected = vec![annexb(&[sps, pps, idr])];
+        expected.extend((0..4).map(|_| annexb(&[base])));
+        assert_eq!(kept, expected);
+        
+        // An SVC prefix NAL's temporal_id wins over the NRI
+        let prefix: &[u8] = &[0x6E, 0x00, 0x00, 1 << 5];
+        let mut depacketizer = RtpDepacketizer::with_max_temporal_layer(Some(0));
+        let kept: Vec<_> = packetizer.packetize(&annexb(&[prefix, base]))
+            .iter()
+            .filter_map(|packet| depacketizer.depacketize(packet))
+            .collect();
+        assert!(kept.iter().all(|frame| types(frame).is_empty()), "{:?}", kept);
+    }
+
+    #[test]
+    fn packetize_into_reuses_packet_buffers() {
+        let mut packetizer = RtpPacketizer::new();
+        let frame = annexb(&[&[0x41; 3000]]);
+        let mut out = Vec::new();
+        packetizer.packetize_into(&frame, &mut out);
+        let buffers: Vec<_> = out.iter().map(|packet| packet.as_ptr()).collect();
+        assert!(buffers.len() > 1);
+        
+        // A same-sized frame goes out in the very same buffers, and still round trips
+        packetizer.packetize_into(&frame, &mut out);
+        assert_eq!(out.len(), buffers.len());
+        assert!(out.iter().all(|packet| buffers.contains(&packet.as_ptr())));
+        let mut depacketizer = RtpDepacketizer::new();
+        let frames: Vec<_> = out.iter().filter_map(|packet| depacketizer.depacketize(packet)).collect();
+        assert_eq!(frames, [frame]);
+    }
+
+    #[test]
+    fn splitter_handles_mixed_start_codes_and_a_trailing_nal() {
+        // 3-byte and 4-byte start codes, trailing_zero_8bits, and a last NAL that runs to
+        // the end of the buffer with no start code after it
+        let data = [
+            0x00, 0x00, 0x01, 0x67, 0x42,
+            0x00, 0x00, 0x00, 0x01, 0x68, 0xCE,
+            0x00, 0x00, 0x01, 0x65, 0x88, 0x00, 0x00,
+            0x00, 0x00, 0x01, 0x41,
+        ];
+        let nals: Vec<_> = nal_units(&data).collect();
+        assert_eq!(nals, [
+            (NalType::Sps, &[0x67, 0x42][..]),
+            (NalType::Pps, &[0x68, 0xCE][..]),
+            (NalType::Idr, &[0x65, 0x88][..]),
+            (NalType::Slice, &[0x41][..]),
+        ]);
+        
+        // Nothing but start codes and zeros holds no NAL
+        assert_eq!(nal_units(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01]).count(), 0);
+        assert_eq!(nal_units(&[]).count(), 0);
+    }
+
+    #[test]
+    fn short_nals_between_start_codes_are_all_kept() {
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E];
+        let pps: &[u8] = &[0x68, 0xCE, 0x38, 0x80];
+        let aud: &[u8] = &[0x09];
+        let idr: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x03];
+        let tiny_slice: &[u8] = &[0x41];
+        let data = annexb(&[sps, pps, aud, idr, tiny_slice]);
+        
+        let nals: Vec<_> = nal_units(&data).collect();
+        assert_eq!(nals, [
+            (NalType::Sps, sps),
+            (NalType::Pps, pps),
+            (NalType::Aud, aud),
+            (NalType::Idr, idr),
+            (NalType::Slice, tiny_slice),
+        ]);
+        
+        // And each goes out in a packet of its own (an AUD this late would start a new
+        // access unit at the student, so only the packets are checked here)
+        let packets = RtpPacketizer::new().packetize(&data);
+        let payload_headers: Vec<_> = packets.iter()
+            .map(|packet| packet[parse_payload_range(packet).expect("payload").0.start])
+            .collect();
+        assert_eq!(payload_headers, [0x67, 0x68, 0x09, 0x65, 0x41]);
+    }
+
+    #[test]
+    fn only_the_last_packet_of_a_frame_is_marked() {
+        let marked = |packets: &[Vec<u8>]| packets.iter().map(|packet| packet[1] & 0x80 != 0).collect::<Vec<_>>();
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E];
+        let pps: &[u8] = &[0x68, 0xCE, 0x38, 0x80];
+        let mut packetizer = RtpPacketizer::with_mtu(500);
+        
+        // Single NAL packets: SPS and PPS unmarked, the IDR marked
+        let packets = packetizer.packetize(&annexb(&[sps, pps, &[0x65, 0x88, 0x84]]));
+        assert_eq!(marked(&packets), [false, false, true]);
+        
+        // A fragmented IDR followed by a slice: no fragment marked, only the slice
+        let packets = packetizer.packetize(&annexb(&[sps, pps, &[0x65; 1200], &[0x41, 0x9A]]));
+        let flags = marked(&packets);
+        assert!(flags.len() > 4);
+        assert_eq!(flags.iter().filter(|&&m| m).count(), 1);
+        assert_eq!(flags.last(), Some(&true));
+        
+        // A frame ending on a fragmented slice marks its last fragment only
+        let packets = packetizer.packetize(&annexb(&[&[0x41; 1200]]));
+        let flags = marked(&packets);
+        assert_eq!(flags.iter().filter(|&&m| m).count(), 1);
+        assert_eq!(flags.last(), Some(&true));
+        
+        // With an AUD inserted, the AUD is not marked either
+        packetizer.set_insert_aud(true);
+        let packets = packetizer.packetize(&annexb(&[&[0x41, 0x9A]]));
+        assert_eq!(marked(&packets), [false, true]);
+    }
+
+    #[test]
+    fn aud_recovers_frame_boundaries_without_markers() {
+        let mut packetizer = RtpPacketizer::new();
+        packetizer.set_insert_aud(true);
+        let aud: &[u8] = &AUD_NAL;
+        let slices: [&[u8]; 3] = [&[0x41, 0x9A, 0x01], &[0x41, 0x9A, 0x02], &[0x41, 0x9A, 0x03]];
+        
+        // Strip every marker and give all frames one timestamp: only the AUDs are left
+        // to tell the frames apart
+        let packets: Vec<Vec<u8>> = slices.iter()
+            .flat_map(|slice| packetizer.packetize(&annexb(&[slice])))
+            .map(|mut packet| {
+                packet[1] &= 0x7F;
+                packet[4..8].fill(0);
+                packet
+            })
+            .collect();
+        assert_eq!(packets.len(), 6);
+        
+        let mut depacketizer = RtpDepacketizer::new();
+        let frames: Vec<_> = packets.iter().filter_map(|packet| depacketizer.depacketize(packet)).collect();
+        assert_eq!(frames, [annexb(&[aud, slices[0]]), annexb(&[aud, slices[1]])]);
+        // The last one is held until the next AUD shows where it ends
+        assert_eq!(depacketizer.take_pending(), None);
+    }
+
+    #[test]
+    fn oversized_frames_and_unread_frames_stay_bounded() {
+        let mut depacketizer = RtpDepacketizer::new();
+        let slice: Vec<u8> = [0x41].into_iter().chain((0..u16::MAX).map(|i| (i % 251) as u8 + 1)).collect();
+        let small: &[u8] = &[0x41, 0x9A, 0x01];
+        let mut sequence = 0u16;
+        let mut send = |depacketizer: &mut RtpDepacketizer, timestamp, last, payload: &[u8]| {
+            sequence = sequence.wrapping_add(1);
+            depacketizer.depacketize(&rtp_packet(sequence, timestamp, last, payload))
+        };
+        
+        // Single NAL packets adding up past MAX_FRAME_BYTES: the frame is dropped
+        let packets = MAX_FRAME_BYTES / slice.len() + 1;
+        for i in 0..packets {
+            assert_eq!(send(&mut depacketizer, 3000, i == packets - 1, &slice), None);
+        }
+        assert!(depacketizer.current_frame.capacity() < MAX_FRAME_BYTES);
+        assert_eq!(send(&mut depacketizer, 6000, true, small), Some(annexb(&[small])));
+        
+        // One FU-A NAL claiming more than MAX_FRAME_BYTES is dropped the same way
+        let fragment = |fu_header: u8| [&[0x7C, fu_header][..], &slice[1..]].concat();
+        assert_eq!(send(&mut depacketizer, 9000, false, &fragment(0x81)), None);
+        for _ in 0..packets {
+            assert_eq!(send(&mut depacketizer, 9000, false, &fragment(0x01)), None);
+        }
+        assert_eq!(send(&mut depacketizer, 9000, true, &fragment(0x41)), None);
+        assert!(depacketizer.fu_buffer.capacity() < MAX_FRAME_BYTES);
+        assert_eq!(send(&mut depacketizer, 12000, true, small), Some(annexb(&[small])));
+        
+        // Frames nobody takes: only the newest MAX_COMPLETED_FRAMES are kept
+        for i in 0..MAX_COMPLETED_FRAMES as u32 + 8 {
+            depacketizer.push_packet(&rtp_packet(100 + i as u16, 15000 + i * 3000, true, small));
+        }
+        assert_eq!(depacketizer.completed.len(), MAX_COMPLETED_FRAMES);
+        depacketizer.take_pending();
+        assert_eq!(depacketizer.last_frame_timestamp(), Some(15000 + 8 * 3000));
+    }
+}
@@ -0,0 +1,164 @@
+//! Loopback self-test of the whole pipeline in one process
+//! capture -> encode -> packetize -> send/receive on localhost -> depacketize -> decode
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::capture::ScreenCapture;
+use super::decoder::H264Decoder;
+use super::encoder::H264Encoder;
+use super::network::{RtpReceiver, RtpSender};
+use super::rtp::{contains_keyframe, RtpDepacketizer, RtpPacketizer};
+use super::types::{BroadcastError, RateControl, StreamConfig};
+
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(2);
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Outcome of one pipeline stage
+#[derive(Debug, Clone, Serialize)]
+pub struct StageResult {
+    pub name: &'static str,
+    pub ok: bool,
+    pub duration_ms: f32,
+    pub detail: String,
+}
+
+/// Self-test report; stages after the first failure are not run
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub failed_stage: Option<&'static str>,
+    pub stages: Vec<StageResult>,
+}
+
+impl SelfTestReport {
+    /// Plain text version for bug reports
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Self-test {}\n", if self.passed { "PASSED" } else { "FAILED" });
+        for stage in &self.stages {
+            text.push_str(&format!("[{}] {:<12} {:>8.1} ms  {}\n",
+                if stage.ok { "ok" } else { "FAIL" }, stage.name, stage.duration_ms, stage.detail));
+        }
+        text
+    }
+}
+
+struct Stages {
+    results: Vec<StageResult>,
+}
+
+impl Stages {
+    /// Run a stage and record it; `None` means it failed and the test stops
+    fn run<T>(&mut self, name: &'static str, f: impl FnOnce() -> Result<(T, String), BroadcastError>) -> Option<T> {
+        let start = Instant::now();
+        let result = f();
+        let duration_ms = start.elapsed().as_secs_f32() * 1000.0;
+
+        match result {
+            Ok((value, detail)) => {
+                self.results.push(StageResult { name, ok: true, duration_ms, detail });
+                Some(value)
+            }
+            Err(e) => {
+                self.results.push(StageResult { name, ok: false, duration_ms, detail: e.to_string() });
+                None
+            }
+        }
+    }
+
+    fn finish(self) -> SelfTestReport {
+        let failed_stage = self.results.iter().find(|s| !s.ok).map(|s| s.name);
+        SelfTestReport {
+            passed: failed_stage.is_none(),
+            failed_stage,
+            stages: self.results,
+        }
+    }
+}
+
+/// Run the pipeline once on loopback; needs no other machine
+pub fn run_self_test() -> SelfTestReport {
+    let mut stages = Stages { results: Vec::new() };
+    run_stages(&mut stages);
+    stages.finish()
+}
+
+fn run_stages(stages: &mut Stages) -> Option<()> {
+    let config = StreamConfig::default();
+
+    let (rgb, (width, height)) = stages.run("capture", || {
+        let mut capture = ScreenCapture::new(config.fps)?;
+        let (width, height) = capture.dimensions();
+        let start = Instant::now();
+        loop {
+            if let Some(rgb) = capture.capture_frame()? {
+                let detail = format!("{}x{}, {} bytes RGB", width, height, rgb.len());
+                return Ok(((rgb, (width, height)), detail));
+            }
+            if start.elapsed() >= CAPTURE_TIMEOUT {
+                return Err(BroadcastError::CaptureError(format!("no frame within {:?}", CAPTURE_TIMEOUT)));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    })?;
+
+    let h264 = stages.run("encode", || {
+        let mut encoder = H264Encoder::new(width, height, config.fps, RateControl::Bitrate(2000), &config)?;
+        let (h264, is_keyframe) = encoder.encode(&rgb)?;
+        if h264.is_empty() {
+            return Err(BroadcastError::EncoderError("encoder produced no data".into()));
+        }
+        let detail = format!("{} bytes, keyframe={}, {}", h264.len(), is_keyframe, encoder.name());
+        Ok((h264, detail))
+    })?;
+
+    stages.run("packetize", || {
+        let packets = RtpPacketizer::new().packetize(&h264);
+        let mut depacketizer = RtpDepacketizer::new();
+        let mut frame = None;
+        for packet in &packets {
+            frame = frame.or(depacketizer.depacketize(packet));
+        }
+        match frame {
+            Some(frame) if contains_keyframe(&frame) => {
+                Ok(((), format!("{} packets, reassembled {} bytes", packets.len(), frame.len())))
+            }
+            Some(_) => Err(BroadcastError::NetworkError("reassembled frame lost its keyframe".into())),
+            None => Err(BroadcastError::NetworkError(format!("{} packets did not reassemble", packets.len()))),
+        }
+    })?;
+
+    let received = stages.run("loopback", || {
+        let receive_config = StreamConfig { port: 0, ..config.clone() };
+        let mut receiver = RtpReceiver::new(&receive_config)?;
+        let port = receiver.local_addr()?.port();
+
+        let mut sender = RtpSender::new(&config)?;
+        sender.set_target(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+        let sent = sender.send_frame(&h264)?;
+
+        let start = Instant::now();
+        while start.elapsed() < RECEIVE_TIMEOUT {
+            if let Some(frame) = receiver.receive_frame()? {
+                let detail = format!("{} bytes sent to 127.0.0.1:{}, {} bytes received", sent, port, frame.len());
+                return Ok((frame, detail));
+            }
+        }
+        Err(BroadcastError::NetworkError(format!(
+            "nothing received on 127.0.0.1:{} within {:?} ({} packets, {} lost)",
+            port, RECEIVE_TIMEOUT, receiver.packets_received(), receiver.packets_lost())))
+    })?;
+
+    stages.run("decode", || {
+        let mut decoder = H264Decoder::new(config.decoder_backend, config.color_space, config.full_range)?;
+        match decoder.decode(&received)? {
+            Some(frame) => Ok(((), format!("{}x{}, {}", frame.width, frame.height, decoder.name()))),
+            None => Err(BroadcastError::DecoderError("decoder returned no frame".into())),
+        }
+    })?;
+
+    Some(())
+}
@@ -0,0 +1,1918 @@
+//! Streaming sessions usable without Tauri: the teacher's capture -> encode -> send loop
+//! and the student's receive -> decode loop, each on its own thread.
+//! Progress is reported through an event callback; returning `ControlFlow::Break` stops the session.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+
+use super::adaptive::{AdaptiveController, AdaptiveStep};
+use super::capture::{self, frame_hash, scale_rgb, ScreenCapture};
+use super::source::FrameSource;
+use super::decoder::H264Decoder;
+use super::encoder::{H264Encoder, VideoEncoder};
+use super::network::{RtpReceiver, RtpSender};
+use super::playback;
+use super::rtp::{contains_keyframe, nal_units, unix_ms, NalType};
+use super::types::{BroadcastError, CaptureRect, DecodedFrame, IntervalStats, LogLevel, NetworkMode, RateControl, StreamConfig, StreamStats, StudentStats};
+
+/// Poll interval while waiting for a screen update in variable frame rate mode
+const VFR_POLL_INTERVAL: Duration = Duration::from_millis(2);
+/// Re-send an unchanged screen at least this often in variable frame rate mode
+const VFR_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long `stop` waits for a session thread before leaving it to finish on its own
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often the teacher checks whether the captured display changed size
+const DISPLAY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Capture errors in a row, without a frame in between, before the teacher stops
+const MAX_CAPTURE_ERRORS: u32 = 30;
+
+/// Debug: log content hashes of roughly one in N frames (0 = disabled)
+static FRAME_HASH_EVERY: AtomicU32 = AtomicU32::new(0);
+
+/// Log content hashes of roughly one in `every_n` frames in all sessions (0 disables).
+/// Frames are sampled by their H.264 hash, so teacher and students log the same frames.
+pub fn set_frame_hash_sampling(every_n: u32) {
+    FRAME_HASH_EVERY.store(every_n, Ordering::Relaxed);
+}
+
+/// Hash of an encoded frame if it is selected for hash logging
+fn sampled_frame_hash(h264_data: &[u8]) -> Option<u64> {
+    let every = FRAME_HASH_EVERY.load(Ordering::Relaxed);
+    if every == 0 {
+        return None;
+    }
+    let hash = frame_hash(h264_data);
+    hash.is_multiple_of(every as u64).then_some(hash)
+}
+
+/// Identifies one of several concurrent sessions of the same kind
+pub type SessionId = u32;
+
+/// Session id used when none is given, so single-session callers need not track ids
+pub const DEFAULT_SESSION: SessionId = 0;
+
+/// A session a `SessionManager` can hold
+pub trait Session: Default {
+    fn is_running(&self) -> bool;
+    fn stop(&mut self);
+}
+
+/// Concurrent sessions of one kind (e.g. students watching two teachers), keyed by id
+pub struct SessionManager<S> {
+    sessions: Mutex<HashMap<SessionId, S>>,
+}
+
+impl<S: Session> SessionManager<S> {
+    pub fn new() -> Self {
+        Self { sessions: Mutex::new(HashMap::new()) }
+    }
+
+    /// Run `f` on the session under `id`, creating an idle one if there is none.
+    /// The manager stays locked while `f` runs, so check-then-start is atomic.
+    pub fn with<R>(&self, id: SessionId, f: impl FnOnce(&mut S) -> R) -> R {
+        f(self.sessions.lock().entry(id).or_default())
+    }
+
+    /// Run `f` on the session under `id` if there is one
+    pub fn get<R>(&self, id: SessionId, f: impl FnOnce(&S) -> R) -> Option<R> {
+        self.sessions.lock().get(&id).map(f)
+    }
+
+    /// Stop and remove the session under `id`; returns whether it was running
+    pub fn stop(&self, id: SessionId) -> bool {
+        // Join outside the lock so other sessions stay usable meanwhile
+        let session = self.sessions.lock().remove(&id);
+        session.is_some_and(|mut session| {
+            let was_running = session.is_running();
+            session.stop();
+            was_running
+        })
+    }
+
+    /// Stop and remove all sessions
+    pub fn stop_all(&self) {
+        let sessions: Vec<S> = self.sessions.lock().drain().map(|(_, s)| s).collect();
+        for mut session in sessions {
+            session.stop();
+        }
+    }
+
+    /// Ids of the sessions currently running
+    pub fn running(&self) -> Vec<SessionId> {
+        let mut ids: Vec<_> = self.sessions.lock()
+            .iter()
+            .filter(|(_, s)| s.is_running())
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+}
+
+impl<S: Session> Default for SessionManager<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Events of a `BroadcastSession`
+pub enum BroadcastEvent {
+    Log(LogLevel, String),
+    /// Sent once per second
+    Stats(StreamStats),
+    Paused(bool),
+    Reconnecting(bool),
+    /// An encoded frame went out, `bytes` on the wire
+    FrameSent { bytes: usize, keyframe: bool },
+    /// File playback reached the end of the file without `playback_loop`
+    PlaybackEnded,
+}
+
+/// Events of a `ReceiveSession`
+pub enum ReceiveEvent {
+    Log(LogLevel, String),
+    /// Sent once per second
+    Stats(StudentStats),
+    Frame(DecodedFrame),
+    /// Nothing arrived in the configured mode, now listening in this one
+    FellBack(NetworkMode),
+    Reconnecting(bool),
+    /// No frame decoded for `stall_timeout_ms` after the stream had started (true),
+    /// or frames are flowing again (false)
+    Stalled(bool),
+    /// The teacher announced it stopped (RTCP BYE)
+    Ended,
+}
+
+/// Events that can carry a log message
+trait LogEvent {
+    fn log(level: LogLevel, message: String) -> Self;
+}
+
+impl LogEvent for BroadcastEvent {
+    fn log(level: LogLevel, message: String) -> Self {
+        BroadcastEvent::Log(level, message)
+    }
+}
+
+impl LogEvent for ReceiveEvent {
+    fn log(level: LogLevel, message: String) -> Self {
+        ReceiveEvent::Log(level, message)
+    }
+}
+
+/// Event callback of a running session; a `Break` from it stops the session
+struct Events<E, F> {
+    on_event: F,
+    running: Arc<AtomicBool>,
+    _event: PhantomData<fn(E)>,
+}
+
+impl<E: LogEvent, F: FnMut(E) -> ControlFlow<()>> Events<E, F> {
+    fn new(on_event: F, running: Arc<AtomicBool>) -> Self {
+        Self { on_event, running, _event: PhantomData }
+    }
+
+    fn emit(&mut self, event: E) {
+        if (self.on_event)(event).is_break() {
+            self.running.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn info(&mut self, message: impl Into<String>) {
+        self.emit(E::log(LogLevel::Info, message.into()));
+    }
+
+    fn warn(&mut self, message: impl Into<String>) {
+        self.emit(E::log(LogLevel::Warn, message.into()));
+    }
+
+    fn error(&mut self, message: impl Into<String>) {
+        self.emit(E::log(LogLevel::Error, message.into()));
+    }
+}
+
+/// State shared between a `BroadcastSession` and its thread
+struct BroadcastShared {
+    running: Arc<AtomicBool>,
+    paused: AtomicBool,
+    /// Config change picked up by the loop on its next iteration
+    config_update: Mutex<Option<StreamConfig>>,
+    /// New capture region picked up by the loop, the inner `None` meaning the whole screen
+    region_update: Mutex<Option<Option<CaptureRect>>>,
+    stats: Mutex<StreamStats>,
+    /// Worst packet loss reported by students since the loop last looked
+    loss_report: Mutex<Option<f32>>,
+}
+
+/// Teacher side: captures the screen, encodes it and sends it as RTP
+pub struct BroadcastSession {
+    shared: Arc<BroadcastShared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl BroadcastSession {
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(BroadcastShared {
+                running: Arc::new(AtomicBool::new(false)),
+                paused: AtomicBool::new(false),
+                config_update: Mutex::new(None),
+                region_update: Mutex::new(None),
+                stats: Mutex::new(StreamStats::default()),
+                loss_report: Mutex::new(None),
+            }),
+            thread: None,
+        }
+    }
+
+    /// Validate `config` and start broadcasting the screen on a new thread.
+    /// Setup errors after this returns are reported as an error `Log` event.
+    pub fn start<F>(&mut self, config: StreamConfig, on_event: F) -> Result<(), BroadcastError>
+    where
+        F: FnMut(BroadcastEvent) -> ControlFlow<()> + Send + 'static,
+    {
+        self.start_with_source(config, |config| {
+            let mut capture = ScreenCapture::new(config.fps)?;
+            capture.set_drain_stale(config.drain_stale);
+            capture.set_region(config.capture_region);
+            // The loop starts at the region's size; nothing to report as a change
+            capture.check_display_change()?;
+            Ok(Box::new(capture))
+        }, on_event)
+    }
+
+    /// Like `start`, encoding from the source `open_source` creates on the broadcast thread
+    /// (sources such as the screen capturer may not move between threads)
+    pub fn start_with_source<S, F>(&mut self, config: StreamConfig, open_source: S, on_event: F) -> Result<(), BroadcastError>
+    where
+        S: FnOnce(&StreamConfig) -> Result<Box<dyn FrameSource>, BroadcastError> + Send + 'static,
+        F: FnMut(BroadcastEvent) -> ControlFlow<()> + Send + 'static,
+    {
+        self.spawn(config, on_event, move |shared, config, events| {
+            run_broadcast(shared, config, open_source, H264Encoder::new, events)
+        })
+    }
+
+    /// Broadcast a recorded H.264 file (Annex-B, or MP4/MOV by extension) as it is, without
+    /// re-encoding. `fps` times raw Annex-B files; `playback_loop` restarts at the end.
+    pub fn start_playback<F>(&mut self, config: StreamConfig, path: PathBuf, on_event: F) -> Result<(), BroadcastError>
+    where
+        F: FnMut(BroadcastEvent) -> ControlFlow<()> + Send + 'static,
+    {
+        self.spawn(config, on_event, move |shared, config, events| run_playback(shared, config, &path, events))
+    }
+
+    fn spawn<F, R>(&mut self, config: StreamConfig, on_event: F, run: R) -> Result<(), BroadcastError>
+    where
+        F: FnMut(BroadcastEvent) -> ControlFlow<()> + Send + 'static,
+        R: FnOnce(&BroadcastShared, StreamConfig, &mut Events<BroadcastEvent, F>) -> Result<(), BroadcastError> + Send + 'static,
+    {
+        if self.is_running() {
+            return Err(BroadcastError::ConfigError("Already broadcasting".into()));
+        }
+        config.validate()?;
+        // A previous run that ended on its own
+        self.stop();
+        
+        self.shared.running.store(true, Ordering::SeqCst);
+        self.shared.paused.store(false, Ordering::SeqCst);
+        *self.shared.config_update.lock() = None;
+        *self.shared.stats.lock() = StreamStats::default();
+        *self.shared.loss_report.lock() = None;
+        
+        let shared = self.shared.clone();
+        self.thread = Some(thread::spawn(move || {
+            let mut events = Events::new(on_event, shared.running.clone());
+            if let Err(e) = run(&shared, config, &mut events) {
+                events.error(format!("Teacher error: {}", e));
+            }
+            shared.running.store(false, Ordering::SeqCst);
+        }));
+        
+        Ok(())
+    }
+
+    /// Stop broadcasting and wait (up to `STOP_TIMEOUT`) for the thread to release
+    /// the capturer and socket
+    pub fn stop(&mut self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.thread.take() {
+            join_with_timeout(handle, "Broadcast");
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::SeqCst)
+    }
+
+    /// Stop sending frames without tearing down capture, encoder or sockets
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume a paused broadcast, starting with a keyframe
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::SeqCst)
+    }
+
+    /// Change settings of the running broadcast.
+    ///
+    /// Applied live: `fps`, `quality` and `rate_control` (the encoder is rebuilt and the next
+    /// frame is a keyframe), `variable_framerate`, `pacing`, `fec`/`fec_group_size`, `mtu`, `adaptive`,
+    /// `capture_region` and `max_width`/`max_height` (the encoder is rebuilt at the new size
+    /// and students get a keyframe).
+    /// Need a restart: `port`, `network_mode`, `interface`, socket buffer sizes, backends,
+    /// `color_space`, `full_range`, `profile` and `drain_stale`.
+    pub fn update_config(&self, config: StreamConfig) -> Result<(), BroadcastError> {
+        if !self.is_running() {
+            return Err(BroadcastError::ConfigError("Not broadcasting".into()));
+        }
+        config.validate()?;
+        *self.shared.config_update.lock() = Some(config);
+        Ok(())
+    }
+
+    /// Capture only `region` of the screen (clamped to the display), or all of it with `None`.
+    /// The encoder is rebuilt for the new size and students get a keyframe.
+    pub fn set_capture_region(&self, region: Option<CaptureRect>) -> Result<(), BroadcastError> {
+        if !self.is_running() {
+            return Err(BroadcastError::ConfigError("Not broadcasting".into()));
+        }
+        if region.is_some_and(|r| r.width == 0 || r.height == 0) {
+            return Err(BroadcastError::ConfigError("capture_region width and height must be greater than 0".into()));
+        }
+        *self.shared.region_update.lock() = Some(region);
+        Ok(())
+    }
+
+    /// Statistics of the last full second
+    pub fn stats(&self) -> StreamStats {
+        self.shared.stats.lock().clone()
+    }
+
+    /// Feed a student's packet loss fraction (0..=1) to the adaptive controller.
+    /// Ignored unless `adaptive` is on.
+    pub fn report_loss(&self, loss: f32) {
+        let mut report = self.shared.loss_report.lock();
+        *report = Some(report.map_or(loss, |worst| worst.max(loss)));
+    }
+}
+
+impl Default for BroadcastSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session for BroadcastSession {
+    fn is_running(&self) -> bool {
+        BroadcastSession::is_running(self)
+    }
+
+    fn stop(&mut self) {
+        BroadcastSession::stop(self)
+    }
+}
+
+impl Drop for BroadcastSession {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Called on the receive thread with each decoded frame, see `ReceiveSession::on_frame`
+pub type FrameCallback = Box<dyn FnMut(&DecodedFrame) + Send>;
+
+/// State shared between a `ReceiveSession` and its thread
+struct ReceiveShared {
+    running: Arc<AtomicBool>,
+    stats: Mutex<StudentStats>,
+    frame_callbacks: Mutex<Vec<FrameCallback>>,
+}
+
+/// Student side: receives the RTP stream and decodes it, each frame is a `Frame` event
+pub struct ReceiveSession {
+    shared: Arc<ReceiveShared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ReceiveSession {
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(ReceiveShared {
+                running: Arc::new(AtomicBool::new(false)),
+                stats: Mutex::new(StudentStats::default()),
+                frame_callbacks: Mutex::new(Vec::new()),
+            }),
+            thread: None,
+        }
+    }
+
+    /// Run `callback` on every decoded frame, e.g. for OCR or motion detection, before the
+    /// frame is passed on as a `Frame` event. It runs on the receive thread with a borrowed
+    /// frame, so it must not block: anything slow holds up decoding and packets are lost.
+    /// Copy the frame and hand it to another thread for heavy work.
+    /// Callbacks stay registered across restarts.
+    pub fn on_frame(&self, callback: impl FnMut(&DecodedFrame) + Send + 'static) {
+        self.shared.frame_callbacks.lock().push(Box::new(callback));
+    }
+
+    /// Validate `config` and start receiving on a new thread.
+    /// Setup errors after this returns are reported as an error `Log` event.
+    pub fn start<F>(&mut self, config: StreamConfig, on_event: F) -> Result<(), BroadcastError>
+    where
+        F: FnMut(ReceiveEvent) -> ControlFlow<()> + Send + 'static,
+    {
+        if self.is_running() {
+            return Err(BroadcastError::ConfigError("Already receiving".into()));
+        }
+        config.validate()?;
+        // A previous run that ended on its own
+        self.stop();
+        
+        self.shared.running.store(true, Ordering::SeqCst);
+        *self.shared.stats.lock() = StudentStats::default();
+        
+        let shared = self.shared.clone();
+        self.thread = Some(thread::spawn(move || {
+            let mut events = Events::new(on_event, shared.running.clone());
+            if let Err(e) = run_receive(&shared, config, &mut events) {
+                events.error(format!("Student error: {}", e));
+            }
+            shared.running.store(false, Ordering::SeqCst);
+        }));
+        
+        Ok(())
+    }
+
+    /// Stop receiving and wait (up to `STOP_TIMEOUT`) for the thread to close its socket,
+    /// so a restart can bind the same port
+    pub fn stop(&mut self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.thread.take() {
+            join_with_timeout(handle, "Receive");
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::SeqCst)
+    }
+
+    /// Statistics of the last full second
+    pub fn stats(&self) -> StudentStats {
+        self.shared.stats.lock().clone()
+    }
+}
+
+impl Default for ReceiveSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session for ReceiveSession {
+    fn is_running(&self) -> bool {
+        ReceiveSession::is_running(self)
+    }
+
+    fn stop(&mut self) {
+        ReceiveSession::stop(self)
+    }
+}
+
+impl Drop for ReceiveSession {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Capture, encode and send until stopped. `create_encoder` builds the encoder for a size,
+/// frame rate and rate control, at the start and whenever the encoded size changes.
+fn run_broadcast<S, C, F>(
+    shared: &BroadcastShared,
+    mut config: StreamConfig,
+    open_source: S,
+    mut create_encoder: C,
+    events: &mut Events<BroadcastEvent, F>,
+) -> Result<(), BroadcastError>
+where
+    S: FnOnce(&StreamConfig) -> Result<Box<dyn FrameSource>, BroadcastError>,
+    C: FnMut(u32, u32, u32, RateControl, &StreamConfig) -> Result<Box<dyn VideoEncoder>, BroadcastError>,
+    F: FnMut(BroadcastEvent) -> ControlFlow<()>,
+{
+    events.info(format!("Starting teacher: {:?} mode, port {}, {} fps", 
+        config.network_mode, config.port, config.fps));
+    
+    // Initialize capture
+    events.info("Initializing capture...");
+    let mut capture = open_source(&config)?;
+    let (mut width, mut height) = capture.dimensions();
+    events.info(format!("Screen: {}x{}", width, height));
+    
+    // Test capture immediately
+    events.info("Testing capture...");
+    let mut test_attempts = 0;
+    let mut test_success = false;
+    while test_attempts < 10 && !test_success {
+        match capture.capture_frame() {
+            Ok(Some(rgb_data)) => {
+                events.info(format!("Test capture OK: {} bytes RGB data", rgb_data.len()));
+                test_success = true;
+            }
+            Ok(None) => {
+                test_attempts += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => {
+                events.warn(format!("Test capture failed: {}", e));
+                return Err(e);
+            }
+        }
+    }
+    
+    if !test_success {
+        events.warn("Warning: Could not capture test frame after 10 attempts");
+    }
+    
+    // Initialize encoder
+    let (mut encoded_width, mut encoded_height) = fit_size(&config, width, height);
+    let rate_control = resolve_rate_control(&config, encoded_width, encoded_height);
+    events.info(format!("Initializing encoder: {}x{} @ {}", encoded_width, encoded_height, rate_control));
+    let mut encoder = create_encoder(encoded_width, encoded_height, config.fps, rate_control, &config)?;
+    events.info(format!("Encoder backend: {}", encoder.name()));
+    events.info(format!("Encoder ready: {}", rate_control));
+    
+    // Initialize RTP sender
+    events.info(format!("Initializing RTP sender: {:?} mode, port {}", config.network_mode, config.port));
+    let mut sender = RtpSender::new(&config)?;
+    sender.set_frame_rate(config.fps);
+    if config.fec {
+        sender.set_fec(Some(config.fec_group_size as usize));
+    }
+    sender.set_pacing(config.pacing);
+    sender.set_insert_aud(config.insert_aud);
+    events.info("RTP sender ready");
+    
+    let mut frame_interval = capture::frame_interval(config.fps);
+    let mut last_stats = Instant::now();
+    let mut frames = 0u64;
+    let mut bytes = 0u64;
+    let mut capture_errors = 0u64;
+    let mut consecutive_capture_errors = 0u32;
+    let mut encode_errors = 0u64;
+    let mut no_frame_count = 0u64;
+    let mut cpu_monitor = ProcessCpuMonitor::new();
+    let mut reconnecting = false;
+    let mut paused = false;
+    let mut last_display_check = Instant::now();
+    let mut check_display = false;
+    // Frame slots skipped because encode+send overran the frame interval
+    let mut dropped_frames = 0u64;
+    // A forced IDR students are waiting for; never skip slots until it is sent
+    let mut keyframe_pending = false;
+    
+    // Per-stage timing, averaged over each stats interval
+    let mut capture_time = Duration::ZERO;
+    let mut encode_time = Duration::ZERO;
+    let mut send_time = Duration::ZERO;
+    let mut captured = 0u32;
+    let mut encoded = 0u32;
+    
+    // Variable frame rate: send on change only, fps is the cap
+    let mut vfr = config.variable_framerate;
+    let stream_start = Instant::now();
+    let mut last_sent = stream_start;
+    let mut last_frame_hash = None;
+    let mut unchanged_count = 0u64;
+    
+    // Adaptive mode: the encoder may run below capture size, rate and bitrate
+    let mut adaptive = AdaptiveController::new();
+    // Rebuild the encoder (display changed) or retune it (config change, adaptive step) at the top of the loop
+    let mut rebuild_encoder = false;
+    let mut retune_pending = false;
+    
+    // Set when an error ends the session, returned once students were told
+    let mut result = Ok(());
+    
+    events.info("Broadcasting started!");
+    events.info(format!("Target: {} fps ({:?} interval){}", config.fps, frame_interval,
+        if vfr { ", variable frame rate" } else { "" }));
+    
+    while shared.running.load(Ordering::SeqCst) {
+        let update = shared.config_update.lock().take();
+        if let Some(update) = update {
+            apply_config(&mut config, update, capture.as_mut(), &mut sender, events);
+            vfr = config.variable_framerate;
+            if !config.adaptive && adaptive.level() > 0 {
+                events.info("Adaptive mode off, back to full quality");
+                adaptive = AdaptiveController::new();
+            }
+            // Frame rate, rate control and size reach the encoder below, scaled to the adaptive level
+            retune_pending = true;
+            // A new capture region shows up as a display change
+            check_display = true;
+        }
+        let region_update = shared.region_update.lock().take();
+        if let Some(region) = region_update {
+            config.capture_region = region;
+            capture.set_region(region);
+            check_display = true;
+        }
+        
+        // Paused: keep the loop (and discovery presence) alive but send nothing
+        if shared.paused.load(Ordering::SeqCst) != paused {
+            paused = !paused;
+            if paused {
+                events.info("Broadcast paused");
+            } else {
+                // Students resume from a fresh IDR instead of decoding against stale references
+                encoder.force_keyframe();
+                keyframe_pending = true;
+                events.info("Broadcast resumed");
+            }
+            events.emit(BroadcastEvent::Paused(paused));
+        }
+        if paused {
+            thread::sleep(frame_interval);
+            continue;
+        }
+        
+        // Display switched or resized: rebuild the encoder at the new size so students get a fresh IDR
+        if check_display || last_display_check.elapsed() >= DISPLAY_CHECK_INTERVAL {
+            check_display = false;
+            last_display_check = Instant::now();
+            match capture.check_display_change() {
+                Ok(Some((new_width, new_height))) => {
+                    events.info(format!("Screen changed: {}x{} -> {}x{}, restarting encoder",
+                        width, height, new_width, new_height));
+                    (width, height) = (new_width, new_height);
+                    rebuild_encoder = true;
+                }
+                Ok(None) => {}
+                Err(e) => events.warn(format!("Display check failed: {}", e)),
+            }
+        }
+        
+        if rebuild_encoder || retune_pending {
+            let (target, fps, rate_control) = encoder_settings(&config, adaptive.step(), width, height);
+            let rebuilt = retune_encoder(&mut encoder, (encoded_width, encoded_height), target,
+                fps, rate_control, rebuild_encoder, |width, height| {
+                    create_encoder(width, height, fps, rate_control, &config)
+                }, events)?;
+            if rebuilt {
+                (encoded_width, encoded_height) = target;
+                keyframe_pending = true;
+                last_frame_hash = None;
+            }
+            frame_interval = capture::frame_interval(fps);
+            capture.set_fps(fps);
+            sender.set_frame_rate(fps);
+            if retune_pending {
+                events.info(format!("Encoding {}x{} @ {} fps, {}", encoded_width, encoded_height, fps, rate_control));
+            }
+            rebuild_encoder = false;
+            retune_pending = false;
+        }
+        
+        let frame_start = Instant::now();
+        let mut poll_fast = false;
+        
+        // Capture
+        match capture.capture_frame() {
+            Ok(Some(rgb_data)) => {
+                no_frame_count = 0;
+                consecutive_capture_errors = 0;
+                capture_time += frame_start.elapsed();
+                captured += 1;
+                
+                // VFR: skip frames identical to the previous one, with a slow refresh
+                let unchanged = vfr && {
+                    let hash = frame_hash(&rgb_data);
+                    let same = last_frame_hash == Some(hash) && last_sent.elapsed() < VFR_REFRESH_INTERVAL;
+                    last_frame_hash = Some(hash);
+                    same
+                };
+                
+                if unchanged {
+                    unchanged_count += 1;
+                } else {
+                    // Encode
+                    let encode_start = Instant::now();
+                    let encode_result = if (encoded_width, encoded_height) == (width, height) {
+                        encoder.encode(&rgb_data)
+                    } else {
+                        encoder.encode(&scale_rgb(&rgb_data, width as usize, height as usize,
+                            encoded_width as usize, encoded_height as usize))
+                    };
+                    encode_time += encode_start.elapsed();
+                    encoded += 1;
+                    match encode_result {
+                        Ok((h264_data, is_keyframe)) => {
+                            if h264_data.is_empty() {
+                                // Encoder skipped frame
+                            } else {
+                                // Send via RTP, stamped with the wall clock capture time
+                                sender.set_capture_time(SystemTime::now() - capture.last_capture_time().elapsed());
+                                let send_start = Instant::now();
+                                let result = if vfr {
+                                    let capture_offset = capture.last_capture_time().duration_since(stream_start);
+                                    sender.send_frame_at(&h264_data, capture_offset)
+                                } else {
+                                    sender.send_frame(&h264_data)
+                                };
+                                match result {
+                                    Ok(sent) => {
+                                        send_time += send_start.elapsed();
+                                        last_sent = Instant::now();
+                                        frames += 1;
+                                        keyframe_pending &= !is_keyframe;
+                                        bytes += sent as u64;
+                                    
+                                        // Log first few frames
+                                        if frames <= 3 || is_keyframe {
+                                            events.info(format!("Sent frame {}: {} bytes H264, {} bytes UDP, keyframe={}", 
+                                                frames, h264_data.len(), sent, is_keyframe));
+                                        }
+                                    
+                                        if let Some(hash) = sampled_frame_hash(&h264_data) {
+                                            events.info(format!("Frame hash: h264={:016x} ({} bytes)", hash, h264_data.len()));
+                                        }
+                                        
+                                        events.emit(BroadcastEvent::FrameSent { bytes: sent, keyframe: is_keyframe });
+                                    }
+                                    Err(e) => {
+                                        events.warn(format!("Send error: {}", e));
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            encode_errors += 1;
+                            if encode_errors <= 5 {
+                                events.warn(format!("Encode error #{}: {}", encode_errors, e));
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(None) => {
+                // No frame ready yet; while the display is locked or asleep just wait at frame rate
+                no_frame_count += 1;
+                poll_fast = vfr && capture.is_available();
+            }
+            Err(e) => {
+                capture_errors += 1;
+                consecutive_capture_errors += 1;
+                if consecutive_capture_errors >= MAX_CAPTURE_ERRORS {
+                    // Nothing left to recover; the error ends the session, after the
+                    // flush and BYE below
+                    result = Err(e);
+                    break;
+                }
+                // Capture usually fails when the display goes away or changes mode
+                check_display = true;
+                if capture_errors <= 5 {
+                    events.warn(format!("Capture error #{}: {}", capture_errors, e));
+                }
+            }
+        }
+        
+        if sender.is_reconnecting() != reconnecting {
+            reconnecting = !reconnecting;
+            events.warn(if reconnecting { "Network lost, reconnecting..." } else { "Network restored" });
+            events.emit(BroadcastEvent::Reconnecting(reconnecting));
+        }
+        
+        // Stats every second
+        if last_stats.elapsed() >= Duration::from_secs(1) {
+            let elapsed = last_stats.elapsed().as_secs_f32();
+            let actual_fps = frames as f32 / elapsed;
+            let capture_ms = average_ms(capture_time, captured);
+            let encode_ms = average_ms(encode_time, encoded);
+            let send_ms = average_ms(send_time, frames as u32);
+            let stats = StreamStats {
+                fps: actual_fps,
+                bitrate_kbps: (bytes as f32 * 8.0 / 1000.0) / elapsed,
+                frame_count: sender.frame_count(),
+                packets_sent: 0,
+                packets_lost: 0,
+                latency_ms: capture_ms + encode_ms + send_ms,
+                cpu_usage: cpu_monitor.sample(),
+                gpu_usage: gpu_usage(),
+                capture_ms,
+                encode_ms,
+                send_ms,
+                dropped_frames,
+                send_intervals: sender.take_send_intervals(),
+            };
+            
+            // Log stats
+            events.info(format!("Stats: {} fps (target {}), {} kbps, cpu={:.0}%, sent={}, no_frame={}, unchanged={}, dropped={}, capture/encode/send={:.1}/{:.1}/{:.1} ms", 
+                actual_fps as u32, config.fps, stats.bitrate_kbps as u32, stats.cpu_usage, frames, no_frame_count, unchanged_count,
+                dropped_frames, capture_ms, encode_ms, send_ms));
+            if let Some(intervals) = stats.send_intervals {
+                events.info(format!("Send intervals: {}", format_intervals(&intervals)));
+            }
+            
+            *shared.stats.lock() = stats.clone();
+            events.emit(BroadcastEvent::Stats(stats));
+            
+            capture_time = Duration::ZERO;
+            encode_time = Duration::ZERO;
+            send_time = Duration::ZERO;
+            captured = 0;
+            encoded = 0;
+            dropped_frames = 0;
+            frames = 0;
+            bytes = 0;
+            no_frame_count = 0;
+            unchanged_count = 0;
+            last_stats = Instant::now();
+            
+            // Worst loss students reported since the last tick
+            let loss = shared.loss_report.lock().take();
+            if let (true, Some(loss)) = (config.adaptive, loss) {
+                if let Some(step) = adaptive.update(loss) {
+                    events.info(format!("Packet loss {:.1}%, adapting to {}", loss * 100.0, step));
+                    retune_pending = true;
+                }
+            }
+        }
+        
+        // Frame rate control - sleep to maintain target FPS
+        let elapsed = frame_start.elapsed();
+        if poll_fast {
+            // VFR: catch the next screen update as soon as it happens
+            thread::sleep(VFR_POLL_INTERVAL);
+        } else {
+            let (delay, skipped) = next_frame_delay(elapsed, frame_interval, keyframe_pending);
+            dropped_frames += skipped;
+            thread::sleep(delay);
+        }
+    }
+    
+    // Frames the encoder still holds go out before the socket closes
+    flush_encoder(encoder.as_mut(), &mut sender, events);
+    // Students show the end right away instead of waiting to notice frames stopped
+    if let Err(e) = sender.send_bye() {
+        events.warn(format!("Sending end of stream failed: {}", e));
+    }
+    
+    events.info("Broadcasting stopped");
+    result
+}
+
+/// Sleep before the next frame after one that took `elapsed`, and the frame slots that
+/// skips. Running behind, the slots the frame overran are dropped so the stream stays near
+/// real time instead of sending every frame late, unless students wait for a forced IDR.
+fn next_frame_delay(elapsed: Duration, frame_interval: Duration, keyframe_pending: bool) -> (Duration, u64) {
+    if elapsed < frame_interval {
+        (frame_interval - elapsed, 0)
+    } else if keyframe_pending {
+        // Yield briefly and go straight on to the keyframe
+        (Duration::from_micros(100), 0)
+    } else {
+        let slots = (elapsed.as_nanos() / frame_interval.as_nanos().max(1)) as u32 + 1;
+        (frame_interval * slots - elapsed, slots as u64 - 1)
+    }
+}
+
+/// Send a recorded file's access units at their own pace, without re-encoding
+fn run_playback<F>(
+    shared: &BroadcastShared,
+    mut config: StreamConfig,
+    path: &Path,
+    events: &mut Events<BroadcastEvent, F>,
+) -> Result<(), BroadcastError>
+where
+    F: FnMut(BroadcastEvent) -> ControlFlow<()>,
+{
+    events.info(format!("Starting playback of {}: {:?} mode, port {}", 
+        path.display(), config.network_mode, config.port));
+    
+    let mut file = playback::open(path, config.fps)?;
+    match file.dimensions() {
+        // Sent as recorded; students size their view from the stream's SPS
+        Some((width, height)) => events.info(format!("File: {}x{}, sent without re-encoding", width, height)),
+        None => events.info(format!("Raw H.264 file, timed at {} fps", config.fps)),
+    }
+    
+    let mut sender = RtpSender::new(&config)?;
+    sender.set_frame_rate(config.fps);
+    if config.fec {
+        sender.set_fec(Some(config.fec_group_size as usize));
+    }
+    sender.set_pacing(config.pacing);
+    sender.set_insert_aud(config.insert_aud);
+    events.info("RTP sender ready");
+    
+    // Last SPS/PPS seen, prepended at loop points so late joiners can start there
+    let mut parameter_sets = Vec::new();
+    let mut waiting_for_keyframe = true;
+    let mut units_this_pass = 0u64;
+    // RTP timeline, keeps running across loops
+    let mut media_time = Duration::ZERO;
+    let mut next_send = Instant::now();
+    let mut last_stats = Instant::now();
+    let mut frames = 0u64;
+    let mut bytes = 0u64;
+    let mut cpu_monitor = ProcessCpuMonitor::new();
+    let mut reconnecting = false;
+    let mut paused = false;
+    
+    events.info("Playback started!");
+    
+    while shared.running.load(Ordering::SeqCst) {
+        let update = shared.config_update.lock().take();
+        if let Some(update) = update {
+            config.playback_loop = update.playback_loop;
+            config.pacing = update.pacing;
+            config.diagnostics = update.diagnostics;
+            sender.set_pacing(config.pacing);
+            sender.set_diagnostics(config.diagnostics);
+        }
+        
+        // Paused: hold the file position, then carry on from it
+        if shared.paused.load(Ordering::SeqCst) != paused {
+            paused = !paused;
+            events.info(if paused { "Playback paused" } else { "Playback resumed" });
+            events.emit(BroadcastEvent::Paused(paused));
+        }
+        if paused {
+            thread::sleep(Duration::from_millis(50));
+            next_send = Instant::now();
+            continue;
+        }
+        
+        let Some(unit) = file.next_unit()? else {
+            if units_this_pass == 0 {
+                return Err(BroadcastError::ConfigError(format!("{} has no playable H.264 frames", path.display())));
+            }
+            if !config.playback_loop {
+                events.info("End of file");
+                events.emit(BroadcastEvent::PlaybackEnded);
+                break;
+            }
+            events.info("End of file, looping");
+            file.rewind()?;
+            waiting_for_keyframe = true;
+            units_this_pass = 0;
+            continue;
+        };
+        
+        // Every pass starts on a keyframe with its parameter sets
+        let mut has_parameter_sets = false;
+        if unit.keyframe {
+            let mut sets = Vec::new();
+            for (nal_type, nal) in nal_units(&unit.data) {
+                if matches!(nal_type, NalType::Sps | NalType::Pps) {
+                    sets.extend_from_slice(&[0, 0, 0, 1]);
+                    sets.extend_from_slice(nal);
+                }
+            }
+            if !sets.is_empty() {
+                parameter_sets = sets;
+                has_parameter_sets = true;
+            }
+        }
+        if waiting_for_keyframe && !unit.keyframe {
+            continue;
+        }
+        let data = if waiting_for_keyframe && !has_parameter_sets && !parameter_sets.is_empty() {
+            [parameter_sets.as_slice(), &unit.data].concat()
+        } else {
+            unit.data
+        };
+        waiting_for_keyframe = false;
+        units_this_pass += 1;
+        
+        match sender.send_frame_at(&data, media_time) {
+            Ok(sent) => {
+                frames += 1;
+                bytes += sent as u64;
+                events.emit(BroadcastEvent::FrameSent { bytes: sent, keyframe: unit.keyframe });
+            }
+            Err(e) => events.warn(format!("Send error: {}", e)),
+        }
+        media_time += unit.duration;
+        
+        if sender.is_reconnecting() != reconnecting {
+            reconnecting = !reconnecting;
+            events.warn(if reconnecting { "Network lost, reconnecting..." } else { "Network restored" });
+            events.emit(BroadcastEvent::Reconnecting(reconnecting));
+        }
+        
+        if last_stats.elapsed() >= Duration::from_secs(1) {
+            let elapsed = last_stats.elapsed().as_secs_f32();
+            let stats = StreamStats {
+                fps: frames as f32 / elapsed,
+                bitrate_kbps: (bytes as f32 * 8.0 / 1000.0) / elapsed,
+                frame_count: sender.frame_count(),
+                cpu_usage: cpu_monitor.sample(),
+                gpu_usage: gpu_usage(),
+                send_intervals: sender.take_send_intervals(),
+                ..StreamStats::default()
+            };
+            events.info(format!("Stats: {} fps, {} kbps, cpu={:.0}%, media time {:.1}s", 
+                stats.fps as u32, stats.bitrate_kbps as u32, stats.cpu_usage, media_time.as_secs_f32()));
+            *shared.stats.lock() = stats.clone();
+            events.emit(BroadcastEvent::Stats(stats));
+            frames = 0;
+            bytes = 0;
+            last_stats = Instant::now();
+        }
+        
+        // Keep the file's own timing; after a long stall start again from now instead of bursting
+        next_send += unit.duration;
+        let now = Instant::now();
+        if next_send > now {
+            thread::sleep(next_send - now);
+        } else if now - next_send > Duration::from_secs(1) {
+            next_send = now;
+        }
+    }
+    
+    if let Err(e) = sender.send_bye() {
+        events.warn(format!("Sending end of stream failed: {}", e));
+    }
+    events.info("Playback stopped");
+    Ok(())
+}
+
+fn apply_config<F>(
+    config: &mut StreamConfig,
+    update: StreamConfig,
+    capture: &mut dyn FrameSource,
+    sender: &mut RtpSender,
+    events: &mut Events<BroadcastEvent, F>,
+) where
+    F: FnMut(BroadcastEvent) -> ControlFlow<()>,
+{
+    // Frame rate, rate control and size are applied to the encoder by the caller
+    if update.fps != config.fps || update.quality != config.quality || update.rate_control != config.rate_control {
+        events.info(format!("Now {} fps, quality {} ({})", update.fps, update.quality, update.rate_control));
+    }
+    if (update.max_width, update.max_height) != (config.max_width, config.max_height) {
+        events.info(format!("Maximum size now {:?}x{:?}", update.max_width, update.max_height));
+    }
+    if update.fec != config.fec || update.fec_group_size != config.fec_group_size {
+        sender.set_fec(update.fec.then_some(update.fec_group_size as usize));
+    }
+    if update.mtu != config.mtu {
+        sender.set_mtu(update.mtu);
+        events.info(format!("MTU now {} bytes", update.mtu));
+    }
+    sender.set_pacing(update.pacing);
+    sender.set_insert_aud(update.insert_aud);
+    sender.set_diagnostics(update.diagnostics);
+    if update.capture_region != config.capture_region {
+        capture.set_region(update.capture_region);
+    }
+    
+    let restart_needed = update.port != config.port
+        || update.network_mode != config.network_mode
+        || update.interface != config.interface
+        || update.send_buffer_bytes != config.send_buffer_bytes
+        || update.encoder_backend != config.encoder_backend
+        || update.color_space != config.color_space
+        || update.full_range != config.full_range
+        || update.profile != config.profile
+        || update.conversion_threads != config.conversion_threads
+        || update.drain_stale != config.drain_stale;
+    if restart_needed {
+        events.info("Port, network mode, interface, buffer, backend and color changes apply after a restart");
+    }
+    
+    // Keep the restart-only fields as they are running
+    *config = StreamConfig {
+        port: config.port,
+        network_mode: config.network_mode,
+        interface: config.interface,
+        send_buffer_bytes: config.send_buffer_bytes,
+        encoder_backend: config.encoder_backend,
+        color_space: config.color_space,
+        full_range: config.full_range,
+        profile: config.profile,
+        conversion_threads: config.conversion_threads,
+        drain_stale: config.drain_stale,
+        ..update
+    };
+}
+
+/// Send what the encoder still holds as a final frame; failures are only logged since
+/// the stream is ending anyway
+fn flush_encoder<F>(encoder: &mut dyn VideoEncoder, sender: &mut RtpSender, events: &mut Events<BroadcastEvent, F>)
+where
+    F: FnMut(BroadcastEvent) -> ControlFlow<()>,
+{
+    match encoder.flush() {
+        Ok(h264_data) if !h264_data.is_empty() => {
+            if let Err(e) = sender.send_frame(&h264_data) {
+                events.warn(format!("Sending flushed frames failed: {}", e));
+            }
+        }
+        Ok(_) => {}
+        Err(e) => events.warn(format!("Encoder flush failed: {}", e)),
+    }
+}
+
+/// Bring the encoder from `encoded_size` to `target` size, `fps` and `rate_control`. A new
+/// size (or `rebuild`) means a new encoder from `create`, starting on a forced keyframe;
+/// otherwise it is retuned in place. Returns whether it was rebuilt.
+#[allow(clippy::too_many_arguments)]
+fn retune_encoder<C, F>(
+    encoder: &mut Box<dyn VideoEncoder>,
+    encoded_size: (u32, u32),
+    target: (u32, u32),
+    fps: u32,
+    rate_control: RateControl,
+    rebuild: bool,
+    create: C,
+    events: &mut Events<BroadcastEvent, F>,
+) -> Result<bool, BroadcastError>
+where
+    C: FnOnce(u32, u32) -> Result<Box<dyn VideoEncoder>, BroadcastError>,
+    F: FnMut(BroadcastEvent) -> ControlFlow<()>,
+{
+    if rebuild || target != encoded_size {
+        // New size: students need a fresh IDR
+        *encoder = create(target.0, target.1)?;
+        encoder.force_keyframe();
+        return Ok(true);
+    }
+    if let Err(e) = encoder.set_rate_control(fps, rate_control) {
+        events.warn(format!("Rate control change failed: {}", e));
+    }
+    Ok(false)
+}
+
+fn run_receive<F>(
+    shared: &ReceiveShared,
+    config: StreamConfig,
+    events: &mut Events<ReceiveEvent, F>,
+) -> Result<(), BroadcastError>
+where
+    F: FnMut(ReceiveEvent) -> ControlFlow<()>,
+{
+    events.info(format!("Starting student: {:?} mode, port {}", config.network_mode, config.port));
+    
+    // Initialize RTP receiver
+    let mut receiver = RtpReceiver::new(&config)?;
+    events.info("RTP receiver ready");
+    
+    // Initialize decoder
+    let mut decoder = H264Decoder::new(config.decoder_backend, config.color_space, config.full_range)?;
+    events.info(format!("Decoder backend: {}", decoder.name()));
+    events.info("Decoder ready");
+    
+    let mut last_log = Instant::now();
+    let mut frames_received = 0u64;
+    let mut waiting_for_keyframe = true;
+    
+    // Modes to fall back to if nothing arrives (e.g. multicast filtered by the switch)
+    let mut fallback_modes: Vec<_> = config.fallback_modes.iter()
+        .copied()
+        .filter(|m| *m != config.network_mode)
+        .collect();
+    fallback_modes.dedup();
+    fallback_modes.reverse(); // pop() takes them in configured order
+    let fallback_timeout = Duration::from_millis(config.fallback_timeout_ms);
+    let mut mode_started = Instant::now();
+    let mut frames_assembled = 0u64;
+    let mut reconnecting = false;
+    let mut ended = false;
+    let mut last_stats = Instant::now();
+    let mut stats_frames = 0u64;
+    let mut latency_sum_ms = 0i64;
+    let mut latency_samples = 0u64;
+    let stall_timeout = (config.stall_timeout_ms > 0).then(|| Duration::from_millis(config.stall_timeout_ms));
+    let mut last_frame_at: Option<Instant> = None;
+    let mut stalled = false;
+    
+    events.info("Waiting for stream...");
+    
+    while shared.running.load(Ordering::SeqCst) {
+        if frames_assembled == 0 && mode_started.elapsed() >= fallback_timeout {
+            if let Some(mode) = fallback_modes.pop() {
+                events.info(format!("No frames in {:?} mode after {:?}, falling back to {:?}", 
+                    receiver.mode(), fallback_timeout, mode));
+                match receiver.switch_mode(mode) {
+                    Ok(()) => {
+                        // No back channel to the teacher: wait for its next periodic IDR
+                        waiting_for_keyframe = true;
+                        events.emit(ReceiveEvent::FellBack(mode));
+                    }
+                    Err(e) => events.warn(format!("Fallback to {:?} failed: {}", mode, e)),
+                }
+                mode_started = Instant::now();
+            }
+        }
+        
+        match receiver.receive_frame() {
+            Ok(Some(h264_frame)) => {
+                frames_assembled += 1;
+                
+                // Only scan for a keyframe (IDR or SPS) while waiting for one; once decoding,
+                // the decoder reports it on the frame
+                if waiting_for_keyframe {
+                    if contains_keyframe(&h264_frame) {
+                        events.info("Got keyframe, starting decode");
+                        waiting_for_keyframe = false;
+                    } else {
+                        continue;
+                    }
+                }
+                
+                let h264_hash = sampled_frame_hash(&h264_frame);
+                
+                // Decode
+                match decoder.decode(&h264_frame) {
+                    Ok(Some(mut frame)) => {
+                        frames_received += 1;
+                        frame.timestamp = receiver.last_frame_timestamp().unwrap_or(0);
+                        
+                        if let Some(hash) = h264_hash {
+                            events.info(format!("Frame hash: h264={:016x} ({} bytes), rgba={:016x}", 
+                                hash, h264_frame.len(), frame.content_hash()));
+                        }
+                        
+                        if frames_received == 1 {
+                            events.info(format!("First frame decoded! {}x{}", frame.width, frame.height));
+                        }
+                        
+                        // Glass-to-glass latency from the teacher's capture timestamp
+                        stats_frames += 1;
+                        last_frame_at = Some(Instant::now());
+                        if stalled {
+                            stalled = false;
+                            events.info("Stream resumed");
+                            events.emit(ReceiveEvent::Stalled(false));
+                        }
+                        if let Some(capture_ms) = receiver.last_frame_capture_ms() {
+                            latency_sum_ms += unix_ms(SystemTime::now()) as i64 - capture_ms as i64;
+                            latency_samples += 1;
+                        }
+                        
+                        for callback in shared.frame_callbacks.lock().iter_mut() {
+                            callback(&frame);
+                        }
+                        events.emit(ReceiveEvent::Frame(frame));
+                    }
+                    Ok(None) => {
+                        // Decoder needs more data or returned no frame
+                        if frames_received == 0 {
+                            log::debug!("Decoder returned None (needs more data)");
+                        }
+                    }
+                    Err(e) => {
+                        if frames_received == 0 {
+                            events.warn(format!("Decode error (waiting for keyframe): {}", e));
+                        } else {
+                            log::warn!("Decode error: {}", e);
+                        }
+                        waiting_for_keyframe = true;
+                    }
+                }
+            }
+            Ok(None) => {
+                // No frame yet
+                if last_log.elapsed() >= Duration::from_secs(5) && frames_received == 0 {
+                    events.info("No frames received yet...");
+                    last_log = Instant::now();
+                }
+                // Small sleep to prevent busy loop
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => {
+                events.warn(format!("Receive error: {}", e));
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        
+        // Stats every second
+        if last_stats.elapsed() >= Duration::from_secs(1) {
+            let stats = StudentStats {
+                fps: stats_frames as f32 / last_stats.elapsed().as_secs_f32(),
+                frames_decoded: frames_received,
+                packets_received: receiver.packets_received(),
+                packets_lost: receiver.packets_lost(),
+                packets_recovered: receiver.packets_recovered(),
+                jitter_ms: receiver.jitter_ms(),
+                latency_ms: if latency_samples > 0 {
+                    latency_sum_ms as f32 / latency_samples as f32
+                } else {
+                    0.0
+                },
+                arrival_intervals: receiver.take_arrival_intervals(),
+            };
+            if let Some(intervals) = stats.arrival_intervals {
+                events.info(format!("Arrival intervals: {}", format_intervals(&intervals)));
+            }
+            *shared.stats.lock() = stats.clone();
+            events.emit(ReceiveEvent::Stats(stats));
+            
+            last_stats = Instant::now();
+            stats_frames = 0;
+            latency_sum_ms = 0;
+            latency_samples = 0;
+        }
+        
+        if receiver.is_reconnecting() != reconnecting {
+            reconnecting = !reconnecting;
+            if reconnecting {
+                events.warn("Network lost, reconnecting...");
+            } else {
+                // Assembly restarted on the new socket
+                events.info("Network restored, waiting for keyframe");
+                waiting_for_keyframe = true;
+            }
+            events.emit(ReceiveEvent::Reconnecting(reconnecting));
+        }
+        
+        if receiver.stream_ended() != ended {
+            ended = !ended;
+            if ended {
+                events.info("Teacher ended the stream");
+                events.emit(ReceiveEvent::Ended);
+            }
+        }
+        
+        // Only a stream that had started can stall; before that the fallback logic applies
+        if let (Some(timeout), Some(last)) = (stall_timeout, last_frame_at) {
+            // A teacher that said goodbye is not stalled
+            if !stalled && !ended && last.elapsed() >= timeout {
+                stalled = true;
+                events.warn(format!("No frames for {:?}, stream stalled", timeout));
+                events.emit(ReceiveEvent::Stalled(true));
+            }
+        }
+    }
+    
+    events.info(format!("Receiving stopped. Total frames: {}", frames_received));
+    Ok(())
+}
+
+/// Join a session thread, giving up after `STOP_TIMEOUT` so a stuck thread cannot hang the caller
+fn join_with_timeout(handle: thread::JoinHandle<()>, name: &str) {
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            log::warn!("{} session thread did not stop within {:?}, detaching it", name, STOP_TIMEOUT);
+            return;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    if handle.join().is_err() {
+        log::error!("{} session thread panicked", name);
+    }
+}
+
+/// Samples CPU usage of the current process (not the whole system)
+pub(crate) struct ProcessCpuMonitor {
+    system: sysinfo::System,
+    pid: Option<sysinfo::Pid>,
+}
+
+impl ProcessCpuMonitor {
+    pub(crate) fn new() -> Self {
+        let mut monitor = Self {
+            system: sysinfo::System::new(),
+            pid: sysinfo::get_current_pid().ok(),
+        };
+        // First refresh only establishes the baseline for the next sample
+        monitor.sample();
+        monitor
+    }
+
+    /// CPU usage since the previous sample, in percent (0..=100 per core)
+    pub(crate) fn sample(&mut self) -> f32 {
+        let Some(pid) = self.pid else { return 0.0 };
+        if !self.system.refresh_process(pid) {
+            return 0.0;
+        }
+        self.system.process(pid).map(|p| p.cpu_usage()).unwrap_or(0.0)
+    }
+}
+
+/// Busiest GPU's load in percent, from the kernel driver's gpu_busy_percent (amdgpu and
+/// some Intel GPUs on Linux). No platform offers a per-process figure without vendor
+/// libraries, so this is the whole GPU; `None` where it is not reported.
+pub(crate) fn gpu_usage() -> Option<f32> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_dir("/sys/class/drm").ok()?
+            .filter_map(|entry| std::fs::read_to_string(entry.ok()?.path().join("device/gpu_busy_percent")).ok())
+            .filter_map(|busy| busy.trim().parse::<f32>().ok())
+            .reduce(f32::max)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+fn format_intervals(intervals: &IntervalStats) -> String {
+    format!("min/mean/max {:.1}/{:.1}/{:.1} ms, stddev {:.1} ms over {} frames",
+        intervals.min_ms, intervals.mean_ms, intervals.max_ms, intervals.stddev_ms, intervals.count)
+}
+
+/// Capture size scaled down to fit `max_width`/`max_height`, keeping the aspect ratio
+fn fit_size(config: &StreamConfig, width: u32, height: u32) -> (u32, u32) {
+    fit_within(width, height, config.max_width, config.max_height)
+}
+
+/// Encoded size, frame rate and rate control for a `width`x`height` capture under `config`,
+/// scaled down to the adaptive `step`
+fn encoder_settings(config: &StreamConfig, step: AdaptiveStep, width: u32, height: u32) -> ((u32, u32), u32, RateControl) {
+    let (fit_width, fit_height) = fit_size(config, width, height);
+    let size = step.scale_size(fit_width, fit_height);
+    let rate_control = step.scale_rate_control(resolve_rate_control(config, size.0, size.1));
+    (size, step.scale_fps(config.fps), rate_control)
+}
+
+/// `width`x`height` scaled down to fit the given bounds, keeping the aspect ratio and even sizes
+pub fn fit_within(width: u32, height: u32, max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+    let limit = |max: Option<u32>, size: u32| max.map_or(1.0, |max| max as f32 / size.max(1) as f32);
+    let scale = limit(max_width, width).min(limit(max_height, height));
+    if scale >= 1.0 {
+        return (width, height);
+    }
+    let fit = |size: u32| ((size as f32 * scale) as u32 & !1).max(2);
+    (fit(width), fit(height))
+}
+
+/// `config.rate_control` with an automatic bitrate worked out for this resolution
+fn resolve_rate_control(config: &StreamConfig, width: u32, height: u32) -> RateControl {
+    match config.rate_control {
+        RateControl::Bitrate(0) => RateControl::Bitrate(calculate_bitrate(width, height, config.fps, config.quality)),
+        rate_control => rate_control,
+    }
+}
+
+fn calculate_bitrate(width: u32, height: u32, fps: u32, quality: u32) -> u32 {
+    let pixels = width * height;
+    let base = match pixels {
+        p if p <= 921600 => 1500,   // 720p
+        p if p <= 2073600 => 3000,  // 1080p
+        _ => 5000,
+    };
+    
+    let fps_factor = fps as f32 / 30.0;
+    let quality_factor = 1.0 - (quality as f32 - 20.0) / 60.0;
+    
+    (base as f32 * fps_factor * quality_factor.max(0.3)) as u32
+}
+
+/// Mean of `count` durations in milliseconds, 0 if there were none
+fn average_ms(total: Duration, count: u32) -> f32 {
+    if count == 0 {
+        0.0
+    } else {
+        total.as_secs_f32() * 1000.0 / count as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcast::h264::sps_dimensions;
+    use crate::broadcast::h264::tests::baseline_sps;
+    use crate::broadcast::source::SyntheticSource;
+
+    /// How the encoder was last configured, shared with the test
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct EncoderSetup {
+        size: (u32, u32),
+        fps: u32,
+        rate_control: RateControl,
+        keyframe_forced: bool,
+    }
+
+    /// Encoder that records its setup instead of encoding
+    struct MockEncoder(Arc<Mutex<EncoderSetup>>);
+
+    impl VideoEncoder for MockEncoder {
+        fn encode(&mut self, _rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError> {
+            Ok((Vec::new(), false))
+        }
+
+        fn set_rate_control(&mut self, fps: u32, rate_control: RateControl) -> Result<(), BroadcastError> {
+            let mut setup = self.0.lock();
+            (setup.fps, setup.rate_control) = (fps, rate_control);
+            Ok(())
+        }
+
+        fn force_keyframe(&mut self) {
+            self.0.lock().keyframe_forced = true;
+        }
+
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+    }
+
+    /// Source that switches to another size once, like a display switch
+    struct ResizingSource {
+        size: (u32, u32),
+        switch_to: Option<(u32, u32)>,
+    }
+
+    impl FrameSource for ResizingSource {
+        fn dimensions(&self) -> (u32, u32) {
+            self.size
+        }
+
+        fn capture_frame(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
+            Ok(Some(vec![0; self.size.0 as usize * self.size.1 as usize * 3]))
+        }
+
+        fn last_capture_time(&self) -> Instant {
+            Instant::now()
+        }
+
+        fn check_display_change(&mut self) -> Result<Option<(u32, u32)>, BroadcastError> {
+            let changed = self.switch_to.take();
+            if let Some(size) = changed {
+                self.size = size;
+            }
+            Ok(changed)
+        }
+    }
+
+    /// Encoder that sends an SPS, PPS and IDR for its size when a keyframe is due, and a
+    /// P slice otherwise
+    struct SizedEncoder {
+        size: (u32, u32),
+        keyframe: bool,
+    }
+
+    impl VideoEncoder for SizedEncoder {
+        fn encode(&mut self, _rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError> {
+            if !std::mem::take(&mut self.keyframe) {
+                return Ok((vec![0, 0, 0, 1, 0x41, 0x9A], false));
+            }
+            let sps = baseline_sps(self.size.0, self.size.1);
+            let frame = [&[0, 0, 0, 1][..], &sps, &[0, 0, 0, 1, 0x68, 0xCE, 0x38, 0x80], &[0, 0, 0, 1, 0x65, 0x88, 0x84]].concat();
+            Ok((frame, true))
+        }
+
+        fn set_rate_control(&mut self, _fps: u32, _rate_control: RateControl) -> Result<(), BroadcastError> {
+            Ok(())
+        }
+
+        fn force_keyframe(&mut self) {
+            self.keyframe = true;
+        }
+
+        fn name(&self) -> &'static str {
+            "sized"
+        }
+    }
+
+    #[test]
+    fn cpu_usage_is_within_core_count() {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get()) as f32;
+        let mut monitor = ProcessCpuMonitor::new();
+        
+        // Keep one core busy for longer than sysinfo's minimum update interval
+        let start = Instant::now();
+        let mut spin = 0u64;
+        while start.elapsed() < Duration::from_millis(300) {
+            spin = std::hint::black_box(spin.wrapping_add(1));
+        }
+        
+        let usage = monitor.sample();
+        assert!((0.0..=100.0 * cores).contains(&usage), "{}% on {} cores", usage, cores);
+    }
+
+    #[test]
+    fn frame_hash_sampling_is_deterministic() {
+        let frame = [0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00];
+        
+        set_frame_hash_sampling(0);
+        assert_eq!(sampled_frame_hash(&frame), None);
+        
+        // Every frame is selected at 1; the FNV-1a value is the same on every machine
+        set_frame_hash_sampling(1);
+        assert_eq!(sampled_frame_hash(&frame), Some(frame_hash(&frame)));
+        assert_eq!(frame_hash(&frame), 0x5857_b029_2fc5_3ecb);
+        
+        set_frame_hash_sampling(0);
+    }
+
+    #[test]
+    fn config_update_reaches_the_encoder_mid_stream() {
+        let (width, height) = (1280, 720);
+        let mut config = StreamConfig { fps: 30, rate_control: RateControl::Bitrate(2000), ..StreamConfig::default() };
+        let mut capture = SyntheticSource::new(width, height);
+        let mut sender = RtpSender::new(&config).expect("open sender");
+        let mut events = Events::new(|_: BroadcastEvent| ControlFlow::Continue(()), Arc::new(AtomicBool::new(true)));
+        let step = AdaptiveController::new().step();
+
+        let setup = Arc::new(Mutex::new(EncoderSetup {
+            size: (width, height),
+            fps: 30,
+            rate_control: RateControl::Bitrate(2000),
+            keyframe_forced: false,
+        }));
+        let mut encoder: Box<dyn VideoEncoder> = Box::new(MockEncoder(setup.clone()));
+        
+        // A new frame rate retunes the running encoder
+        let update = StreamConfig { fps: 15, ..config.clone() };
+        apply_config(&mut config, update, &mut capture, &mut sender, &mut events);
+        let (target, fps, rate_control) = encoder_settings(&config, step, width, height);
+        let rebuilt = retune_encoder(&mut encoder, (width, height), target, fps, rate_control, false,
+            |_, _| panic!("same size, no new encoder"), &mut events).expect("retune");
+        assert!(!rebuilt);
+        let retuned = *setup.lock();
+        assert_eq!((retuned.size, retuned.fps, retuned.keyframe_forced), ((width, height), 15, false));
+        
+        // A smaller maximum size makes a new encoder at that size, starting on a keyframe
+        let update = StreamConfig { max_width: Some(640), ..config.clone() };
+        apply_config(&mut config, update, &mut capture, &mut sender, &mut events);
+        assert_eq!(config.max_width, Some(640));
+        let (target, fps, rate_control) = encoder_settings(&config, step, width, height);
+        assert_eq!(target, (640, 360));
+        let resized = Arc::new(Mutex::new(EncoderSetup { size: (0, 0), ..retuned }));
+        let rebuilt = retune_encoder(&mut encoder, (width, height), target, fps, rate_control, false, |w, h| {
+            *resized.lock() = EncoderSetup { size: (w, h), fps, rate_control, keyframe_forced: false };
+            Ok(Box::new(MockEncoder(resized.clone())))
+        }, &mut events).expect("rebuild");
+        assert!(rebuilt);
+        assert_eq!(*resized.lock(), EncoderSetup { size: (640, 360), fps: 15, rate_control, keyframe_forced: true });
+        
+        // So does the lower frame rate of an adaptive step
+        let half_rate = AdaptiveStep { fps_scale: 0.5, ..step };
+        assert_eq!(encoder_settings(&config, half_rate, width, height).1, 8);
+    }
+
+    #[test]
+    fn display_change_rebuilds_the_encoder_at_the_new_size() {
+        let port = std::net::UdpSocket::bind("0.0.0.0:0").and_then(|s| s.local_addr()).expect("free port").port();
+        let config = StreamConfig { port, fps: 30, ..StreamConfig::default() };
+        let mut receiver = RtpReceiver::new(&config).expect("bind receiver");
+        let session = BroadcastSession::new();
+        session.shared.running.store(true, Ordering::SeqCst);
+        
+        // Count frames sent before the teacher loop noticed the new size, and stop a few after
+        let (mut sent, mut sent_before_change) = (0, None);
+        let start = Instant::now();
+        let mut events = Events::new(|event: BroadcastEvent| {
+            match event {
+                BroadcastEvent::Log(_, message) if message.starts_with("Screen changed") => sent_before_change = Some(sent),
+                BroadcastEvent::FrameSent { .. } => sent += 1,
+                _ => {}
+            }
+            let done = sent_before_change.is_some_and(|before| sent >= before + 3);
+            if done || start.elapsed() > Duration::from_secs(5) { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        }, session.shared.running.clone());
+        let source = ResizingSource { size: (640, 480), switch_to: Some((320, 240)) };
+        run_broadcast(&session.shared, config, |_| Ok(Box::new(source)), |width, height, _, _, _| {
+            Ok(Box::new(SizedEncoder { size: (width, height), keyframe: true }))
+        }, &mut events).expect("broadcast");
+        let before = sent_before_change.expect("display change noticed");
+        assert!(before > 0, "no frames before the change");
+        
+        let mut received = Vec::new();
+        let start = Instant::now();
+        while received.len() < sent && start.elapsed() < Duration::from_secs(2) {
+            if let Some(frame) = receiver.receive_frame().expect("receive") {
+                received.push(frame);
+            }
+        }
+        assert_eq!(received.len(), sent);
+        let sps_size = |frame: &[u8]| nal_units(frame)
+            .find(|(nal_type, _)| *nal_type == NalType::Sps)
+            .and_then(|(_, sps)| sps_dimensions(sps));
+        
+        // Students start on an IDR at the old size, and the first frame after the switch is a
+        // fresh SPS and IDR at the new one
+        assert_eq!(sps_size(&received[0]), Some((640, 480)));
+        assert!(received[1..before].iter().all(|frame| !contains_keyframe(frame)));
+        assert!(contains_keyframe(&received[before]));
+        assert_eq!(sps_size(&received[before]), Some((320, 240)));
+        assert!(received[before + 1..].iter().all(|frame| !contains_keyframe(frame)));
+    }
+
+    #[test]
+    fn slow_encoder_drops_frame_slots_and_stays_near_real_time() {
+        /// Encoder taking 2.5 frame intervals per frame
+        struct SlowEncoder(Duration);
+
+        impl VideoEncoder for SlowEncoder {
+            fn encode(&mut self, _rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError> {
+                thread::sleep(self.0);
+                Ok((vec![0, 0, 0, 1, 0x41, 0x9A], false))
+            }
+
+            fn set_rate_control(&mut self, _fps: u32, _rate_control: RateControl) -> Result<(), BroadcastError> {
+                Ok(())
+            }
+
+            fn force_keyframe(&mut self) {}
+
+            fn name(&self) -> &'static str {
+                "slow"
+            }
+        }
+        
+        let port = std::net::UdpSocket::bind("0.0.0.0:0").and_then(|s| s.local_addr()).expect("free port").port();
+        let config = StreamConfig { port, fps: 50, diagnostics: true, ..StreamConfig::default() };
+        let frame_interval = capture::frame_interval(config.fps);
+        let session = BroadcastSession::new();
+        session.shared.running.store(true, Ordering::SeqCst);
+        
+        // Run the teacher loop for one stats interval
+        let mut stats = None;
+        let mut events = Events::new(|event: BroadcastEvent| match event {
+            BroadcastEvent::Stats(interval) => {
+                stats = Some(interval);
+                ControlFlow::Break(())
+            }
+            _ => ControlFlow::Continue(()),
+        }, session.shared.running.clone());
+        run_broadcast(&session.shared, config, |_| Ok(Box::new(SyntheticSource::new(64, 48))), |_, _, _, _, _| {
+            Ok(Box::new(SlowEncoder(frame_interval * 5 / 2)))
+        }, &mut events).expect("broadcast");
+        let stats = stats.expect("stats emitted");
+        
+        // Each frame overruns into two more slots, which are dropped rather than sent late:
+        // frames go out every third slot, a third of the target rate
+        let sent = stats.send_intervals.expect("send intervals");
+        assert!(sent.count >= 10, "{} frames sent", sent.count);
+        assert!(stats.dropped_frames >= 2 * sent.count, "{} dropped for {} sent", stats.dropped_frames, sent.count);
+        assert!(stats.dropped_frames < 3 * sent.count, "{} dropped for {} sent", stats.dropped_frames, sent.count);
+        let slot_ms = frame_interval.as_secs_f32() * 1000.0;
+        assert!((sent.mean_ms - 3.0 * slot_ms).abs() < slot_ms / 2.0, "{} ms between frames", sent.mean_ms);
+        assert!(sent.max_ms < 4.0 * slot_ms, "{} ms gap", sent.max_ms);
+        assert!((stats.fps - 50.0 / 3.0).abs() < 3.0, "{} fps", stats.fps);
+        
+        // A pending keyframe is never dropped
+        let (delay, skipped) = next_frame_delay(frame_interval * 3, frame_interval, true);
+        assert_eq!(skipped, 0);
+        assert!(delay < frame_interval);
+        
+        // On time, nothing is dropped and the rest of the interval is slept
+        assert_eq!(next_frame_delay(frame_interval / 4, frame_interval, false), (frame_interval * 3 / 4, 0));
+    }
+
+    #[test]
+    fn rapid_restarts_rebind_the_same_port() {
+        let port = std::net::UdpSocket::bind("0.0.0.0:0").and_then(|s| s.local_addr()).expect("free port").port();
+        let config = StreamConfig { port, ..StreamConfig::default() };
+        let mut session = ReceiveSession::new();
+        
+        for round in 0..10 {
+            let (tx, rx) = std::sync::mpsc::channel();
+            session.start(config.clone(), move |event| {
+                if let ReceiveEvent::Log(level, message) = event {
+                    let _ = tx.send((level, message));
+                }
+                ControlFlow::Continue(())
+            }).expect("start");
+            
+            // Up and listening, without a bind error
+            loop {
+                let (level, message) = rx.recv_timeout(Duration::from_secs(5)).expect("receiver log");
+                assert!(!matches!(level, LogLevel::Error), "round {}: {}", round, message);
+                if message == "RTP receiver ready" {
+                    break;
+                }
+            }
+            
+            // Joined: the socket is closed, so even a socket without SO_REUSEADDR can bind the port
+            session.stop();
+            assert!(!session.is_running());
+            drop(std::net::UdpSocket::bind(("0.0.0.0", port)).expect("port released"));
+        }
+    }
+
+    #[test]
+    fn capture_failure_still_ends_the_stream_cleanly() {
+        /// Source whose first frame works and every later one fails
+        struct FailingSource(bool);
+
+        impl FrameSource for FailingSource {
+            fn dimensions(&self) -> (u32, u32) {
+                (64, 64)
+            }
+
+            fn capture_frame(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
+                if std::mem::replace(&mut self.0, true) {
+                    return Err(BroadcastError::CaptureError("display gone".into()));
+                }
+                Ok(Some(vec![0; 64 * 64 * 3]))
+            }
+
+            fn last_capture_time(&self) -> Instant {
+                Instant::now()
+            }
+        }
+        
+        let port = std::net::UdpSocket::bind("0.0.0.0:0").and_then(|s| s.local_addr()).expect("free port").port();
+        let config = StreamConfig { port, fps: 60, ..StreamConfig::default() };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut session = BroadcastSession::new();
+        session.start_with_source(config, |_| Ok(Box::new(FailingSource(false))), move |event| {
+            if let BroadcastEvent::Log(level, message) = event {
+                let _ = tx.send((level, message));
+            }
+            ControlFlow::Continue(())
+        }).expect("start");
+        
+        // The encoder is flushed and the BYE sent before the error ends the session
+        let mut stopped = false;
+        loop {
+            let (level, message) = rx.recv_timeout(Duration::from_secs(10)).expect("teacher log");
+            if message == "Broadcasting stopped" {
+                stopped = true;
+            }
+            if matches!(level, LogLevel::Error) {
+                assert!(message.contains("display gone"), "{}", message);
+                break;
+            }
+        }
+        assert!(stopped, "the error skipped the shutdown");
+        session.stop();
+    }
+
+    #[test]
+    fn flush_sends_held_frames_once() {
+        /// Encoder with one frame of lookahead: each frame comes out on the next encode
+        struct LookaheadEncoder(Option<Vec<u8>>);
+
+        impl VideoEncoder for LookaheadEncoder {
+            fn encode(&mut self, rgb_data: &[u8]) -> Result<(Vec<u8>, bool), BroadcastError> {
+                let frame = [&[0, 0, 0, 1, 0x41][..], rgb_data].concat();
+                Ok((self.0.replace(frame).unwrap_or_default(), false))
+            }
+
+            fn set_rate_control(&mut self, _fps: u32, _rate_control: RateControl) -> Result<(), BroadcastError> {
+                Ok(())
+            }
+
+            fn force_keyframe(&mut self) {}
+
+            fn flush(&mut self) -> Result<Vec<u8>, BroadcastError> {
+                Ok(self.0.take().unwrap_or_default())
+            }
+
+            fn name(&self) -> &'static str {
+                "lookahead"
+            }
+        }
+        
+        let config = StreamConfig::default();
+        let mut receiver = RtpReceiver::new(&StreamConfig { port: 0, ..config.clone() }).expect("bind receiver");
+        let mut sender = RtpSender::new(&config).expect("open sender");
+        sender.set_target(([127, 0, 0, 1], receiver.local_addr().expect("receiver address").port()).into());
+        let mut events = Events::new(|_: BroadcastEvent| ControlFlow::Continue(()), Arc::new(AtomicBool::new(true)));
+        
+        let mut encoder = LookaheadEncoder(None);
+        for i in 1..=3u8 {
+            let (h264_data, _) = encoder.encode(&[i; 8]).expect("encode");
+            if !h264_data.is_empty() {
+                sender.send_frame(&h264_data).expect("send");
+            }
+        }
+        
+        // The third frame is still held until the flush sends it
+        flush_encoder(&mut encoder, &mut sender, &mut events);
+        let mut received = Vec::new();
+        let start = Instant::now();
+        while received.len() < 3 && start.elapsed() < Duration::from_secs(2) {
+            if let Some(frame) = receiver.receive_frame().expect("receive") {
+                received.push(frame[5..].to_vec());
+            }
+        }
+        assert_eq!(received, [[1; 8], [2; 8], [3; 8]]);
+        
+        // Nothing left: a second flush, or one of an encoder that holds nothing, sends nothing
+        flush_encoder(&mut encoder, &mut sender, &mut events);
+        let mut idle = MockEncoder(Arc::new(Mutex::new(EncoderSetup {
+            size: (64, 64),
+            fps: 30,
+            rate_control: RateControl::Bitrate(2000),
+            keyframe_forced: false,
+        })));
+        assert!(idle.flush().expect("flush").is_empty());
+        flush_encoder(&mut idle, &mut sender, &mut events);
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            assert_eq!(receiver.receive_frame().expect("receive"), None);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Frame sources the teacher pipeline can encode from: the screen, or generated frames
+//! for benchmarks and tests without a display
+
+use std::time::Instant;
+
+use super::capture::ScreenCapture;
+use super::types::{BroadcastError, CaptureRect};
+
+/// Something that produces packed RGB frames, what the teacher loop encodes from
+pub trait FrameSource {
+    fn dimensions(&self) -> (u32, u32);
+
+    /// Next frame as packed RGB, `Ok(None)` when none is ready yet
+    fn capture_frame(&mut self) -> Result<Option<Vec<u8>>, BroadcastError>;
+
+    /// When the last frame was captured
+    fn last_capture_time(&self) -> Instant;
+
+    /// False while the source cannot produce frames for now (display locked, asleep...)
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    /// New dimensions if the source changed size since the last call
+    fn check_display_change(&mut self) -> Result<Option<(u32, u32)>, BroadcastError> {
+        Ok(None)
+    }
+
+    /// Rate the caller will ask for frames at, for sources that pace themselves
+    fn set_fps(&mut self, _fps: u32) {}
+
+    /// Produce only this part of the frame (None = all of it); a new frame size is
+    /// reported by `check_display_change`
+    fn set_region(&mut self, _region: Option<CaptureRect>) {}
+}
+
+impl FrameSource for ScreenCapture {
+    fn dimensions(&self) -> (u32, u32) {
+        ScreenCapture::dimensions(self)
+    }
+
+    fn capture_frame(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
+        ScreenCapture::capture_frame(self)
+    }
+
+    fn last_capture_time(&self) -> Instant {
+        ScreenCapture::last_capture_time(self)
+    }
+
+    fn is_available(&self) -> bool {
+        self.is_display_available()
+    }
+
+    fn check_display_change(&mut self) -> Result<Option<(u32, u32)>, BroadcastError> {
+        ScreenCapture::check_display_change(self)
+    }
+
+    fn set_fps(&mut self, fps: u32) {
+        ScreenCapture::set_fps(self, fps)
+    }
+
+    fn set_region(&mut self, region: Option<CaptureRect>) {
+        ScreenCapture::set_region(self, region)
+    }
+}
+
+/// A diagonal gradient that moves a few pixels every frame, so each frame differs
+/// from the last like a scrolling screen
+pub struct SyntheticSource {
+    width: u32,
+    height: u32,
+    frame: u32,
+    last_capture: Instant,
+}
+
+/// Pixels the gradient moves per frame
+const SYNTHETIC_STEP: u32 = 4;
+
+impl SyntheticSource {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, frame: 0, last_capture: Instant::now() }
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn capture_frame(&mut self) -> Result<Option<Vec<u8>>, BroadcastError> {
+        let offset = self.frame.wrapping_mul(SYNTHETIC_STEP);
+        self.frame = self.frame.wrapping_add(1);
+        self.last_capture = Instant::now();
+
+        let mut rgb = Vec::with_capacity(self.width as usize * self.height as usize * 3);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let shifted = x.wrapping_add(offset);
+                rgb.extend_from_slice(&[shifted as u8, y as u8, shifted.wrapping_add(y) as u8]);
+            }
+        }
+        Ok(Some(rgb))
+    }
+
+    fn last_capture_time(&self) -> Instant {
+        self.last_capture
+    }
+}
@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv4Addr;
 use thiserror::Error;
 
+use super::capture::frame_hash;
+use super::rtp::{DEFAULT_MTU, MAX_MTU, MIN_MTU};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NetworkMode {
     Multicast,
@@ -13,12 +18,193 @@ impl Default for NetworkMode {
     }
 }
 
+/// Video codec implementation to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum CodecBackend {
+    #[default]
+    Software,
+    /// Platform hardware codec, falls back to software if unavailable
+    Hardware,
+}
+
+/// Highest bitrate accepted for `RateControl::Bitrate`, far above any LAN screen stream
+pub const MAX_BITRATE_KBPS: u32 = 200_000;
+
+/// How the encoder trades bandwidth for quality
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateControl {
+    /// Target bitrate in kbps, 0 = derived from resolution, fps and `quality`.
+    /// Predictable bandwidth, but static slides still use it all and motion gets blocky.
+    Bitrate(u32),
+    /// Constant quality at this QP (0-51, lower is better). Static slides cost almost
+    /// nothing and motion takes what it needs, so bandwidth peaks are not bounded.
+    Quality(u32),
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        RateControl::Bitrate(0)
+    }
+}
+
+impl fmt::Display for RateControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateControl::Bitrate(0) => write!(f, "auto bitrate"),
+            RateControl::Bitrate(kbps) => write!(f, "{} kbps", kbps),
+            RateControl::Quality(qp) => write!(f, "constant quality QP {}", qp),
+        }
+    }
+}
+
+/// H.264 profile of the encoded stream. Higher profiles compress better but need a
+/// decoder that supports them; ordered from most to least compatible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
+pub enum H264Profile {
+    #[default]
+    Baseline,
+    Main,
+    High,
+}
+
+/// YUV matrix of the RGB <-> YUV conversion. It is not signalled in the stream,
+/// so teacher and students must use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum ColorSpace {
+    /// SD matrix, the one openh264's own conversion uses
+    #[default]
+    Bt601,
+    /// HD matrix, correct colors for 720p content and up
+    Bt709,
+}
+
+impl ColorSpace {
+    /// RGB to Y, U and V rows in 8-bit fixed point (x256)
+    pub fn rgb_to_yuv(self, full_range: bool) -> [[i32; 3]; 3] {
+        match (self, full_range) {
+            (ColorSpace::Bt601, false) => [[66, 129, 25], [-38, -74, 112], [112, -94, -18]],
+            (ColorSpace::Bt709, false) => [[47, 157, 16], [-26, -86, 112], [112, -102, -10]],
+            (ColorSpace::Bt601, true) => [[77, 150, 29], [-43, -85, 128], [128, -107, -21]],
+            (ColorSpace::Bt709, true) => [[54, 183, 19], [-29, -99, 128], [128, -116, -12]],
+        }
+    }
+
+    /// YUV to RGB factors in 8-bit fixed point (x256):
+    /// Y scale, V to R, U to G, V to G, U to B
+    pub fn yuv_to_rgb(self, full_range: bool) -> [i32; 5] {
+        match (self, full_range) {
+            (ColorSpace::Bt601, false) => [298, 409, 100, 208, 516],
+            (ColorSpace::Bt709, false) => [298, 459, 55, 136, 541],
+            (ColorSpace::Bt601, true) => [256, 359, 88, 183, 454],
+            (ColorSpace::Bt709, true) => [256, 403, 48, 120, 475],
+        }
+    }
+}
+
+/// Offset of black in the Y plane: 16 in limited range (16-235), 0 in full range (0-255)
+pub fn luma_offset(full_range: bool) -> i32 {
+    if full_range { 0 } else { 16 }
+}
+
+/// Rectangle of the screen in pixels, origin at the top left of the display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CaptureRect {
+    /// The part of the rectangle that lies on a `width`x`height` display, `None` if none does.
+    /// Width and height round down to even, at least 2, for the encoder's 4:2:0 chroma; a
+    /// 1-pixel sliver at the display edge grows inwards to 2.
+    pub fn clamp_to(self, width: u32, height: u32) -> Option<CaptureRect> {
+        let clamp = |start: u32, len: u32, display: u32| {
+            let start = start.min(display);
+            let len = len.min(display - start);
+            if len == 0 || display < 2 {
+                return None;
+            }
+            let len = (len & !1).max(2);
+            Some((start.min(display - len), len))
+        };
+        let (x, width) = clamp(self.x, self.width, width)?;
+        let (y, height) = clamp(self.y, self.height, height)?;
+        Some(CaptureRect { x, y, width, height })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct StreamConfig {
     pub port: u16,
     pub fps: u32,
     pub quality: u32,
+    pub rate_control: RateControl,
     pub network_mode: NetworkMode,
+    /// Modes the student tries, in order, when no frame arrives in the current one
+    pub fallback_modes: Vec<NetworkMode>,
+    /// How long the student waits for a first frame before falling back
+    pub fallback_timeout_ms: u64,
+    /// How long the student goes without a decoded frame, once the stream has
+    /// started, before reporting it stalled; 0 disables the check
+    pub stall_timeout_ms: u64,
+    /// Student: skip forwarding a decoded frame to the UI when it is identical to the
+    /// previous one, apart from a slow heartbeat
+    pub dedupe_frames: bool,
+    /// Student: scale frames down to fit this size before sending them to the UI
+    /// (None = decoded size); the native viewer scales at render time instead
+    pub display_width: Option<u32>,
+    pub display_height: Option<u32>,
+    /// Skip frames the screen capturer buffered and encode the newest, for capturing
+    /// well below the display refresh rate
+    pub drain_stale: bool,
+    /// Teacher: capture only this part of the screen, clamped to the display (None = all of it)
+    pub capture_region: Option<CaptureRect>,
+    /// Send frames only when the screen changes, stamped with their capture time.
+    /// `fps` becomes the upper bound.
+    pub variable_framerate: bool,
+    /// File playback: start over at the end of the file instead of stopping
+    pub playback_loop: bool,
+    /// Record frame send (teacher) and arrival (student) intervals for the stats
+    pub diagnostics: bool,
+    /// Send an XOR parity packet per group of `fec_group_size` packets
+    pub fec: bool,
+    pub fec_group_size: u32,
+    /// Highest temporal layer a student decodes (None = all, Some(0) = base layer only).
+    /// Receiver-only: drops the upper layers of streams from encoders that make them
+    pub max_temporal_layer: Option<u8>,
+    /// Spread each frame's packets over the frame interval (off for lowest latency)
+    pub pacing: bool,
+    /// Start every frame with an access unit delimiter, a second frame boundary
+    /// for students besides the RTP marker bit
+    pub insert_aud: bool,
+    /// Step bitrate, then resolution, then frame rate down while students report
+    /// sustained packet loss, and back up once it clears
+    pub adaptive: bool,
+    /// Socket buffer sizes; raise them for high-bitrate streams
+    pub send_buffer_bytes: usize,
+    pub recv_buffer_bytes: usize,
+    /// Path MTU in bytes; lower it on VPNs, raise it on jumbo frame networks
+    pub mtu: usize,
+    /// Local interface address to send from and join multicast on (None = OS default)
+    pub interface: Option<Ipv4Addr>,
+    /// Only accept stream packets sent from this address (students watching one teacher)
+    pub source: Option<Ipv4Addr>,
+    pub encoder_backend: CodecBackend,
+    pub profile: H264Profile,
+    /// Encode at most this size; a larger capture is scaled down to fit (None = capture size)
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Bands the software encoder converts RGB to YUV in, in parallel
+    /// (0 = auto: one per core from 900p up, 1 = serial)
+    pub conversion_threads: u32,
+    pub decoder_backend: CodecBackend,
+    pub color_space: ColorSpace,
+    /// Full range (0-255) instead of limited range (16-235) YUV. Like `color_space` it is
+    /// not signalled in the stream, so teacher and students must agree.
+    pub full_range: bool,
 }
 
 impl Default for StreamConfig {
@@ -27,8 +213,88 @@ impl Default for StreamConfig {
             port: 5000,
             fps: 15,
             quality: 28,
+            rate_control: RateControl::Bitrate(0),
             network_mode: NetworkMode::Broadcast,
+            fallback_modes: vec![NetworkMode::Broadcast],
+            fallback_timeout_ms: 3000,
+            stall_timeout_ms: 3000,
+            dedupe_frames: false,
+            display_width: None,
+            display_height: None,
+            drain_stale: false,
+            capture_region: None,
+            variable_framerate: false,
+            playback_loop: false,
+            diagnostics: false,
+            fec: false,
+            fec_group_size: 8,
+            max_temporal_layer: None,
+            pacing: false,
+            insert_aud: false,
+            adaptive: false,
+            send_buffer_bytes: 2 * 1024 * 1024,
+            recv_buffer_bytes: 4 * 1024 * 1024,
+            mtu: DEFAULT_MTU,
+            interface: None,
+            source: None,
+            encoder_backend: CodecBackend::Software,
+            profile: H264Profile::Baseline,
+            max_width: None,
+            max_height: None,
+            conversion_threads: 0,
+            decoder_backend: CodecBackend::Software,
+            color_space: ColorSpace::Bt601,
+            full_range: false,
+        }
+    }
+}
+
+impl StreamConfig {
+    /// Check value ranges before starting a stream
+    pub fn validate(&self) -> Result<(), BroadcastError> {
+        if !(1..=120).contains(&self.fps) {
+            return Err(BroadcastError::ConfigError(format!("fps must be between 1 and 120, got {}", self.fps)));
+        }
+        if self.quality > 51 {
+            return Err(BroadcastError::ConfigError(format!("quality must be between 0 and 51, got {}", self.quality)));
+        }
+        match self.rate_control {
+            RateControl::Quality(qp) if qp > 51 => {
+                return Err(BroadcastError::ConfigError(format!("rate_control QP must be between 0 and 51, got {}", qp)));
+            }
+            RateControl::Bitrate(kbps) if kbps > MAX_BITRATE_KBPS => {
+                return Err(BroadcastError::ConfigError(format!(
+                    "rate_control bitrate must be at most {} kbps, got {}", MAX_BITRATE_KBPS, kbps)));
+            }
+            _ => {}
+        }
+        if self.port == 0 {
+            return Err(BroadcastError::ConfigError("port must be between 1 and 65535, got 0".into()));
         }
+        if self.fec && !(2..=255).contains(&self.fec_group_size) {
+            return Err(BroadcastError::ConfigError(format!(
+                "fec_group_size must be between 2 and 255, got {}", self.fec_group_size)));
+        }
+        if self.max_width == Some(0) || self.max_height == Some(0) {
+            return Err(BroadcastError::ConfigError("max_width and max_height must be greater than 0".into()));
+        }
+        if self.display_width == Some(0) || self.display_height == Some(0) {
+            return Err(BroadcastError::ConfigError("display_width and display_height must be greater than 0".into()));
+        }
+        if self.capture_region.is_some_and(|r| r.width == 0 || r.height == 0) {
+            return Err(BroadcastError::ConfigError("capture_region width and height must be greater than 0".into()));
+        }
+        if self.send_buffer_bytes == 0 {
+            return Err(BroadcastError::ConfigError("send_buffer_bytes must be greater than 0".into()));
+        }
+        if self.recv_buffer_bytes == 0 {
+            return Err(BroadcastError::ConfigError("recv_buffer_bytes must be greater than 0".into()));
+        }
+        if !(MIN_MTU..=MAX_MTU).contains(&self.mtu) {
+            return Err(BroadcastError::ConfigError(format!(
+                "mtu must be between {} and {}, got {}", MIN_MTU, MAX_MTU, self.mtu)));
+        }
+        Ok(())
     }
 }
 
@@ -39,7 +305,27 @@ pub struct StreamStats {
     pub frame_count: u64,
     pub packets_sent: u64,
     pub packets_lost: u64,
+    /// Capture to send complete, the sum of the three stage times below
     pub latency_ms: f32,
+    /// CPU usage of this process in percent (0..=100 per core)
+    #[serde(default)]
+    pub cpu_usage: f32,
+    /// GPU busy percent, where the platform reports it (whole GPU, not just this process)
+    #[serde(default)]
+    pub gpu_usage: Option<f32>,
+    /// Average time per frame spent in screen capture, encoding and sending
+    #[serde(default)]
+    pub capture_ms: f32,
+    #[serde(default)]
+    pub encode_ms: f32,
+    #[serde(default)]
+    pub send_ms: f32,
+    /// Frame slots skipped in the last second because encoding and sending fell behind
+    #[serde(default)]
+    pub dropped_frames: u64,
+    /// Gaps between frames going out over the last second, with `diagnostics` on
+    #[serde(default)]
+    pub send_intervals: Option<IntervalStats>,
 }
 
 impl Default for StreamStats {
@@ -51,10 +337,92 @@ impl Default for StreamStats {
             packets_sent: 0,
             packets_lost: 0,
             latency_ms: 0.0,
+            cpu_usage: 0.0,
+            gpu_usage: None,
+            capture_ms: 0.0,
+            encode_ms: 0.0,
+            send_ms: 0.0,
+            dropped_frames: 0,
+            send_intervals: None,
         }
     }
 }
 
+/// Spread of the gaps between consecutive frames, in milliseconds.
+/// Steady send intervals with spread out arrivals point to the network; uneven send
+/// intervals point to capture or encode stalls on the teacher.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct IntervalStats {
+    pub count: u64,
+    pub min_ms: f32,
+    pub max_ms: f32,
+    pub mean_ms: f32,
+    pub stddev_ms: f32,
+}
+
+/// A decoded picture, as every receive path hands it on
+#[derive(Clone)]
+pub struct DecodedFrame {
+    pub rgba_data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// RTP timestamp (90 kHz) of the access unit, set by the receiver; 0 when unknown
+    pub timestamp: u32,
+    /// Decoded from an IDR (or SPS-led) access unit, a point decoding can start from
+    pub is_keyframe: bool,
+}
+
+impl DecodedFrame {
+    /// Exact hash of the decoded RGBA content
+    pub fn content_hash(&self) -> u64 {
+        frame_hash(&self.rgba_data)
+    }
+}
+
+/// Reception statistics of a student, emitted as "student-stats"
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StudentStats {
+    pub fps: f32,
+    pub frames_decoded: u64,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub packets_recovered: u64,
+    pub jitter_ms: f32,
+    /// Teacher capture to student decode, averaged over the last second.
+    /// Compares two machines' wall clocks, so it is only absolute if they are synced (NTP);
+    /// otherwise it includes the clock offset and is only meaningful relative to itself.
+    pub latency_ms: f32,
+    /// Gaps between assembled frames over the last second, with `diagnostics` on
+    #[serde(default)]
+    pub arrival_intervals: Option<IntervalStats>,
+}
+
+/// Native viewer statistics, all streams together
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ViewerStats {
+    /// Frames decoded per second over the last interval
+    pub decoded_fps: f32,
+    /// Frames drawn to the window per second over the last interval
+    pub rendered_fps: f32,
+    pub frames_decoded: u64,
+    /// Decoded frames dropped because the window had not taken the previous ones yet
+    pub dropped_frames: u64,
+    /// Size of the latest frame of the first stream showing one
+    pub width: u32,
+    pub height: u32,
+    /// Frames waiting in the jitter buffers
+    pub buffered_frames: usize,
+}
+
+/// Severity of a log message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
 #[derive(Error, Debug)]
 pub enum BroadcastError {
     #[error("Screen capture error: {0}")]
@@ -71,6 +439,9 @@ pub enum BroadcastError {
     
     #[error("Configuration error: {0}")]
     ConfigError(String),
+    
+    #[error("Viewer error: {0}")]
+    ViewerError(String),
 }
 
 impl From<std::io::Error> for BroadcastError {
@@ -1,36 +1,216 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, Receiver, Sender};
 use parking_lot::Mutex;
-use once_cell::sync::Lazy;
-use tauri::{AppHandle, Emitter};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+use tauri::ipc::{Channel, InvokeResponseBody};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 
 use crate::broadcast::{
-    StreamConfig, StreamStats, BroadcastError,
-    ScreenCapture, H264Encoder, H264Decoder,
-    RtpSender, RtpReceiver,
-    DiscoveryService, PeerInfo, PeerRole,
+    StreamConfig, CaptureRect, LogLevel, NetworkMode, ViewerStats,
+    BroadcastSession, BroadcastEvent, ReceiveSession, ReceiveEvent, SessionId, SessionManager,
+    DiscoveryConfig, DiscoveryService, PeerInfo, PeerRole, RttSample,
     NativeViewer,
 };
+use crate::broadcast::capture::scale_rgba;
+use crate::broadcast::network::{self, NetworkInterface};
+use crate::broadcast::selftest::{self, SelfTestReport};
+use crate::broadcast::session::{self, DEFAULT_SESSION};
 
 // Global state
-static TEACHER_RUNNING: Lazy<Arc<Mutex<bool>>> = Lazy::new(|| Arc::new(Mutex::new(false)));
-static STUDENT_RUNNING: Lazy<Arc<Mutex<bool>>> = Lazy::new(|| Arc::new(Mutex::new(false)));
+static TEACHERS: Lazy<SessionManager<BroadcastSession>> = Lazy::new(SessionManager::new);
+static STUDENTS: Lazy<SessionManager<ReceiveSession>> = Lazy::new(SessionManager::new);
 static NATIVE_VIEWER: Lazy<Arc<Mutex<Option<NativeViewer>>>> = Lazy::new(|| Arc::new(Mutex::new(None)));
 static DISCOVERY: Lazy<Arc<Mutex<Option<DiscoveryService>>>> = Lazy::new(|| Arc::new(Mutex::new(None)));
-static LOGS: Lazy<Arc<Mutex<Vec<String>>>> = Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
+static LOGS: Lazy<Mutex<VecDeque<LogRecord>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+/// Maximum number of log records kept; the oldest are dropped first
+static LOG_CAPACITY: AtomicUsize = AtomicUsize::new(1000);
+/// Mirror of the log in a file, set by `set_log_file`
+static LOG_FILE: Lazy<Mutex<Option<LogFile>>> = Lazy::new(|| Mutex::new(None));
+/// Lines queued for the log file writer; further lines are dropped rather than block the caller
+const LOG_FILE_BACKLOG: usize = 4096;
+/// Rotated log files kept next to the current one (.1 newest, .2 oldest)
+const LOG_FILE_KEEP: usize = 2;
+const DEFAULT_LOG_FILE_BYTES: u64 = 5 * 1024 * 1024;
+/// Set once at startup so log records can be pushed to the frontend as they happen
+static APP_HANDLE: OnceCell<AppHandle> = OnceCell::new();
+/// How often a student watching one teacher checks that it is still discovered
+const TEACHER_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// With `dedupe_frames`, an unchanged frame is still forwarded this often
+const DEDUPE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Known peers file in the app data directory
+const PEER_CACHE_FILE: &str = "known_peers.json";
+/// Our own discovery id, kept with the peer cache
+const PEER_ID_FILE: &str = "peer_id";
 
-fn log_msg(msg: &str) {
-    let timestamp = chrono::Local::now().format("%H:%M:%S").to_string();
-    let log_entry = format!("[{}] {}", timestamp, msg);
-    log::info!("{}", msg);
+/// One log entry, also emitted as a "log-record" event when it is added
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    /// Local time, HH:MM:SS
+    pub timestamp: String,
+    pub level: LogLevel,
+    /// Subsystem that logged it: app, discovery, teacher, student or viewer
+    pub category: &'static str,
+    pub message: String,
+}
+
+impl LogRecord {
+    /// The "[HH:MM:SS] message" form used before records were structured
+    fn to_text(&self) -> String {
+        format!("[{}] {}", self.timestamp, self.message)
+    }
+}
+
+/// Register the app handle used to emit "log-record" events
+pub fn init_log_events(app: AppHandle) {
+    let _ = APP_HANDLE.set(app);
+}
+
+fn log_record(level: LogLevel, category: &'static str, msg: &str) {
+    match level {
+        LogLevel::Info => log::info!("[{}] {}", category, msg),
+        LogLevel::Warn => log::warn!("[{}] {}", category, msg),
+        LogLevel::Error => log::error!("[{}] {}", category, msg),
+    }
+    
+    let record = LogRecord {
+        timestamp: chrono::Local::now().format("%H:%M:%S").to_string(),
+        level,
+        category,
+        message: msg.to_string(),
+    };
+    
+    {
+        let mut logs = LOGS.lock();
+        logs.push_back(record.clone());
+        trim_logs(&mut logs, LOG_CAPACITY.load(Ordering::Relaxed));
+    }
+    
+    if let Some(file) = LOG_FILE.lock().as_ref() {
+        let line = format!(
+            "{} {:?} [{}] {}\n",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+            record.level,
+            record.category,
+            record.message,
+        );
+        let _ = file.lines.try_send(line);
+    }
+    
+    if let Some(app) = APP_HANDLE.get() {
+        let _ = app.emit("log-record", &record);
+    }
+}
+
+/// Drop the oldest records until at most `capacity` are left
+fn trim_logs(logs: &mut VecDeque<LogRecord>, capacity: usize) {
+    while logs.len() > capacity {
+        logs.pop_front();
+    }
+}
+
+/// Background writer for the log file, so logging from the capture and send threads never
+/// waits on the disk
+struct LogFile {
+    lines: Sender<String>,
+    writer: JoinHandle<()>,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_bytes: u64) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = open_append(&path)?;
+        let (lines, rx) = bounded(LOG_FILE_BACKLOG);
+        let writer = thread::Builder::new()
+            .name("log-file".into())
+            .spawn(move || write_log_file(path, max_bytes, file, rx))?;
+        Ok(Self { lines, writer })
+    }
+
+    /// Write out what is queued and stop the writer
+    fn close(self) {
+        drop(self.lines);
+        let _ = self.writer.join();
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `path` with ".n" appended, e.g. screenshare.log.1
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Shift path -> path.1 -> path.2 ..., dropping the oldest
+fn rotate_log_files(path: &Path) -> std::io::Result<()> {
+    for n in (1..LOG_FILE_KEEP).rev() {
+        let from = rotated_path(path, n);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+fn write_log_file(path: PathBuf, max_bytes: u64, file: File, lines: Receiver<String>) {
+    let mut size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let mut out = BufWriter::new(file);
     
-    let mut logs = LOGS.lock();
-    logs.push(log_entry);
-    if logs.len() > 100 {
-        logs.remove(0);
+    for line in lines.iter() {
+        if size > 0 && size + line.len() as u64 > max_bytes {
+            let _ = out.flush();
+            let reopened = rotate_log_files(&path).and_then(|_| open_append(&path));
+            match reopened {
+                Ok(file) => {
+                    out = BufWriter::new(file);
+                    size = 0;
+                }
+                Err(e) => {
+                    log::warn!("Log file rotation failed, logging to {} stopped: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        
+        if let Err(e) = out.write_all(line.as_bytes()) {
+            log::warn!("Writing {} failed, logging to it stopped: {}", path.display(), e);
+            return;
+        }
+        size += line.len() as u64;
+        // Flush once the queue is drained, so a crash loses at most the latest burst
+        if lines.is_empty() {
+            let _ = out.flush();
+        }
     }
+    let _ = out.flush();
+}
+
+fn log_info(category: &'static str, msg: &str) {
+    log_record(LogLevel::Info, category, msg);
+}
+
+fn log_warn(category: &'static str, msg: &str) {
+    log_record(LogLevel::Warn, category, msg);
+}
+
+fn log_error(category: &'static str, msg: &str) {
+    log_record(LogLevel::Error, category, msg);
 }
 
 // ============ Config Commands ============
@@ -41,8 +221,31 @@ pub fn get_default_config() -> StreamConfig {
 }
 
 #[tauri::command]
-pub fn get_logs() -> Vec<String> {
-    LOGS.lock().clone()
+pub fn list_interfaces() -> Result<Vec<NetworkInterface>, String> {
+    network::list_interfaces().map_err(|e| e.to_string())
+}
+
+/// Stored log records, oldest first
+#[tauri::command]
+pub fn get_logs() -> Vec<LogRecord> {
+    LOGS.lock().iter().cloned().collect()
+}
+
+/// Stored logs as "[HH:MM:SS] message" lines, oldest first
+#[tauri::command]
+pub fn get_logs_text() -> Vec<String> {
+    LOGS.lock().iter().map(LogRecord::to_text).collect()
+}
+
+/// Change how many log records are kept, dropping the oldest if over the new size
+#[tauri::command]
+pub fn set_log_capacity(capacity: usize) -> Result<(), String> {
+    if capacity == 0 {
+        return Err("log capacity must be greater than 0".into());
+    }
+    LOG_CAPACITY.store(capacity, Ordering::Relaxed);
+    trim_logs(&mut LOGS.lock(), capacity);
+    Ok(())
 }
 
 #[tauri::command]
@@ -50,28 +253,130 @@ pub fn clear_logs() {
     LOGS.lock().clear();
 }
 
+/// Mirror the log to `path` (relative to the app data directory), starting a new file
+/// past `max_bytes` and keeping the last two as .1 and .2. `None` stops writing it.
+#[tauri::command]
+pub fn set_log_file(app: AppHandle, path: Option<String>, max_bytes: Option<u64>) -> Result<(), String> {
+    let max_bytes = max_bytes.unwrap_or(DEFAULT_LOG_FILE_BYTES);
+    if max_bytes == 0 {
+        return Err("max_bytes must be greater than 0".into());
+    }
+    
+    let previous = LOG_FILE.lock().take();
+    if let Some(previous) = previous {
+        previous.close();
+    }
+    let Some(path) = path else {
+        log_info("app", "Log file disabled");
+        return Ok(());
+    };
+    
+    let path = PathBuf::from(path);
+    let path = if path.is_absolute() {
+        path
+    } else {
+        app.path().app_data_dir()
+            .map_err(|e| format!("No app data directory for the log file: {}", e))?
+            .join(path)
+    };
+    let file = LogFile::open(path.clone(), max_bytes)
+        .map_err(|e| format!("Cannot open log file {}: {}", path.display(), e))?;
+    *LOG_FILE.lock() = Some(file);
+    log_info("app", &format!("Logging to {} (rotated at {} bytes)", path.display(), max_bytes));
+    Ok(())
+}
+
+/// Enable frame hash logging for cross-machine pipeline verification.
+/// Frames are sampled by their H.264 hash, so teacher and students log the same frames.
+#[tauri::command]
+pub fn set_frame_hash_logging(every_n: u32) {
+    session::set_frame_hash_sampling(every_n);
+    if every_n == 0 {
+        log_info("app", "Frame hash logging disabled");
+    } else {
+        log_info("app", &format!("Frame hash logging enabled: ~1 in {} frames", every_n));
+    }
+}
+
+/// Run the whole pipeline once over loopback and report each stage
+#[tauri::command]
+pub async fn run_self_test() -> Result<SelfTestReport, String> {
+    log_info("app", "Running self-test...");
+    let report = tauri::async_runtime::spawn_blocking(selftest::run_self_test)
+        .await
+        .map_err(|e| e.to_string())?;
+    for line in report.to_text().lines() {
+        log_info("app", line);
+    }
+    Ok(report)
+}
+
 // ============ Discovery Commands ============
 
+/// Optional `start_discovery` settings
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryOptions {
+    /// Largest stream a student decodes smoothly
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_fps: Option<u32>,
+    /// Keep the peer list across restarts, shown stale until re-confirmed
+    pub remember_peers: bool,
+    /// Override the default discovery timing
+    pub announce_interval_ms: Option<u64>,
+    pub peer_timeout_ms: Option<u64>,
+    /// Listened on if the discovery port cannot be bound
+    pub fallback_port: Option<u16>,
+}
+
+/// Start announcing this device. Students may pass decode limits in `options`;
+/// teachers started afterwards stay within every student's limits.
 #[tauri::command]
-pub fn start_discovery(name: String, is_teacher: bool, port: u16) -> Result<(), String> {
+pub fn start_discovery(
+    app: AppHandle,
+    name: String,
+    is_teacher: bool,
+    port: u16,
+    options: Option<DiscoveryOptions>,
+) -> Result<(), String> {
+    let options = options.unwrap_or_default();
     let role = if is_teacher { PeerRole::Teacher } else { PeerRole::Student };
+    let defaults = DiscoveryConfig::default();
+    let discovery_config = DiscoveryConfig {
+        announce_interval: options.announce_interval_ms.map_or(defaults.announce_interval, Duration::from_millis),
+        peer_timeout: options.peer_timeout_ms.map_or(defaults.peer_timeout, Duration::from_millis),
+        fallback_port: options.fallback_port,
+        ..defaults
+    };
     
-    let service = DiscoveryService::new(&name, role, port)
-        .map_err(|e| format!("Failed to start discovery: {}", e))?;
+    let mut service = DiscoveryService::new(&name, role, port, discovery_config)
+        .map_err(|e| format!("Failed to start discovery: {}", e))?
+        .with_decode_limits(options.max_width, options.max_height, options.max_fps);
+    if options.remember_peers {
+        match app.path().app_data_dir() {
+            Ok(dir) => {
+                service = service
+                    .with_persistent_id(&dir.join(PEER_ID_FILE))
+                    .with_peer_cache(dir.join(PEER_CACHE_FILE));
+            }
+            Err(e) => log_warn("discovery", &format!("No app data directory for the peer cache: {}", e)),
+        }
+    }
     
     service.start().map_err(|e| e.to_string())?;
     
     *DISCOVERY.lock() = Some(service);
-    log_msg(&format!("Discovery started as {:?}: {}", role, name));
+    log_info("discovery", &format!("Discovery started as {:?}: {}", role, name));
     
     Ok(())
 }
 
 #[tauri::command]
 pub fn stop_discovery() {
-    if let Some(service) = DISCOVERY.lock().take() {
+    if let Some(mut service) = DISCOVERY.lock().take() {
         service.stop();
-        log_msg("Discovery stopped");
+        log_info("discovery", "Discovery stopped");
     }
 }
 
@@ -94,19 +399,29 @@ pub fn discovery_query() -> Result<(), String> {
 #[tauri::command]
 pub fn get_discovered_peers() -> Vec<PeerInfo> {
     if let Some(ref service) = *DISCOVERY.lock() {
-        // Process any pending messages
-        while let Ok(Some(peer)) = service.process() {
-            log_msg(&format!("Discovered: {} ({:?}) at {}", peer.name, peer.role, peer.ip));
-        }
         return service.get_peers();
     }
     Vec::new()
 }
 
+/// Send a ping to a peer; read the result later with `get_peer_rtt`
+#[tauri::command]
+pub fn discovery_ping(peer_id: String) -> Result<(), String> {
+    if let Some(ref service) = *DISCOVERY.lock() {
+        service.ping(&peer_id).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_peer_rtt(peer_id: String) -> Option<RttSample> {
+    let guard = DISCOVERY.lock();
+    guard.as_ref()?.rtt_sample(&peer_id)
+}
+
 #[tauri::command]
 pub fn get_teachers() -> Vec<PeerInfo> {
     if let Some(ref service) = *DISCOVERY.lock() {
-        while let Ok(Some(_)) = service.process() {}
         return service.get_teachers();
     }
     Vec::new()
@@ -114,332 +429,399 @@ pub fn get_teachers() -> Vec<PeerInfo> {
 
 // ============ Teacher Commands ============
 
+// Teacher and student commands take an optional session id so several sessions can run at
+// once; without one they all act on `DEFAULT_SESSION`.
+
+/// Start broadcasting, returns the session id
 #[tauri::command]
-pub async fn start_teacher(app: AppHandle, config: StreamConfig) -> Result<(), String> {
-    if *TEACHER_RUNNING.lock() {
-        return Err("Already broadcasting".into());
-    }
-    
-    *TEACHER_RUNNING.lock() = true;
+pub async fn start_teacher(app: AppHandle, mut config: StreamConfig, session_id: Option<SessionId>) -> Result<SessionId, String> {
+    let id = session_id.unwrap_or(DEFAULT_SESSION);
     
-    let running = TEACHER_RUNNING.clone();
+    // Stay within what every discovered student can decode
+    let limits = DISCOVERY.lock().as_ref().map(|d| d.student_limits()).unwrap_or_default();
+    if let Some(profile) = limits.max_profile.filter(|p| *p < config.profile) {
+        log_warn("teacher", &format!("A student only decodes {:?}, using it instead of {:?}", profile, config.profile));
+        config.profile = profile;
+    }
+    if let Some(fps) = limits.max_fps.filter(|fps| *fps < config.fps) {
+        log_warn("teacher", &format!("A student decodes at most {} fps, capping {} fps", fps, config.fps));
+        config.fps = fps.max(1);
+    }
+    config.max_width = min_limit(config.max_width, limits.max_width);
+    config.max_height = min_limit(config.max_height, limits.max_height);
     
-    thread::spawn(move || {
-        if let Err(e) = run_teacher(running, config, app) {
-            log_msg(&format!("Teacher error: {}", e));
+    TEACHERS.with(id, |teacher| {
+        if teacher.is_running() {
+            return Err("Already broadcasting".into());
         }
-    });
-    
-    Ok(())
+        let port = config.port;
+        teacher.start(config, teacher_events(app, id, port)).map_err(|e| e.to_string())
+    })?;
+    Ok(id)
 }
 
-fn run_teacher(running: Arc<Mutex<bool>>, config: StreamConfig, app: AppHandle) -> Result<(), BroadcastError> {
-    log_msg(&format!("Starting teacher: {:?} mode, port {}, {} fps", 
-        config.network_mode, config.port, config.fps));
-    
-    // Initialize capture
-    log_msg("Initializing screen capture...");
-    let mut capture = ScreenCapture::new(config.fps)?;
-    let (width, height) = capture.dimensions();
-    log_msg(&format!("Screen: {}x{}", width, height));
-    
-    // Test capture immediately
-    log_msg("Testing capture...");
-    let mut test_attempts = 0;
-    let mut test_success = false;
-    while test_attempts < 10 && !test_success {
-        match capture.capture_frame() {
-            Ok(Some(rgb_data)) => {
-                log_msg(&format!("Test capture OK: {} bytes RGB data", rgb_data.len()));
-                test_success = true;
-            }
-            Ok(None) => {
-                test_attempts += 1;
-                thread::sleep(Duration::from_millis(100));
-            }
-            Err(e) => {
-                log_msg(&format!("Test capture failed: {}", e));
-                return Err(e);
-            }
-        }
-    }
+/// Broadcast a recorded H.264 file (.h264, or .mp4/.m4v/.mov) as it is, without re-encoding.
+/// `config` provides the network settings and `playback_loop`, defaults if omitted.
+#[tauri::command]
+pub async fn start_teacher_from_file(
+    app: AppHandle,
+    path: String,
+    config: Option<StreamConfig>,
+    session_id: Option<SessionId>,
+) -> Result<SessionId, String> {
+    let id = session_id.unwrap_or(DEFAULT_SESSION);
+    let config = config.unwrap_or_default();
+    log_info("teacher", &format!("Playing {}", path));
     
-    if !test_success {
-        log_msg("Warning: Could not capture test frame after 10 attempts");
+    TEACHERS.with(id, |teacher| {
+        if teacher.is_running() {
+            return Err("Already broadcasting".into());
+        }
+        let port = config.port;
+        teacher.start_playback(config, path.into(), teacher_events(app, id, port)).map_err(|e| e.to_string())
+    })?;
+    Ok(id)
+}
+
+/// The tighter of two optional limits
+fn min_limit(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
     }
-    
-    // Initialize encoder
-    let bitrate = calculate_bitrate(width, height, config.fps, config.quality);
-    log_msg(&format!("Initializing encoder: {}x{} @ {} kbps", width, height, bitrate));
-    let mut encoder = H264Encoder::new(width, height, config.fps, bitrate)?;
-    log_msg(&format!("Encoder ready: {} kbps", bitrate));
-    
-    // Initialize RTP sender
-    log_msg(&format!("Initializing RTP sender: {:?} mode, port {}", config.network_mode, config.port));
-    let mut sender = RtpSender::new(config.port, config.network_mode)?;
-    log_msg("RTP sender ready");
-    
-    let frame_interval = Duration::from_millis(1000 / config.fps as u64);
-    let mut last_stats = Instant::now();
-    let mut frames = 0u64;
-    let mut bytes = 0u64;
-    let mut capture_errors = 0u64;
-    let mut encode_errors = 0u64;
-    let mut no_frame_count = 0u64;
-    let start_time = Instant::now();
-    
-    log_msg("Broadcasting started!");
-    log_msg(&format!("Target: {} fps ({:?} interval)", config.fps, frame_interval));
-    
-    while *running.lock() {
-        let frame_start = Instant::now();
-        
-        // Capture
-        match capture.capture_frame() {
-            Ok(Some(rgb_data)) => {
-                no_frame_count = 0;
-                
-                // Encode
-                match encoder.encode(&rgb_data) {
-                    Ok((h264_data, is_keyframe)) => {
-                        if h264_data.is_empty() {
-                            // Encoder skipped frame
-                        } else {
-                            // Send via RTP
-                            let timestamp_ms = start_time.elapsed().as_millis() as u32;
-                            match sender.send_frame(&h264_data, timestamp_ms) {
-                                Ok(sent) => {
-                                    frames += 1;
-                                    bytes += sent as u64;
-                                    
-                                    // Log first few frames
-                                    if frames <= 3 || is_keyframe {
-                                        log_msg(&format!("Sent frame {}: {} bytes H264, {} bytes UDP, keyframe={}", 
-                                            frames, h264_data.len(), sent, is_keyframe));
-                                    }
-                                }
-                                Err(e) => {
-                                    log_msg(&format!("Send error: {}", e));
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        encode_errors += 1;
-                        if encode_errors <= 5 {
-                            log_msg(&format!("Encode error #{}: {}", encode_errors, e));
-                        }
-                    }
+}
+
+/// Forward a teacher session's events to the log and the frontend,
+/// and students' loss reports for `port` back to the session
+fn teacher_events(app: AppHandle, id: SessionId, port: u16) -> impl FnMut(BroadcastEvent) -> ControlFlow<()> + Send + 'static {
+    move |event| {
+        match event {
+            BroadcastEvent::Log(level, msg) => log_record(level, "teacher", &msg),
+            BroadcastEvent::Stats(stats) => {
+                let _ = app.emit("stream-stats", &stats);
+                // try_lock: never stall the broadcast loop behind a discovery poll
+                let loss = DISCOVERY.try_lock().and_then(|d| d.as_ref()?.take_loss_report(port));
+                if let Some(loss) = loss {
+                    TEACHERS.get(id, |teacher| teacher.report_loss(loss));
                 }
             }
-            Ok(None) => {
-                // No frame ready yet
-                no_frame_count += 1;
+            BroadcastEvent::Paused(paused) => {
+                let _ = app.emit("teacher-paused", paused);
             }
-            Err(e) => {
-                capture_errors += 1;
-                if capture_errors <= 5 {
-                    log_msg(&format!("Capture error #{}: {}", capture_errors, e));
-                }
+            BroadcastEvent::Reconnecting(reconnecting) => {
+                let _ = app.emit("teacher-reconnecting", reconnecting);
+            }
+            BroadcastEvent::FrameSent { .. } => {}
+            BroadcastEvent::PlaybackEnded => {
+                let _ = app.emit("playback-ended", id);
             }
         }
-        
-        // Stats every second
-        if last_stats.elapsed() >= Duration::from_secs(1) {
-            let elapsed = last_stats.elapsed().as_secs_f32();
-            let actual_fps = frames as f32 / elapsed;
-            let stats = StreamStats {
-                fps: actual_fps,
-                bitrate_kbps: (bytes as f32 * 8.0 / 1000.0) / elapsed,
-                frame_count: sender.frame_count(),
-                packets_sent: 0,
-                packets_lost: 0,
-                latency_ms: frame_start.elapsed().as_secs_f32() * 1000.0,
-            };
-            
-            let _ = app.emit("stream-stats", &stats);
-            
-            // Log stats
-            log_msg(&format!("Stats: {} fps (target {}), {} kbps, sent={}, no_frame={}", 
-                actual_fps as u32, config.fps, stats.bitrate_kbps as u32, frames, no_frame_count));
-            
-            frames = 0;
-            bytes = 0;
-            no_frame_count = 0;
-            last_stats = Instant::now();
-        }
-        
-        // Frame rate control - sleep to maintain target FPS
-        let elapsed = frame_start.elapsed();
-        if elapsed < frame_interval {
-            thread::sleep(frame_interval - elapsed);
-        } else {
-            // Running behind, yield briefly
-            thread::sleep(Duration::from_micros(100));
-        }
+        ControlFlow::Continue(())
     }
-    
-    log_msg("Broadcasting stopped");
-    Ok(())
+}
+
+/// Stop sending frames without tearing down capture, encoder or discovery
+#[tauri::command]
+pub fn pause_teacher(session_id: Option<SessionId>) {
+    TEACHERS.get(session_id.unwrap_or(DEFAULT_SESSION), |teacher| teacher.pause());
+}
+
+/// Resume a paused broadcast, starting with a keyframe
+#[tauri::command]
+pub fn resume_teacher(session_id: Option<SessionId>) {
+    TEACHERS.get(session_id.unwrap_or(DEFAULT_SESSION), |teacher| teacher.resume());
+}
+
+#[tauri::command]
+pub fn is_teacher_paused(session_id: Option<SessionId>) -> bool {
+    TEACHERS.get(session_id.unwrap_or(DEFAULT_SESSION), |teacher| teacher.is_paused()).unwrap_or(false)
+}
+
+/// Change the running teacher's settings without dropping students,
+/// see `BroadcastSession::update_config` for what applies live
+#[tauri::command]
+pub fn update_teacher_config(config: StreamConfig, session_id: Option<SessionId>) -> Result<(), String> {
+    TEACHERS.get(session_id.unwrap_or(DEFAULT_SESSION), |teacher| {
+        if !teacher.is_running() {
+            return Err("Not broadcasting".into());
+        }
+        teacher.update_config(config).map_err(|e| e.to_string())
+    }).unwrap_or_else(|| Err("Not broadcasting".into()))
+}
+
+/// Broadcast only `region` of the screen, or all of it with `None`
+#[tauri::command]
+pub fn set_capture_region(region: Option<CaptureRect>, session_id: Option<SessionId>) -> Result<(), String> {
+    TEACHERS.get(session_id.unwrap_or(DEFAULT_SESSION), |teacher| {
+        teacher.set_capture_region(region).map_err(|e| e.to_string())
+    }).unwrap_or_else(|| Err("Not broadcasting".into()))
 }
 
 #[tauri::command]
-pub fn stop_teacher() {
-    *TEACHER_RUNNING.lock() = false;
-    log_msg("Stopping teacher...");
+pub fn stop_teacher(session_id: Option<SessionId>) {
+    log_info("teacher", "Stopping teacher...");
+    TEACHERS.stop(session_id.unwrap_or(DEFAULT_SESSION));
 }
 
 #[tauri::command]
-pub fn is_teacher_running() -> bool {
-    *TEACHER_RUNNING.lock()
+pub fn is_teacher_running(session_id: Option<SessionId>) -> bool {
+    TEACHERS.get(session_id.unwrap_or(DEFAULT_SESSION), |teacher| teacher.is_running()).unwrap_or(false)
 }
 
 // ============ Student Commands ============
 
+/// Start receiving, returns the session id.
+/// With `frame_channel`, frames go over it as binary (see `binary_frame`) instead of
+/// as base64 "video-frame-jpeg" events.
+#[tauri::command]
+pub async fn start_student(
+    app: AppHandle,
+    config: StreamConfig,
+    session_id: Option<SessionId>,
+    frame_channel: Option<Channel>,
+) -> Result<SessionId, String> {
+    start_receiving(app, session_id.unwrap_or(DEFAULT_SESSION), config, None, frame_channel)
+}
+
+/// Watch one discovered teacher: uses its stream port and ignores packets from other senders.
+/// `config` provides the remaining settings (mode, interface...), defaults if omitted.
 #[tauri::command]
-pub async fn start_student(app: AppHandle, config: StreamConfig) -> Result<(), String> {
-    if *STUDENT_RUNNING.lock() {
+pub async fn connect_to_teacher(
+    app: AppHandle,
+    peer_id: String,
+    config: Option<StreamConfig>,
+    session_id: Option<SessionId>,
+    frame_channel: Option<Channel>,
+) -> Result<SessionId, String> {
+    let id = session_id.unwrap_or(DEFAULT_SESSION);
+    if STUDENTS.get(id, |student| student.is_running()).unwrap_or(false) {
         return Err("Already receiving".into());
     }
     
-    *STUDENT_RUNNING.lock() = true;
-    
-    let running = STUDENT_RUNNING.clone();
-    
-    thread::spawn(move || {
-        if let Err(e) = run_student(running, config, app) {
-            log_msg(&format!("Student error: {}", e));
-        }
-    });
+    let teacher = find_teacher(&peer_id).map_err(|e| e.to_string())?;
+    let config = teacher_stream_config(&teacher, config.unwrap_or_default()).map_err(|e| e.to_string())?;
     
-    Ok(())
+    log_info("student", &format!("Connecting to teacher {} at {}:{}", teacher.name, teacher.ip, teacher.stream_port));
+    start_receiving(app, id, config, Some(teacher), frame_channel)
 }
 
-fn run_student(running: Arc<Mutex<bool>>, config: StreamConfig, app: AppHandle) -> Result<(), BroadcastError> {
-    log_msg(&format!("Starting student: {:?} mode, port {}", config.network_mode, config.port));
-    
-    // Initialize RTP receiver
-    let mut receiver = RtpReceiver::new(config.port, config.network_mode)?;
-    log_msg("RTP receiver ready");
-    
-    // Initialize decoder
-    let mut decoder = H264Decoder::new()?;
-    log_msg("Decoder ready");
-    
-    let mut last_log = Instant::now();
+/// Why a discovered teacher could not be watched, sent to the UI as `{ kind, detail }`
+#[derive(Debug, Serialize, thiserror::Error)]
+#[serde(tag = "kind", content = "detail")]
+pub enum TeacherError {
+    #[error("Discovery is not running")]
+    DiscoveryStopped,
+    #[error("Teacher {0} not found")]
+    NotFound(String),
+    /// Known, but no announce heard recently
+    #[error("Teacher {0} is offline")]
+    Offline(String),
+    #[error("Teacher {0} runs an incompatible version")]
+    VersionMismatch(String),
+    #[error("Teacher has no IPv4 address: {0}")]
+    BadAddress(String),
+    #[error("{0}")]
+    StartFailed(String),
+}
+
+/// A teacher known to discovery that speaks our protocol; it may be `stale`
+fn find_teacher(peer_id: &str) -> Result<PeerInfo, TeacherError> {
+    let teacher = DISCOVERY.lock()
+        .as_ref()
+        .ok_or(TeacherError::DiscoveryStopped)?
+        .get_peers()
+        .into_iter()
+        .find(|p| p.id == peer_id && p.role == PeerRole::Teacher)
+        .ok_or_else(|| TeacherError::NotFound(peer_id.to_string()))?;
+    if teacher.protocol_mismatch {
+        return Err(TeacherError::VersionMismatch(teacher.name));
+    }
+    Ok(teacher)
+}
+
+/// `config` pointed at `teacher`'s stream: its port, and only packets from its address
+fn teacher_stream_config(teacher: &PeerInfo, config: StreamConfig) -> Result<StreamConfig, TeacherError> {
+    let source = teacher.ip.parse()
+        .map_err(|_| TeacherError::BadAddress(teacher.ip.clone()))?;
+    Ok(StreamConfig {
+        port: teacher.stream_port,
+        source: Some(source),
+        ..config
+    })
+}
+
+/// Start the student session, forwarding its events to the frontend.
+/// Stops with "stream-ended" when the teacher sends its end-of-stream BYE, or, with
+/// `teacher` set, once that teacher has dropped out of discovery and its frames have stopped too.
+/// The event carries `teacher` (null without one).
+fn start_receiving(
+    app: AppHandle,
+    id: SessionId,
+    config: StreamConfig,
+    teacher: Option<PeerInfo>,
+    frame_channel: Option<Channel>,
+) -> Result<SessionId, String> {
     let mut frames_received = 0u64;
-    let mut waiting_for_keyframe = true;
     let mut last_frame_time = Instant::now();
+    let mut last_teacher_check = Instant::now();
+    // Packet totals at the last loss report to the teacher
+    let mut reported = (0u64, 0u64);
+    let port = config.port;
+    let stall_timeout = Duration::from_millis(config.stall_timeout_ms);
+    let dedupe_frames = config.dedupe_frames;
+    let display_size = (config.display_width, config.display_height);
+    let mut last_emitted: Option<(u64, Instant)> = None;
     
-    log_msg("Waiting for stream...");
-    
-    while *running.lock() {
-        match receiver.receive_frame() {
-            Ok(Some(h264_frame)) => {
-                // Check for keyframe (IDR NAL type = 5)
-                let is_keyframe = h264_frame.windows(5).any(|w| {
-                    (w[0] == 0 && w[1] == 0 && w[2] == 0 && w[3] == 1 && (w[4] & 0x1F) == 5) ||
-                    (w[0] == 0 && w[1] == 0 && w[2] == 1 && (w[3] & 0x1F) == 5)
-                });
+    let on_event = move |event: ReceiveEvent| {
+        match event {
+            ReceiveEvent::Log(level, msg) => log_record(level, "student", &msg),
+            ReceiveEvent::Frame(frame) => {
+                frames_received += 1;
+                let frame_time = last_frame_time.elapsed();
+                last_frame_time = Instant::now();
                 
-                if waiting_for_keyframe {
-                    if is_keyframe {
-                        log_msg("Got keyframe, starting decode");
-                        waiting_for_keyframe = false;
-                    } else {
-                        continue;
+                // Static screen: skip the JPEG encode and IPC for a frame the UI already shows
+                if dedupe_frames {
+                    let hash = frame.content_hash();
+                    if last_emitted.is_some_and(|(last, at)| last == hash && at.elapsed() < DEDUPE_HEARTBEAT_INTERVAL) {
+                        return ControlFlow::Continue(());
                     }
+                    last_emitted = Some((hash, Instant::now()));
                 }
                 
-                // Decode
-                match decoder.decode(&h264_frame) {
-                    Ok(Some(frame)) => {
-                        frames_received += 1;
-                        
-                        if frames_received == 1 {
-                            log_msg(&format!("First frame decoded! {}x{}", frame.width, frame.height));
-                        }
-                        
-                        // Calculate actual FPS
-                        let frame_time = last_frame_time.elapsed();
-                        last_frame_time = Instant::now();
-                        
-                        // OPTIMIZED: Encode as JPEG instead of raw RGBA
-                        // This reduces data from ~8MB to ~50-100KB per frame!
-                        let jpeg_data = encode_rgba_to_jpeg(&frame.rgba_data, frame.width, frame.height, 75);
-                        
-                        let frame_data = JpegFrameData {
-                            width: frame.width,
-                            height: frame.height,
+                // OPTIMIZED: Encode as JPEG instead of raw RGBA
+                // This reduces data from ~8MB to ~50-100KB per frame!
+                let (width, height) = session::fit_within(frame.width, frame.height, display_size.0, display_size.1);
+                let jpeg_data = if (width, height) == (frame.width, frame.height) {
+                    encode_rgba_to_jpeg(&frame.rgba_data, width, height, 75)
+                } else {
+                    // Smaller canvas: fewer pixels to JPEG encode and send
+                    let scaled = scale_rgba(&frame.rgba_data, frame.width as usize, frame.height as usize,
+                        width as usize, height as usize);
+                    encode_rgba_to_jpeg(&scaled, width, height, 75)
+                };
+                
+                let result = match frame_channel {
+                    // Raw bytes skip the base64 encode here and the decode in the webview
+                    Some(ref channel) => channel
+                        .send(InvokeResponseBody::Raw(binary_frame(id, width, height, &jpeg_data)))
+                        .map_err(|e| e.to_string()),
+                    None => app
+                        .emit("video-frame-jpeg", &JpegFrameData {
+                            session_id: id,
+                            width,
+                            height,
                             jpeg: BASE64.encode(&jpeg_data),
-                        };
-                        
-                        if let Err(e) = app.emit("video-frame-jpeg", &frame_data) {
-                            log_msg(&format!("Emit error: {}", e));
-                        }
-                        
-                        if frames_received % 30 == 0 {
-                            let fps = 1000.0 / frame_time.as_millis().max(1) as f32;
-                            log_msg(&format!("Decoded {} frames, ~{:.1} fps, jpeg={}KB", 
-                                frames_received, fps, jpeg_data.len() / 1024));
-                        }
-                    }
-                    Ok(None) => {
-                        // Decoder needs more data or returned no frame
-                        if frames_received == 0 {
-                            log::debug!("Decoder returned None (needs more data)");
-                        }
-                    }
-                    Err(e) => {
-                        if frames_received == 0 {
-                            log_msg(&format!("Decode error (waiting for keyframe): {}", e));
-                        } else {
-                            log::warn!("Decode error: {}", e);
+                        })
+                        .map_err(|e| e.to_string()),
+                };
+                if let Err(e) = result {
+                    log_warn("student", &format!("Emit error: {}", e));
+                }
+                
+                if frames_received % 30 == 0 {
+                    let fps = 1000.0 / frame_time.as_millis().max(1) as f32;
+                    log_info("student", &format!("Decoded {} frames, ~{:.1} fps, jpeg={}KB", 
+                        frames_received, fps, jpeg_data.len() / 1024));
+                }
+            }
+            ReceiveEvent::Stats(stats) => {
+                let _ = app.emit("student-stats", &stats);
+                // Report the loss since the last report so an adaptive teacher can step down
+                if let Some(ref teacher) = teacher {
+                    let received = stats.packets_received.saturating_sub(reported.0);
+                    let lost = stats.packets_lost.saturating_sub(reported.1);
+                    if received + lost > 0 {
+                        let loss = lost as f32 / (received + lost) as f32;
+                        if let Some(d) = DISCOVERY.try_lock().as_ref().and_then(|d| d.as_ref()) {
+                            let _ = d.report_loss(&teacher.id, port, loss);
                         }
-                        waiting_for_keyframe = true;
                     }
+                    reported = (stats.packets_received, stats.packets_lost);
                 }
             }
-            Ok(None) => {
-                // No frame yet
-                if last_log.elapsed() >= Duration::from_secs(5) && frames_received == 0 {
-                    log_msg("No frames received yet...");
-                    last_log = Instant::now();
-                }
-                // Small sleep to prevent busy loop
-                thread::sleep(Duration::from_millis(1));
+            ReceiveEvent::FellBack(mode) => {
+                let event = match mode {
+                    NetworkMode::Broadcast => "fell-back-to-broadcast",
+                    NetworkMode::Multicast => "fell-back-to-multicast",
+                };
+                let _ = app.emit(event, mode);
+            }
+            ReceiveEvent::Reconnecting(reconnecting) => {
+                let _ = app.emit("student-reconnecting", reconnecting);
             }
-            Err(e) => {
-                log_msg(&format!("Receive error: {}", e));
-                thread::sleep(Duration::from_millis(10));
+            ReceiveEvent::Stalled(stalled) => {
+                let _ = app.emit("stream-stalled", stalled);
+            }
+            ReceiveEvent::Ended => {
+                log_info("student", "Teacher ended the stream, stopping");
+                let _ = app.emit("stream-ended", &teacher);
+                return ControlFlow::Break(());
             }
         }
-    }
+        
+        // Stop when the watched teacher drops out of discovery and its frames have stopped,
+        // so a lost announce or a short network blip does not end a live stream
+        if let Some(ref teacher) = teacher {
+            if last_teacher_check.elapsed() >= TEACHER_CHECK_INTERVAL {
+                last_teacher_check = Instant::now();
+                let present = DISCOVERY.lock()
+                    .as_ref()
+                    .map_or(true, |d| d.get_teachers().iter().any(|t| t.id == teacher.id));
+                if !present && last_frame_time.elapsed() >= stall_timeout {
+                    log_warn("student", &format!("Teacher {} disappeared, stopping", teacher.name));
+                    let _ = app.emit("stream-ended", teacher);
+                    return ControlFlow::Break(());
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    };
     
-    log_msg(&format!("Receiving stopped. Total frames: {}", frames_received));
-    Ok(())
+    STUDENTS.with(id, |student| {
+        if student.is_running() {
+            return Err("Already receiving".into());
+        }
+        student.start(config, on_event).map_err(|e| e.to_string())
+    })?;
+    Ok(id)
 }
 
 #[tauri::command]
-pub fn stop_student() {
-    *STUDENT_RUNNING.lock() = false;
-    log_msg("Stopping student...");
+pub fn stop_student(session_id: Option<SessionId>) {
+    log_info("student", "Stopping student...");
+    STUDENTS.stop(session_id.unwrap_or(DEFAULT_SESSION));
 }
 
 #[tauri::command]
-pub fn is_student_running() -> bool {
-    *STUDENT_RUNNING.lock()
+pub fn is_student_running(session_id: Option<SessionId>) -> bool {
+    STUDENTS.get(session_id.unwrap_or(DEFAULT_SESSION), |student| student.is_running()).unwrap_or(false)
 }
 
 // ============ Helpers ============
 
 #[derive(Clone, serde::Serialize)]
 struct JpegFrameData {
+    session_id: SessionId,
     width: u32,
     height: u32,
     jpeg: String, // base64 encoded JPEG
 }
 
+/// Size of the `binary_frame` header
+const BINARY_FRAME_HEADER: usize = 12;
+
+/// Frame layout on the binary channel: session id, width and height as little endian
+/// u32s, then the JPEG bytes
+fn binary_frame(session_id: SessionId, width: u32, height: u32, jpeg: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(BINARY_FRAME_HEADER + jpeg.len());
+    data.extend_from_slice(&session_id.to_le_bytes());
+    data.extend_from_slice(&width.to_le_bytes());
+    data.extend_from_slice(&height.to_le_bytes());
+    data.extend_from_slice(jpeg);
+    data
+}
+
 /// Encode RGBA to JPEG - much smaller than raw RGBA
 fn encode_rgba_to_jpeg(rgba: &[u8], width: u32, height: u32, quality: u8) -> Vec<u8> {
     use image::{ImageBuffer, Rgba, ImageEncoder};
@@ -466,24 +848,61 @@ fn encode_rgba_to_jpeg(rgba: &[u8], width: u32, height: u32, quality: u8) -> Vec
     jpeg_data
 }
 
-fn calculate_bitrate(width: u32, height: u32, fps: u32, quality: u32) -> u32 {
-    let pixels = width * height;
-    let base = match pixels {
-        p if p <= 921600 => 1500,   // 720p
-        p if p <= 2073600 => 3000,  // 1080p
-        _ => 5000,
-    };
+// ============ Native Viewer Commands (Ultra Low Latency) ============
+
+/// Watch a teacher in a native window. `jitter_buffer_ms` delays frames by that much to
+/// present them evenly on jittery networks (None or 0 = show them as soon as they decode)
+#[tauri::command]
+pub fn start_native_viewer(app: AppHandle, config: StreamConfig, jitter_buffer_ms: Option<u32>) -> Result<(), String> {
+    start_viewer(app, config, jitter_buffer_ms)
+}
+
+/// Watch a teacher found by discovery in a native window, on its port and address;
+/// `config` supplies the other settings (defaults when None)
+#[tauri::command]
+pub fn start_native_viewer_for_peer(
+    app: AppHandle,
+    peer_id: String,
+    config: Option<StreamConfig>,
+    jitter_buffer_ms: Option<u32>,
+) -> Result<(), TeacherError> {
+    let teacher = find_teacher(&peer_id)?;
+    // A window waiting on a teacher that is not there would just stay black
+    if teacher.stale {
+        return Err(TeacherError::Offline(teacher.name));
+    }
+    let config = teacher_stream_config(&teacher, config.unwrap_or_default())?;
+    log_info("viewer", &format!("Viewing teacher {} at {}:{}", teacher.name, teacher.ip, teacher.stream_port));
+    start_viewer(app, config, jitter_buffer_ms).map_err(TeacherError::StartFailed)
+}
+
+fn start_viewer(app: AppHandle, config: StreamConfig, jitter_buffer_ms: Option<u32>) -> Result<(), String> {
+    let mut viewer_guard = NATIVE_VIEWER.lock();
+    
+    if let Some(ref viewer) = *viewer_guard {
+        if viewer.is_running() {
+            return Err("Native viewer already running".into());
+        }
+    }
+    
+    config.validate().map_err(|e| e.to_string())?;
+    
+    let mut viewer = NativeViewer::new();
+    viewer.set_jitter_buffer_ms(jitter_buffer_ms.unwrap_or(0));
+    viewer.on_stats(move |stats| {
+        let _ = app.emit("native-viewer-stats", stats);
+    });
+    viewer.start(config).map_err(|e| e.to_string())?;
     
-    let fps_factor = fps as f32 / 30.0;
-    let quality_factor = 1.0 - (quality as f32 - 20.0) / 60.0;
+    *viewer_guard = Some(viewer);
+    log_info("viewer", "Native viewer started - ultra low latency mode");
     
-    (base as f32 * fps_factor * quality_factor.max(0.3)) as u32
+    Ok(())
 }
 
-// ============ Native Viewer Commands (Ultra Low Latency) ============
-
+/// Watch several teachers at once, tiled in a single native window
 #[tauri::command]
-pub fn start_native_viewer(config: StreamConfig) -> Result<(), String> {
+pub fn start_native_viewer_grid(app: AppHandle, streams: Vec<(String, StreamConfig)>, jitter_buffer_ms: Option<u32>) -> Result<(), String> {
     let mut viewer_guard = NATIVE_VIEWER.lock();
     
     if let Some(ref viewer) = *viewer_guard {
@@ -492,11 +911,20 @@ pub fn start_native_viewer(config: StreamConfig) -> Result<(), String> {
         }
     }
     
+    for (label, config) in &streams {
+        config.validate().map_err(|e| format!("{}: {}", label, e))?;
+    }
+    
+    let count = streams.len();
     let mut viewer = NativeViewer::new();
-    viewer.start(config).map_err(|e| e.to_string())?;
+    viewer.set_jitter_buffer_ms(jitter_buffer_ms.unwrap_or(0));
+    viewer.on_stats(move |stats| {
+        let _ = app.emit("native-viewer-stats", stats);
+    });
+    viewer.start_grid(streams).map_err(|e| e.to_string())?;
     
     *viewer_guard = Some(viewer);
-    log_msg("Native viewer started - ultra low latency mode");
+    log_info("viewer", &format!("Native viewer started - {} streams", count));
     
     Ok(())
 }
@@ -506,11 +934,24 @@ pub fn stop_native_viewer() {
     let mut viewer_guard = NATIVE_VIEWER.lock();
     if let Some(ref mut viewer) = *viewer_guard {
         viewer.stop();
-        log_msg("Native viewer stopped");
+        log_info("viewer", "Native viewer stopped");
     }
     *viewer_guard = None;
 }
 
+/// Stop every session, the native viewer and discovery, waiting for their threads.
+/// Called when the app exits so sockets and the capturer are released cleanly.
+pub fn shutdown() {
+    TEACHERS.stop_all();
+    STUDENTS.stop_all();
+    if let Some(mut viewer) = NATIVE_VIEWER.lock().take() {
+        viewer.stop();
+    }
+    if let Some(mut service) = DISCOVERY.lock().take() {
+        service.stop();
+    }
+}
+
 #[tauri::command]
 pub fn is_native_viewer_running() -> bool {
     if let Some(ref viewer) = *NATIVE_VIEWER.lock() {
@@ -518,3 +959,87 @@ pub fn is_native_viewer_running() -> bool {
     }
     false
 }
+
+/// Point the open native viewer (cell `index`, default 0) at another stream, keeping the window
+#[tauri::command]
+pub fn switch_native_viewer_stream(config: StreamConfig, index: Option<usize>) -> Result<(), String> {
+    let mut viewer_guard = NATIVE_VIEWER.lock();
+    let viewer = viewer_guard.as_mut()
+        .filter(|viewer| viewer.is_running())
+        .ok_or("Native viewer not running")?;
+    viewer.switch_stream(index.unwrap_or(0), config).map_err(|e| e.to_string())?;
+    log_info("viewer", "Native viewer switched stream");
+    Ok(())
+}
+
+/// Native viewer statistics of the last full second, like "student-stats" for the JS view;
+/// also sent every second as "native-viewer-stats"
+#[tauri::command]
+pub fn get_native_viewer_stats() -> Option<ViewerStats> {
+    NATIVE_VIEWER.lock().as_ref()
+        .filter(|viewer| viewer.is_running())
+        .map(NativeViewer::stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            timestamp: "12:00:00".into(),
+            level: LogLevel::Info,
+            category: "app",
+            message: message.into(),
+        }
+    }
+
+    fn messages(logs: &VecDeque<LogRecord>) -> Vec<&str> {
+        logs.iter().map(|r| r.message.as_str()).collect()
+    }
+
+    #[test]
+    fn trimming_keeps_the_newest_records_in_order() {
+        let mut logs = VecDeque::new();
+        for i in 0..5 {
+            logs.push_back(record(&i.to_string()));
+            trim_logs(&mut logs, 3);
+        }
+        assert_eq!(messages(&logs), ["2", "3", "4"]);
+        
+        // A smaller capacity drops from the front; a larger one drops nothing
+        trim_logs(&mut logs, 2);
+        assert_eq!(messages(&logs), ["3", "4"]);
+        trim_logs(&mut logs, 10);
+        assert_eq!(messages(&logs), ["3", "4"]);
+        assert_eq!(logs[1].to_text(), "[12:00:00] 4");
+    }
+
+    #[test]
+    fn log_file_rotates_and_drops_the_oldest() {
+        let dir = std::env::temp_dir().join(format!("screenshare-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+        
+        // 8-byte lines and a 20-byte limit: two lines per file
+        let (tx, rx) = bounded(16);
+        for i in 0..8 {
+            tx.send(format!("line {:02}\n", i)).unwrap();
+        }
+        drop(tx);
+        write_log_file(path.clone(), 20, open_append(&path).unwrap(), rx);
+        
+        let read = |n| fs::read_to_string(if n == 0 { path.clone() } else { rotated_path(&path, n) }).unwrap();
+        assert_eq!(read(0), "line 06\nline 07\n");
+        assert_eq!(read(1), "line 04\nline 05\n");
+        assert_eq!(read(2), "line 02\nline 03\n");
+        assert!(!rotated_path(&path, 3).exists());
+        
+        // Rotating again shifts every file down; the oldest, lines 02-03, is gone
+        rotate_log_files(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(read(1), "line 06\nline 07\n");
+        assert_eq!(read(2), "line 04\nline 05\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-mod broadcast;
+pub mod broadcast;
 mod commands;
 
 use commands::*;
@@ -9,11 +9,21 @@ pub fn run() {
     
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
+        .setup(|app| {
+            init_log_events(app.handle().clone());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             // Config
             get_default_config,
+            list_interfaces,
             get_logs,
+            get_logs_text,
+            set_log_capacity,
             clear_logs,
+            set_log_file,
+            set_frame_hash_logging,
+            run_self_test,
             // Discovery
             start_discovery,
             stop_discovery,
@@ -21,19 +31,37 @@ pub fn run() {
             discovery_query,
             get_discovered_peers,
             get_teachers,
+            discovery_ping,
+            get_peer_rtt,
             // Teacher
             start_teacher,
+            start_teacher_from_file,
             stop_teacher,
+            pause_teacher,
+            update_teacher_config,
+            set_capture_region,
+            resume_teacher,
+            is_teacher_paused,
             is_teacher_running,
             // Student (JS rendering - slower)
             start_student,
+            connect_to_teacher,
             stop_student,
             is_student_running,
             // Native Viewer (ultra low latency)
             start_native_viewer,
+            start_native_viewer_for_peer,
+            start_native_viewer_grid,
             stop_native_viewer,
             is_native_viewer_running,
+            switch_native_viewer_stream,
+            get_native_viewer_stats,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|_app, event| {
+            if let tauri::RunEvent::Exit = event {
+                shutdown();
+            }
+        });
 }
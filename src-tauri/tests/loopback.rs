@@ -0,0 +1,117 @@
+//! Sender -> receiver over a real UDP socket on localhost: packetize, FU-A fragmentation,
+//! markers and depacketize, checked byte for byte. No screen or encoder involved.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use screenshare_udp_native_lib::broadcast::{RtpReceiver, RtpSender, StreamConfig};
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Receiver on an OS-assigned port and a sender aimed at it
+fn loopback_pair() -> (RtpSender, RtpReceiver) {
+    let config = StreamConfig::default();
+    let receiver = RtpReceiver::new(&StreamConfig { port: 0, ..config.clone() }).expect("bind receiver");
+    let port = receiver.local_addr().expect("receiver address").port();
+    let mut sender = RtpSender::new(&config).expect("open sender");
+    sender.set_target(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+    (sender, receiver)
+}
+
+fn receive(receiver: &mut RtpReceiver) -> Vec<u8> {
+    let start = Instant::now();
+    while start.elapsed() < RECEIVE_TIMEOUT {
+        if let Some(frame) = receiver.receive_frame().expect("receive") {
+            return frame;
+        }
+    }
+    panic!("no frame within {:?}", RECEIVE_TIMEOUT);
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).expect("clock after 1970").as_millis() as u64
+}
+
+/// NAL unit with a 4-byte start code; the payload never contains zero bytes, so it
+/// cannot emulate a start code
+fn nal(header: u8, len: usize) -> Vec<u8> {
+    let mut nal = vec![0, 0, 0, 1, header];
+    nal.extend((0..len).map(|i| (i % 251) as u8 + 1));
+    nal
+}
+
+/// SPS, PPS and an IDR slice of `slice_len` bytes
+fn keyframe(slice_len: usize) -> Vec<u8> {
+    [nal(0x67, 12), nal(0x68, 4), nal(0x65, slice_len)].concat()
+}
+
+#[test]
+fn small_frame_round_trips() {
+    let (mut sender, mut receiver) = loopback_pair();
+    let frame = keyframe(200);
+
+    sender.send_frame(&frame).expect("send");
+    assert_eq!(receive(&mut receiver), frame);
+}
+
+#[test]
+fn fragmented_frame_round_trips() {
+    let (mut sender, mut receiver) = loopback_pair();
+    // Far over one MTU, so the slice goes out as FU-A fragments
+    let frame = keyframe(100_000);
+
+    sender.send_frame(&frame).expect("send");
+    assert_eq!(receive(&mut receiver), frame);
+}
+
+#[test]
+fn consecutive_frames_stay_separate() {
+    let (mut sender, mut receiver) = loopback_pair();
+    let frames = [keyframe(5_000), nal(0x41, 300), nal(0x41, 20_000), nal(0x41, 50)];
+
+    for frame in &frames {
+        sender.send_frame(frame).expect("send");
+        assert_eq!(&receive(&mut receiver), frame);
+    }
+}
+
+#[test]
+fn bye_ends_the_stream() {
+    let (mut sender, mut receiver) = loopback_pair();
+    let frame = keyframe(200);
+    sender.send_frame(&frame).expect("send");
+    assert_eq!(receive(&mut receiver), frame);
+    assert!(!receiver.stream_ended());
+
+    sender.send_bye().expect("send bye");
+    let start = Instant::now();
+    while !receiver.stream_ended() && start.elapsed() < RECEIVE_TIMEOUT {
+        assert_eq!(receiver.receive_frame().expect("receive"), None);
+    }
+    assert!(receiver.stream_ended());
+
+    // A restarted teacher's media clears it again
+    sender.send_frame(&frame).expect("send");
+    assert_eq!(receive(&mut receiver), frame);
+    assert!(!receiver.stream_ended());
+}
+
+#[test]
+fn capture_time_gives_near_zero_latency() {
+    let (mut sender, mut receiver) = loopback_pair();
+    let frame = keyframe(200);
+
+    // Stamped at send time by default: on one machine the latency is the loopback hop
+    sender.send_frame(&frame).expect("send");
+    assert_eq!(receive(&mut receiver), frame);
+    let capture_ms = receiver.last_frame_capture_ms().expect("capture time");
+    let latency_ms = unix_ms(SystemTime::now()) as i64 - capture_ms as i64;
+    assert!((0..100).contains(&latency_ms), "{} ms", latency_ms);
+
+    // An explicit capture time is carried as it is
+    let captured = SystemTime::now() - Duration::from_millis(250);
+    sender.set_capture_time(captured);
+    sender.send_frame(&frame).expect("send");
+    assert_eq!(receive(&mut receiver), frame);
+    assert_eq!(receiver.last_frame_capture_ms(), Some(unix_ms(captured)));
+}
@@ -1,5 +1,5 @@
 import { useState, useEffect, useRef } from "react";
-import { invoke } from "@tauri-apps/api/core";
+import { invoke, Channel } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 
@@ -7,7 +7,15 @@ interface StreamConfig {
   port: number;
   fps: number;
   quality: number;
+  rate_control?: { Bitrate: number } | { Quality: number };
   network_mode: "Multicast" | "Broadcast";
+  interface: string | null;
+  dedupe_frames?: boolean;
+}
+
+interface NetworkInterface {
+  name: string;
+  ip: string;
 }
 
 interface StreamStats {
@@ -17,14 +25,64 @@ interface StreamStats {
   packets_sent: number;
   packets_lost: number;
   latency_ms: number;
+  cpu_usage: number;
+  gpu_usage?: number | null;
+  capture_ms?: number;
+  encode_ms?: number;
+  send_ms?: number;
+  dropped_frames?: number;
+}
+
+interface StudentStats {
+  fps: number;
+  frames_decoded: number;
+  packets_received: number;
+  packets_lost: number;
+  packets_recovered: number;
+  jitter_ms: number;
+  latency_ms: number;
+}
+
+interface SelfTestReport {
+  passed: boolean;
+  failed_stage: string | null;
+  stages: { name: string; ok: boolean; duration_ms: number; detail: string }[];
 }
 
+type LogLevel = "info" | "warn" | "error";
+
+interface LogRecord {
+  timestamp: string;
+  level: LogLevel;
+  category: string;
+  message: string;
+}
+
+// Matches the backend's default log capacity
+const MAX_LOGS = 1000;
+
 interface PeerInfo {
   id: string;
   name: string;
   role: "Teacher" | "Student";
   ip: string;
   stream_port: number;
+  // Decode limits, absent for peers without any
+  max_width?: number | null;
+  max_height?: number | null;
+  max_fps?: number | null;
+  // Known from a previous run, not seen on the network yet
+  stale?: boolean;
+  protocol_mismatch?: boolean;
+  last_seen?: number;
+}
+
+/** "max 1280x720 @ 30 fps" style summary of a peer's decode limits, or null */
+function peerLimits(p: PeerInfo): string | null {
+  const size = p.max_width || p.max_height ? `${p.max_width ?? "any"}x${p.max_height ?? "any"}` : null;
+  const fps = p.max_fps ? `${p.max_fps} fps` : null;
+  if (!size && !fps) return null;
+  return "max " + [size, fps].filter(Boolean).join(" @ ");
 }
 
 interface JpegFrameData {
@@ -33,6 +91,9 @@ interface JpegFrameData {
   jpeg: string; // base64 encoded JPEG
 }
 
+/** Header bytes before the JPEG in a binary frame */
+const BINARY_FRAME_HEADER = 12;
+
 type AppMode = "select" | "teacher" | "student";
 
 function App() {
@@ -40,10 +101,16 @@ function App() {
   const [config, setConfig] = useState<StreamConfig | null>(null);
   const [isRunning, setIsRunning] = useState(false);
   const [stats, setStats] = useState<StreamStats | null>(null);
-  const [logs, setLogs] = useState<string[]>([]);
+  const [logs, setLogs] = useState<LogRecord[]>([]);
   const [peers, setPeers] = useState<PeerInfo[]>([]);
   const [deviceName, setDeviceName] = useState("My Device");
   const [frameCount, setFrameCount] = useState(0);
+  const [isPaused, setIsPaused] = useState(false);
+  const [studentStats, setStudentStats] = useState<StudentStats | null>(null);
+  const [isStalled, setIsStalled] = useState(false);
+  const [selfTest, setSelfTest] = useState<SelfTestReport | null>(null);
+  const [selfTestRunning, setSelfTestRunning] = useState(false);
+  const [interfaces, setInterfaces] = useState<NetworkInterface[]>([]);
   
   const canvasRef = useRef<HTMLCanvasElement>(null);
   const ctxRef = useRef<CanvasRenderingContext2D | null>(null);
@@ -51,6 +118,7 @@ function App() {
   // Load config
   useEffect(() => {
     invoke<StreamConfig>("get_default_config").then(setConfig);
+    invoke<NetworkInterface[]>("list_interfaces").then(setInterfaces).catch(() => setInterfaces([]));
   }, []);
 
   // Setup canvas
@@ -60,14 +128,14 @@ function App() {
     }
   }, [mode]);
 
-  // Poll logs
+  // Load stored logs, then append new records as they are emitted
   useEffect(() => {
     if (mode === "select") return;
-    const interval = setInterval(async () => {
-      const newLogs = await invoke<string[]>("get_logs");
-      setLogs(newLogs);
-    }, 500);
-    return () => clearInterval(interval);
+    invoke<LogRecord[]>("get_logs").then(setLogs);
+    const unlisten = listen<LogRecord>("log-record", (e) => {
+      setLogs(prev => [...prev, e.payload].slice(-MAX_LOGS));
+    });
+    return () => { unlisten.then(fn => fn()); };
   }, [mode]);
 
   // Poll peers
@@ -80,14 +148,14 @@ function App() {
     return () => clearInterval(interval);
   }, [mode]);
 
-  // Discovery announce
+  // Students run discovery for the whole session so the teacher list stays live
   useEffect(() => {
-    if (mode === "select" || !isRunning) return;
-    const interval = setInterval(() => {
-      invoke("discovery_announce").catch(console.error);
-    }, 2000);
-    return () => clearInterval(interval);
-  }, [mode, isRunning]);
+    if (mode !== "student") return;
+    invoke("start_discovery", { name: deviceName, isTeacher: false, port: 0, options: { remember_peers: true } })
+      .then(() => invoke("discovery_query"))
+      .catch(console.error);
+    return () => { invoke("stop_discovery"); };
+  }, [mode]);
 
   // Listen for stats
   useEffect(() => {
@@ -96,21 +164,59 @@ function App() {
     return () => { unlisten.then(fn => fn()); };
   }, [mode, isRunning]);
 
+  useEffect(() => {
+    if (mode !== "student" || !isRunning) return;
+    const unlisten = listen<StudentStats>("student-stats", (e) => setStudentStats(e.payload));
+    return () => { unlisten.then(fn => fn()); };
+  }, [mode, isRunning]);
+
+  useEffect(() => {
+    if (mode !== "student" || !isRunning) return;
+    const unlisten = listen<PeerInfo | null>("stream-ended", (e) => {
+      setIsRunning(false);
+      alert(e.payload ? `Teacher ${e.payload.name} is no longer available` : "The teacher ended the stream");
+    });
+    return () => { unlisten.then(fn => fn()); };
+  }, [mode, isRunning]);
+
+  useEffect(() => {
+    if (mode !== "student" || !isRunning) return;
+    const unlisten = listen<boolean>("stream-stalled", (e) => setIsStalled(e.payload));
+    return () => { unlisten.then(fn => fn()); setIsStalled(false); };
+  }, [mode, isRunning]);
+
   // Listen for frames - optimized with JPEG decoding
   const pendingFrameRef = useRef<string | null>(null);
   const animationFrameRef = useRef<number | null>(null);
   const imgRef = useRef<HTMLImageElement | null>(null);
 
+  // Keep only the latest frame until the next animation frame draws it
+  const showFrame = (url: string) => {
+    const dropped = pendingFrameRef.current;
+    if (dropped?.startsWith("blob:")) URL.revokeObjectURL(dropped);
+    pendingFrameRef.current = url;
+    setFrameCount(c => c + 1);
+  };
+
+  // Binary frames: session id, width, height as little endian u32s, then the JPEG
+  const frameChannel = () => {
+    const channel = new Channel<ArrayBuffer>();
+    channel.onmessage = (buffer) => {
+      const jpeg = new Blob([new Uint8Array(buffer, BINARY_FRAME_HEADER)], { type: "image/jpeg" });
+      showFrame(URL.createObjectURL(jpeg));
+    };
+    return channel;
+  };
+
   useEffect(() => {
     if (mode !== "student" || !isRunning) return;
     
     // Create reusable Image object
     imgRef.current = new Image();
     
+    // Base64 fallback when frames are not sent over a channel
     const unlisten = listen<JpegFrameData>("video-frame-jpeg", (e) => {
-      // Store latest frame as data URL
-      pendingFrameRef.current = `data:image/jpeg;base64,${e.payload.jpeg}`;
-      setFrameCount(c => c + 1);
+      showFrame(`data:image/jpeg;base64,${e.payload.jpeg}`);
     });
     
     // Render loop using requestAnimationFrame for smooth 60fps
@@ -120,6 +226,7 @@ function App() {
         pendingFrameRef.current = null;
         
         imgRef.current.onload = () => {
+          if (dataUrl.startsWith("blob:")) URL.revokeObjectURL(dataUrl);
           const canvas = canvasRef.current;
           const ctx = ctxRef.current;
           if (!canvas || !ctx || !imgRef.current) return;
@@ -150,8 +257,14 @@ function App() {
   const startTeacher = async () => {
     if (!config) return;
     await invoke("clear_logs");
-    await invoke("start_discovery", { name: deviceName, isTeacher: true, port: config.port });
-    await invoke("start_teacher", { config });
+    await invoke("start_discovery", { name: deviceName, isTeacher: true, port: config.port, options: { remember_peers: true } });
+    try {
+      await invoke("start_teacher", { config });
+    } catch (e) {
+      await invoke("stop_discovery");
+      alert(`Cannot start: ${e}`);
+      return;
+    }
     setIsRunning(true);
   };
 
@@ -159,24 +272,69 @@ function App() {
     await invoke("stop_teacher");
     await invoke("stop_discovery");
     setIsRunning(false);
+    setIsPaused(false);
     setStats(null);
   };
 
+  // FPS and quality apply to a running broadcast without restarting it
+  const updateLiveConfig = (next: StreamConfig) => {
+    setConfig(next);
+    if (isRunning) {
+      invoke("update_teacher_config", { config: next }).catch(e => console.error(e));
+    }
+  };
+
+  // 0 = bitrate worked out from resolution, fps and quality
+  const pinnedKbps = config?.rate_control && "Bitrate" in config.rate_control ? config.rate_control.Bitrate : 0;
+
+  const togglePause = async () => {
+    await invoke(isPaused ? "resume_teacher" : "pause_teacher");
+    setIsPaused(!isPaused);
+  };
+
   const startStudent = async () => {
     if (!config) return;
     await invoke("clear_logs");
     setFrameCount(0);
-    await invoke("start_discovery", { name: deviceName, isTeacher: false, port: config.port });
-    await invoke("start_student", { config });
+    try {
+      await invoke("start_student", { config, frameChannel: frameChannel() });
+    } catch (e) {
+      alert(`Cannot start: ${e}`);
+      return;
+    }
+    setIsRunning(true);
+  };
+
+  // Watch one teacher from the discovery list
+  const connectToTeacher = async (teacher: PeerInfo) => {
+    if (!config) return;
+    await invoke("clear_logs");
+    setFrameCount(0);
+    try {
+      await invoke("connect_to_teacher", { peerId: teacher.id, config, frameChannel: frameChannel() });
+    } catch (e) {
+      alert(`Cannot connect: ${e}`);
+      return;
+    }
+    setConfig({...config, port: teacher.stream_port});
     setIsRunning(true);
   };
 
   const stopStudent = async () => {
     await invoke("stop_student");
-    await invoke("stop_discovery");
     setIsRunning(false);
   };
 
+  const runSelfTest = async () => {
+    setSelfTestRunning(true);
+    try {
+      setSelfTest(await invoke<SelfTestReport>("run_self_test"));
+    } catch (e) {
+      alert(`Self-test error: ${e}`);
+    }
+    setSelfTestRunning(false);
+  };
+
   // Mode Selection
   if (mode === "select") {
     return (
@@ -207,6 +365,20 @@ function App() {
             <span className="desc">View screen</span>
           </button>
         </div>
+
+        <div className="self-test">
+          <button onClick={runSelfTest} disabled={selfTestRunning}>
+            {selfTestRunning ? "Running self-test..." : "🩺 Run self-test"}
+          </button>
+          {selfTest && (
+            <pre className="self-test-report">
+              {`Self-test ${selfTest.passed ? "PASSED" : "FAILED"}\n`}
+              {selfTest.stages.map(s =>
+                `[${s.ok ? "ok" : "FAIL"}] ${s.name}: ${s.duration_ms.toFixed(1)} ms - ${s.detail}\n`
+              ).join("")}
+            </pre>
+          )}
+        </div>
       </div>
     );
   }
@@ -236,6 +408,19 @@ function App() {
                   <option value="Multicast">Multicast</option>
                 </select>
               </label>
+              <label>
+                Interface:
+                <select
+                  value={config.interface ?? ""}
+                  onChange={e => setConfig({...config, interface: e.target.value || null})}
+                  disabled={isRunning}
+                >
+                  <option value="">Auto</option>
+                  {interfaces.map(i => (
+                    <option key={i.ip} value={i.ip}>{i.name} ({i.ip})</option>
+                  ))}
+                </select>
+              </label>
               <label>
                 Port:
                 <input type="number" value={config.port} 
@@ -245,14 +430,17 @@ function App() {
               <label>
                 FPS: {config.fps}
                 <input type="range" min="5" max="30" value={config.fps}
-                  onChange={e => setConfig({...config, fps: parseInt(e.target.value)})}
-                  disabled={isRunning} />
+                  onChange={e => updateLiveConfig({...config, fps: parseInt(e.target.value)})} />
               </label>
               <label>
                 Quality: {config.quality}
                 <input type="range" min="18" max="40" value={config.quality}
-                  onChange={e => setConfig({...config, quality: parseInt(e.target.value)})}
-                  disabled={isRunning} />
+                  onChange={e => updateLiveConfig({...config, quality: parseInt(e.target.value)})} />
+              </label>
+              <label title="Pin the bitrate for a known link capacity; 0 derives it from resolution and quality">
+                Bitrate (kbps, 0 = auto):
+                <input type="number" min="0" step="500" value={pinnedKbps}
+                  onChange={e => updateLiveConfig({...config, rate_control: { Bitrate: Math.max(0, parseInt(e.target.value) || 0) }})} />
               </label>
             </div>
           </div>
@@ -262,7 +450,12 @@ function App() {
           {!isRunning ? (
             <button className="start-btn" onClick={startTeacher}>▶️ Start Broadcast</button>
           ) : (
-            <button className="stop-btn" onClick={stopTeacher}>⏹️ Stop</button>
+            <>
+              <button className="start-btn" onClick={togglePause}>
+                {isPaused ? "▶️ Resume" : "⏸️ Pause"}
+              </button>
+              <button className="stop-btn" onClick={stopTeacher}>⏹️ Stop</button>
+            </>
           )}
         </div>
 
@@ -273,6 +466,13 @@ function App() {
               <div className="stat"><span className="value">{stats.bitrate_kbps.toFixed(0)}</span><span className="label">Kbps</span></div>
               <div className="stat"><span className="value">{stats.frame_count}</span><span className="label">Frames</span></div>
               <div className="stat"><span className="value">{stats.latency_ms.toFixed(1)}</span><span className="label">ms</span></div>
+              <div className="stat"><span className="value">{stats.cpu_usage.toFixed(0)}%</span><span className="label">CPU</span></div>
+              {stats.gpu_usage != null && (
+                <div className="stat"><span className="value">{stats.gpu_usage.toFixed(0)}%</span><span className="label">GPU</span></div>
+              )}
+            </div>
+            <div className="latency-breakdown">
+              Capture {(stats.capture_ms ?? 0).toFixed(1)} ms · Encode {(stats.encode_ms ?? 0).toFixed(1)} ms · Send {(stats.send_ms ?? 0).toFixed(1)} ms · Dropped {stats.dropped_frames ?? 0}
             </div>
           </div>
         )}
@@ -284,6 +484,11 @@ function App() {
               {peers.filter(p => p.role === "Student").map(p => (
                 <div key={p.id} className="peer-item">
                   <span className="peer-name">{p.name}</span>
+                  {peerLimits(p) && (
+                    <span className={`peer-limits ${p.max_fps && config && config.fps > p.max_fps ? "peer-mismatch" : ""}`}>
+                      {peerLimits(p)}
+                    </span>
+                  )}
                   <span className="peer-ip">{p.ip}</span>
                 </div>
               ))}
@@ -305,6 +510,14 @@ function App() {
         </button>
         <h2>👨‍🎓 Student: {deviceName}</h2>
         {isRunning && <span className="frame-counter">Frames: {frameCount}</span>}
+        {isRunning && studentStats && (
+          <span className="frame-counter" title="Relative unless teacher and student clocks are synced">
+            Latency: {studentStats.latency_ms.toFixed(0)} ms
+          </span>
+        )}
+        {isRunning && isStalled && (
+          <span className="frame-counter stalled">Stream stalled, waiting for teacher...</span>
+        )}
       </header>
 
       {config && !isRunning && (
@@ -318,20 +531,38 @@ function App() {
                 <option value="Multicast">Multicast</option>
               </select>
             </label>
+            <label>
+              Interface:
+              <select value={config.interface ?? ""}
+                onChange={e => setConfig({...config, interface: e.target.value || null})}>
+                <option value="">Auto</option>
+                {interfaces.map(i => (
+                  <option key={i.ip} value={i.ip}>{i.name} ({i.ip})</option>
+                ))}
+              </select>
+            </label>
             <label>
               Port:
               <input type="number" value={config.port}
                 onChange={e => setConfig({...config, port: parseInt(e.target.value)})} />
             </label>
+            <label title="Skip redrawing frames identical to the previous one">
+              Skip unchanged frames:
+              <input type="checkbox" checked={config.dedupe_frames ?? false}
+                onChange={e => setConfig({...config, dedupe_frames: e.target.checked})} />
+            </label>
           </div>
           
           {peers.filter(p => p.role === "Teacher").length > 0 && (
             <div className="teachers-list">
               <h4>📡 Available Teachers:</h4>
               {peers.filter(p => p.role === "Teacher").map(t => (
-                <button key={t.id} className="teacher-btn"
-                  onClick={() => setConfig({...config, port: t.stream_port})}>
-                  {t.name} ({t.ip}:{t.stream_port})
+                <button key={t.id} className={`teacher-btn ${t.stale ? "peer-stale" : ""}`}
+                  onClick={() => connectToTeacher(t)}
+                  disabled={t.protocol_mismatch}
+                  title={t.protocol_mismatch ? "This teacher runs an incompatible version"
+                    : t.stale && t.last_seen ? `Last seen ${new Date(t.last_seen).toLocaleString()}` : undefined}>
+                  {t.name} ({t.ip}:{t.stream_port}){t.stale && " · offline?"}{t.protocol_mismatch && " · version mismatch"}
                 </button>
               ))}
             </div>
@@ -358,9 +589,13 @@ function App() {
   );
 }
 
-function LogPanel({ logs, onClear }: { logs: string[], onClear: () => void }) {
+function LogPanel({ logs, onClear }: { logs: LogRecord[], onClear: () => void }) {
   const containerRef = useRef<HTMLDivElement>(null);
   const [autoScroll, setAutoScroll] = useState(true);
+  const [minLevel, setMinLevel] = useState<LogLevel>("info");
+  
+  const levelRank: Record<LogLevel, number> = { info: 0, warn: 1, error: 2 };
+  const visible = logs.filter(log => levelRank[log.level] >= levelRank[minLevel]);
   
   useEffect(() => {
     if (autoScroll && containerRef.current) {
@@ -379,15 +614,26 @@ function LogPanel({ logs, onClear }: { logs: string[], onClear: () => void }) {
   return (
     <div className="log-panel">
       <div className="log-header">
-        <h3>📋 Logs ({logs.length})</h3>
-        <button onClick={onClear}>Clear</button>
+        <h3>📋 Logs ({visible.length})</h3>
+        <div className="log-actions">
+          <select value={minLevel} onChange={(e) => setMinLevel(e.target.value as LogLevel)}>
+            <option value="info">All</option>
+            <option value="warn">Warnings</option>
+            <option value="error">Errors</option>
+          </select>
+          <button onClick={onClear}>Clear</button>
+        </div>
       </div>
       <div 
         className="log-content" 
         ref={containerRef}
         onScroll={handleScroll}
       >
-        {logs.map((log, i) => <div key={i} className="log-line">{log}</div>)}
+        {visible.map((log, i) => (
+          <div key={i} className={`log-line log-${log.level}`}>
+            [{log.timestamp}] [{log.category}] {log.message}
+          </div>
+        ))}
       </div>
     </div>
   );